@@ -0,0 +1,535 @@
+//! Self-description of contracts, so that generic tools can talk to any of them.
+
+use alloc::string::String;
+use parity_scale_codec::{Decode, Encode};
+use scale_info::{
+    form::{Form, PortableForm},
+    meta_type, PortableRegistry, Registry, TypeInfo,
+};
+
+pub type AccountId = chain::AccountId;
+
+/// The id of a type in the `PortableRegistry` of a contract.
+pub type TypeId = <PortableForm as Form>::Type;
+
+/// The metadata of a contract, returned by the framework-level metadata query.
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct ContractMetadata {
+    pub name: String,
+    pub version: String,
+    pub owner: Option<AccountId>,
+    /// The types of the messages accepted by the contract, if the contract describes them.
+    pub types: Option<MessageTypes>,
+}
+
+/// The `scale-info` description of the command and query types of a contract.
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct MessageTypes {
+    pub registry: PortableRegistry,
+    pub command: TypeId,
+    pub query_request: TypeId,
+    pub query_response: TypeId,
+}
+
+impl MessageTypes {
+    pub fn of<Cmd, QReq, QResp>() -> Self
+    where
+        Cmd: TypeInfo + 'static,
+        QReq: TypeInfo + 'static,
+        QResp: TypeInfo + 'static,
+    {
+        let mut registry = Registry::new();
+        let command = registry.register_type(&meta_type::<Cmd>());
+        let query_request = registry.register_type(&meta_type::<QReq>());
+        let query_response = registry.register_type(&meta_type::<QResp>());
+        MessageTypes {
+            registry: registry.into(),
+            command,
+            query_request,
+            query_response,
+        }
+    }
+}
+
+/// Converts between JSON values and SCALE encoded messages, guided by the type metadata.
+///
+/// The JSON representation follows serde's defaults: structs are objects (or arrays when the
+/// fields are unnamed), enum variants are externally tagged (`"Unit"` or `{"Variant": fields}`)
+/// and byte sequences are `0x` prefixed hex strings. 128-bit integers are decoded as decimal
+/// strings, since they don't fit in a JSON number.
+#[cfg(feature = "json")]
+pub mod json {
+    use super::TypeId;
+    use alloc::format;
+    use alloc::string::{String, ToString};
+    use alloc::vec::Vec;
+    use core::convert::TryFrom;
+    use parity_scale_codec::{Compact, Decode, Encode, Error as CodecError};
+    use scale_info::{form::PortableForm, Field, PortableRegistry, TypeDef, TypeDefPrimitive};
+    use serde_json::{Map, Value};
+
+    #[derive(Debug, derive_more::Display)]
+    pub enum Error {
+        #[display(fmt = "unknown type")]
+        UnknownType,
+        #[display(fmt = "unsupported type: {}", _0)]
+        Unsupported(&'static str),
+        #[display(fmt = "bad value: {}", _0)]
+        BadValue(String),
+        #[display(fmt = "decode failed: {:?}", _0)]
+        Decode(CodecError),
+    }
+
+    impl From<CodecError> for Error {
+        fn from(err: CodecError) -> Self {
+            Error::Decode(err)
+        }
+    }
+
+    type Fields = [Field<PortableForm>];
+
+    fn resolve<'a>(
+        registry: &'a PortableRegistry,
+        ty: &TypeId,
+    ) -> Result<&'a TypeDef<PortableForm>, Error> {
+        registry
+            .resolve(ty.id())
+            .map(|ty| ty.type_def())
+            .ok_or(Error::UnknownType)
+    }
+
+    fn is_u8(registry: &PortableRegistry, ty: &TypeId) -> bool {
+        matches!(
+            resolve(registry, ty),
+            Ok(TypeDef::Primitive(TypeDefPrimitive::U8))
+        )
+    }
+
+    fn bad_value(expected: &str, value: &Value) -> Error {
+        Error::BadValue(format!("expected {}, got {}", expected, value))
+    }
+
+    /// Encodes the JSON value as the given type.
+    pub fn encode(
+        registry: &PortableRegistry,
+        ty: &TypeId,
+        value: &Value,
+    ) -> Result<Vec<u8>, Error> {
+        let mut out = Vec::new();
+        encode_to(registry, ty, value, &mut out)?;
+        Ok(out)
+    }
+
+    /// Decodes a value of the given type into JSON, requiring all the input to be consumed.
+    pub fn decode(registry: &PortableRegistry, ty: &TypeId, data: &[u8]) -> Result<Value, Error> {
+        let mut input = data;
+        let value = decode_from(registry, ty, &mut input)?;
+        if !input.is_empty() {
+            return Err(Error::BadValue(format!("{} trailing bytes", input.len())));
+        }
+        Ok(value)
+    }
+
+    fn encode_to(
+        registry: &PortableRegistry,
+        ty: &TypeId,
+        value: &Value,
+        out: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        match resolve(registry, ty)? {
+            TypeDef::Composite(def) => encode_fields(registry, def.fields(), value, out),
+            TypeDef::Variant(def) => {
+                let (name, fields) = match value {
+                    Value::String(name) => (name.as_str(), &Value::Null),
+                    Value::Object(map) if map.len() == 1 => {
+                        let (name, fields) = map.iter().next().expect("Checked length; qed.");
+                        (name.as_str(), fields)
+                    }
+                    _ => return Err(bad_value("enum variant", value)),
+                };
+                // The variants are encoded by their positions, as none of the contract messages
+                // assign explicit indices.
+                let (index, variant) = def
+                    .variants()
+                    .iter()
+                    .enumerate()
+                    .find(|(_, variant)| variant.name() == name)
+                    .ok_or_else(|| Error::BadValue(format!("unknown variant {}", name)))?;
+                out.push(index as u8);
+                encode_fields(registry, variant.fields(), fields, out)
+            }
+            TypeDef::Sequence(def) => {
+                let items = encode_items(registry, def.type_param(), value)?;
+                Compact(items.len() as u32).encode_to(out);
+                items.into_iter().for_each(|item| out.extend(item));
+                Ok(())
+            }
+            TypeDef::Array(def) => {
+                let items = encode_items(registry, def.type_param(), value)?;
+                if items.len() != def.len() as usize {
+                    return Err(Error::BadValue(format!(
+                        "expected {} items, got {}",
+                        def.len(),
+                        items.len()
+                    )));
+                }
+                items.into_iter().for_each(|item| out.extend(item));
+                Ok(())
+            }
+            TypeDef::Tuple(def) => {
+                if def.fields().is_empty() {
+                    return Ok(());
+                }
+                let items = value
+                    .as_array()
+                    .filter(|items| items.len() == def.fields().len())
+                    .ok_or_else(|| bad_value("tuple", value))?;
+                for (ty, item) in def.fields().iter().zip(items) {
+                    encode_to(registry, ty, item, out)?;
+                }
+                Ok(())
+            }
+            TypeDef::Primitive(def) => encode_primitive(def, value, out),
+            TypeDef::Compact(_) => {
+                Compact(as_uint(value)?).encode_to(out);
+                Ok(())
+            }
+            TypeDef::BitSequence(_) => Err(Error::Unsupported("bit sequence")),
+        }
+    }
+
+    /// Encodes the items of a sequence, which may be given as a hex string for bytes.
+    fn encode_items(
+        registry: &PortableRegistry,
+        ty: &TypeId,
+        value: &Value,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        if let (Value::String(hex_str), true) = (value, is_u8(registry, ty)) {
+            let bytes = hex::decode(hex_str.strip_prefix("0x").unwrap_or(hex_str))
+                .map_err(|_| bad_value("hex string", value))?;
+            return Ok(bytes.into_iter().map(|b| alloc::vec![b]).collect());
+        }
+        value
+            .as_array()
+            .ok_or_else(|| bad_value("array", value))?
+            .iter()
+            .map(|item| encode(registry, ty, item))
+            .collect()
+    }
+
+    fn encode_fields(
+        registry: &PortableRegistry,
+        fields: &Fields,
+        value: &Value,
+        out: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        match fields {
+            [] => Ok(()),
+            [field] if field.name().is_none() => encode_to(registry, field.ty(), value, out),
+            _ if fields[0].name().is_some() => {
+                let map = value
+                    .as_object()
+                    .ok_or_else(|| bad_value("object", value))?;
+                for field in fields {
+                    let name = field.name().map(|name| name.as_str()).unwrap_or_default();
+                    let value = map
+                        .get(name)
+                        .ok_or_else(|| Error::BadValue(format!("missing field {}", name)))?;
+                    encode_to(registry, field.ty(), value, out)?;
+                }
+                Ok(())
+            }
+            _ => {
+                let items = value
+                    .as_array()
+                    .filter(|items| items.len() == fields.len())
+                    .ok_or_else(|| bad_value("array", value))?;
+                for (field, item) in fields.iter().zip(items) {
+                    encode_to(registry, field.ty(), item, out)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn as_uint(value: &Value) -> Result<u128, Error> {
+        match value {
+            Value::Number(n) => n.as_u64().map(Into::into),
+            Value::String(s) => s.parse().ok(),
+            _ => None,
+        }
+        .ok_or_else(|| bad_value("unsigned integer", value))
+    }
+
+    fn as_int(value: &Value) -> Result<i128, Error> {
+        match value {
+            Value::Number(n) => n.as_i64().map(Into::into),
+            Value::String(s) => s.parse().ok(),
+            _ => None,
+        }
+        .ok_or_else(|| bad_value("integer", value))
+    }
+
+    fn encode_primitive(
+        def: &TypeDefPrimitive,
+        value: &Value,
+        out: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        macro_rules! encode_int {
+            ($t: ty, $v: expr) => {
+                <$t>::try_from($v)
+                    .map_err(|_| bad_value(stringify!($t), value))?
+                    .encode_to(out)
+            };
+        }
+        match def {
+            TypeDefPrimitive::Bool => value
+                .as_bool()
+                .ok_or_else(|| bad_value("bool", value))?
+                .encode_to(out),
+            TypeDefPrimitive::Char => {
+                let mut chars = value.as_str().unwrap_or_default().chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => (c as u32).encode_to(out),
+                    _ => return Err(bad_value("char", value)),
+                }
+            }
+            TypeDefPrimitive::Str => value
+                .as_str()
+                .ok_or_else(|| bad_value("string", value))?
+                .encode_to(out),
+            TypeDefPrimitive::U8 => encode_int!(u8, as_uint(value)?),
+            TypeDefPrimitive::U16 => encode_int!(u16, as_uint(value)?),
+            TypeDefPrimitive::U32 => encode_int!(u32, as_uint(value)?),
+            TypeDefPrimitive::U64 => encode_int!(u64, as_uint(value)?),
+            TypeDefPrimitive::U128 => as_uint(value)?.encode_to(out),
+            TypeDefPrimitive::I8 => encode_int!(i8, as_int(value)?),
+            TypeDefPrimitive::I16 => encode_int!(i16, as_int(value)?),
+            TypeDefPrimitive::I32 => encode_int!(i32, as_int(value)?),
+            TypeDefPrimitive::I64 => encode_int!(i64, as_int(value)?),
+            TypeDefPrimitive::I128 => as_int(value)?.encode_to(out),
+            TypeDefPrimitive::U256 | TypeDefPrimitive::I256 => {
+                return Err(Error::Unsupported("256-bit integer"))
+            }
+        }
+        Ok(())
+    }
+
+    fn decode_from(
+        registry: &PortableRegistry,
+        ty: &TypeId,
+        input: &mut &[u8],
+    ) -> Result<Value, Error> {
+        match resolve(registry, ty)? {
+            TypeDef::Composite(def) => decode_fields(registry, def.fields(), input),
+            TypeDef::Variant(def) => {
+                let index = u8::decode(input)?;
+                let variant = def
+                    .variants()
+                    .get(index as usize)
+                    .ok_or_else(|| Error::BadValue(format!("unknown variant index {}", index)))?;
+                let name = variant.name().to_string();
+                if variant.fields().is_empty() {
+                    return Ok(Value::String(name));
+                }
+                let fields = decode_fields(registry, variant.fields(), input)?;
+                let mut map = Map::new();
+                map.insert(name, fields);
+                Ok(Value::Object(map))
+            }
+            TypeDef::Sequence(def) => {
+                let len = Compact::<u32>::decode(input)?.0;
+                decode_items(registry, def.type_param(), len, input)
+            }
+            TypeDef::Array(def) => decode_items(registry, def.type_param(), def.len(), input),
+            TypeDef::Tuple(def) => {
+                if def.fields().is_empty() {
+                    return Ok(Value::Null);
+                }
+                def.fields()
+                    .iter()
+                    .map(|ty| decode_from(registry, ty, input))
+                    .collect::<Result<_, _>>()
+                    .map(Value::Array)
+            }
+            TypeDef::Primitive(def) => decode_primitive(def, input),
+            TypeDef::Compact(_) => Ok(uint_value(Compact::<u128>::decode(input)?.0)),
+            TypeDef::BitSequence(_) => Err(Error::Unsupported("bit sequence")),
+        }
+    }
+
+    fn decode_items(
+        registry: &PortableRegistry,
+        ty: &TypeId,
+        len: u32,
+        input: &mut &[u8],
+    ) -> Result<Value, Error> {
+        if is_u8(registry, ty) {
+            if input.len() < len as usize {
+                return Err(CodecError::from("Not enough data for the bytes").into());
+            }
+            let (bytes, rest) = input.split_at(len as usize);
+            *input = rest;
+            return Ok(Value::String(format!("0x{}", hex::encode(bytes))));
+        }
+        (0..len)
+            .map(|_| decode_from(registry, ty, input))
+            .collect::<Result<_, _>>()
+            .map(Value::Array)
+    }
+
+    fn decode_fields(
+        registry: &PortableRegistry,
+        fields: &Fields,
+        input: &mut &[u8],
+    ) -> Result<Value, Error> {
+        match fields {
+            [] => Ok(Value::Null),
+            [field] if field.name().is_none() => decode_from(registry, field.ty(), input),
+            _ if fields[0].name().is_some() => {
+                let mut map = Map::new();
+                for field in fields {
+                    let name = field.name().cloned().unwrap_or_default();
+                    map.insert(name, decode_from(registry, field.ty(), input)?);
+                }
+                Ok(Value::Object(map))
+            }
+            _ => fields
+                .iter()
+                .map(|field| decode_from(registry, field.ty(), input))
+                .collect::<Result<_, _>>()
+                .map(Value::Array),
+        }
+    }
+
+    fn uint_value(v: u128) -> Value {
+        match u64::try_from(v) {
+            Ok(v) => v.into(),
+            Err(_) => v.to_string().into(),
+        }
+    }
+
+    fn int_value(v: i128) -> Value {
+        match i64::try_from(v) {
+            Ok(v) => v.into(),
+            Err(_) => v.to_string().into(),
+        }
+    }
+
+    fn decode_primitive(def: &TypeDefPrimitive, input: &mut &[u8]) -> Result<Value, Error> {
+        let value = match def {
+            TypeDefPrimitive::Bool => bool::decode(input)?.into(),
+            TypeDefPrimitive::Char => {
+                let c = core::char::from_u32(u32::decode(input)?)
+                    .ok_or_else(|| Error::BadValue("invalid char".to_string()))?;
+                c.to_string().into()
+            }
+            TypeDefPrimitive::Str => String::decode(input)?.into(),
+            TypeDefPrimitive::U8 => u8::decode(input)?.into(),
+            TypeDefPrimitive::U16 => u16::decode(input)?.into(),
+            TypeDefPrimitive::U32 => u32::decode(input)?.into(),
+            TypeDefPrimitive::U64 => u64::decode(input)?.into(),
+            TypeDefPrimitive::U128 => uint_value(u128::decode(input)?),
+            TypeDefPrimitive::I8 => i8::decode(input)?.into(),
+            TypeDefPrimitive::I16 => i16::decode(input)?.into(),
+            TypeDefPrimitive::I32 => i32::decode(input)?.into(),
+            TypeDefPrimitive::I64 => i64::decode(input)?.into(),
+            TypeDefPrimitive::I128 => int_value(i128::decode(input)?),
+            TypeDefPrimitive::U256 | TypeDefPrimitive::I256 => {
+                return Err(Error::Unsupported("256-bit integer"))
+            }
+        };
+        Ok(value)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::super::MessageTypes;
+        use super::*;
+        use alloc::vec;
+        use scale_info::TypeInfo;
+        use serde_json::json;
+
+        #[derive(Encode, TypeInfo)]
+        enum Command {
+            Transfer { dest: [u8; 4], value: u128 },
+            Memo(String, Option<u32>),
+            Reset,
+        }
+
+        #[derive(Encode, TypeInfo)]
+        struct Query {
+            accounts: Vec<[u8; 2]>,
+            data: Vec<u8>,
+            delta: (i8, bool),
+        }
+
+        fn types() -> MessageTypes {
+            MessageTypes::of::<Command, Query, ()>()
+        }
+
+        fn assert_round_trip(ty: &TypeId, value: Value, expected: Vec<u8>) {
+            let types = types();
+            let encoded = encode(&types.registry, ty, &value).unwrap();
+            assert_eq!(encoded, expected);
+            assert_eq!(decode(&types.registry, ty, &encoded).unwrap(), value);
+        }
+
+        #[test]
+        fn enums_round_trip() {
+            let command = types().command;
+            assert_round_trip(
+                &command,
+                json!({"Transfer": {"dest": "0x01020304", "value": "340282366920938463463374607431768211455"}}),
+                Command::Transfer {
+                    dest: [1, 2, 3, 4],
+                    value: u128::MAX,
+                }
+                .encode(),
+            );
+            assert_round_trip(
+                &command,
+                json!({"Memo": ["hi", {"Some": 7}]}),
+                Command::Memo("hi".into(), Some(7)).encode(),
+            );
+            assert_round_trip(&command, json!("Reset"), Command::Reset.encode());
+        }
+
+        #[test]
+        fn structs_round_trip() {
+            assert_round_trip(
+                &types().query_request,
+                json!({"accounts": ["0x0102", "0x0304"], "data": "0x", "delta": [-1, true]}),
+                Query {
+                    accounts: vec![[1, 2], [3, 4]],
+                    data: vec![],
+                    delta: (-1, true),
+                }
+                .encode(),
+            );
+        }
+
+        #[test]
+        fn bad_values_are_rejected() {
+            let types = types();
+            let encode = |value| encode(&types.registry, &types.command, &value);
+            assert!(matches!(encode(json!("Burn")), Err(Error::BadValue(_))));
+            assert!(matches!(
+                encode(json!({"Transfer": {"dest": "0x01020304"}})),
+                Err(Error::BadValue(_))
+            ));
+            assert!(matches!(
+                encode(json!({"Transfer": {"dest": "0x0102", "value": 1}})),
+                Err(Error::BadValue(_))
+            ));
+            assert!(matches!(
+                decode(&types.registry, &types.command, &[2, 0]),
+                Err(Error::BadValue(_))
+            ));
+            assert!(matches!(
+                decode(&types.registry, &types.command, &[3]),
+                Err(Error::BadValue(_))
+            ));
+        }
+    }
+}
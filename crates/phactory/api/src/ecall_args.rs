@@ -1,6 +1,7 @@
 
 use parity_scale_codec::{Encode, Decode};
 use alloc::string::{String, ToString};
+use alloc::vec::Vec;
 
 #[derive(Debug, Encode, Decode, Default, Clone)]
 pub struct InitArgs {
@@ -18,6 +19,18 @@ pub struct InitArgs {
 
     /// The git commit hash which this binary was built from.
     pub git_revision: String,
+
+    /// Native contracts to enable (`name`) or disable (`-name`) on top of the default set.
+    pub native_contracts: Vec<String>,
+
+    /// The query budgets: `steps` for the default, or `contract=steps` for a contract.
+    pub query_budgets: Vec<String>,
+
+    /// Whether to keep a write-ahead log of the egress message queue in the sealing path.
+    pub persist_mq: bool,
+
+    /// The max number of ingress messages to trace the hops of, 0 to disable the tracing.
+    pub trace_messages: u32,
 }
 
 pub fn git_revision() -> String {
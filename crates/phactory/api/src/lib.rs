@@ -1,8 +1,10 @@
 #![no_std]
 extern crate alloc;
 
+pub mod contract_metadata;
 pub mod crypto;
 pub mod ecall_args;
+pub mod system;
 
 mod proto_generated;
 
@@ -11,6 +13,7 @@ pub mod prpc {
     use alloc::vec::Vec;
     use phala_types::messaging::{MessageOrigin, SignedMessage};
     pub use prpc::{client, server, Message};
+    /// The messages grouped by sender, with the senders of a higher `MessageClass` first.
     pub type EgressMessages = Vec<(MessageOrigin, Vec<SignedMessage>)>;
 
     pub const SIG_LEN: usize = 64;
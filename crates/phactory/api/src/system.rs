@@ -0,0 +1,87 @@
+//! The queries to the system contract of pRuntime, shared with the clients.
+
+use alloc::string::String;
+use parity_scale_codec::{Decode, Encode};
+use phala_mq::{BadOrigin, MessageOrigin};
+use phala_types::{contract::ContractId, EcdhPublicKey};
+
+use crate::contract_metadata::ContractMetadata;
+
+pub type TransactionResult = Result<(), TransactionError>;
+
+#[derive(Encode, Decode, Debug, Clone)]
+pub enum TransactionError {
+    BadInput,
+    BadOrigin,
+    // general
+    InsufficientBalance,
+    NoBalance,
+    UnknownError,
+    BadContractId,
+    BadCommand,
+    SymbolExist,
+    AssetIdNotFound,
+    NotAssetOwner,
+    BadSecret,
+    BadMachineId,
+    FailedToSign,
+    BadDecimal,
+    DestroyNotAllowed,
+    // for pdiem
+    BadAccountInfo,
+    BadLedgerInfo,
+    BadTrustedStateData,
+    BadEpochChangedProofData,
+    BadTrustedState,
+    InvalidAccount,
+    BadTransactionWithProof,
+    FailedToVerify,
+    FailedToGetTransaction,
+    FailedToCalculateBalance,
+    BadChainId,
+    TransferringNotAllowed,
+    // for wasm contracts
+    BadCode,
+    OutOfGas,
+    ContractTrapped,
+    ContractReverted,
+}
+
+impl From<BadOrigin> for TransactionError {
+    fn from(_: BadOrigin) -> TransactionError {
+        TransactionError::BadOrigin
+    }
+}
+
+/// The outcome of a command processed by a contract.
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct CommandReceipt {
+    /// The block in which the command was processed.
+    pub block_number: chain::BlockNumber,
+    pub result: TransactionResult,
+}
+
+/// The queries to the system contract.
+#[derive(Encode, Decode, Debug, Clone)]
+pub enum Request {
+    /// Gets the receipt of the command from the sender to the contract, pushed on chain in block
+    /// `sent_at` as the `index`th message of the block, counted from 0.
+    CommandReceipt {
+        contract: ContractId,
+        sender: MessageOrigin,
+        sent_at: chain::BlockNumber,
+        index: u64,
+    },
+    /// Gets the name, version, owner and message types of the contract.
+    ContractMetadata { contract: ContractId },
+    /// Gets the ecdh public key the queries to the contract are encrypted to.
+    ContractKey { contract: ContractId },
+}
+
+#[derive(Encode, Decode, Debug)]
+pub enum Response {
+    Error(String),
+    CommandReceipt(Option<CommandReceipt>),
+    ContractMetadata(ContractMetadata),
+    ContractKey(EcdhPublicKey),
+}
@@ -13,6 +13,7 @@ pub trait Sealing {
 
     fn seal_data(&self, path: impl AsRef<Path>, data: &[u8]) -> Result<(), Self::SealError>;
     fn unseal_data(&self, path: impl AsRef<Path>) -> Result<Option<Vec<u8>>, Self::UnsealError>;
+    fn remove_data(&self, path: impl AsRef<Path>) -> Result<(), Self::SealError>;
 }
 
 pub trait RA {
@@ -36,5 +37,5 @@ pub trait Machine {
     fn cpu_feature_level(&self) -> u32;
 }
 
-pub trait Platform: Sealing + RA + Machine + MemoryStats + Clone {}
-impl<T: Sealing + RA + Machine + MemoryStats + Clone> Platform for T {}
+pub trait Platform: Sealing + RA + Machine + MemoryStats + Clone + Send + Sync + 'static {}
+impl<T: Sealing + RA + Machine + MemoryStats + Clone + Send + Sync + 'static> Platform for T {}
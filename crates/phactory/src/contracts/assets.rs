@@ -7,8 +7,8 @@ use phala_mq::MessageOrigin;
 use std::collections::BTreeMap;
 
 use super::{NativeContext, TransactionError, TransactionResult};
-use crate::contracts;
 use crate::contracts::AccountId;
+use crate::contracts::{self, query_budget};
 use phala_types::messaging::{AssetCommand, AssetId};
 
 type Command = AssetCommand<chain::AccountId, chain::Balance>;
@@ -91,6 +91,72 @@ impl Assets {
     }
 }
 
+/// Mirrors of the messages, described with `AccountIdInfo` for `AccountId`.
+mod info {
+    use super::*;
+    use crate::contracts::AccountIdInfo as AccountId;
+    use scale_info::TypeInfo;
+
+    #[derive(Encode, TypeInfo)]
+    pub enum Command {
+        Issue {
+            symbol: String,
+            total: chain::Balance,
+        },
+        Destroy {
+            id: AssetId,
+        },
+        Transfer {
+            id: AssetId,
+            dest: AccountId,
+            value: chain::Balance,
+            index: u64,
+        },
+    }
+
+    #[derive(Encode, TypeInfo)]
+    pub enum Request {
+        Balance { id: AssetId, account: AccountId },
+        TotalSupply { id: AssetId },
+        Metadata,
+        History { account: AccountId },
+        ListAssets { available_only: bool },
+    }
+
+    #[derive(Encode, TypeInfo)]
+    pub struct AssetMetadata {
+        pub owner: AccountId,
+        pub total_supply: u128,
+        pub symbol: String,
+        pub id: u32,
+    }
+
+    #[derive(Encode, TypeInfo)]
+    pub struct AssetMetadataBalance {
+        pub metadata: AssetMetadata,
+        pub balance: chain::Balance,
+    }
+
+    #[derive(Encode, TypeInfo)]
+    pub struct AssetsTx {
+        pub index: u64,
+        pub asset_id: u32,
+        pub from: AccountId,
+        pub to: AccountId,
+        pub amount: chain::Balance,
+    }
+
+    #[derive(Encode, TypeInfo)]
+    pub enum Response {
+        Balance { balance: chain::Balance },
+        TotalSupply { total_issuance: chain::Balance },
+        Metadata { metadata: Vec<AssetMetadata> },
+        History { history: Vec<AssetsTx> },
+        ListAssets { assets: Vec<AssetMetadataBalance> },
+        Error(String),
+    }
+}
+
 impl contracts::NativeContract for Assets {
     type Cmd = Command;
     type QReq = Request;
@@ -100,6 +166,18 @@ impl contracts::NativeContract for Assets {
         contracts::ASSETS
     }
 
+    fn report_receipts(&self) -> bool {
+        true
+    }
+
+    fn message_types(&self) -> Option<contracts::MessageTypes> {
+        Some(contracts::MessageTypes::of::<
+            info::Command,
+            info::Request,
+            info::Response,
+        >())
+    }
+
     fn handle_command(
         &mut self,
         _context: &mut NativeContext,
@@ -217,7 +295,7 @@ impl contracts::NativeContract for Assets {
         }
     }
 
-    fn handle_query(&mut self, origin: Option<&chain::AccountId>, req: Self::QReq) -> Self::QResp {
+    fn handle_query(&self, origin: Option<&chain::AccountId>, req: Self::QReq) -> Self::QResp {
         let inner = || -> Result<Response> {
             match req {
                 Request::Balance { id, account } => {
@@ -249,16 +327,23 @@ impl contracts::NativeContract for Assets {
                         ))))
                     }
                 }
-                Request::Metadata => Ok(Response::Metadata {
-                    metadata: self.metadata.values().cloned().collect(),
-                }),
+                Request::Metadata => {
+                    query_budget::charge_items_or_bail(self.metadata.len())?;
+                    Ok(Response::Metadata {
+                        metadata: self.metadata.values().cloned().collect(),
+                    })
+                }
                 Request::History { account } => {
-                    let tx_list = self.history.get(&account).cloned().unwrap_or_default();
-                    Ok(Response::History { history: tx_list })
+                    let tx_list = self.history.get(&account);
+                    query_budget::charge_items_or_bail(tx_list.map_or(0, Vec::len))?;
+                    Ok(Response::History {
+                        history: tx_list.cloned().unwrap_or_default(),
+                    })
                 }
                 Request::ListAssets { available_only } => {
                     let raw_origin =
                         origin.ok_or_else(|| anyhow::Error::msg(Error::NotAuthorized))?;
+                    query_budget::charge_items_or_bail(self.assets.len())?;
                     let o = raw_origin.clone();
                     // TODO: simplify the two way logic here?
                     let assets = if available_only {
@@ -296,3 +381,165 @@ impl contracts::NativeContract for Assets {
 fn is_tracked(_id: &AccountId) -> bool {
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contracts::account_id_info;
+
+    /// Mirrors the messages, matching all the variants so that none is left out of the mirrors.
+    fn mirror_command(command: &Command) -> info::Command {
+        match command {
+            Command::Issue { symbol, total } => info::Command::Issue {
+                symbol: symbol.clone(),
+                total: *total,
+            },
+            Command::Destroy { id } => info::Command::Destroy { id: *id },
+            Command::Transfer {
+                id,
+                dest,
+                value,
+                index,
+            } => info::Command::Transfer {
+                id: *id,
+                dest: account_id_info(dest),
+                value: *value,
+                index: *index,
+            },
+        }
+    }
+
+    fn mirror_request(request: &Request) -> info::Request {
+        match request {
+            Request::Balance { id, account } => info::Request::Balance {
+                id: *id,
+                account: account_id_info(account),
+            },
+            Request::TotalSupply { id } => info::Request::TotalSupply { id: *id },
+            Request::Metadata => info::Request::Metadata,
+            Request::History { account } => info::Request::History {
+                account: account_id_info(account),
+            },
+            Request::ListAssets { available_only } => info::Request::ListAssets {
+                available_only: *available_only,
+            },
+        }
+    }
+
+    fn mirror_metadata(metadata: &AssetMetadata) -> info::AssetMetadata {
+        info::AssetMetadata {
+            owner: account_id_info(&metadata.owner),
+            total_supply: metadata.total_supply,
+            symbol: metadata.symbol.clone(),
+            id: metadata.id,
+        }
+    }
+
+    fn mirror_response(response: &Response) -> info::Response {
+        match response {
+            Response::Balance { balance } => info::Response::Balance { balance: *balance },
+            Response::TotalSupply { total_issuance } => info::Response::TotalSupply {
+                total_issuance: *total_issuance,
+            },
+            Response::Metadata { metadata } => info::Response::Metadata {
+                metadata: metadata.iter().map(mirror_metadata).collect(),
+            },
+            Response::History { history } => info::Response::History {
+                history: history
+                    .iter()
+                    .map(|tx| info::AssetsTx {
+                        index: tx.index,
+                        asset_id: tx.asset_id,
+                        from: account_id_info(&tx.from),
+                        to: account_id_info(&tx.to),
+                        amount: tx.amount,
+                    })
+                    .collect(),
+            },
+            Response::ListAssets { assets } => info::Response::ListAssets {
+                assets: assets
+                    .iter()
+                    .map(|asset| info::AssetMetadataBalance {
+                        metadata: mirror_metadata(&asset.metadata),
+                        balance: asset.balance,
+                    })
+                    .collect(),
+            },
+            Response::Error(err) => info::Response::Error(err.clone()),
+        }
+    }
+
+    #[test]
+    fn message_types_mirror_the_encoding() {
+        let alice = AccountId::new([1; 32]);
+        let bob = AccountId::new([2; 32]);
+        let metadata = AssetMetadata {
+            owner: alice.clone(),
+            total_supply: 100,
+            symbol: "PHA".into(),
+            id: 1,
+        };
+
+        let commands = vec![
+            Command::Issue {
+                symbol: "PHA".into(),
+                total: 100,
+            },
+            Command::Destroy { id: 1 },
+            Command::Transfer {
+                id: 1,
+                dest: alice.clone(),
+                value: 10,
+                index: 2,
+            },
+        ];
+        for command in commands {
+            assert_eq!(command.encode(), mirror_command(&command).encode());
+        }
+        let requests = vec![
+            Request::Balance {
+                id: 1,
+                account: alice.clone(),
+            },
+            Request::TotalSupply { id: 1 },
+            Request::Metadata,
+            Request::History {
+                account: alice.clone(),
+            },
+            Request::ListAssets {
+                available_only: true,
+            },
+        ];
+        for request in requests {
+            assert_eq!(request.encode(), mirror_request(&request).encode());
+        }
+        let responses = vec![
+            Response::Balance { balance: 10 },
+            Response::TotalSupply {
+                total_issuance: 100,
+            },
+            Response::Metadata {
+                metadata: vec![metadata.clone()],
+            },
+            Response::History {
+                history: vec![AssetsTx {
+                    index: 2,
+                    asset_id: 1,
+                    from: alice,
+                    to: bob,
+                    amount: 10,
+                }],
+            },
+            Response::ListAssets {
+                assets: vec![AssetMetadataBalance {
+                    metadata,
+                    balance: 10,
+                }],
+            },
+            Response::Error("error".into()),
+        ];
+        for response in responses {
+            assert_eq!(response.encode(), mirror_response(&response).encode());
+        }
+    }
+}
@@ -49,6 +49,42 @@ pub enum Response {
     Error(String),
 }
 
+/// Mirrors of the messages, described with `AccountIdInfo` for `AccountId`.
+mod info {
+    use super::*;
+    use crate::contracts::AccountIdInfo as AccountId;
+    use scale_info::TypeInfo;
+
+    #[derive(Encode, TypeInfo)]
+    pub enum Command {
+        Transfer {
+            dest: AccountId,
+            value: chain::Balance,
+        },
+        TransferToChain {
+            dest: AccountId,
+            value: chain::Balance,
+        },
+        TransferToTee {
+            who: AccountId,
+            amount: chain::Balance,
+        },
+    }
+
+    #[derive(Encode, TypeInfo)]
+    pub enum Request {
+        FreeBalance { account: AccountId },
+        TotalIssuance,
+    }
+
+    #[derive(Encode, TypeInfo)]
+    pub enum Response {
+        FreeBalance { balance: chain::Balance },
+        TotalIssuance { total_issuance: chain::Balance },
+        Error(String),
+    }
+}
+
 impl Balances {
     pub fn new() -> Self {
         Balances {
@@ -67,6 +103,18 @@ impl contracts::NativeContract for Balances {
         contracts::BALANCES
     }
 
+    fn report_receipts(&self) -> bool {
+        true
+    }
+
+    fn message_types(&self) -> Option<contracts::MessageTypes> {
+        Some(contracts::MessageTypes::of::<
+            info::Command,
+            info::Request,
+            info::Response,
+        >())
+    }
+
     fn handle_command(
         &mut self,
         context: &mut NativeContext,
@@ -156,7 +204,7 @@ impl contracts::NativeContract for Balances {
         }
     }
 
-    fn handle_query(&mut self, origin: Option<&chain::AccountId>, req: Request) -> Response {
+    fn handle_query(&self, origin: Option<&chain::AccountId>, req: Request) -> Response {
         let inner = || -> Result<Response> {
             match req {
                 Request::FreeBalance { account } => {
@@ -180,3 +228,83 @@ impl contracts::NativeContract for Balances {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contracts::account_id_info;
+
+    /// Mirrors the messages, matching all the variants so that none is left out of the mirrors.
+    fn mirror_command(command: &Command) -> info::Command {
+        match command {
+            Command::Transfer { dest, value } => info::Command::Transfer {
+                dest: account_id_info(dest),
+                value: *value,
+            },
+            Command::TransferToChain { dest, value } => info::Command::TransferToChain {
+                dest: account_id_info(dest),
+                value: *value,
+            },
+            Command::TransferToTee { who, amount } => info::Command::TransferToTee {
+                who: account_id_info(who),
+                amount: *amount,
+            },
+        }
+    }
+
+    fn mirror_request(request: &Request) -> info::Request {
+        match request {
+            Request::FreeBalance { account } => info::Request::FreeBalance {
+                account: account_id_info(account),
+            },
+            Request::TotalIssuance => info::Request::TotalIssuance,
+        }
+    }
+
+    fn mirror_response(response: &Response) -> info::Response {
+        match response {
+            Response::FreeBalance { balance } => info::Response::FreeBalance { balance: *balance },
+            Response::TotalIssuance { total_issuance } => info::Response::TotalIssuance {
+                total_issuance: *total_issuance,
+            },
+            Response::Error(err) => info::Response::Error(err.clone()),
+        }
+    }
+
+    #[test]
+    fn message_types_mirror_the_encoding() {
+        let alice = AccountId::new([1; 32]);
+        let commands = vec![
+            Command::Transfer {
+                dest: alice.clone(),
+                value: 1,
+            },
+            Command::TransferToChain {
+                dest: alice.clone(),
+                value: 2,
+            },
+            Command::TransferToTee {
+                who: alice.clone(),
+                amount: 3,
+            },
+        ];
+        for command in commands {
+            assert_eq!(command.encode(), mirror_command(&command).encode());
+        }
+        let requests = vec![
+            Request::FreeBalance { account: alice },
+            Request::TotalIssuance,
+        ];
+        for request in requests {
+            assert_eq!(request.encode(), mirror_request(&request).encode());
+        }
+        let responses = vec![
+            Response::FreeBalance { balance: 1 },
+            Response::TotalIssuance { total_issuance: 2 },
+            Response::Error("error".into()),
+        ];
+        for response in responses {
+            assert_eq!(response.encode(), mirror_response(&response).encode());
+        }
+    }
+}
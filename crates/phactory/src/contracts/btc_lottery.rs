@@ -1,6 +1,6 @@
 use super::{account_id_from_hex, TransactionError, TransactionResult};
 use crate::chain;
-use crate::contracts::{self, AccountId};
+use crate::contracts::{self, query_budget, AccountId};
 
 use std::{
     collections::{
@@ -17,6 +17,7 @@ use log::error;
 use parity_scale_codec::{Decode, Encode};
 use phala_mq::{MessageOrigin, Sr25519MessageChannel as MessageChannel};
 use rand::{rngs::StdRng, seq::IteratorRandom, SeedableRng};
+use scale_info::TypeInfo;
 use sp_core::{crypto::Pair, hashing::blake2_256, sr25519, U256};
 use sp_runtime_interface::pass_by::PassByInner as _;
 
@@ -62,12 +63,12 @@ impl core::fmt::Debug for BtcLottery {
     }
 }
 
-#[derive(Encode, Decode, Debug)]
+#[derive(Encode, Decode, Debug, TypeInfo)]
 pub enum Error {
     InvalidRequest,
 }
 
-#[derive(Encode, Decode, Debug, Clone)]
+#[derive(Encode, Decode, Debug, Clone, TypeInfo)]
 pub enum Request {
     GetAllRounds,
     GetRoundInfo { round_id: u32 },
@@ -78,7 +79,7 @@ pub enum Request {
 
 type AddressString = String;
 
-#[derive(Encode, Decode, Debug)]
+#[derive(Encode, Decode, Debug, TypeInfo)]
 pub enum Response {
     GetAllRounds {
         round_id: u32,
@@ -103,6 +104,43 @@ pub enum Response {
     Error(Error),
 }
 
+/// Mirrors of the commands defined in `phala_types`, which has no `TypeInfo`
+mod info {
+    use super::*;
+
+    #[derive(Encode, TypeInfo)]
+    pub enum Command {
+        UserCommand(UserCommand),
+        PalletCommand(PalletCommand),
+    }
+
+    #[derive(Encode, TypeInfo)]
+    pub enum UserCommand {
+        SubmitUtxo {
+            round_id: u32,
+            address: String,
+            utxo: (Txid, u32, u64),
+        },
+        SetAdmin {
+            new_admin: String,
+        },
+    }
+
+    #[derive(Encode, TypeInfo)]
+    pub enum PalletCommand {
+        NewRound {
+            round_id: u32,
+            total_count: u32,
+            winner_count: u32,
+        },
+        OpenBox {
+            round_id: u32,
+            token_id: u32,
+            btc_address: Vec<u8>,
+        },
+    }
+}
+
 impl BtcLottery {
     /// Initializes the contract
     pub fn new(secret: Option<sr25519::Pair>) -> Self {
@@ -327,6 +365,14 @@ impl contracts::NativeContract for BtcLottery {
         contracts::BTC_LOTTERY
     }
 
+    fn message_types(&self) -> Option<contracts::MessageTypes> {
+        Some(contracts::MessageTypes::of::<
+            info::Command,
+            Request,
+            Response,
+        >())
+    }
+
     fn handle_command(
         &mut self,
         context: &mut NativeContext,
@@ -339,7 +385,9 @@ impl contracts::NativeContract for BtcLottery {
         }
     }
 
-    fn handle_query(&mut self, _origin: Option<&chain::AccountId>, req: Request) -> Response {
+    fn handle_query(&self, _origin: Option<&chain::AccountId>, req: Request) -> Response {
+        // A query out of budget fails with a timeout, whatever it responds
+        let out_of_budget = |count: usize| query_budget::charge_items(count).is_err();
         match req {
             Request::GetAllRounds => Response::GetAllRounds {
                 round_id: self.round_id,
@@ -372,6 +420,9 @@ impl contracts::NativeContract for BtcLottery {
                         .lottery_set
                         .get(&round_id)
                         .expect("round_id is known in the lottery_set; qed");
+                    if out_of_budget(temp.len()) {
+                        return Response::Error(Error::InvalidRequest);
+                    }
                     let mut address_set = Vec::new();
                     for (_, private_key) in temp.iter() {
                         let secp = Secp256k1::new();
@@ -391,7 +442,11 @@ impl contracts::NativeContract for BtcLottery {
                     let utxo = self
                         .utxo
                         .get(&round_id)
-                        .expect("round_id is known in the utxo set; qed")
+                        .expect("round_id is known in the utxo set; qed");
+                    if out_of_budget(utxo.len()) {
+                        return Response::Error(Error::InvalidRequest);
+                    }
+                    let utxo = utxo
                         .iter()
                         .map(|(addr, utxo)| (addr.to_string(), *utxo))
                         .collect();
@@ -400,9 +455,14 @@ impl contracts::NativeContract for BtcLottery {
                     Response::Error(Error::InvalidRequest)
                 }
             }
-            Request::GetSignedTx { round_id: _ } => Response::GetSignedTx {
-                tx_set: self.tx_set.clone(),
-            },
+            Request::GetSignedTx { round_id: _ } => {
+                if out_of_budget(self.tx_set.len()) {
+                    return Response::Error(Error::InvalidRequest);
+                }
+                Response::GetSignedTx {
+                    tx_set: self.tx_set.clone(),
+                }
+            }
         }
     }
 }
@@ -480,3 +540,67 @@ impl BtcLottery {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Mirrors the commands, matching all the variants so that none is left out of the mirrors.
+    fn mirror_command(command: &Command) -> info::Command {
+        match command {
+            Command::UserCommand(cmd) => info::Command::UserCommand(match cmd {
+                LotteryUserCommand::SubmitUtxo {
+                    round_id,
+                    address,
+                    utxo,
+                } => info::UserCommand::SubmitUtxo {
+                    round_id: *round_id,
+                    address: address.clone(),
+                    utxo: *utxo,
+                },
+                LotteryUserCommand::SetAdmin { new_admin } => info::UserCommand::SetAdmin {
+                    new_admin: new_admin.clone(),
+                },
+            }),
+            Command::PalletCommand(cmd) => info::Command::PalletCommand(match cmd {
+                LotteryPalletCommand::NewRound {
+                    round_id,
+                    total_count,
+                    winner_count,
+                } => info::PalletCommand::NewRound {
+                    round_id: *round_id,
+                    total_count: *total_count,
+                    winner_count: *winner_count,
+                },
+                LotteryPalletCommand::OpenBox {
+                    round_id,
+                    token_id,
+                    btc_address,
+                } => info::PalletCommand::OpenBox {
+                    round_id: *round_id,
+                    token_id: *token_id,
+                    btc_address: btc_address.clone(),
+                },
+            }),
+        }
+    }
+
+    #[test]
+    fn message_types_mirror_the_encoding() {
+        let commands = vec![
+            Command::UserCommand(LotteryUserCommand::SubmitUtxo {
+                round_id: 1,
+                address: "address".into(),
+                utxo: ([1; 32], 2, 3),
+            }),
+            Command::UserCommand(LotteryUserCommand::SetAdmin {
+                new_admin: ALICE.into(),
+            }),
+            Command::new_round(1, 10, 2),
+            Command::open_box(1, 2, b"address".to_vec()),
+        ];
+        for command in commands {
+            assert_eq!(command.encode(), mirror_command(&command).encode());
+        }
+    }
+}
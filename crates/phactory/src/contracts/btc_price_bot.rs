@@ -2,15 +2,15 @@ use anyhow::Result;
 use log::info;
 use parity_scale_codec::{Decode, Encode};
 use phala_mq::MessageOrigin;
+use scale_info::TypeInfo;
 use serde::{Deserialize, Serialize};
 use serde_json;
 
-use surf;
-
 use super::{TransactionError, TransactionResult};
 use crate::contracts;
+use crate::contracts::http::{self, HttpRequest, HttpResult, SurfBackend};
+use crate::contracts::side_task_votes::{Aggregation, AgreedValue};
 use crate::contracts::{AccountId, NativeContext};
-use crate::side_task::async_side_task::AsyncSideTask;
 extern crate runtime as chain;
 
 use phala_types::messaging::BtcPriceBotCommand;
@@ -19,10 +19,15 @@ type Command = BtcPriceBotCommand;
 
 /// Contract Overview
 ///
-/// This contract show the ability of Phala contracts to send HTTP request with `AsyncSideTask`.
+/// This contract show the ability of Phala contracts to send HTTP request as side tasks, to the domains allowed
+/// in `http_allowed_domains`.
+///
+/// Each worker running the contract fetches the price on its own, so the workers may see different
+/// prices. The price the contract keeps is the median of the ones reported by the workers, agreed
+/// on with `NativeContext::spawn_aggregated_task`.
 ///
-/// We recommend first to read about asynchronous programming in Rust <https://rust-lang.github.io/async-book/> since our
-/// `AsyncSideTask` follows the same manner.
+/// We recommend first to read about asynchronous programming in Rust <https://rust-lang.github.io/async-book/> since the
+/// side tasks follow the same manner.
 ///
 /// For now, you cannot use `tokio`-based HTTP crate since it is not compatible in SGX. Read more about the details in our
 /// PR <https://github.com/Phala-Network/phala-blockchain/pull/483> for the reason why.
@@ -36,13 +41,15 @@ pub struct BtcPriceBot {
     owner: AccountId,
     bot_token: String,
     chat_id: String,
+    /// The latest agreed BTC price in USD cents
+    price: Option<u128>,
 }
 
 /// The Queries to this contract
 ///
 /// End users query the contract state by directly sending Queries to the pRuntime without going on chain.
 /// They should not change the contract state.
-#[derive(Encode, Decode, Debug, Clone)]
+#[derive(Encode, Decode, Debug, Clone, TypeInfo)]
 pub enum Request {
     /// Query the current owner of the contract
     QueryOwner,
@@ -52,6 +59,8 @@ pub enum Request {
     /// Query the identifier to target chat
     /// refer to: https://core.telegram.org/bots/api#sendmessage
     QueryChatId,
+    /// Query the latest BTC price agreed on by the workers
+    QueryPrice,
 }
 
 /// The Query results
@@ -60,20 +69,44 @@ pub enum Response {
     Owner(AccountId),
     BotToken(String),
     ChatId(String),
+    /// The BTC price in USD cents, if any has been agreed on
+    Price(Option<u128>),
 }
 
-#[derive(Encode, Decode, Debug)]
+#[derive(Encode, Decode, Debug, TypeInfo)]
 pub enum Error {
     OriginUnavailable,
     NotAuthorized,
 }
 
+/// Mirrors of the messages containing `AccountId`, which has no `TypeInfo`
+mod info {
+    use super::*;
+    use crate::contracts::AccountIdInfo as AccountId;
+
+    #[derive(Encode, TypeInfo)]
+    pub enum Command {
+        SetOwner { owner: AccountId },
+        SetupBot { token: String, chat_id: String },
+        ReportBtcPrice,
+    }
+
+    #[derive(Encode, TypeInfo)]
+    pub enum Response {
+        Owner(AccountId),
+        BotToken(String),
+        ChatId(String),
+        Price(Option<u128>),
+    }
+}
+
 impl BtcPriceBot {
     pub fn new() -> Self {
         BtcPriceBot {
             owner: Default::default(),
             bot_token: Default::default(),
             chat_id: Default::default(),
+            price: None,
         }
     }
 }
@@ -93,6 +126,12 @@ struct BtcPrice {
     usd: f64,
 }
 
+/// The domains this contract sends HTTP requests to
+const ALLOWED_DOMAINS: &[&str] = &["min-api.cryptocompare.com", "api.telegram.org"];
+
+/// The number of blocks to collect the prices reported by the workers
+const REPORT_WINDOW: chain::BlockNumber = 10;
+
 // Alice is the pre-defined root account in dev mode
 const ALICE: &str = "d43593c715fdd31c61141abd04a99fd6822c8558854ccde39a5684e7a56da27d";
 
@@ -106,6 +145,20 @@ impl contracts::NativeContract for BtcPriceBot {
         contracts::BTC_PRICE_BOT
     }
 
+    /// Report the owner in the contract metadata
+    fn owner(&self) -> Option<AccountId> {
+        Some(self.owner.clone())
+    }
+
+    /// Describe the messages in the contract metadata
+    fn message_types(&self) -> Option<contracts::MessageTypes> {
+        Some(contracts::MessageTypes::of::<
+            info::Command,
+            Request,
+            Result<info::Response, Error>,
+        >())
+    }
+
     /// Handle the Commands from transactions on the blockchain. This method doesn't respond.
     fn handle_command(
         &mut self,
@@ -113,7 +166,11 @@ impl contracts::NativeContract for BtcPriceBot {
         origin: MessageOrigin,
         cmd: Command,
     ) -> TransactionResult {
-        info!("Command received: {:?}", &cmd);
+        // The bot token is kept out of the logs
+        match &cmd {
+            Command::SetupBot { chat_id, .. } => info!("Command received: SetupBot to {}", chat_id),
+            cmd => info!("Command received: {:?}", cmd),
+        }
 
         // we want to limit the sender who can use the Commands to the pre-define root account
         let sender = match &origin {
@@ -143,90 +200,119 @@ impl contracts::NativeContract for BtcPriceBot {
                     return Err(TransactionError::BadOrigin);
                 }
 
-                let bot_token = self.bot_token.clone();
-                let chat_id = self.chat_id.clone();
-
-                // This Command triggers the use of `AsyncSideTask`, it first send a HTTP request to get the current BTC
-                // price from https://min-api.cryptocompare.com/, then sends the price to a Telegram bot through another
-                // HTTP request
+                // This Command triggers an aggregated side task, it sends a HTTP request to get the current BTC price
+                // from https://min-api.cryptocompare.com/
                 //
                 // To ensure the state consistency, the time to start the task and the time to upload the HTTP response
-                // to chain must be determined. In this case, we start the task in the current `block_number`, and report
-                // the result, whether succeeded or failed, to the chain after `duration`
+                // to chain must be determined. In this case, we start the task in the current `block_number`, and each
+                // worker reports its result, whether succeeded or failed, to the chain after `duration`. The median of
+                // the prices reported in the following `REPORT_WINDOW` blocks is then handed to
+                // `handle_side_task_result`
                 //
                 // Report the result after 2 blocks no matter whether has received the HTTP response
-                let block_number = context.block.block_number;
                 let duration = 2;
 
-                let task = AsyncSideTask::spawn(
-                    block_number,
+                context.spawn_aggregated_task(
                     duration,
+                    REPORT_WINDOW,
+                    Aggregation::Median,
                     async move {
                         // Do network request in this block and return the result.
                         // Do NOT send mq message in this block.
                         log::info!("Side task starts to get BTC price");
-                        let mut resp = match surf::get(
+                        let request = HttpRequest::get(
                             "https://min-api.cryptocompare.com/data/price?fsym=BTC&tsyms=USD",
-                        )
-                        .send()
-                        .await
-                        {
+                        );
+                        let resp = match http::send(&SurfBackend, ALLOWED_DOMAINS, request).await {
                             Ok(r) => r,
                             Err(err) => {
-                                return format!("Network error: {:?}", err);
-                            }
-                        };
-                        let result = match resp.body_string().await {
-                            Ok(body) => body,
-                            Err(err) => {
-                                format!("Network error: {:?}", err)
+                                log::error!("Network error: {:?}", err);
+                                return None;
                             }
                         };
-                        log::info!("Side task got BTC price: {}", result);
-
-                        let price: BtcPrice =
-                            serde_json::from_str(result.as_str()).expect("broken BTC price result");
-                        let text = format!("BTC price: ${}", price.usd);
-                        let uri = format!(
-                            "https://api.telegram.org/bot{}/{}",
-                            bot_token, "sendMessage"
+                        log::info!(
+                            "Side task got BTC price: {}",
+                            String::from_utf8_lossy(&resp.body)
                         );
-                        let data = &TgMessage { chat_id, text };
 
-                        let mut resp = match surf::post(uri)
-                            .body_json(data)
-                            .expect("should not fail with valid data; qed.")
-                            .await
-                        {
-                            Ok(r) => r,
-                            Err(err) => {
-                                return format!("Network error: {:?}", err);
-                            }
-                        };
-                        let result = match resp.body_string().await {
-                            Ok(body) => body,
+                        let price: BtcPrice = match serde_json::from_slice(&resp.body) {
+                            Ok(price) => price,
                             Err(err) => {
-                                format!("Network error: {:?}", err)
+                                log::error!("Broken BTC price result: {:?}", err);
+                                return None;
                             }
                         };
-                        log::info!("Side task sent BTC price: {}", result);
-                        result
-                    },
-                    |_result, _context| {
-                        // You can send deterministic number of transactions in the result process
-                        // In this case, we don't send the price since it has already been reported to the TG bot above
+                        let cents = (price.usd * 100.0) as u128;
+                        Some(cents.encode())
                     },
                 );
-                context.block.side_task_man.add_task(task);
 
                 Ok(())
             }
         }
     }
 
+    /// Handle the price agreed on by the workers, and send it to the Telegram bot
+    fn handle_side_task_result(
+        &mut self,
+        context: &mut NativeContext,
+        _task_id: u64,
+        result: Option<AgreedValue>,
+    ) {
+        let agreed = result.and_then(|result| {
+            let price = u128::decode(&mut &result.value[..]).ok()?;
+            Some((price, result.reporter))
+        });
+        let (price, reporter) = match agreed {
+            Some(agreed) => agreed,
+            None => {
+                info!("The workers failed to agree on the BTC price");
+                return;
+            }
+        };
+        self.price = Some(price);
+
+        let uri = format!(
+            "https://api.telegram.org/bot{}/{}",
+            self.bot_token, "sendMessage"
+        );
+        let data = TgMessage {
+            chat_id: self.chat_id.clone(),
+            text: format!("BTC price: ${}.{:02}", price / 100, price % 100),
+        };
+        let request = HttpRequest {
+            method: "POST".into(),
+            url: uri,
+            headers: vec![("Content-Type".into(), "application/json".into())],
+            body: serde_json::to_vec(&data).expect("should not fail with valid data; qed."),
+            timeout_ms: http::MAX_TIMEOUT_MS,
+        };
+        // Only the worker reporting the agreed price sends the message, and reports whether it's
+        // sent after 2 blocks
+        if let Err(err) = context.http_request_from(&reporter, request, 2) {
+            log::error!("Failed to send the BTC price: {:?}", err);
+        }
+    }
+
+    fn http_allowed_domains(&self) -> &'static [&'static str] {
+        ALLOWED_DOMAINS
+    }
+
+    fn handle_http_response(
+        &mut self,
+        _context: &mut NativeContext,
+        _request_id: u64,
+        response: HttpResult,
+    ) {
+        match response {
+            Ok(resp) => log::info!("BTC price sent: {}", String::from_utf8_lossy(&resp.body)),
+            Err(err) => log::error!("Failed to send the BTC price: {:?}", err),
+        }
+    }
+
     // Handle a direct Query and respond to it. It shouldn't modify the contract state.
     fn handle_query(
-        &mut self,
+        &self,
         origin: Option<&chain::AccountId>,
         req: Request,
     ) -> Result<Response, Error> {
@@ -251,6 +337,63 @@ impl contracts::NativeContract for BtcPriceBot {
 
                 Ok(Response::ChatId(self.chat_id.clone()))
             }
+            Request::QueryPrice => Ok(Response::Price(self.price)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contracts::account_id_info;
+
+    /// Mirrors the messages, matching all the variants so that none is left out of the mirrors.
+    fn mirror_command(command: &Command) -> info::Command {
+        match command {
+            Command::SetOwner { owner } => info::Command::SetOwner {
+                owner: account_id_info(owner),
+            },
+            Command::SetupBot { token, chat_id } => info::Command::SetupBot {
+                token: token.clone(),
+                chat_id: chat_id.clone(),
+            },
+            Command::ReportBtcPrice => info::Command::ReportBtcPrice,
+        }
+    }
+
+    fn mirror_response(response: &Response) -> info::Response {
+        match response {
+            Response::Owner(owner) => info::Response::Owner(account_id_info(owner)),
+            Response::BotToken(token) => info::Response::BotToken(token.clone()),
+            Response::ChatId(chat_id) => info::Response::ChatId(chat_id.clone()),
+            Response::Price(price) => info::Response::Price(*price),
+        }
+    }
+
+    #[test]
+    fn message_types_mirror_the_encoding() {
+        let alice = AccountId::new([1; 32]);
+        let commands = vec![
+            Command::SetOwner {
+                owner: alice.clone(),
+            },
+            Command::SetupBot {
+                token: "token".into(),
+                chat_id: "chat".into(),
+            },
+            Command::ReportBtcPrice,
+        ];
+        for command in commands {
+            assert_eq!(command.encode(), mirror_command(&command).encode());
+        }
+        let responses = vec![
+            Response::Owner(alice),
+            Response::BotToken("token".into()),
+            Response::ChatId("chat".into()),
+            Response::Price(Some(1)),
+        ];
+        for response in responses {
+            assert_eq!(response.encode(), mirror_response(&response).encode());
         }
     }
 }
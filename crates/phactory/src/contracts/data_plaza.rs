@@ -1,29 +1,30 @@
-use super::{NativeContext, TransactionError, TransactionResult};
+use super::{NativeContext, QueryScratch, TransactionError, TransactionResult};
 use std::collections::{HashMap, HashSet};
 use csv_core::{ReadRecordResult, Reader};
 use log::info;
 
-use crate::contracts;
+use crate::contracts::{self, query_budget};
 use parity_scale_codec::{Decode, Encode};
 use phala_mq::MessageOrigin;
+use scale_info::TypeInfo;
 
 pub type ItemId = u32;
 pub type OrderId = u32;
 
-#[derive(Encode, Decode, Debug)]
+#[derive(Encode, Decode, Debug, TypeInfo)]
 pub enum Command {
     List(ItemDetails),
     OpenOrder(OrderDetails),
 }
 
-#[derive(Encode, Decode, Debug, Clone)]
+#[derive(Encode, Decode, Debug, Clone, TypeInfo)]
 pub struct OrderDetails {
     item_id: ItemId,
     query_link: String,
     index: u64,
 }
 
-#[derive(Encode, Decode, Debug, Clone)]
+#[derive(Encode, Decode, Debug, Clone, TypeInfo)]
 pub struct ItemDetails {
     pub name: String,
     pub category: String,
@@ -34,14 +35,14 @@ pub struct ItemDetails {
     pub index: u64,
 }
 
-#[derive(Encode, Decode, Debug, Clone)]
+#[derive(Encode, Decode, Debug, Clone, TypeInfo)]
 pub enum PricePolicy {
     PerRow { price: chain::Balance },
 }
 
 // item
 
-#[derive(Encode, Decode, Debug, Clone)]
+#[derive(Encode, Decode, Debug, Clone, TypeInfo)]
 pub struct Item {
     id: ItemId,
     // txref: TxRef,
@@ -51,7 +52,7 @@ pub struct Item {
 
 // order
 
-#[derive(Encode, Decode, Debug, Clone)]
+#[derive(Encode, Decode, Debug, Clone, TypeInfo)]
 pub struct Order {
     id: OrderId,
     // txref: TxRef,
@@ -60,7 +61,7 @@ pub struct Order {
     state: OrderState, // maybe shouldn't serialize this
 }
 
-#[derive(Encode, Decode, Debug, Clone)]
+#[derive(Encode, Decode, Debug, Clone, TypeInfo)]
 pub struct OrderState {
     data_ready: bool,
     query_ready: bool,
@@ -71,7 +72,7 @@ pub struct OrderState {
 
 // contract
 
-#[derive(Encode, Decode, Debug, Clone)]
+#[derive(Encode, Decode, Debug, Clone, TypeInfo)]
 pub enum Request {
     GetItems,
     GetOrders,
@@ -79,7 +80,7 @@ pub enum Request {
     Get(String),
 }
 
-#[derive(Encode, Decode, Debug)]
+#[derive(Encode, Decode, Debug, TypeInfo)]
 pub enum Response {
     GetItems { items: Vec<Item> },
     GetOrders { orders: Vec<Order> },
@@ -91,7 +92,8 @@ pub enum Response {
 pub struct DataPlaza {
     items: Vec<Item>,
     orders: Vec<Order>,
-    dataset: HashMap<String, Vec<u8>>,
+    /// The data uploaded by the queries, and the results of the orders.
+    dataset: QueryScratch<HashMap<String, Vec<u8>>>,
 }
 
 impl DataPlaza {
@@ -99,42 +101,47 @@ impl DataPlaza {
         Self {
             items: Vec::<Item>::new(),
             orders: Vec::<Order>::new(),
-            dataset: HashMap::<String, Vec<u8>>::new(),
+            dataset: Default::default(),
         }
     }
 
-    pub fn set(&mut self, key: String, value: Vec<u8>) {
-        self.dataset.insert(key, value);
+    pub fn set(&self, key: String, value: Vec<u8>) {
+        self.dataset.with(|dataset| dataset.insert(key, value));
     }
 
-    pub fn get(&self, key: &str) -> Option<&Vec<u8>> {
-        self.dataset.get(key)
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        self.dataset.with(|dataset| dataset.get(key).cloned())
     }
 
-    fn update_order_state(&mut self) {
-        for order in &mut self.orders {
-            let item_id = order.details.item_id;
-            let item = &self.items[item_id as usize];
-            // check data available
-            let data_link = &item.details.dataset_link;
-            if self.dataset.contains_key(data_link) {
-                order.state.data_ready = true;
-            }
-            // check query available
-            let query_link = &order.details.query_link;
-            if self.dataset.contains_key(query_link) {
-                order.state.query_ready = true;
-            }
-            // compute if possible
-            if order.state.data_ready && order.state.query_ready {
-                let dataset = &self.dataset[data_link];
-                let query = &self.dataset[query_link];
-                let data = Self::compute(order, dataset, query);
-
-                let path = order.state.result_path.clone();
-                self.dataset.insert(path, data);
+    /// Returns the orders with their states updated against the dataset.
+    fn updated_orders(&self) -> Vec<Order> {
+        let mut orders = self.orders.clone();
+        self.dataset.with(|dataset| {
+            for order in &mut orders {
+                let item_id = order.details.item_id;
+                let item = &self.items[item_id as usize];
+                // check data available
+                let data_link = &item.details.dataset_link;
+                if dataset.contains_key(data_link) {
+                    order.state.data_ready = true;
+                }
+                // check query available
+                let query_link = &order.details.query_link;
+                if dataset.contains_key(query_link) {
+                    order.state.query_ready = true;
+                }
+                // compute if possible
+                if order.state.data_ready && order.state.query_ready {
+                    let data_set = &dataset[data_link];
+                    let query = &dataset[query_link];
+                    let data = Self::compute(order, data_set, query);
+
+                    let path = order.state.result_path.clone();
+                    dataset.insert(path, data);
+                }
             }
-        }
+        });
+        orders
     }
 
     fn compute(order: &mut Order, dataset: &[u8], query: &[u8]) -> Vec<u8> {
@@ -258,6 +265,10 @@ impl contracts::NativeContract for DataPlaza {
         contracts::DATA_PLAZA
     }
 
+    fn message_types(&self) -> Option<contracts::MessageTypes> {
+        Some(contracts::MessageTypes::of::<Command, Request, Response>())
+    }
+
     fn handle_command(
         &mut self,
         _context: &mut NativeContext,
@@ -299,17 +310,24 @@ impl contracts::NativeContract for DataPlaza {
         }
     }
 
-    fn handle_query(&mut self, _origin: Option<&chain::AccountId>, req: Request) -> Response {
+    fn handle_query(&self, _origin: Option<&chain::AccountId>, req: Request) -> Response {
+        // A query out of budget fails with a timeout, whatever it responds
+        let out_of_budget = |count: usize| query_budget::charge_items(count).is_err();
         match req {
             Request::GetItems => Response::GetItems {
-                items: self.items.clone(),
+                items: if out_of_budget(self.items.len()) {
+                    Vec::new()
+                } else {
+                    self.items.clone()
+                },
+            },
+            Request::GetOrders => Response::GetOrders {
+                orders: if out_of_budget(self.orders.len()) {
+                    Vec::new()
+                } else {
+                    self.updated_orders()
+                },
             },
-            Request::GetOrders => {
-                self.update_order_state();
-                Response::GetOrders {
-                    orders: self.orders.clone(),
-                }
-            }
             Request::Set(k, v) => {
                 let v = match base64::decode(v) {
                     Ok(v) => v,
@@ -6,8 +6,8 @@ use anyhow::Result;
 use core::{fmt, str};
 use log::{error, info};
 
-use crate::contracts;
 use crate::contracts::AccountId;
+use crate::contracts::{self, query_budget};
 use crate::TransactionResult;
 
 //diem type
@@ -38,6 +38,7 @@ use diem_types::{
 type SparseMerkleProof = diem_types::proof::SparseMerkleProof<AccountStateBlob>;
 use parity_scale_codec::{Decode, Encode};
 use phala_types::messaging::{DiemCommand as Command, MessageOrigin, PushCommand};
+use scale_info::TypeInfo;
 use rand::{rngs::OsRng, Rng, SeedableRng};
 
 use super::NativeContext;
@@ -96,7 +97,7 @@ impl fmt::Display for Error {
     }
 }
 
-#[derive(Encode, Decode, Debug, Clone)]
+#[derive(Encode, Decode, Debug, Clone, TypeInfo)]
 pub enum Request {
     /// Gets all the verified transactions, in hex hash string
     VerifiedTransactions,
@@ -165,12 +166,67 @@ pub struct AccountData {
     locked: u64,
 }
 
-#[derive(Encode, Decode, Debug, Clone)]
+#[derive(Encode, Decode, Debug, Clone, TypeInfo)]
 pub struct State {
     queue_seq: u64,
     account_address: Vec<String>,
 }
 
+/// Mirrors of the messages containing `AccountId`, `AccountAddress` or the types of `phala_types`,
+/// which have no `TypeInfo`
+mod info {
+    use super::*;
+    use crate::contracts::AccountIdInfo as AccountId;
+
+    /// `AccountAddress` described as the bytes it's SCALE encoded to
+    pub type AccountAddress = [u8; 16];
+
+    #[derive(Encode, TypeInfo)]
+    pub enum Command {
+        AccountInfo {
+            account_info_b64: String,
+        },
+        VerifyTransaction {
+            account_address: String,
+            transaction_with_proof_b64: String,
+        },
+        SetTrustedState {
+            trusted_state_b64: String,
+            chain_id: u8,
+        },
+        VerifyEpochProof {
+            ledger_info_with_signatures_b64: String,
+            epoch_change_proof_b64: String,
+        },
+        NewAccount {
+            seq_number: u64,
+        },
+        TransferXUS {
+            to: String,
+            amount: u64,
+        },
+    }
+
+    #[derive(Encode, TypeInfo)]
+    pub struct AccountData {
+        pub is_vasp: bool,
+        pub address: AccountAddress,
+        pub phala_address: AccountId,
+        pub sequence: u64,
+        pub free: u64,
+        pub locked: u64,
+    }
+
+    #[derive(Encode, TypeInfo)]
+    pub enum Response {
+        VerifiedTransactions { hash: Vec<String> },
+        GetSignedTransactions { queue_b64: String },
+        CurrentState { state: State },
+        AccountData { data: Vec<AccountData> },
+        Error(String),
+    }
+}
+
 pub struct Diem {
     chain_id: u8,
     account_info: Vec<AccountInfo>,
@@ -518,6 +574,14 @@ impl contracts::NativeContract for Diem {
         contracts::DIEM
     }
 
+    fn message_types(&self) -> Option<contracts::MessageTypes> {
+        Some(contracts::MessageTypes::of::<
+            info::Command,
+            Request,
+            info::Response,
+        >())
+    }
+
     fn handle_command(
         &mut self,
         _context: &mut NativeContext,
@@ -912,15 +976,17 @@ impl contracts::NativeContract for Diem {
         }
     }
 
-    fn handle_query(&mut self, _origin: Option<&chain::AccountId>, req: Request) -> Response {
+    fn handle_query(&self, _origin: Option<&chain::AccountId>, req: Request) -> Response {
         let inner = || -> Result<Response> {
             match req {
                 Request::VerifiedTransactions => {
+                    query_budget::charge_items_or_bail(self.verified.len())?;
                     let hash: Vec<_> = self.verified.keys().cloned().collect();
                     Ok(Response::VerifiedTransactions { hash })
                 }
                 Request::GetSignedTransactions { start } => {
                     info!("GetSignedTransactions: {:}", start);
+                    query_budget::charge_items_or_bail(self.tx_queue.len())?;
                     let queue: Vec<&TransactionData> = self
                         .tx_queue
                         .iter()
@@ -930,6 +996,7 @@ impl contracts::NativeContract for Diem {
                     Ok(Response::GetSignedTransactions { queue_b64 })
                 }
                 Request::CurrentState => {
+                    query_budget::charge_items_or_bail(self.account_address.len())?;
                     let state = State {
                         queue_seq: self.queue_seq,
                         account_address: self.account_address.clone(),
@@ -938,6 +1005,7 @@ impl contracts::NativeContract for Diem {
                     Ok(Response::CurrentState { state })
                 }
                 Request::AccountData => {
+                    query_budget::charge_items_or_bail(self.account_address.len())?;
                     let mut account_data = Vec::new();
                     for account_address in self.account_address.iter() {
                         if let Some(phala_address) = self.address.get(account_address) {
@@ -986,3 +1054,134 @@ impl core::fmt::Debug for Diem {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contracts::account_id_info;
+
+    /// Mirrors the messages, matching all the variants so that none is left out of the mirrors.
+    fn mirror_command(command: &Command) -> info::Command {
+        match command {
+            Command::AccountInfo { account_info_b64 } => info::Command::AccountInfo {
+                account_info_b64: account_info_b64.clone(),
+            },
+            Command::VerifyTransaction {
+                account_address,
+                transaction_with_proof_b64,
+            } => info::Command::VerifyTransaction {
+                account_address: account_address.clone(),
+                transaction_with_proof_b64: transaction_with_proof_b64.clone(),
+            },
+            Command::SetTrustedState {
+                trusted_state_b64,
+                chain_id,
+            } => info::Command::SetTrustedState {
+                trusted_state_b64: trusted_state_b64.clone(),
+                chain_id: *chain_id,
+            },
+            Command::VerifyEpochProof {
+                ledger_info_with_signatures_b64,
+                epoch_change_proof_b64,
+            } => info::Command::VerifyEpochProof {
+                ledger_info_with_signatures_b64: ledger_info_with_signatures_b64.clone(),
+                epoch_change_proof_b64: epoch_change_proof_b64.clone(),
+            },
+            Command::NewAccount { seq_number } => info::Command::NewAccount {
+                seq_number: *seq_number,
+            },
+            Command::TransferXUS { to, amount } => info::Command::TransferXUS {
+                to: to.clone(),
+                amount: *amount,
+            },
+        }
+    }
+
+    fn mirror_response(response: &Response) -> info::Response {
+        match response {
+            Response::VerifiedTransactions { hash } => {
+                info::Response::VerifiedTransactions { hash: hash.clone() }
+            }
+            Response::GetSignedTransactions { queue_b64 } => {
+                info::Response::GetSignedTransactions {
+                    queue_b64: queue_b64.clone(),
+                }
+            }
+            Response::CurrentState { state } => info::Response::CurrentState {
+                state: state.clone(),
+            },
+            Response::AccountData { data } => info::Response::AccountData {
+                data: data
+                    .iter()
+                    .map(|data| info::AccountData {
+                        is_vasp: data.is_vasp,
+                        address: data.address.to_u8(),
+                        phala_address: account_id_info(&data.phala_address),
+                        sequence: data.sequence,
+                        free: data.free,
+                        locked: data.locked,
+                    })
+                    .collect(),
+            },
+            Response::Error(err) => info::Response::Error(err.clone()),
+        }
+    }
+
+    #[test]
+    fn message_types_mirror_the_encoding() {
+        let commands = vec![
+            Command::AccountInfo {
+                account_info_b64: "info".into(),
+            },
+            Command::VerifyTransaction {
+                account_address: ALICE_ADDRESS.into(),
+                transaction_with_proof_b64: "proof".into(),
+            },
+            Command::SetTrustedState {
+                trusted_state_b64: "state".into(),
+                chain_id: 1,
+            },
+            Command::VerifyEpochProof {
+                ledger_info_with_signatures_b64: "ledger".into(),
+                epoch_change_proof_b64: "proof".into(),
+            },
+            Command::NewAccount { seq_number: 1 },
+            Command::TransferXUS {
+                to: ALICE_ADDRESS.into(),
+                amount: 1,
+            },
+        ];
+        for command in commands {
+            assert_eq!(command.encode(), mirror_command(&command).encode());
+        }
+        let responses = vec![
+            Response::VerifiedTransactions {
+                hash: vec!["hash".into()],
+            },
+            Response::GetSignedTransactions {
+                queue_b64: "queue".into(),
+            },
+            Response::CurrentState {
+                state: State {
+                    queue_seq: 1,
+                    account_address: vec![ALICE_ADDRESS.into()],
+                },
+            },
+            Response::AccountData {
+                data: vec![AccountData {
+                    is_vasp: true,
+                    address: AccountAddress::from_hex_literal("0xD4F0C053205BA934BB2AC0C4E8479E77")
+                        .unwrap(),
+                    phala_address: AccountId::new([1; 32]),
+                    sequence: 1,
+                    free: 2,
+                    locked: 3,
+                }],
+            },
+            Response::Error("error".into()),
+        ];
+        for response in responses {
+            assert_eq!(response.encode(), mirror_response(&response).encode());
+        }
+    }
+}
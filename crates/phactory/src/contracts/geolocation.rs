@@ -6,10 +6,11 @@ use core::fmt;
 use log::info;
 use parity_scale_codec::{Decode, Encode};
 use phala_mq::MessageOrigin;
+use scale_info::TypeInfo;
 use std::convert::TryFrom;
 
 use super::{TransactionError, TransactionResult};
-use crate::contracts;
+use crate::contracts::{self, query_budget};
 use crate::contracts::{AccountId, NativeContext};
 extern crate runtime as chain;
 
@@ -22,7 +23,7 @@ pub struct Geolocation {
     city_distribution: BTreeMap<String, Vec<AccountId>>,
 }
 
-#[derive(Encode, Decode, Debug)]
+#[derive(Encode, Decode, Debug, TypeInfo)]
 pub enum Error {
     // InvalidRequest,
     NoRecord,
@@ -48,6 +49,41 @@ pub enum Response {
     Error(String),
 }
 
+/// Mirrors of the messages containing `AccountId` or the types of `phala_types`, which have no `TypeInfo`
+mod info {
+    use super::*;
+    use crate::contracts::AccountIdInfo as AccountId;
+
+    #[derive(Encode, TypeInfo)]
+    pub struct CoordinateInfo {
+        pub latitude: i32,
+        pub longitude: i32,
+        pub city_name: String,
+    }
+
+    #[derive(Encode, TypeInfo)]
+    pub enum Command {
+        UpdateGeolocation { geolocation_info: CoordinateInfo },
+    }
+
+    #[derive(Encode, TypeInfo)]
+    pub enum Request {
+        GetGeolocationInfo { account: AccountId },
+        GetAvailableCityName {},
+        GetCityDistribution { city_name: String },
+        GetCityDistributionCount { city_name: String },
+    }
+
+    #[derive(Encode, TypeInfo)]
+    pub enum Response {
+        GetGeolocationInfo { geolocation_info: CoordinateInfo },
+        GetAvailableCityName { city_names: Vec<String> },
+        GetCityDistribution { workers: Vec<AccountId> },
+        GetCityDistributionCount { count: u32 },
+        Error(String),
+    }
+}
+
 impl Geolocation {
     pub fn new() -> Self {
         Geolocation {
@@ -66,6 +102,14 @@ impl contracts::NativeContract for Geolocation {
         contracts::GEOLOCATION
     }
 
+    fn message_types(&self) -> Option<contracts::MessageTypes> {
+        Some(contracts::MessageTypes::of::<
+            info::Command,
+            info::Request,
+            Result<info::Response, Error>,
+        >())
+    }
+
     fn handle_command(
         &mut self,
         context: &mut NativeContext,
@@ -119,7 +163,7 @@ impl contracts::NativeContract for Geolocation {
     }
 
     fn handle_query(
-        &mut self,
+        &self,
         origin: Option<&chain::AccountId>,
         req: Request,
     ) -> Result<Response, Error> {
@@ -136,6 +180,9 @@ impl contracts::NativeContract for Geolocation {
                 }
             }
             Request::GetAvailableCityName {} => {
+                // A query out of budget fails with a timeout, whatever it responds
+                query_budget::charge_items(self.city_distribution.len())
+                    .map_err(|_| Error::Unimplemented)?;
                 let city_names: Vec<String> = self.city_distribution.keys().cloned().collect();
                 Ok(Response::GetAvailableCityName { city_names })
             }
@@ -160,3 +207,107 @@ impl contracts::NativeContract for Geolocation {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contracts::account_id_info;
+
+    /// Mirrors the messages, matching all the variants so that none is left out of the mirrors.
+    fn mirror_coordinate(info: &CoordinateInfo) -> info::CoordinateInfo {
+        info::CoordinateInfo {
+            latitude: info.latitude,
+            longitude: info.longitude,
+            city_name: info.city_name.clone(),
+        }
+    }
+
+    fn mirror_command(command: &Command) -> info::Command {
+        match command {
+            Command::UpdateGeolocation { geolocation_info } => info::Command::UpdateGeolocation {
+                geolocation_info: mirror_coordinate(geolocation_info),
+            },
+        }
+    }
+
+    fn mirror_request(request: &Request) -> info::Request {
+        match request {
+            Request::GetGeolocationInfo { account } => info::Request::GetGeolocationInfo {
+                account: account_id_info(account),
+            },
+            Request::GetAvailableCityName {} => info::Request::GetAvailableCityName {},
+            Request::GetCityDistribution { city_name } => info::Request::GetCityDistribution {
+                city_name: city_name.clone(),
+            },
+            Request::GetCityDistributionCount { city_name } => {
+                info::Request::GetCityDistributionCount {
+                    city_name: city_name.clone(),
+                }
+            }
+        }
+    }
+
+    fn mirror_response(response: &Response) -> info::Response {
+        match response {
+            Response::GetGeolocationInfo { geolocation_info } => {
+                info::Response::GetGeolocationInfo {
+                    geolocation_info: mirror_coordinate(geolocation_info),
+                }
+            }
+            Response::GetAvailableCityName { city_names } => info::Response::GetAvailableCityName {
+                city_names: city_names.clone(),
+            },
+            Response::GetCityDistribution { workers } => info::Response::GetCityDistribution {
+                workers: workers.iter().map(account_id_info).collect(),
+            },
+            Response::GetCityDistributionCount { count } => {
+                info::Response::GetCityDistributionCount { count: *count }
+            }
+            Response::Error(err) => info::Response::Error(err.clone()),
+        }
+    }
+
+    #[test]
+    fn message_types_mirror_the_encoding() {
+        let alice = AccountId::new([1; 32]);
+        let coordinate = CoordinateInfo {
+            latitude: 1,
+            longitude: -2,
+            city_name: "city".into(),
+        };
+
+        let command = Command::update_geolocation(coordinate.clone());
+        assert_eq!(command.encode(), mirror_command(&command).encode());
+        let requests = vec![
+            Request::GetGeolocationInfo {
+                account: alice.clone(),
+            },
+            Request::GetAvailableCityName {},
+            Request::GetCityDistribution {
+                city_name: "city".into(),
+            },
+            Request::GetCityDistributionCount {
+                city_name: "city".into(),
+            },
+        ];
+        for request in requests {
+            assert_eq!(request.encode(), mirror_request(&request).encode());
+        }
+        let responses = vec![
+            Response::GetGeolocationInfo {
+                geolocation_info: coordinate,
+            },
+            Response::GetAvailableCityName {
+                city_names: vec!["city".into()],
+            },
+            Response::GetCityDistribution {
+                workers: vec![alice],
+            },
+            Response::GetCityDistributionCount { count: 1 },
+            Response::Error("error".into()),
+        ];
+        for response in responses {
+            assert_eq!(response.encode(), mirror_response(&response).encode());
+        }
+    }
+}
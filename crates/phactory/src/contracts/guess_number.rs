@@ -2,12 +2,13 @@ use anyhow::Result;
 use log::info;
 use parity_scale_codec::{Decode, Encode};
 use phala_mq::MessageOrigin;
+use scale_info::TypeInfo;
 use sp_core::hashing;
 use std::convert::TryInto;
 
 use super::{TransactionError, TransactionResult};
 use crate::contracts;
-use crate::contracts::{AccountId, NativeContext};
+use crate::contracts::{AccountId, NativeContext, QueryScratch};
 extern crate runtime as chain;
 
 use phala_types::messaging::GuessNumberCommand;
@@ -43,6 +44,14 @@ type sCore= i32;
 pub struct GuessNumber {
     owner: AccountId,
     random_number: RandomNumber,
+    game: QueryScratch<Game>,
+}
+
+/// The game against the bot, played by the queries
+///
+/// The queries are not allowed to change the contract state, so the game is kept as the query scratch state, which
+/// is local to this instance of the contract.
+struct Game {
     bot_number: RandomNumber,
     min: RandomNumber,
     max: RandomNumber,
@@ -54,7 +63,7 @@ pub struct GuessNumber {
 ///
 /// End users query the contract state by directly sending Queries to the pRuntime without going on chain.
 /// They should not change the contract state.
-#[derive(Encode, Decode, Debug, Clone)]
+#[derive(Encode, Decode, Debug, Clone, TypeInfo)]
 pub enum Request {
     /// Query the current owner of the contract
     QueryOwner,
@@ -67,7 +76,7 @@ pub enum Request {
     QueryScore1,
 }
 
-#[derive(Encode, Decode, Debug, Clone)]
+#[derive(Encode, Decode, Debug, Clone, TypeInfo)]
 pub enum GuessResult {
     TooLarge,
     TooSmall,
@@ -85,6 +94,39 @@ pub enum Response {
     Score1(sCore),
 }
 
+/// Mirrors of the messages containing `AccountId`, which has no `TypeInfo`
+///
+/// They are encoded the same as the messages, so the message types reported in the contract metadata can describe
+/// the messages with them.
+mod info {
+    use super::*;
+    use crate::contracts::AccountIdInfo as AccountId;
+
+    #[derive(Encode, TypeInfo)]
+    pub enum Command {
+        NextRandom,
+        SetOwner { owner: AccountId },
+    }
+
+    #[derive(Encode, TypeInfo)]
+    pub enum Response {
+        Owner(AccountId),
+        GuessResult(GuessResult),
+        RandomNumber(RandomNumber),
+        Score0(sCore),
+        Score1(sCore),
+    }
+}
+
+/// The events published to the subscribers of this contract
+///
+/// Clients subscribe to them with `SubscribeContractEvents` instead of polling the contract with Queries.
+#[derive(Encode, Decode, Debug, Clone)]
+pub enum Event {
+    /// A new round started with a new random number
+    NewRound,
+}
+
 #[derive(Encode, Decode, Debug)]
 pub enum Error {
     OriginUnavailable,
@@ -96,11 +138,13 @@ impl GuessNumber {
         GuessNumber {
             owner: Default::default(),
             random_number: Default::default(),
-            bot_number: Default::default(),
-            min: Default::default(),
-            max: RandomNumber::MAX,
-            sc0:0,
-            sc1:0,
+            game: QueryScratch::new(Game {
+                bot_number: Default::default(),
+                min: Default::default(),
+                max: RandomNumber::MAX,
+                sc0:0,
+                sc1:0,
+            }),
         }
     }
 
@@ -137,14 +181,16 @@ impl GuessNumber {
                 }
 
     }
+}
 
-    pub fn botNumber(&mut self) {
-        if self.bot_number > self.random_number {
+impl Game {
+    fn botNumber(&mut self, random_number: RandomNumber) {
+        if self.bot_number > random_number {
             self.max= self.bot_number;
             let flt = (&self.max-&self.min) as f64;
             let flt = (flt*0.5) as i32;
             self.bot_number=&self.min+flt;
-                } else if self.bot_number < self.random_number {
+                } else if self.bot_number < random_number {
                     self.min= self.bot_number;
                     let flt = (&self.max-&self.min) as f64;
                     let flt = (flt*0.5) as i32;
@@ -167,6 +213,25 @@ impl contracts::NativeContract for GuessNumber {
         contracts::GUESS_NUMBER
     }
 
+    /// Report the owner in the contract metadata
+    fn owner(&self) -> Option<AccountId> {
+        Some(self.owner.clone())
+    }
+
+    /// Describe the messages in the contract metadata, so that clients can query the contract in JSON
+    fn message_types(&self) -> Option<contracts::MessageTypes> {
+        Some(contracts::MessageTypes::of::<
+            info::Command,
+            Request,
+            info::Response,
+        >())
+    }
+
+    /// Cover the contract state in the query checks of debug builds
+    fn state_digest(&self) -> Option<[u8; 32]> {
+        Some(hashing::blake2_256(&(&self.owner, self.random_number).encode()))
+    }
+
     /// Handle the Commands from transactions on the blockchain. This method doesn't respond.
     ///
     /// # Arguments
@@ -200,6 +265,7 @@ impl contracts::NativeContract for GuessNumber {
                     self.random_number*=-1;
                     //self.bot_number*=-1;    
                 }
+                context.publish_event(None, &Event::NewRound);
                 Ok(())
             }
             Command::SetOwner { owner } => {
@@ -219,24 +285,24 @@ impl contracts::NativeContract for GuessNumber {
     /// * `origin` - For off-chain Query, the sender can only be AccountId
     /// * `req` — Off-chain Query to handle
     fn handle_query(
-        &mut self,
+        &self,
         origin: Option<&chain::AccountId>,
         req: Request,
     ) -> Result<Response, Error> {
         info!("Query received: {:?}", &req);
         match req {
             Request::QueryOwner => Ok(Response::Owner(self.owner.clone())),
-            Request::Guess { guess_number } => {      
+            Request::Guess { guess_number } => self.game.with(|game| {
 
                 //Calculating scores
-                let s1= GuessNumber::calc_score(&guess_number,&self.random_number,&self.sc0);                
-                self.sc0=s1;
-                //self.sc0=5;
-                self.botNumber();
-                let s2= GuessNumber::calc_score(&self.bot_number,&self.random_number,&self.sc1);
-                self.sc1=s2;               
+                let s1= GuessNumber::calc_score(&guess_number,&self.random_number,&game.sc0);                
+                game.sc0=s1;
+                //game.sc0=5;
+                game.botNumber(self.random_number);
+                let s2= GuessNumber::calc_score(&game.bot_number,&self.random_number,&game.sc1);
+                game.sc1=s2;               
                 
-                if self.bot_number == self.random_number {
+                if game.bot_number == self.random_number {
                     Ok(Response::GuessResult(GuessResult::BotWin))
                 }else if guess_number > self.random_number {                    
                     Ok(Response::GuessResult(GuessResult::TooLarge))
@@ -245,7 +311,7 @@ impl contracts::NativeContract for GuessNumber {
                 } else {
                     Ok(Response::GuessResult(GuessResult::Correct))
                 }
-            }
+            }),
 
          
             Request::PeekRandomNumber => {
@@ -257,19 +323,21 @@ impl contracts::NativeContract for GuessNumber {
                 if sender != &alice && sender != &self.owner {
                     return Err(Error::NotAuthorized);
                 }
-                self.sc0=0;
-                self.sc1=0;
+                self.game.with(|game| {
+                    game.sc0=0;
+                    game.sc1=0;
+                });
                 Ok(Response::RandomNumber(self.random_number))
             }
 
             Request::QueryScore0 => {                
                 //self.sc0=3;
-                Ok(Response::Score0(self.sc0))                    
+                Ok(Response::Score0(self.game.with(|game| game.sc0)))                    
                 }
 
             Request::QueryScore1 => {
                 //self.sc1=2;
-                Ok(Response::Score1(self.sc1))
+                Ok(Response::Score1(self.game.with(|game| game.sc1)))
                     
                 }
                 
@@ -277,4 +345,54 @@ impl contracts::NativeContract for GuessNumber {
         }
     }
     
-    
\ No newline at end of file
+    
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contracts::account_id_info;
+
+    /// Mirrors the messages, matching all the variants so that none is left out of the mirrors.
+    fn mirror_command(command: &Command) -> info::Command {
+        match command {
+            Command::NextRandom => info::Command::NextRandom,
+            Command::SetOwner { owner } => info::Command::SetOwner {
+                owner: account_id_info(owner),
+            },
+        }
+    }
+
+    fn mirror_response(response: &Response) -> info::Response {
+        match response {
+            Response::Owner(owner) => info::Response::Owner(account_id_info(owner)),
+            Response::GuessResult(result) => info::Response::GuessResult(result.clone()),
+            Response::RandomNumber(number) => info::Response::RandomNumber(*number),
+            Response::Score0(score) => info::Response::Score0(*score),
+            Response::Score1(score) => info::Response::Score1(*score),
+        }
+    }
+
+    #[test]
+    fn message_types_mirror_the_encoding() {
+        let alice = AccountId::new([1; 32]);
+        let commands = vec![
+            Command::NextRandom,
+            Command::SetOwner {
+                owner: alice.clone(),
+            },
+        ];
+        for command in commands {
+            assert_eq!(command.encode(), mirror_command(&command).encode());
+        }
+        let responses = vec![
+            Response::Owner(alice),
+            Response::GuessResult(GuessResult::BotWin),
+            Response::RandomNumber(-1),
+            Response::Score0(2),
+            Response::Score1(3),
+        ];
+        for response in responses {
+            assert_eq!(response.encode(), mirror_response(&response).encode());
+        }
+    }
+}
@@ -0,0 +1,247 @@
+//! HTTP requests sent by the contracts, as side tasks.
+//!
+//! A contract can only reach the domains it allows in `NativeContract::http_allowed_domains`.
+//! The outcome of a request is reported on chain by each worker, encrypted to the contract, so the
+//! response is cut to its status and body, which are limited to `MAX_RESPONSE_SIZE` bytes.
+
+use super::*;
+use async_std::io::ReadExt as _;
+use futures::Future;
+use std::fmt;
+use std::pin::Pin;
+use std::str::FromStr as _;
+use std::time::Duration;
+use surf::http::headers::{HeaderName, HeaderValue};
+use surf::http::Method;
+use surf::Url;
+
+/// The max size of the body of a response.
+pub const MAX_RESPONSE_SIZE: usize = 16 * 1024;
+/// The max time to wait for a response, in milliseconds.
+pub const MAX_TIMEOUT_MS: u64 = 30_000;
+/// The number of blocks the outcomes of a request are collected from the workers.
+pub const REPORT_WINDOW: chain::BlockNumber = 10;
+
+/// An HTTP request sent by a contract.
+///
+/// The url may carry a secret, e.g. the token of a bot API, so only its host is printed.
+#[derive(Encode, Decode, Clone, PartialEq, Eq)]
+pub struct HttpRequest {
+    /// The method, e.g. `GET` or `POST`.
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// The time to wait for the response, capped at `MAX_TIMEOUT_MS`.
+    pub timeout_ms: u64,
+}
+
+impl HttpRequest {
+    /// A `GET` request with the max timeout.
+    pub fn get(url: impl Into<String>) -> Self {
+        HttpRequest {
+            method: "GET".into(),
+            url: url.into(),
+            headers: vec![],
+            body: vec![],
+            timeout_ms: MAX_TIMEOUT_MS,
+        }
+    }
+}
+
+impl fmt::Debug for HttpRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HttpRequest")
+            .field("method", &self.method)
+            .field("host", &host_of(&self.url))
+            .field("timeout_ms", &self.timeout_ms)
+            .finish()
+    }
+}
+
+/// The host of the url, to be printed in place of the url.
+fn host_of(url: &str) -> Option<String> {
+    Url::parse(url).ok()?.host_str().map(ToString::to_string)
+}
+
+/// The response to an `HttpRequest`.
+///
+/// The headers are dropped, for they usually differ between the workers, e.g. the `Date`.
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub body: Vec<u8>,
+}
+
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+pub enum HttpError {
+    /// The host of the url is not allowed by the contract.
+    NotAllowed,
+    /// The method, url or headers are malformed.
+    InvalidRequest,
+    Timeout,
+    ResponseTooLarge,
+    NetworkError,
+    /// The workers failed to agree on the outcome of the request.
+    NoAgreement,
+}
+
+pub type HttpResult = Result<HttpResponse, HttpError>;
+
+/// Sends the requests to the network.
+pub trait HttpBackend: Send + Sync {
+    /// Sends a request, failing with `ResponseTooLarge` if the body exceeds `max_size` bytes.
+    fn send(
+        &self,
+        request: HttpRequest,
+        max_size: usize,
+    ) -> Pin<Box<dyn Future<Output = HttpResult> + Send>>;
+}
+
+/// Sends the requests with `surf`.
+pub struct SurfBackend;
+
+impl HttpBackend for SurfBackend {
+    fn send(
+        &self,
+        request: HttpRequest,
+        max_size: usize,
+    ) -> Pin<Box<dyn Future<Output = HttpResult> + Send>> {
+        Box::pin(async move {
+            let method = Method::from_str(&request.method).or(Err(HttpError::InvalidRequest))?;
+            let url = Url::parse(&request.url).or(Err(HttpError::InvalidRequest))?;
+            let mut builder = surf::RequestBuilder::new(method, url).body(request.body);
+            for (name, value) in request.headers.iter() {
+                let name = HeaderName::from_str(name).or(Err(HttpError::InvalidRequest))?;
+                let value = HeaderValue::from_str(value).or(Err(HttpError::InvalidRequest))?;
+                builder = builder.header(name, value);
+            }
+            let response = builder.send().await.map_err(|err| {
+                // The error may carry the url
+                info!(
+                    "HTTP request to {:?} failed: {}",
+                    host_of(&request.url),
+                    err.status()
+                );
+                HttpError::NetworkError
+            })?;
+            let status_code = response.status().into();
+            let mut body = Vec::new();
+            response
+                .take(max_size as u64 + 1)
+                .read_to_end(&mut body)
+                .await
+                .or(Err(HttpError::NetworkError))?;
+            if body.len() > max_size {
+                return Err(HttpError::ResponseTooLarge);
+            }
+            Ok(HttpResponse { status_code, body })
+        })
+    }
+}
+
+/// Checks whether the host of the url is one of the domains, or a subdomain of them.
+pub fn check_allowed(allowed_domains: &[&str], url: &str) -> Result<(), HttpError> {
+    let url = Url::parse(url).or(Err(HttpError::InvalidRequest))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(HttpError::InvalidRequest);
+    }
+    let host = url.host_str().ok_or(HttpError::InvalidRequest)?;
+    let allowed = allowed_domains.iter().any(|domain| {
+        host == *domain
+            || host
+                .strip_suffix(domain)
+                .map_or(false, |prefix| prefix.ends_with('.'))
+    });
+    if allowed {
+        Ok(())
+    } else {
+        Err(HttpError::NotAllowed)
+    }
+}
+
+/// Sends a request to one of the allowed domains, within its timeout and `MAX_RESPONSE_SIZE`.
+pub async fn send(
+    backend: &dyn HttpBackend,
+    allowed_domains: &[&str],
+    request: HttpRequest,
+) -> HttpResult {
+    check_allowed(allowed_domains, &request.url)?;
+    let timeout = Duration::from_millis(request.timeout_ms.min(MAX_TIMEOUT_MS));
+    async_std::future::timeout(timeout, backend.send(request, MAX_RESPONSE_SIZE))
+        .await
+        .unwrap_or(Err(HttpError::Timeout))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Replies to the requests with the canned bodies, by url, and never replies to the others.
+    struct MockBackend(BTreeMap<String, Vec<u8>>);
+
+    impl HttpBackend for MockBackend {
+        fn send(
+            &self,
+            request: HttpRequest,
+            max_size: usize,
+        ) -> Pin<Box<dyn Future<Output = HttpResult> + Send>> {
+            match self.0.get(&request.url).cloned() {
+                Some(body) if body.len() > max_size => {
+                    Box::pin(async { Err(HttpError::ResponseTooLarge) })
+                }
+                Some(body) => Box::pin(async move {
+                    Ok(HttpResponse {
+                        status_code: 200,
+                        body,
+                    })
+                }),
+                None => Box::pin(futures::future::pending()),
+            }
+        }
+    }
+
+    #[test]
+    fn requests_are_checked_and_limited() {
+        let backend = MockBackend(
+            vec![
+                ("https://api.example.com/price".to_string(), b"42".to_vec()),
+                (
+                    "https://example.com/large".to_string(),
+                    vec![0; MAX_RESPONSE_SIZE + 1],
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let allowed = ["example.com"];
+        let fetch = |request| async_std::task::block_on(send(&backend, &allowed, request));
+
+        assert_eq!(
+            fetch(HttpRequest::get("https://api.example.com/price")),
+            Ok(HttpResponse {
+                status_code: 200,
+                body: b"42".to_vec()
+            })
+        );
+        assert_eq!(
+            fetch(HttpRequest::get("https://example.com/large")),
+            Err(HttpError::ResponseTooLarge)
+        );
+        assert_eq!(
+            fetch(HttpRequest::get("https://badexample.com/")),
+            Err(HttpError::NotAllowed)
+        );
+        assert_eq!(
+            fetch(HttpRequest::get("ftp://example.com/")),
+            Err(HttpError::InvalidRequest)
+        );
+        assert_eq!(
+            fetch(HttpRequest {
+                timeout_ms: 10,
+                ..HttpRequest::get("https://example.com/slow")
+            }),
+            Err(HttpError::Timeout)
+        );
+    }
+}
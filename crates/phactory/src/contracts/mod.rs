@@ -1,6 +1,8 @@
 use crate::secret_channel::{
-    storage_prefix_for_topic_pubkey, KeyPair, Peeler, PeelingReceiver, SecretMessageChannel,
+    storage_prefix_for_topic_pubkey, BadMessage, Group, KeyPair, Peeler, PeelingReceiver,
+    SecretMessageChannel,
 };
+use std::collections::{BTreeMap, VecDeque};
 use std::fmt::Debug;
 use std::convert::TryFrom as _;
 
@@ -9,7 +11,8 @@ use crate::types::{deopaque_query, OpaqueError, OpaqueQuery, OpaqueReply};
 use anyhow::{Context, Error, Result};
 use chain::AccountId;
 use parity_scale_codec::{Decode, Encode};
-use phala_mq::{MessageOrigin, Sr25519MessageChannel as MessageChannel};
+use phala_mq::{MessageOrigin, Sr25519MessageChannel as MessageChannel, TypedReceiver};
+use phala_types::messaging::{CommandReceiptBatch, CompactReceipt, MultiLocation, SideTaskResult};
 
 pub mod assets;
 pub mod balances;
@@ -21,9 +24,17 @@ pub mod web3analytics;
 pub mod geolocation;
 
 pub mod guess_number;
+pub mod http;
 
 pub mod btc_price_bot;
 
+pub mod query_budget;
+pub mod query_nonces;
+pub mod registry;
+pub mod side_task_votes;
+pub mod wasm;
+
+pub use phactory_api::contract_metadata::{ContractMetadata, MessageTypes};
 pub use phala_types::contract::*;
 
 pub fn account_id_from_hex(s: &str) -> Result<AccountId> {
@@ -34,38 +45,432 @@ pub fn account_id_from_hex(s: &str) -> Result<AccountId> {
         .map_err(|err| anyhow::anyhow!("Failed to convert AccountId: {:?}", err))
 }
 
+/// `AccountId` in the mirror types describing the messages of a contract.
+///
+/// `AccountId` has no `TypeInfo`, so it's described as the bytes it's SCALE encoded to.
+pub type AccountIdInfo = [u8; 32];
+
+/// Mirrors an `AccountId`, to check the mirror types are encoded the same as the messages.
+#[cfg(test)]
+pub fn account_id_info(account: &AccountId) -> AccountIdInfo {
+    account.clone().into()
+}
+
 pub use support::*;
 mod support {
     use core::convert::TryInto;
 
     use super::*;
+    use crate::side_task::async_side_task::AsyncSideTask;
     use crate::types::BlockInfo;
+    use futures::Future;
+    use http::{HttpError, HttpRequest, HttpResult};
+    use phala_types::WorkerPublicKey;
+    use side_task_votes::{Aggregation, AgreedValue, SideTaskSealer, SideTaskVotes, TaskKind};
 
-    pub struct ExecuteEnv<'a> {
-        pub block: &'a mut BlockInfo<'a>,
+    /// The contracts running in this worker, ordered by their ids.
+    pub type ContractMap = BTreeMap<ContractId, Box<dyn Contract + Send>>;
+
+    pub struct ExecuteEnv<'a, 'b> {
+        pub block: &'a mut BlockInfo<'b>,
+        /// The other contracts in this worker, excluding the one being executed.
+        pub contracts: &'a mut ContractMap,
     }
 
     pub struct NativeContext<'a, 'b> {
         pub block: &'a mut BlockInfo<'b>,
+        id: ContractId,
         mq: &'a MessageChannel,
-        #[allow(unused)] // TODO.kevin: remove this.
+        worker_mq: &'a MessageChannel,
         secret_mq: SecretMessageChannel<'a>,
+        contracts: &'a mut ContractMap,
+        events: &'a mut ContractEvents,
+        side_tasks: &'a mut SideTaskVotes,
+        side_task_sealer: &'a SideTaskSealer,
+        http_allowed_domains: &'static [&'static str],
     }
 
     impl NativeContext<'_, '_> {
         pub fn mq(&self) -> &MessageChannel {
             self.mq
         }
+
+        /// Sends a command to another contract, encrypted to the key of the target contract.
+        ///
+        /// The command is received by the target with `MessageOrigin::Contract` of the caller.
+        pub fn send_command<Cmd: Encode>(&self, to: ContractId, cmd: &Cmd) -> TransactionResult {
+            let pubkey = crate::system::chain_state::read_contract_pubkey(&to, self.block.storage)
+                .ok_or(TransactionError::BadContractId)?;
+            self.secret_mq
+                .sendto(command_topic(to), cmd, Some(&pubkey.0));
+            Ok(())
+        }
+
+        /// Broadcasts a message to the members of the group at once.
+        ///
+        /// The key of the group is derived from the key of the contract, see `Group`.
+        pub fn send_to_group<M: Encode>(&self, group: &mut Group, message: &M) {
+            self.secret_mq.send_to_group(group, message)
+        }
+
+        /// Queries another contract running in the same worker.
+        ///
+        /// Contracts are executed in the order of their ids, so the target reflects the commands
+        /// it has processed so far in the current block. The query is sent without origin. It's
+        /// rejected if it reads the `QueryScratch` of the target, which differs between workers.
+        pub fn query_contract<Req: Encode, Resp: Decode + Debug>(
+            &self,
+            to: ContractId,
+            req: &Req,
+        ) -> Result<Resp, ContractQueryError> {
+            let contract = self
+                .contracts
+                .get(&to)
+                .ok_or(ContractQueryError::ContractNotFound)?;
+            let outer = SCRATCH_ACCESS.with(|access| access.replace(Some(false)));
+            let reply = contract.handle_query(None, &req.encode());
+            let touched = SCRATCH_ACCESS.with(|access| access.replace(outer));
+            if touched == Some(true) {
+                return Err(ContractQueryError::OtherError(
+                    "The query depends on the query scratch state".into(),
+                ));
+            }
+            deopaque_query(&reply?)
+        }
+
+        /// Publishes an event to the subscribers of the contract.
+        ///
+        /// An event published to an account is only streamed to the subscriptions signed by it.
+        pub fn publish_event<E: Encode>(&mut self, to: Option<AccountId>, event: &E) -> u64 {
+            self.events
+                .publish(to, self.block.block_number, event.encode())
+        }
+
+        /// Spawns a side task whose result is agreed on by the workers running the contract.
+        ///
+        /// Each worker reports the result of its own run on chain after `duration` blocks. The
+        /// results reported in the following `window` blocks are aggregated, and the agreed value
+        /// is handed to `NativeContract::handle_side_task_result` along with the returned task id.
+        pub fn spawn_aggregated_task(
+            &mut self,
+            duration: chain::BlockNumber,
+            window: chain::BlockNumber,
+            aggregation: Aggregation,
+            task_future: impl Future<Output = Option<Vec<u8>>> + Send + 'static,
+        ) -> u64 {
+            self.spawn_voted_task(
+                TaskKind::Aggregated,
+                duration,
+                window,
+                aggregation,
+                task_future,
+            )
+        }
+
+        /// Sends an HTTP request as a side task, failing if its host is not allowed.
+        ///
+        /// Each worker reports the outcome of its own request on chain after `duration` blocks.
+        /// The outcome reported by at least `quorum` workers in the following
+        /// `http::REPORT_WINDOW` blocks is handed to `NativeContract::handle_http_response`,
+        /// along with the returned request id.
+        pub fn http_request(
+            &mut self,
+            request: HttpRequest,
+            duration: chain::BlockNumber,
+            quorum: u32,
+        ) -> Result<u64, HttpError> {
+            let allowed_domains = self.http_allowed_domains;
+            http::check_allowed(allowed_domains, &request.url)?;
+            Ok(self.spawn_voted_task(
+                TaskKind::Http,
+                duration,
+                http::REPORT_WINDOW,
+                Aggregation::Quorum(quorum),
+                http_task(allowed_domains, request),
+            ))
+        }
+
+        /// Sends an HTTP request from a single worker, e.g. the `AgreedValue::reporter`, failing
+        /// if its host is not allowed.
+        ///
+        /// For the requests which must not be repeated, e.g. posting a message. The outcome the
+        /// worker reports is handed to `NativeContract::handle_http_response` like the ones of
+        /// `http_request`, or `HttpError::NoAgreement` if it fails to report one.
+        pub fn http_request_from(
+            &mut self,
+            worker: &WorkerPublicKey,
+            request: HttpRequest,
+            duration: chain::BlockNumber,
+        ) -> Result<u64, HttpError> {
+            let allowed_domains = self.http_allowed_domains;
+            http::check_allowed(allowed_domains, &request.url)?;
+            let task_id = self.open_voting(
+                TaskKind::Http,
+                duration,
+                http::REPORT_WINDOW,
+                Aggregation::Quorum(1),
+            );
+            if self.side_task_sealer.worker() == worker {
+                self.spawn_reported_task(task_id, duration, http_task(allowed_domains, request));
+            }
+            Ok(task_id)
+        }
+
+        fn spawn_voted_task(
+            &mut self,
+            kind: TaskKind,
+            duration: chain::BlockNumber,
+            window: chain::BlockNumber,
+            aggregation: Aggregation,
+            task_future: impl Future<Output = Option<Vec<u8>>> + Send + 'static,
+        ) -> u64 {
+            let task_id = self.open_voting(kind, duration, window, aggregation);
+            self.spawn_reported_task(task_id, duration, task_future);
+            task_id
+        }
+
+        /// Opens the voting of a task on all the instances of the contract, returning its id.
+        fn open_voting(
+            &mut self,
+            kind: TaskKind,
+            duration: chain::BlockNumber,
+            window: chain::BlockNumber,
+            aggregation: Aggregation,
+        ) -> u64 {
+            let close_at = self.block.block_number + duration + window;
+            self.side_tasks.open(kind, aggregation, close_at)
+        }
+
+        /// Runs the task in this worker, reporting its result after `duration` blocks.
+        fn spawn_reported_task(
+            &mut self,
+            task_id: u64,
+            duration: chain::BlockNumber,
+            task_future: impl Future<Output = Option<Vec<u8>>> + Send + 'static,
+        ) {
+            let mq = self.worker_mq.clone();
+            let sealer = self.side_task_sealer.clone();
+            let topic = side_task_topic(self.id);
+            let task = AsyncSideTask::spawn(
+                self.block.block_number,
+                duration,
+                task_future,
+                move |value, _context| {
+                    let value = value.flatten();
+                    mq.sendto(&sealer.seal(task_id, value), topic);
+                },
+            );
+            self.block.side_task_man.add_task(task);
+        }
+    }
+
+    fn http_task(
+        allowed_domains: &'static [&'static str],
+        request: HttpRequest,
+    ) -> impl Future<Output = Option<Vec<u8>>> + Send + 'static {
+        async move {
+            let result = http::send(&http::SurfBackend, allowed_domains, request).await;
+            Some(result.encode())
+        }
     }
 
     pub trait Contract {
         fn id(&self) -> ContractId;
+        /// Handles a query, which must not change the state of the contract.
         fn handle_query(
-            &mut self,
+            &self,
             origin: Option<&chain::AccountId>,
             req: OpaqueQuery,
         ) -> Result<OpaqueReply, OpaqueError>;
-        fn process_messages<'a>(&'a mut self, env: &mut ExecuteEnv<'a>);
+        fn process_messages(&mut self, env: &mut ExecuteEnv);
+        /// The receipt of the command pushed on chain in `sent_at` with the given index.
+        fn command_receipt(
+            &self,
+            sender: &MessageOrigin,
+            sent_at: chain::BlockNumber,
+            index: u64,
+        ) -> Option<CommandReceipt>;
+        fn metadata(&self) -> ContractMetadata;
+        fn events(&self) -> &ContractEvents;
+    }
+
+    /// The max number of receipts kept for each sender of a contract.
+    pub const MAX_RECEIPTS_PER_SENDER: u64 = 128;
+
+    pub use phactory_api::system::CommandReceipt;
+
+    /// The receipts of the commands processed by a contract, keyed by (sender, block, index).
+    ///
+    /// A command is identified by the block it was pushed on chain in and its index among the
+    /// messages pushed in that block, both known to the sender once the command is on chain. Only
+    /// the latest `MAX_RECEIPTS_PER_SENDER` receipts of each sender are kept.
+    #[derive(Default)]
+    pub struct CommandReceipts {
+        /// Whether to report the receipts on chain.
+        report: bool,
+        counts: BTreeMap<MessageOrigin, u64>,
+        receipts: BTreeMap<(MessageOrigin, chain::BlockNumber, u64), CommandReceipt>,
+        unreported: Vec<CompactReceipt>,
+    }
+
+    impl CommandReceipts {
+        pub fn new(report: bool) -> Self {
+            CommandReceipts {
+                report,
+                ..Default::default()
+            }
+        }
+
+        /// Records the result of the command pushed on chain in `sent_at` with the given index.
+        pub fn record(
+            &mut self,
+            sender: MessageOrigin,
+            sent_at: chain::BlockNumber,
+            index: u64,
+            block_number: chain::BlockNumber,
+            result: TransactionResult,
+        ) {
+            let count = self.counts.entry(sender.clone()).or_default();
+            if *count < MAX_RECEIPTS_PER_SENDER {
+                *count += 1;
+            } else {
+                let oldest = self
+                    .receipts
+                    .range((sender.clone(), 0, 0)..)
+                    .next()
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    self.receipts.remove(&oldest);
+                }
+            }
+            if self.report {
+                self.unreported.push(CompactReceipt {
+                    sender: sender.clone(),
+                    sent_at,
+                    index,
+                    error: result.as_ref().err().map(Encode::encode),
+                });
+            }
+            self.receipts.insert(
+                (sender, sent_at, index),
+                CommandReceipt {
+                    block_number,
+                    result,
+                },
+            );
+        }
+
+        /// Records a command failed to be taken from the queue, if the sender is known.
+        pub fn record_bad_command(
+            &mut self,
+            err: &Error,
+            sent_at: chain::BlockNumber,
+            block_number: chain::BlockNumber,
+        ) {
+            if let Some(bad) = err.downcast_ref::<BadMessage>() {
+                self.record(
+                    bad.origin.clone(),
+                    sent_at,
+                    bad.sequence,
+                    block_number,
+                    Err(TransactionError::BadInput),
+                );
+            }
+        }
+
+        pub fn get(
+            &self,
+            sender: &MessageOrigin,
+            sent_at: chain::BlockNumber,
+            index: u64,
+        ) -> Option<&CommandReceipt> {
+            self.receipts.get(&(sender.clone(), sent_at, index))
+        }
+
+        /// Takes the receipts to be reported on chain, if any.
+        pub fn take_batch(
+            &mut self,
+            block_number: chain::BlockNumber,
+        ) -> Option<CommandReceiptBatch> {
+            if self.unreported.is_empty() {
+                return None;
+            }
+            Some(CommandReceiptBatch {
+                block_number,
+                receipts: std::mem::take(&mut self.unreported),
+            })
+        }
+    }
+
+    /// The max number of events kept for the subscribers of a contract.
+    pub const MAX_EVENTS: usize = 1024;
+
+    /// The events published by a contract, with the recipient of each, if any.
+    ///
+    /// Only the latest `MAX_EVENTS` events are kept, so a subscriber falling too far behind misses
+    /// the older ones.
+    #[derive(Default)]
+    pub struct ContractEvents {
+        next_sequence: u64,
+        events: VecDeque<(Option<AccountId>, ContractEvent)>,
+    }
+
+    impl ContractEvents {
+        /// Publishes an event, returning its sequence.
+        pub fn publish(
+            &mut self,
+            to: Option<AccountId>,
+            block_number: chain::BlockNumber,
+            data: Vec<u8>,
+        ) -> u64 {
+            let sequence = self.next_sequence;
+            self.next_sequence += 1;
+            if self.events.len() == MAX_EVENTS {
+                self.events.pop_front();
+            }
+            self.events.push_back((
+                to,
+                ContractEvent {
+                    sequence,
+                    block_number,
+                    data,
+                },
+            ));
+            sequence
+        }
+
+        /// The events from the given sequence on, visible to the subscriber.
+        ///
+        /// Anonymous subscribers only see the events published to everyone.
+        pub fn since<'a>(
+            &'a self,
+            from_sequence: u64,
+            subscriber: Option<&'a AccountId>,
+        ) -> impl Iterator<Item = &'a ContractEvent> + 'a {
+            self.events
+                .iter()
+                .skip_while(move |(_, event)| event.sequence < from_sequence)
+                .filter(move |(to, _)| to.is_none() || to.as_ref() == subscriber)
+                .map(|(_, event)| event)
+        }
+    }
+
+    /// Lets each contract process its messages, in the order of the contract ids.
+    ///
+    /// A contract is taken out of the map while it is executed, so that it can query the others.
+    pub fn process_messages(contracts: &mut ContractMap, block: &mut BlockInfo) {
+        let ids: Vec<ContractId> = contracts.keys().cloned().collect();
+        for id in ids {
+            let mut contract = match contracts.remove(&id) {
+                Some(contract) => contract,
+                None => continue,
+            };
+            contract.process_messages(&mut ExecuteEnv {
+                block: &mut *block,
+                contracts: &mut *contracts,
+            });
+            contracts.insert(id, contract);
+        }
     }
 
     pub trait NativeContract {
@@ -82,11 +487,107 @@ mod support {
         ) -> TransactionResult {
             Ok(())
         }
-        fn handle_query(
+        /// Handles a command sent by a remote location, e.g. a parachain over XCM.
+        ///
+        /// The commands of the remote locations are sent in plain. They are rejected by default.
+        fn handle_remote_command(
             &mut self,
-            origin: Option<&chain::AccountId>,
-            req: Self::QReq,
-        ) -> Self::QResp;
+            _context: &mut NativeContext,
+            _location: MultiLocation,
+            _cmd: Self::Cmd,
+        ) -> TransactionResult {
+            Err(TransactionError::BadOrigin)
+        }
+        /// Handles a query.
+        ///
+        /// Queries are not replayed on the other instances of the contract, so they can't change
+        /// its state. A contract keeping state across queries, e.g. data uploaded by the clients,
+        /// has to put it in a `QueryScratch`.
+        fn handle_query(&self, origin: Option<&chain::AccountId>, req: Self::QReq) -> Self::QResp;
+        /// Whether the receipts of the commands are reported on chain.
+        fn report_receipts(&self) -> bool {
+            false
+        }
+        /// The name of the contract, defaults to the name of the implementing type.
+        fn name(&self) -> &'static str {
+            let name = std::any::type_name::<Self>();
+            name.rsplit("::").next().unwrap_or(name)
+        }
+        /// The version of the contract, defaults to the version of pRuntime it's compiled into.
+        fn version(&self) -> &'static str {
+            env!("CARGO_PKG_VERSION")
+        }
+        fn owner(&self) -> Option<AccountId> {
+            None
+        }
+        /// The type metadata of `Cmd`, `QReq` and `QResp`, usually `MessageTypes::of`.
+        ///
+        /// A type containing a foreign type without `TypeInfo`, e.g. `AccountId`, can be described
+        /// by a mirror type encoded the same, with `AccountIdInfo` in place of `AccountId`.
+        /// Returns None if the types can't be described.
+        fn message_types(&self) -> Option<MessageTypes> {
+            None
+        }
+        /// A digest of the state of the contract, excluding its `QueryScratch`.
+        ///
+        /// Debug builds compare the digests before and after each query, to catch the queries
+        /// changing the state through interior mutability. Returns None to skip the check.
+        fn state_digest(&self) -> Option<[u8; 32]> {
+            None
+        }
+        /// Handles the agreed result of a task spawned by `NativeContext::spawn_aggregated_task`.
+        ///
+        /// The result is None if the workers failed to agree on one.
+        fn handle_side_task_result(
+            &mut self,
+            _context: &mut NativeContext,
+            _task_id: u64,
+            _result: Option<AgreedValue>,
+        ) {
+        }
+        /// The domains the contract is allowed to send HTTP requests to, including their subdomains.
+        fn http_allowed_domains(&self) -> &'static [&'static str] {
+            &[]
+        }
+        /// Handles the agreed outcome of a request sent by `NativeContext::http_request`.
+        fn handle_http_response(
+            &mut self,
+            _context: &mut NativeContext,
+            _request_id: u64,
+            _response: HttpResult,
+        ) {
+        }
+    }
+
+    thread_local! {
+        /// Whether the `QueryScratch` has been accessed by the query a command is making, or None
+        /// outside of the queries made by commands.
+        static SCRATCH_ACCESS: std::cell::Cell<Option<bool>> = std::cell::Cell::new(None);
+    }
+
+    /// The state a contract keeps across queries, outside of its consensus state.
+    ///
+    /// It's the only state a query can change. Commands must not depend on it, or the instances
+    /// of the contract on different workers would diverge, so the queries made by commands fail
+    /// once they access it.
+    #[derive(Default, Debug)]
+    pub struct QueryScratch<T>(std::sync::Mutex<T>);
+
+    impl<T> QueryScratch<T> {
+        pub fn new(value: T) -> Self {
+            QueryScratch(std::sync::Mutex::new(value))
+        }
+
+        /// Runs `f` with write access to the scratch state.
+        pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+            SCRATCH_ACCESS.with(|access| {
+                if access.get().is_some() {
+                    access.set(Some(true));
+                }
+            });
+            let mut value = self.0.lock().unwrap_or_else(|err| err.into_inner());
+            f(&mut value)
+        }
     }
 
     pub struct NativeCompatContract<Con, Cmd, CmdWrp, CmdPlr, QReq, QResp>
@@ -100,8 +601,15 @@ mod support {
     {
         contract: Con,
         send_mq: MessageChannel,
+        /// Reports the results of the side tasks, signed by the worker.
+        worker_mq: MessageChannel,
         cmd_rcv_mq: PeelingReceiver<Cmd, CmdWrp, CmdPlr>,
+        side_task_rcv_mq: TypedReceiver<SideTaskResult>,
         ecdh_key: KeyPair,
+        receipts: CommandReceipts,
+        events: ContractEvents,
+        side_tasks: SideTaskVotes,
+        side_task_sealer: SideTaskSealer,
     }
 
     impl<Con, Cmd, CmdWrp, CmdPlr, QReq, QResp>
@@ -118,14 +626,24 @@ mod support {
         pub fn new(
             contract: Con,
             send_mq: MessageChannel,
+            worker_mq: MessageChannel,
             cmd_rcv_mq: PeelingReceiver<Cmd, CmdWrp, CmdPlr>,
+            side_task_rcv_mq: TypedReceiver<SideTaskResult>,
             ecdh_key: KeyPair,
+            side_task_sealer: SideTaskSealer,
         ) -> Self {
+            let receipts = CommandReceipts::new(contract.report_receipts());
             NativeCompatContract {
                 contract,
                 send_mq,
+                worker_mq,
                 cmd_rcv_mq,
+                side_task_rcv_mq,
                 ecdh_key,
+                receipts,
+                events: Default::default(),
+                side_tasks: Default::default(),
+                side_task_sealer,
             }
         }
     }
@@ -146,15 +664,29 @@ mod support {
         }
 
         fn handle_query(
-            &mut self,
+            &self,
             origin: Option<&runtime::AccountId>,
             req: OpaqueQuery,
         ) -> Result<OpaqueReply, OpaqueError> {
-            let response = self.contract.handle_query(origin, deopaque_query(req)?);
+            let req = deopaque_query(req)?;
+            let digest = if cfg!(debug_assertions) {
+                self.contract.state_digest()
+            } else {
+                None
+            };
+            let response = self.contract.handle_query(origin, req);
+            if digest.is_some() && self.contract.state_digest() != digest {
+                error!("Contract {} changed its state in a query", self.id());
+                return Err(OpaqueError::OtherError("State changed by the query".into()));
+            }
+            // Counted without encoding, to fail before encoding an oversized response
+            let size = response.encoded_size() as u64;
+            query_budget::charge(size.saturating_mul(query_budget::STEPS_PER_RESPONSE_BYTE))?;
             Ok(response.encode())
         }
 
         fn process_messages(&mut self, env: &mut ExecuteEnv) {
+            let block_number = env.block.block_number;
             let storage = env.block.storage;
             let key_map = |topic: &[u8]| {
                 // TODO.kevin: query contract pubkey for contract topic's when the feature in GK is available.
@@ -164,19 +696,57 @@ mod support {
                     .flatten()
             };
             let secret_mq = SecretMessageChannel::new(&self.ecdh_key, &self.send_mq, &key_map);
+            let id = self.id();
             let mut context = NativeContext {
                 block: env.block,
+                id,
                 mq: &self.send_mq,
+                worker_mq: &self.worker_mq,
                 secret_mq,
+                contracts: env.contracts,
+                events: &mut self.events,
+                side_tasks: &mut self.side_tasks,
+                side_task_sealer: &self.side_task_sealer,
+                http_allowed_domains: self.contract.http_allowed_domains(),
             };
             loop {
                 let ok = phala_mq::select! {
                     next_cmd = self.cmd_rcv_mq => match next_cmd {
-                        Ok((_, cmd, origin)) => {
-                            let _status = self.contract.handle_command(&mut context, origin, cmd);
+                        Ok((index, cmd, origin)) => {
+                            let sent_at = self.cmd_rcv_mq.held_in().unwrap_or(block_number);
+                            let result = match origin.as_location() {
+                                Some(location) => self
+                                    .contract
+                                    .handle_remote_command(&mut context, location, cmd),
+                                None => {
+                                    self.contract.handle_command(&mut context, origin.clone(), cmd)
+                                }
+                            };
+                            self.receipts
+                                .record(origin, sent_at, index, block_number, result);
                         }
                         Err(e) => {
-                            error!("Read command failed [{}]: {:?}", self.id(), e);
+                            error!("Read command failed [{}]: {:?}", id, e);
+                            let sent_at = self.cmd_rcv_mq.held_in().unwrap_or(block_number);
+                            self.receipts.record_bad_command(&e, sent_at, block_number);
+                        }
+                    },
+                    next_result = self.side_task_rcv_mq => match next_result {
+                        Ok((_, result, MessageOrigin::Worker(worker))) => {
+                            match self.side_task_sealer.open(&worker, result) {
+                                Some((task_id, value)) => {
+                                    context.side_tasks.vote(worker, task_id, value);
+                                }
+                                None => {
+                                    error!("Side task result not sealed by the contract [{}]", id);
+                                }
+                            }
+                        }
+                        Ok((_, _, origin)) => {
+                            error!("Invalid side task result sender [{}]: {:?}", id, origin);
+                        }
+                        Err(e) => {
+                            error!("Read side task result failed [{}]: {:?}", id, e);
                         }
                     },
                 };
@@ -184,6 +754,573 @@ mod support {
                     break;
                 }
             }
+            for (task_id, kind, result) in context.side_tasks.close(block_number) {
+                match kind {
+                    TaskKind::Aggregated => {
+                        self.contract
+                            .handle_side_task_result(&mut context, task_id, result);
+                    }
+                    TaskKind::Http => {
+                        let response = result
+                            .and_then(|result| HttpResult::decode(&mut &result.value[..]).ok())
+                            .unwrap_or(Err(HttpError::NoAgreement));
+                        self.contract
+                            .handle_http_response(&mut context, task_id, response);
+                    }
+                }
+            }
+            if let Some(batch) = self.receipts.take_batch(block_number) {
+                self.send_mq.send(&batch);
+            }
+        }
+
+        fn command_receipt(
+            &self,
+            sender: &MessageOrigin,
+            sent_at: chain::BlockNumber,
+            index: u64,
+        ) -> Option<CommandReceipt> {
+            self.receipts.get(sender, sent_at, index).cloned()
+        }
+
+        fn metadata(&self) -> ContractMetadata {
+            ContractMetadata {
+                name: self.contract.name().into(),
+                version: self.contract.version().into(),
+                owner: self.contract.owner(),
+                types: self.contract.message_types(),
+            }
+        }
+
+        fn events(&self) -> &ContractEvents {
+            &self.events
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secret_channel::Payload;
+    use crate::storage::Storage;
+    use crate::types::BlockInfo;
+    use phala_mq::{Message, MessageDispatcher, MessageSendQueue};
+    use scale_info::TypeInfo;
+    use sp_core::{hashing::blake2_256, sr25519, Pair};
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    const COUNTER: ContractId32 = 1000;
+    const OBSERVER: ContractId32 = 1001;
+    const QUERY_COUNTER: ContractId32 = 1002;
+    const REMOTE_RECORDER: ContractId32 = 1003;
+    const SCRATCH_OBSERVER: ContractId32 = 1004;
+
+    #[derive(Encode, Decode, Debug, TypeInfo)]
+    struct Increment;
+
+    #[derive(Encode, Decode, Debug)]
+    struct Observe;
+
+    #[derive(Encode, Decode, Debug, TypeInfo)]
+    struct Get;
+
+    struct Counter(u32);
+
+    impl NativeContract for Counter {
+        type Cmd = Increment;
+        type QReq = Get;
+        type QResp = u32;
+
+        fn id(&self) -> ContractId32 {
+            COUNTER
+        }
+
+        fn handle_command(
+            &mut self,
+            _context: &mut NativeContext,
+            _origin: MessageOrigin,
+            _cmd: Increment,
+        ) -> TransactionResult {
+            self.0 += 1;
+            Ok(())
+        }
+
+        fn handle_query(&self, _origin: Option<&chain::AccountId>, _req: Get) -> u32 {
+            self.0
+        }
+
+        fn message_types(&self) -> Option<MessageTypes> {
+            Some(MessageTypes::of::<Increment, Get, u32>())
+        }
+    }
+
+    /// Records the values of the counter observed on each command.
+    struct Observer(Vec<u32>);
+
+    impl NativeContract for Observer {
+        type Cmd = Observe;
+        type QReq = Get;
+        type QResp = Vec<u32>;
+
+        fn id(&self) -> ContractId32 {
+            OBSERVER
+        }
+
+        fn handle_command(
+            &mut self,
+            context: &mut NativeContext,
+            _origin: MessageOrigin,
+            _cmd: Observe,
+        ) -> TransactionResult {
+            let value = context
+                .query_contract(id256(COUNTER), &Get)
+                .or(Err(TransactionError::BadContractId))?;
+            self.0.push(value);
+            Ok(())
+        }
+
+        fn handle_query(&self, _origin: Option<&chain::AccountId>, _req: Get) -> Vec<u32> {
+            self.0.clone()
+        }
+    }
+
+    /// Counts the queries, in either its scratch state or, sneakily, its consensus state.
+    struct QueryCounter {
+        sneaky: bool,
+        count: AtomicU32,
+        scratch: QueryScratch<u32>,
+    }
+
+    impl QueryCounter {
+        fn new(sneaky: bool) -> Self {
+            QueryCounter {
+                sneaky,
+                count: Default::default(),
+                scratch: Default::default(),
+            }
+        }
+    }
+
+    impl NativeContract for QueryCounter {
+        type Cmd = Increment;
+        type QReq = Get;
+        type QResp = u32;
+
+        fn id(&self) -> ContractId32 {
+            QUERY_COUNTER
+        }
+
+        fn handle_query(&self, _origin: Option<&chain::AccountId>, _req: Get) -> u32 {
+            if self.sneaky {
+                self.count.fetch_add(1, Ordering::Relaxed) + 1
+            } else {
+                self.scratch.with(|count| {
+                    *count += 1;
+                    *count
+                })
+            }
+        }
+
+        fn state_digest(&self) -> Option<[u8; 32]> {
+            Some(blake2_256(&self.count.load(Ordering::Relaxed).encode()))
+        }
+    }
+
+    /// Records the counts of the queries observed on each command, None if the query failed.
+    struct ScratchObserver(Vec<Option<u32>>);
+
+    impl NativeContract for ScratchObserver {
+        type Cmd = Observe;
+        type QReq = Get;
+        type QResp = Vec<Option<u32>>;
+
+        fn id(&self) -> ContractId32 {
+            SCRATCH_OBSERVER
+        }
+
+        fn handle_command(
+            &mut self,
+            context: &mut NativeContext,
+            _origin: MessageOrigin,
+            _cmd: Observe,
+        ) -> TransactionResult {
+            let value = context.query_contract(id256(QUERY_COUNTER), &Get);
+            self.0.push(value.ok());
+            Ok(())
+        }
+
+        fn handle_query(&self, _origin: Option<&chain::AccountId>, _req: Get) -> Self::QResp {
+            self.0.clone()
         }
     }
+
+    #[derive(Encode, Decode, Debug)]
+    struct Record(u32);
+
+    /// Records the values sent by the remote locations.
+    struct RemoteRecorder(Vec<(MultiLocation, u32)>);
+
+    impl NativeContract for RemoteRecorder {
+        type Cmd = Record;
+        type QReq = Get;
+        type QResp = Vec<(MultiLocation, u32)>;
+
+        fn id(&self) -> ContractId32 {
+            REMOTE_RECORDER
+        }
+
+        fn handle_remote_command(
+            &mut self,
+            _context: &mut NativeContext,
+            location: MultiLocation,
+            cmd: Record,
+        ) -> TransactionResult {
+            self.0.push((location, cmd.0));
+            Ok(())
+        }
+
+        fn handle_query(&self, _origin: Option<&chain::AccountId>, _req: Get) -> Self::QResp {
+            self.0.clone()
+        }
+    }
+
+    fn send_command(recv_mq: &mut MessageDispatcher, to: ContractId32, cmd: impl Encode) {
+        let sender = MessageOrigin::AccountId([1; 32].into());
+        let payload = Payload::Plain(cmd).encode();
+        recv_mq.dispatch(Message::new(sender, command_topic(id256(to)), payload));
+    }
+
+    fn run_block(contracts: &mut ContractMap, recv_mq: &mut MessageDispatcher, block_number: u32) {
+        let storage = Storage::default();
+        let mut side_task_man = Default::default();
+        let mut block = BlockInfo {
+            block_number,
+            now_ms: 0,
+            storage: &storage,
+            recv_mq,
+            side_task_man: &mut side_task_man,
+        };
+        process_messages(contracts, &mut block);
+    }
+
+    #[test]
+    fn contracts_can_query_each_other_in_id_order() {
+        let send_mq = MessageSendQueue::default();
+        let mut recv_mq = MessageDispatcher::default();
+        let key = sr25519::Pair::from_seed(&[1; 32]);
+        let mut contracts = ContractMap::new();
+        let mut ctx = registry::InstallContext {
+            send_mq: &send_mq,
+            recv_mq: &mut recv_mq,
+            identity_key: &key,
+            contract_key: &key,
+            held_commands: None,
+        };
+        for contract in vec![ctx.install(Observer(vec![])), ctx.install(Counter(0))] {
+            contracts.insert(contract.id(), contract);
+        }
+
+        // The counter is executed before the observer, regardless of the message order.
+        send_command(&mut recv_mq, OBSERVER, Observe);
+        send_command(&mut recv_mq, COUNTER, Increment);
+        run_block(&mut contracts, &mut recv_mq, 1);
+
+        send_command(&mut recv_mq, OBSERVER, Observe);
+        send_command(&mut recv_mq, COUNTER, Increment);
+        run_block(&mut contracts, &mut recv_mq, 2);
+
+        let observer = contracts.get(&id256(OBSERVER)).unwrap();
+        let reply = observer.handle_query(None, &Get.encode()).unwrap();
+        assert_eq!(Vec::<u32>::decode(&mut &reply[..]).unwrap(), vec![1, 2]);
+        // The observer is put back after its execution
+        assert_eq!(contracts.len(), 2);
+    }
+
+    #[test]
+    fn remote_locations_send_plain_commands() {
+        let send_mq = MessageSendQueue::default();
+        let mut recv_mq = MessageDispatcher::default();
+        let key = sr25519::Pair::from_seed(&[1; 32]);
+        let mut contracts = ContractMap::new();
+        let mut ctx = registry::InstallContext {
+            send_mq: &send_mq,
+            recv_mq: &mut recv_mq,
+            identity_key: &key,
+            contract_key: &key,
+            held_commands: None,
+        };
+        for contract in vec![ctx.install(RemoteRecorder(vec![])), ctx.install(Counter(0))] {
+            contracts.insert(contract.id(), contract);
+        }
+
+        let location = MultiLocation::sibling(2000, None);
+        let remote = MessageOrigin::location(&location);
+        let bad_location = MessageOrigin::MultiLocation(vec![1, 9]);
+        let mut send = |from: &MessageOrigin, to: ContractId32, cmd: Vec<u8>| {
+            recv_mq.dispatch(Message::new(from.clone(), command_topic(id256(to)), cmd));
+        };
+        send(&remote, REMOTE_RECORDER, Record(42).encode());
+        send(&bad_location, REMOTE_RECORDER, Record(43).encode());
+        send(&remote, COUNTER, Increment.encode());
+        run_block(&mut contracts, &mut recv_mq, 1);
+
+        let recorder = contracts.get(&id256(REMOTE_RECORDER)).unwrap();
+        let reply = recorder.handle_query(None, &Get.encode()).unwrap();
+        let records = Vec::<(MultiLocation, u32)>::decode(&mut &reply[..]).unwrap();
+        assert_eq!(records, vec![(location, 42)]);
+        assert!(matches!(
+            recorder.command_receipt(&bad_location, 1, 1),
+            Some(CommandReceipt {
+                result: Err(TransactionError::BadInput),
+                ..
+            })
+        ));
+        // Rejected by the contracts not handling them
+        let counter = contracts.get(&id256(COUNTER)).unwrap();
+        assert!(matches!(
+            counter.command_receipt(&remote, 1, 2),
+            Some(CommandReceipt {
+                result: Err(TransactionError::BadOrigin),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn commands_held_before_the_install_are_replayed() {
+        let send_mq = MessageSendQueue::default();
+        let mut recv_mq = MessageDispatcher::default();
+        let key = sr25519::Pair::from_seed(&[1; 32]);
+        let mut held = registry::HeldCommands::subscribe(&mut recv_mq, id256(COUNTER));
+
+        // Sent while the key is not available yet
+        send_command(&mut recv_mq, COUNTER, Increment);
+        held.hold(1);
+        recv_mq.clear();
+        send_command(&mut recv_mq, COUNTER, Increment);
+        held.hold(2);
+        recv_mq.clear();
+
+        send_command(&mut recv_mq, COUNTER, Increment);
+        held.hold(3);
+        let mut ctx = registry::InstallContext {
+            send_mq: &send_mq,
+            recv_mq: &mut recv_mq,
+            identity_key: &key,
+            contract_key: &key,
+            held_commands: Some(held),
+        };
+        let mut contracts = ContractMap::new();
+        let counter = ctx.install(Counter(0));
+        contracts.insert(counter.id(), counter);
+        send_command(&mut recv_mq, COUNTER, Increment);
+        run_block(&mut contracts, &mut recv_mq, 3);
+
+        let counter = contracts.get(&id256(COUNTER)).unwrap();
+        let reply = counter.handle_query(None, &Get.encode()).unwrap();
+        assert_eq!(u32::decode(&mut &reply[..]).unwrap(), 4);
+        // The receipts are looked up by where the commands were pushed on chain
+        let sender = MessageOrigin::AccountId([1; 32].into());
+        assert!(matches!(
+            counter.command_receipt(&sender, 1, 0),
+            Some(CommandReceipt {
+                block_number: 3,
+                result: Ok(()),
+            })
+        ));
+        assert!(counter.command_receipt(&sender, 2, 1).is_some());
+        assert!(counter.command_receipt(&sender, 3, 1).is_none());
+    }
+
+    #[test]
+    fn command_receipts_are_kept_per_sender() {
+        let alice = MessageOrigin::AccountId([1; 32].into());
+        let bob = MessageOrigin::AccountId([2; 32].into());
+        let mut receipts = CommandReceipts::new(true);
+
+        receipts.record(alice.clone(), 1, 0, 1, Ok(()));
+        receipts.record(bob.clone(), 1, 1, 1, Err(TransactionError::BadOrigin));
+        // Held since block 1 and processed in block 2
+        receipts.record(alice.clone(), 1, 2, 2, Err(TransactionError::BadInput));
+
+        assert!(matches!(
+            receipts.get(&bob, 1, 1),
+            Some(CommandReceipt {
+                block_number: 1,
+                result: Err(TransactionError::BadOrigin)
+            })
+        ));
+        assert!(matches!(
+            receipts.get(&alice, 1, 2),
+            Some(CommandReceipt {
+                block_number: 2,
+                result: Err(TransactionError::BadInput)
+            })
+        ));
+        // Only the sender of the command can be looked up
+        assert!(receipts.get(&bob, 1, 0).is_none());
+        assert!(receipts.get(&alice, 2, 2).is_none());
+
+        let batch = receipts.take_batch(2).unwrap();
+        assert_eq!(batch.receipts.len(), 3);
+        assert_eq!(batch.receipts[0].error, None);
+        assert_eq!((batch.receipts[1].sent_at, batch.receipts[1].index), (1, 1));
+        assert_eq!(
+            batch.receipts[1].error,
+            Some(TransactionError::BadOrigin.encode())
+        );
+        assert!(receipts.take_batch(3).is_none());
+
+        // Old receipts are dropped
+        for index in 0..MAX_RECEIPTS_PER_SENDER - 1 {
+            receipts.record(alice.clone(), 3, index, 3, Ok(()));
+        }
+        assert!(receipts.get(&alice, 1, 0).is_none());
+        assert!(receipts.get(&alice, 1, 2).is_some());
+        assert!(receipts.get(&bob, 1, 1).is_some());
+    }
+
+    #[test]
+    fn bad_commands_get_receipts() {
+        let alice = MessageOrigin::AccountId([1; 32].into());
+        let mut receipts = CommandReceipts::new(false);
+        let bad = Error::new(BadMessage {
+            origin: alice.clone(),
+            sequence: 3,
+            error: anyhow::anyhow!("Decode message failed"),
+        });
+        receipts.record_bad_command(&bad, 1, 2);
+        // Errors without a known sender are not recorded
+        let gone = anyhow::anyhow!("All senders of the channel have gone");
+        receipts.record_bad_command(&gone, 1, 2);
+        assert!(matches!(
+            receipts.get(&alice, 1, 3),
+            Some(CommandReceipt {
+                block_number: 2,
+                result: Err(TransactionError::BadInput),
+            })
+        ));
+        assert_eq!(receipts.counts.values().sum::<u64>(), 1);
+    }
+
+    #[test]
+    fn events_are_visible_to_their_recipients() {
+        let alice = AccountId::new([1; 32]);
+        let bob = AccountId::new([2; 32]);
+        let mut events = ContractEvents::default();
+        assert_eq!(events.publish(None, 1, vec![0]), 0);
+        assert_eq!(events.publish(Some(alice.clone()), 1, vec![1]), 1);
+        assert_eq!(events.publish(Some(bob.clone()), 2, vec![2]), 2);
+
+        fn sequences(events: &ContractEvents, from: u64, to: Option<&AccountId>) -> Vec<u64> {
+            events.since(from, to).map(|event| event.sequence).collect()
+        }
+        assert_eq!(sequences(&events, 0, None), vec![0]);
+        assert_eq!(sequences(&events, 0, Some(&alice)), vec![0, 1]);
+        assert_eq!(sequences(&events, 1, Some(&bob)), vec![2]);
+
+        // Old events are dropped
+        for _ in 0..MAX_EVENTS {
+            events.publish(None, 3, vec![]);
+        }
+        assert_eq!(sequences(&events, 0, Some(&alice)).first(), Some(&3));
+    }
+
+    #[test]
+    fn metadata_describes_the_contract() {
+        let send_mq = MessageSendQueue::default();
+        let mut recv_mq = MessageDispatcher::default();
+        let key = sr25519::Pair::from_seed(&[1; 32]);
+        let mut ctx = registry::InstallContext {
+            send_mq: &send_mq,
+            recv_mq: &mut recv_mq,
+            identity_key: &key,
+            contract_key: &key,
+            held_commands: None,
+        };
+
+        // The metadata is sent to the clients SCALE encoded
+        let encoded = ctx.install(Counter(0)).metadata().encode();
+        let counter = ContractMetadata::decode(&mut &encoded[..]).unwrap();
+        assert_eq!(counter.name, "Counter");
+        assert_eq!(counter.version, env!("CARGO_PKG_VERSION"));
+        assert!(counter.owner.is_none());
+        let types = counter.types.expect("Counter describes its message types");
+        let response = types
+            .registry
+            .resolve(types.query_response.id())
+            .expect("The response type should be registered");
+        assert!(matches!(
+            response.type_def(),
+            scale_info::TypeDef::Primitive(scale_info::TypeDefPrimitive::U32)
+        ));
+
+        let observer = ctx.install(Observer(vec![])).metadata();
+        assert_eq!(observer.name, "Observer");
+        assert!(observer.types.is_none());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn queries_can_only_change_the_scratch_state() {
+        let send_mq = MessageSendQueue::default();
+        let mut recv_mq = MessageDispatcher::default();
+        let key = sr25519::Pair::from_seed(&[1; 32]);
+        let mut ctx = registry::InstallContext {
+            send_mq: &send_mq,
+            recv_mq: &mut recv_mq,
+            identity_key: &key,
+            contract_key: &key,
+            held_commands: None,
+        };
+
+        let honest = ctx.install(QueryCounter::new(false));
+        assert_eq!(
+            honest.handle_query(None, &Get.encode()).unwrap(),
+            1u32.encode()
+        );
+        assert_eq!(
+            honest.handle_query(None, &Get.encode()).unwrap(),
+            2u32.encode()
+        );
+
+        let sneaky = ctx.install(QueryCounter::new(true));
+        assert!(matches!(
+            sneaky.handle_query(None, &Get.encode()),
+            Err(ContractQueryError::OtherError(_))
+        ));
+    }
+
+    #[test]
+    fn commands_can_not_query_the_scratch_state() {
+        let observe = |warm: bool| {
+            let send_mq = MessageSendQueue::default();
+            let mut recv_mq = MessageDispatcher::default();
+            let key = sr25519::Pair::from_seed(&[1; 32]);
+            let mut contracts = ContractMap::new();
+            let mut ctx = registry::InstallContext {
+                send_mq: &send_mq,
+                recv_mq: &mut recv_mq,
+                identity_key: &key,
+                contract_key: &key,
+                held_commands: None,
+            };
+            let counter = ctx.install(QueryCounter::new(false));
+            let observer = ctx.install(ScratchObserver(vec![]));
+            if warm {
+                counter.handle_query(None, &Get.encode()).unwrap();
+            }
+            contracts.insert(counter.id(), counter);
+            contracts.insert(observer.id(), observer);
+
+            send_command(&mut recv_mq, SCRATCH_OBSERVER, Observe);
+            run_block(&mut contracts, &mut recv_mq, 1);
+
+            let observer = contracts.get(&id256(SCRATCH_OBSERVER)).unwrap();
+            let reply = observer.handle_query(None, &Get.encode()).unwrap();
+            Vec::<Option<u32>>::decode(&mut &reply[..]).unwrap()
+        };
+        assert_eq!(observe(false), vec![None]);
+        assert_eq!(observe(true), observe(false));
+    }
 }
@@ -0,0 +1,169 @@
+//! Bounds the work done by contract queries.
+//!
+//! A query runs with a budget of steps, where a step is about the cost of a wasm instruction.
+//! Wasm contracts are charged the gas they use, and native contracts charge an estimate of the
+//! heavy parts of their queries with `charge`. On top of that, every native query is charged for
+//! the size of its response. A query running out of budget fails with
+//! `ContractQueryError::Timeout`, so a heavy query can't stall the block sync.
+
+use super::*;
+use std::cell::Cell;
+
+/// The budget of the queries to the contracts without a configured budget.
+pub const DEFAULT_QUERY_BUDGET: u64 = 100_000_000;
+
+/// The steps charged for each item a native query scans or copies.
+pub const STEPS_PER_ITEM: u64 = 1_000;
+
+/// The steps charged for each byte of the response of a native query, before it's encoded.
+pub const STEPS_PER_RESPONSE_BYTE: u64 = 10;
+
+#[derive(Clone, Copy)]
+enum Budget {
+    /// Not running a query, e.g. a query between contracts while processing a command.
+    Unlimited,
+    Remaining(u64),
+    Exhausted,
+}
+
+thread_local! {
+    static BUDGET: Cell<Budget> = Cell::new(Budget::Unlimited);
+}
+
+/// Charges the running query with the given steps, failing if it runs out of budget.
+pub fn charge(steps: u64) -> Result<(), ContractQueryError> {
+    BUDGET.with(|budget| match budget.get() {
+        Budget::Unlimited => Ok(()),
+        Budget::Remaining(remaining) if remaining >= steps => {
+            budget.set(Budget::Remaining(remaining - steps));
+            Ok(())
+        }
+        _ => {
+            budget.set(Budget::Exhausted);
+            Err(ContractQueryError::Timeout)
+        }
+    })
+}
+
+/// Charges the running query for the items it scans or copies.
+pub fn charge_items(count: usize) -> Result<(), ContractQueryError> {
+    charge((count as u64).saturating_mul(STEPS_PER_ITEM))
+}
+
+/// Like `charge_items`, for the queries failing with `anyhow::Error`.
+pub fn charge_items_or_bail(count: usize) -> Result<()> {
+    charge_items(count).map_err(|err| anyhow::anyhow!("{:?}", err))
+}
+
+/// The remaining steps of the running query, or None if unlimited.
+pub fn remaining() -> Option<u64> {
+    BUDGET.with(|budget| match budget.get() {
+        Budget::Unlimited => None,
+        Budget::Remaining(remaining) => Some(remaining),
+        Budget::Exhausted => Some(0),
+    })
+}
+
+/// Runs a contract query with the given budget.
+///
+/// The query fails with `ContractQueryError::Timeout` if it has run out of budget, no matter what
+/// the contract responded.
+pub fn run<R>(
+    steps: u64,
+    query: impl FnOnce() -> Result<R, ContractQueryError>,
+) -> Result<R, ContractQueryError> {
+    let outer = BUDGET.with(|budget| budget.replace(Budget::Remaining(steps)));
+    let result = query();
+    let budget = BUDGET.with(|budget| budget.replace(outer));
+    match budget {
+        Budget::Exhausted => Err(ContractQueryError::Timeout),
+        _ => result,
+    }
+}
+
+/// The query budgets of the contracts, configured by `--query-budget`.
+pub struct QueryBudgets {
+    default: u64,
+    contracts: BTreeMap<ContractId, u64>,
+}
+
+impl Default for QueryBudgets {
+    fn default() -> Self {
+        QueryBudgets {
+            default: DEFAULT_QUERY_BUDGET,
+            contracts: Default::default(),
+        }
+    }
+}
+
+impl QueryBudgets {
+    /// Parses the budgets from the config items.
+    ///
+    /// `steps` sets the default budget, and `contract=steps` sets the budget of a contract, given
+    /// by the name or the numeric id of a native contract, or the hex id of a deployed contract.
+    pub fn parse(items: &[String]) -> Result<Self> {
+        let mut budgets = Self::default();
+        for item in items {
+            let parse_steps = |steps: &str| {
+                steps
+                    .parse::<u64>()
+                    .with_context(|| format!("Invalid query budget: {}", item))
+            };
+            match item.split_once('=') {
+                None => budgets.default = parse_steps(item)?,
+                Some((contract, steps)) => {
+                    let id = match registry::find(contract) {
+                        Some(entry) => id256(entry.id),
+                        None => {
+                            let bytes = hex::decode(contract.trim_start_matches("0x"))
+                                .ok()
+                                .filter(|bytes| bytes.len() == 32)
+                                .ok_or_else(|| anyhow::anyhow!("Unknown contract: {}", contract))?;
+                            ContractId::from_slice(&bytes)
+                        }
+                    };
+                    budgets.contracts.insert(id, parse_steps(steps)?);
+                }
+            }
+        }
+        Ok(budgets)
+    }
+
+    pub fn get(&self, id: &ContractId) -> u64 {
+        self.contracts.get(id).cloned().unwrap_or(self.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queries_fail_when_out_of_budget() {
+        assert_eq!(run(10, || charge(10).map(|_| 1)).ok(), Some(1));
+        assert!(matches!(
+            run(10, || {
+                charge(5)?;
+                charge(6)
+            }),
+            Err(ContractQueryError::Timeout)
+        ));
+        // Even if the contract swallowed the error
+        assert!(matches!(
+            run(10, || Ok(charge(11).is_ok())),
+            Err(ContractQueryError::Timeout)
+        ));
+        // Unlimited out of queries
+        assert!(charge(u64::MAX).is_ok());
+        assert_eq!(remaining(), None);
+    }
+
+    #[test]
+    fn budgets_are_configured_per_contract() {
+        let items = vec!["1000".to_string(), "guess_number=10".to_string()];
+        let budgets = QueryBudgets::parse(&items).unwrap();
+        assert_eq!(budgets.get(&id256(GUESS_NUMBER)), 10);
+        assert_eq!(budgets.get(&id256(BALANCES)), 1000);
+        assert!(QueryBudgets::parse(&["nope=1".to_string()]).is_err());
+    }
+}
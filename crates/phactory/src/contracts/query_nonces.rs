@@ -0,0 +1,135 @@
+//! Rejects replayed contract queries.
+//!
+//! A signed query captured on the way can be sent again on behalf of its signer. The worker
+//! remembers the latest nonces of each origin, and rejects the queries reusing them. The cache is
+//! bounded, so clients should also bound the lifetime of their queries with a `ContractQueryHeadV2`,
+//! to make sure a query can't be replayed once its nonce is evicted.
+
+use super::*;
+use std::collections::{BTreeSet, VecDeque};
+
+/// The max number of nonces remembered for each origin.
+pub const MAX_NONCES_PER_ORIGIN: usize = 1024;
+/// The max number of origins whose nonces are remembered.
+pub const MAX_ORIGINS: usize = 4096;
+
+pub type Nonce = [u8; 32];
+
+#[derive(Default)]
+struct OriginNonces {
+    /// The last time (in the number of checked queries) the origin sent a query.
+    last_seen: u64,
+    nonces: BTreeSet<Nonce>,
+    /// The nonces in the order they were seen, to evict the oldest one.
+    order: VecDeque<Nonce>,
+}
+
+/// The nonces of the latest signed queries of each origin.
+#[derive(Default)]
+pub struct QueryNonces {
+    clock: u64,
+    origins: BTreeMap<AccountId, OriginNonces>,
+}
+
+impl QueryNonces {
+    /// Checks a query is neither expired at the current block nor replayed by its origin.
+    pub fn check_query(
+        &mut self,
+        origin: Option<&AccountId>,
+        nonce: Nonce,
+        valid_until_block: Option<chain::BlockNumber>,
+        current_block: chain::BlockNumber,
+    ) -> Result<(), ContractQueryError> {
+        if let Some(valid_until_block) = valid_until_block {
+            if current_block > valid_until_block {
+                return Err(ContractQueryError::OtherError("Query expired".into()));
+            }
+        }
+        match origin {
+            Some(origin) => self.check(origin, nonce),
+            // Unsigned queries are not bound to anyone, there is nothing to replay
+            None => Ok(()),
+        }
+    }
+
+    /// Records the nonce of a query from the origin, failing if the nonce has been seen.
+    pub fn check(&mut self, origin: &AccountId, nonce: Nonce) -> Result<(), ContractQueryError> {
+        self.clock += 1;
+        if !self.origins.contains_key(origin) && self.origins.len() >= MAX_ORIGINS {
+            self.evict_origin();
+        }
+        let entry = self.origins.entry(origin.clone()).or_default();
+        entry.last_seen = self.clock;
+        if !entry.nonces.insert(nonce) {
+            return Err(ContractQueryError::OtherError("Nonce reused".into()));
+        }
+        entry.order.push_back(nonce);
+        if entry.order.len() > MAX_NONCES_PER_ORIGIN {
+            if let Some(oldest) = entry.order.pop_front() {
+                entry.nonces.remove(&oldest);
+            }
+        }
+        Ok(())
+    }
+
+    /// Forgets the origin seen least recently.
+    fn evict_origin(&mut self) {
+        let oldest = self
+            .origins
+            .iter()
+            .min_by_key(|(_, nonces)| nonces.last_seen)
+            .map(|(origin, _)| origin.clone());
+        if let Some(origin) = oldest {
+            self.origins.remove(&origin);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reused_nonces_are_rejected() {
+        let alice = AccountId::new([1; 32]);
+        let bob = AccountId::new([2; 32]);
+        let mut nonces = QueryNonces::default();
+        assert!(nonces.check(&alice, [0; 32]).is_ok());
+        assert!(nonces.check(&alice, [0; 32]).is_err());
+        // Nonces are per origin
+        assert!(nonces.check(&bob, [0; 32]).is_ok());
+
+        // The oldest nonces are evicted
+        for i in 1..=MAX_NONCES_PER_ORIGIN {
+            let mut nonce = [0; 32];
+            nonce[..8].copy_from_slice(&(i as u64).to_le_bytes());
+            assert!(nonces.check(&alice, nonce).is_ok());
+        }
+        assert!(nonces.check(&alice, [0; 32]).is_ok());
+        assert!(nonces.check(&bob, [0; 32]).is_err());
+    }
+
+    #[test]
+    fn expired_and_replayed_queries_are_rejected() {
+        let alice = AccountId::new([1; 32]);
+        let mut nonces = QueryNonces::default();
+        let mut accepts = |origin, nonce, valid_until_block, current_block| {
+            nonces
+                .check_query(origin, nonce, valid_until_block, current_block)
+                .is_ok()
+        };
+
+        // Expired
+        assert!(!accepts(Some(&alice), [1; 32], Some(9), 10));
+        assert!(!accepts(None, [1; 32], Some(9), 10));
+        // Valid until the end of the block
+        assert!(accepts(Some(&alice), [1; 32], Some(10), 10));
+        assert!(accepts(Some(&alice), [2; 32], None, 10));
+
+        // Replayed
+        assert!(!accepts(Some(&alice), [1; 32], Some(10), 10));
+        assert!(!accepts(Some(&alice), [2; 32], None, 11));
+        // Unsigned queries can't be told apart
+        assert!(accepts(None, [2; 32], None, 11));
+    }
+}
@@ -0,0 +1,226 @@
+//! The registry of native contracts built into pRuntime.
+//!
+//! Each entry knows its contract id, a human readable name and how to construct the contract.
+//! Which entries get installed is decided at runtime initialization, starting from the
+//! default set in dev mode and adjusted by the `--native-contracts` list. The selection can be
+//! overridden on chain by `pallet_registry::NativeContracts`, which enables or disables a contract
+//! on all the workers. An enabled contract is installed once its key, derived from the gatekeeper
+//! master key, is available to the worker. The workers get the keys at different blocks, so the
+//! commands sent to an enabled contract are held from the start and replayed once it's installed,
+//! to keep its state the same on all the workers.
+
+use super::*;
+use phala_crypto::sr25519::KDF;
+use phala_mq::{Message, MessageDispatcher, MessageSendQueue, OverflowPolicy, Receiver};
+use sp_core::sr25519;
+
+/// The max number of commands of a contract kept in memory, the overflowing ones are spilled to
+/// the sealed storage.
+pub const COMMAND_QUEUE_CAPACITY: usize = 1024;
+
+/// The context used to construct and wire up a native contract.
+pub struct InstallContext<'a> {
+    pub send_mq: &'a MessageSendQueue,
+    pub recv_mq: &'a mut MessageDispatcher,
+    pub identity_key: &'a sr25519::Pair,
+    pub contract_key: &'a sr25519::Pair,
+    /// The commands held while the contract was waiting for its key.
+    pub held_commands: Option<HeldCommands>,
+}
+
+impl InstallContext<'_> {
+    /// Wraps a `NativeContract` into a `Contract` with its own egress channel and command queue.
+    ///
+    /// Egress messages are signed with the contract key, and commands sent to the contract are
+    /// decrypted with the ecdh key derived from it. The results of its side tasks are reported
+    /// with the identity key of the worker, to be told apart from the other workers, and sealed
+    /// with the contract key.
+    pub fn install<Con>(&mut self, contract: Con) -> Box<dyn Contract + Send>
+    where
+        Con: NativeContract + Send + Sync + 'static,
+        Con::Cmd: Send + Sync + 'static,
+        Con::QReq: 'static,
+        Con::QResp: 'static,
+    {
+        let id = contract.id();
+        let contract_id = id256(id);
+        let ecdh_key = self
+            .contract_key
+            .derive_ecdh_key()
+            .expect("Failed to derive contract ecdh key");
+        let sender = MessageOrigin::native_contract(id);
+        let mq = self.send_mq.channel(sender, self.contract_key.clone());
+        let worker = MessageOrigin::Worker(self.identity_key.public());
+        let worker_mq = self.send_mq.channel(worker, self.identity_key.clone());
+        let (cmd_queue, backlog) = match self.held_commands.take() {
+            Some(held) => (held.queue, held.held),
+            None => (subscribe_commands(self.recv_mq, contract_id), Vec::new()),
+        };
+        let cmd_mq =
+            PeelingReceiver::new_secret(cmd_queue.into(), ecdh_key.clone()).with_backlog(backlog);
+        let side_task_mq = self.recv_mq.subscribe(side_task_topic(contract_id)).into();
+        let side_task_sealer = side_task_votes::SideTaskSealer::new(
+            contract_id,
+            self.identity_key.public(),
+            self.contract_key.clone(),
+        );
+        Box::new(NativeCompatContract::new(
+            contract,
+            mq,
+            worker_mq,
+            cmd_mq,
+            side_task_mq,
+            ecdh_key,
+            side_task_sealer,
+        ))
+    }
+}
+
+/// Subscribes to the commands of a contract, spilling the ones over `COMMAND_QUEUE_CAPACITY`.
+pub(crate) fn subscribe_commands(
+    recv_mq: &mut MessageDispatcher,
+    contract_id: ContractId,
+) -> Receiver<(u64, Message)> {
+    let cmd_topic = command_topic(contract_id);
+    recv_mq.set_capacity(cmd_topic.clone(), COMMAND_QUEUE_CAPACITY);
+    recv_mq.subscribe_with_policy(cmd_topic, OverflowPolicy::Spill)
+}
+
+/// The commands sent to an enabled contract before its key is available.
+///
+/// The queues of the dispatcher are cleared at the end of each block, so the commands are moved
+/// out of the queue block by block.
+pub struct HeldCommands {
+    queue: Receiver<(u64, Message)>,
+    held: Vec<(chain::BlockNumber, u64, Message)>,
+}
+
+impl HeldCommands {
+    pub fn subscribe(recv_mq: &mut MessageDispatcher, contract_id: ContractId) -> Self {
+        HeldCommands {
+            queue: subscribe_commands(recv_mq, contract_id),
+            held: Vec::new(),
+        }
+    }
+
+    /// Takes the commands dispatched in this block out of the queue.
+    pub fn hold(&mut self, block_number: chain::BlockNumber) {
+        while let Ok(Some((seq, message))) = self.queue.try_next() {
+            self.held.push((block_number, seq, message));
+        }
+    }
+}
+
+/// Derives the key of a contract from the gatekeeper master key.
+///
+/// The public part is published in `pallet_registry::ContractKey` by the gatekeeper.
+pub fn derive_contract_key(master_key: &sr25519::Pair, id: &ContractId) -> sr25519::Pair {
+    master_key
+        .derive_sr25519_pair(&[&b"contract_key"[..], id.as_bytes()])
+        .expect("should not fail with valid info; qed.")
+}
+
+pub type Constructor = fn(&mut InstallContext) -> Box<dyn Contract + Send>;
+
+pub struct ContractEntry {
+    pub id: ContractId32,
+    pub name: &'static str,
+    /// Whether the contract is installed when running in dev mode without an explicit list.
+    pub dev_default: bool,
+    pub constructor: Constructor,
+}
+
+/// All native contracts known to this build of pRuntime.
+pub const NATIVE_CONTRACTS: &[ContractEntry] = &[
+    ContractEntry {
+        id: DATA_PLAZA,
+        name: "data_plaza",
+        dev_default: true,
+        constructor: |ctx| ctx.install(data_plaza::DataPlaza::new()),
+    },
+    ContractEntry {
+        id: BALANCES,
+        name: "balances",
+        dev_default: true,
+        constructor: |ctx| ctx.install(balances::Balances::new()),
+    },
+    ContractEntry {
+        id: ASSETS,
+        name: "assets",
+        dev_default: true,
+        constructor: |ctx| ctx.install(assets::Assets::new()),
+    },
+    // TODO.kevin: This is temporaryly disabled due to the dependency on CPUID which is not allowed in SGX.
+    ContractEntry {
+        id: WEB3_ANALYTICS,
+        name: "web3_analytics",
+        dev_default: false,
+        constructor: |ctx| ctx.install(web3analytics::Web3Analytics::new()),
+    },
+    ContractEntry {
+        id: SUBSTRATE_KITTIES,
+        name: "substrate_kitties",
+        dev_default: true,
+        constructor: |ctx| ctx.install(substrate_kitties::SubstrateKitties::new()),
+    },
+    ContractEntry {
+        id: BTC_LOTTERY,
+        name: "btc_lottery",
+        dev_default: true,
+        constructor: |ctx| {
+            let secret = Some(ctx.identity_key.clone());
+            ctx.install(btc_lottery::BtcLottery::new(secret))
+        },
+    },
+    ContractEntry {
+        id: GEOLOCATION,
+        name: "geolocation",
+        dev_default: true,
+        constructor: |ctx| ctx.install(geolocation::Geolocation::new()),
+    },
+    ContractEntry {
+        id: GUESS_NUMBER,
+        name: "guess_number",
+        dev_default: true,
+        constructor: |ctx| ctx.install(guess_number::GuessNumber::new()),
+    },
+    ContractEntry {
+        id: BTC_PRICE_BOT,
+        name: "btc_price_bot",
+        dev_default: true,
+        constructor: |ctx| ctx.install(btc_price_bot::BtcPriceBot::new()),
+    },
+];
+
+/// Looks up a native contract by its name or its numeric id.
+pub fn find(name_or_id: &str) -> Option<&'static ContractEntry> {
+    let id: Option<ContractId32> = name_or_id.parse().ok();
+    NATIVE_CONTRACTS
+        .iter()
+        .find(|entry| entry.name == name_or_id || Some(entry.id) == id)
+}
+
+/// Resolves the contracts to install.
+///
+/// Starts from the dev mode default set (or nothing when not in dev mode), then applies the
+/// explicit list in order: `name` enables a contract and `-name` disables it.
+pub fn resolve(explicit: &[String], dev_mode: bool) -> Result<Vec<&'static ContractEntry>> {
+    let mut entries: Vec<&'static ContractEntry> = if dev_mode {
+        NATIVE_CONTRACTS.iter().filter(|e| e.dev_default).collect()
+    } else {
+        Vec::new()
+    };
+    for item in explicit {
+        let (disable, name) = match item.strip_prefix('-') {
+            Some(name) => (true, name),
+            None => (false, item.as_str()),
+        };
+        let entry = find(name).ok_or_else(|| anyhow::anyhow!("Unknown contract: {}", name))?;
+        entries.retain(|e| e.id != entry.id);
+        if !disable {
+            entries.push(entry);
+        }
+    }
+    entries.sort_by_key(|e| e.id);
+    Ok(entries)
+}
@@ -0,0 +1,333 @@
+//! Agrees on the results of the side tasks of a contract among the workers running it.
+//!
+//! A side task, e.g. an HTTP request, can see a different result on each worker. Instead of acting
+//! on its own result, each worker reports it on chain as a `SideTaskResult` signed by its identity
+//! key. The reports are dispatched to every instance of the contract in the same order, so all the
+//! instances count the same votes, and resolve the same agreed value when the voting closes.
+//!
+//! The results are also signed by the contract key, so only the workers the contract key has been
+//! dispatched to, i.e. the ones running the contract, get a vote. The values are encrypted with a
+//! key derived from the contract key, so they are not published on chain in plaintext.
+
+use super::*;
+use phala_crypto::{
+    aead,
+    sr25519::{Persistence as _, KDF as _},
+};
+use phala_types::messaging::SideTaskResult;
+use phala_types::WorkerPublicKey;
+use sp_core::{sr25519, Pair as _};
+
+/// Seals the side task results of a contract reported by this worker, and opens the ones reported
+/// by the others.
+#[derive(Clone)]
+pub struct SideTaskSealer {
+    contract: ContractId,
+    worker: WorkerPublicKey,
+    contract_key: sr25519::Pair,
+    secret: [u8; 32],
+}
+
+impl SideTaskSealer {
+    pub fn new(contract: ContractId, worker: WorkerPublicKey, contract_key: sr25519::Pair) -> Self {
+        let secret_key = contract_key
+            .derive_sr25519_pair(&[b"side_task_result"])
+            .expect("Should never fail with valid contract key; qed.")
+            .dump_secret_key();
+        let mut secret = [0; 32];
+        secret.copy_from_slice(&secret_key[..32]);
+        SideTaskSealer {
+            contract,
+            worker,
+            contract_key,
+            secret,
+        }
+    }
+
+    /// The worker the results are sealed by, i.e. this worker.
+    pub fn worker(&self) -> &WorkerPublicKey {
+        &self.worker
+    }
+
+    /// Encrypts and signs the result of a task run by this worker.
+    pub fn seal(&self, task_id: u64, value: Option<Vec<u8>>) -> SideTaskResult {
+        let encrypted_value = value.map(|mut data| {
+            let iv = crate::generate_random_iv();
+            aead::encrypt(&iv, &self.secret, &mut data)
+                .expect("Should never fail with valid secret; qed.");
+            let mut sealed = iv.to_vec();
+            sealed.append(&mut data);
+            sealed
+        });
+        let data =
+            SideTaskResult::data_be_signed(&self.contract, &self.worker, task_id, &encrypted_value);
+        SideTaskResult {
+            task_id,
+            encrypted_value,
+            signature: self.contract_key.sign(&data),
+        }
+    }
+
+    /// Verifies and decrypts a result reported by the worker, returning the task id and the value.
+    ///
+    /// Returns None if the result is not signed by the contract key or fails to decrypt.
+    pub fn open(
+        &self,
+        worker: &WorkerPublicKey,
+        result: SideTaskResult,
+    ) -> Option<(u64, Option<Vec<u8>>)> {
+        let data = SideTaskResult::data_be_signed(
+            &self.contract,
+            worker,
+            result.task_id,
+            &result.encrypted_value,
+        );
+        if !sr25519::Pair::verify(&result.signature, &data, &self.contract_key.public()) {
+            return None;
+        }
+        let value = match result.encrypted_value {
+            Some(mut sealed) if sealed.len() >= aead::IV_BYTES => {
+                let mut data = sealed.split_off(aead::IV_BYTES);
+                let value = aead::decrypt(&sealed, &self.secret, &mut data).ok()?;
+                Some(value.to_vec())
+            }
+            Some(_) => return None,
+            None => None,
+        };
+        Some((result.task_id, value))
+    }
+}
+
+/// How the results reported by the workers are aggregated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregation {
+    /// The median of the values, which must be SCALE encoded `u128`s.
+    ///
+    /// The lower one of the two middle values is taken if the number of values is even.
+    Median,
+    /// The value reported by most workers, if reported by at least the given number of them.
+    Quorum(u32),
+}
+
+/// The value agreed on by the workers for a task.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgreedValue {
+    pub value: Vec<u8>,
+    /// The worker with the lowest key among the ones reporting the value, which is the same on
+    /// all the instances of the contract.
+    pub reporter: WorkerPublicKey,
+}
+
+/// What the agreed value of a task is handed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskKind {
+    /// `NativeContract::handle_side_task_result`
+    Aggregated,
+    /// `NativeContract::handle_http_response`
+    Http,
+}
+
+struct Voting {
+    kind: TaskKind,
+    aggregation: Aggregation,
+    close_at: chain::BlockNumber,
+    votes: BTreeMap<WorkerPublicKey, Vec<u8>>,
+}
+
+/// The side tasks of a contract being voted on.
+#[derive(Default)]
+pub struct SideTaskVotes {
+    next_task_id: u64,
+    votings: BTreeMap<u64, Voting>,
+}
+
+impl SideTaskVotes {
+    /// Opens the voting of a new task, returning its id.
+    ///
+    /// The votes reported up to the block `close_at` are counted.
+    pub fn open(
+        &mut self,
+        kind: TaskKind,
+        aggregation: Aggregation,
+        close_at: chain::BlockNumber,
+    ) -> u64 {
+        let task_id = self.next_task_id;
+        self.next_task_id += 1;
+        self.votings.insert(
+            task_id,
+            Voting {
+                kind,
+                aggregation,
+                close_at,
+                votes: Default::default(),
+            },
+        );
+        task_id
+    }
+
+    /// Counts the result reported by a worker.
+    ///
+    /// Only the first vote of each worker is counted. Votes for unknown or closed tasks, or
+    /// reporting no value, are ignored.
+    pub fn vote(&mut self, worker: WorkerPublicKey, task_id: u64, value: Option<Vec<u8>>) {
+        let voting = match self.votings.get_mut(&task_id) {
+            Some(voting) => voting,
+            None => {
+                warn!("Ignored the vote for unknown side task {}", task_id);
+                return;
+            }
+        };
+        if let Some(value) = value {
+            voting.votes.entry(worker).or_insert(value);
+        }
+    }
+
+    /// Closes the votings due at the block, returning the agreed values by task id.
+    pub fn close(
+        &mut self,
+        block_number: chain::BlockNumber,
+    ) -> Vec<(u64, TaskKind, Option<AgreedValue>)> {
+        let due: Vec<u64> = self
+            .votings
+            .iter()
+            .filter(|(_, voting)| voting.close_at <= block_number)
+            .map(|(task_id, _)| *task_id)
+            .collect();
+        due.into_iter()
+            .filter_map(|task_id| self.votings.remove(&task_id).map(|v| (task_id, v)))
+            .map(|(task_id, voting)| (task_id, voting.kind, voting.resolve()))
+            .collect()
+    }
+}
+
+impl Voting {
+    fn resolve(&self) -> Option<AgreedValue> {
+        match self.aggregation {
+            Aggregation::Median => {
+                let mut values: Vec<(u128, &WorkerPublicKey)> = self
+                    .votes
+                    .iter()
+                    .filter_map(|(worker, value)| {
+                        Some((u128::decode(&mut &value[..]).ok()?, worker))
+                    })
+                    .collect();
+                if values.is_empty() {
+                    return None;
+                }
+                // Sorted by the workers too, for all the instances to pick the same reporter
+                values.sort_unstable();
+                let (median, reporter) = values[(values.len() - 1) / 2];
+                Some(AgreedValue {
+                    value: median.encode(),
+                    reporter: reporter.clone(),
+                })
+            }
+            Aggregation::Quorum(quorum) => {
+                let mut counts: BTreeMap<&Vec<u8>, u32> = BTreeMap::new();
+                for value in self.votes.values() {
+                    *counts.entry(value).or_default() += 1;
+                }
+                // Ties go to the greatest value, for all the instances to pick the same one
+                let (value, count) = counts.into_iter().max_by_key(|(_, count)| *count)?;
+                if count < quorum {
+                    return None;
+                }
+                let (reporter, _) = self.votes.iter().find(|(_, vote)| *vote == value)?;
+                Some(AgreedValue {
+                    value: value.clone(),
+                    reporter: reporter.clone(),
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn worker(n: u8) -> WorkerPublicKey {
+        sr25519::Public::from_raw([n; 32])
+    }
+
+    fn vote(votes: &mut SideTaskVotes, n: u8, task_id: u64, value: impl Encode) {
+        votes.vote(worker(n), task_id, Some(value.encode()));
+    }
+
+    fn agreed(value: impl Encode, n: u8) -> Option<AgreedValue> {
+        Some(AgreedValue {
+            value: value.encode(),
+            reporter: worker(n),
+        })
+    }
+
+    #[test]
+    fn votes_are_sealed_with_the_contract_key() {
+        let contract = ContractId::from_low_u64_be(1);
+        let contract_key = sr25519::Pair::from_seed(&[1; 32]);
+        let sealer = SideTaskSealer::new(contract, worker(1), contract_key.clone());
+        let value = b"secret value".to_vec();
+        let result = sealer.seal(0, Some(value.clone()));
+        let sealed = result.encrypted_value.clone().unwrap();
+        assert!(!sealed.windows(value.len()).any(|w| w == &value[..]));
+        assert_eq!(
+            sealer.open(&worker(1), result.clone()),
+            Some((0, Some(value.clone())))
+        );
+        assert_eq!(
+            sealer.open(&worker(1), sealer.seal(1, None)),
+            Some((1, None))
+        );
+
+        // Bound to the worker, the task and the value
+        assert_eq!(sealer.open(&worker(2), result.clone()), None);
+        let mut forged = result.clone();
+        forged.task_id = 1;
+        assert_eq!(sealer.open(&worker(1), forged), None);
+        let mut forged = result.clone();
+        forged.encrypted_value = None;
+        assert_eq!(sealer.open(&worker(1), forged), None);
+
+        // Bound to the contract and its key
+        let other = SideTaskSealer::new(ContractId::from_low_u64_be(2), worker(1), contract_key);
+        assert_eq!(other.open(&worker(1), result), None);
+        let impostor = SideTaskSealer::new(contract, worker(1), sr25519::Pair::from_seed(&[2; 32]));
+        assert_eq!(sealer.open(&worker(1), impostor.seal(0, Some(value))), None);
+    }
+
+    #[test]
+    fn votes_resolve_deterministically() {
+        let mut votes = SideTaskVotes::default();
+        let median = votes.open(TaskKind::Aggregated, Aggregation::Median, 10);
+        let quorum = votes.open(TaskKind::Http, Aggregation::Quorum(2), 12);
+
+        vote(&mut votes, 1, median, 300u128);
+        vote(&mut votes, 2, median, 100u128);
+        vote(&mut votes, 3, median, 200u128);
+        vote(&mut votes, 4, median, 400u128);
+        // Only the first vote of a worker counts
+        vote(&mut votes, 4, median, 0u128);
+
+        vote(&mut votes, 1, quorum, "b");
+        vote(&mut votes, 2, quorum, "a");
+        vote(&mut votes, 3, quorum, "b");
+
+        assert!(votes.close(9).is_empty());
+        assert_eq!(
+            votes.close(10),
+            vec![(median, TaskKind::Aggregated, agreed(200u128, 3))]
+        );
+        // Closed tasks don't count the late votes
+        vote(&mut votes, 5, median, 0u128);
+        assert!(votes.votings.get(&median).is_none());
+        assert_eq!(
+            votes.close(12),
+            vec![(quorum, TaskKind::Http, agreed("b", 1))]
+        );
+
+        let missed = votes.open(TaskKind::Aggregated, Aggregation::Quorum(2), 13);
+        vote(&mut votes, 1, missed, "a");
+        vote(&mut votes, 2, missed, "b");
+        assert_eq!(votes.close(13), vec![(missed, TaskKind::Aggregated, None)]);
+    }
+}
@@ -1,13 +1,14 @@
 use super::account_id_from_hex;
 use super::{TransactionError, TransactionResult};
-use crate::contracts;
 use crate::contracts::AccountId;
+use crate::contracts::{self, query_budget};
 use lazy_static;
 use sp_core::hashing::blake2_128;
 use sp_core::H256 as Hash;
 use sp_core::U256;
 extern crate runtime as chain;
 use parity_scale_codec::{Decode, Encode};
+use scale_info::TypeInfo;
 
 use std::collections::BTreeMap;
 use rand::Rng;
@@ -60,7 +61,7 @@ impl core::fmt::Debug for SubstrateKitties {
     }
 }
 
-#[derive(Encode, Decode, Debug, Clone, Default)]
+#[derive(Encode, Decode, Debug, Clone, Default, TypeInfo)]
 pub struct BlindBox {
     // Use String to store the U256 type ID, preventing the Serialize implementation
     blind_box_id: String,
@@ -72,14 +73,14 @@ pub struct Kitty {
 }
 
 /// The errors that the contract could throw for some queries
-#[derive(Encode, Decode, Debug)]
+#[derive(Encode, Decode, Debug, TypeInfo)]
 pub enum Error {
     NotAuthorized,
 }
 
 /// Query requests. The end users can only query the contract states by sending requests.
 /// Queries are not supposed to write to the contract states.
-#[derive(Encode, Decode, Debug, Clone)]
+#[derive(Encode, Decode, Debug, Clone, TypeInfo)]
 pub enum Request {
     /// Users can require to see the blind boxes list
     ObserveBox,
@@ -110,6 +111,40 @@ pub enum Response {
     Error(Error),
 }
 
+/// Mirrors of the messages containing `AccountId` or `Hash`, which have no `TypeInfo`
+mod info {
+    use super::*;
+    use crate::contracts::AccountIdInfo as AccountId;
+
+    /// `Hash` described as the bytes it's SCALE encoded to
+    pub type Hash = [u8; 32];
+
+    #[derive(Encode, TypeInfo)]
+    pub enum Command {
+        Pack {},
+        Transfer { dest: String, blind_box_id: String },
+        Open { blind_box_id: String },
+        Created(AccountId, Hash),
+    }
+
+    #[derive(Encode, TypeInfo)]
+    pub enum Response {
+        ObserveBox {
+            blind_box: BTreeMap<String, BlindBox>,
+        },
+        ObserveOwnedBox {
+            owned_box: Vec<String>,
+        },
+        ObserveLeftKitties {
+            kitties: Vec<Vec<u8>>,
+        },
+        OwnerOf {
+            owner: AccountId,
+        },
+        Error(Error),
+    }
+}
+
 impl SubstrateKitties {
     /// Initializes the contract
     pub fn new() -> Self {
@@ -142,6 +177,14 @@ impl contracts::NativeContract for SubstrateKitties {
         contracts::SUBSTRATE_KITTIES
     }
 
+    fn message_types(&self) -> Option<contracts::MessageTypes> {
+        Some(contracts::MessageTypes::of::<
+            info::Command,
+            Request,
+            info::Response,
+        >())
+    }
+
     // Handles the commands from transactions on the blockchain. This method doesn't respond.
     fn handle_command(
         &mut self,
@@ -292,15 +335,22 @@ impl contracts::NativeContract for SubstrateKitties {
     }
 
     // Handles a direct query and responds to the query. It shouldn't modify the contract states.
-    fn handle_query(&mut self, origin: Option<&chain::AccountId>, req: Request) -> Response {
+    fn handle_query(&self, origin: Option<&chain::AccountId>, req: Request) -> Response {
+        // A query out of budget fails with a timeout, whatever it responds
+        let charge_items =
+            |count: usize| query_budget::charge_items(count).map_err(|_| Error::NotAuthorized);
         let inner = || -> Result<Response, Error> {
             match req {
-                Request::ObserveBox => Ok(Response::ObserveBox {
-                    blind_box: self.blind_boxes.clone(),
-                }),
+                Request::ObserveBox => {
+                    charge_items(self.blind_boxes.len())?;
+                    Ok(Response::ObserveBox {
+                        blind_box: self.blind_boxes.clone(),
+                    })
+                }
                 Request::ObserveOwnedBox => {
                     let sender = origin.unwrap().clone();
                     let owned_boxes = self.owned_boxes.get(&sender);
+                    charge_items(owned_boxes.map_or(0, Vec::len))?;
                     match owned_boxes {
                         Some(_) => Ok(Response::ObserveOwnedBox {
                             owned_box: owned_boxes.unwrap().clone(),
@@ -310,9 +360,12 @@ impl contracts::NativeContract for SubstrateKitties {
                         }),
                     }
                 }
-                Request::ObserveLeftKitties => Ok(Response::ObserveLeftKitties {
-                    kitties: self.left_kitties.clone(),
-                }),
+                Request::ObserveLeftKitties => {
+                    charge_items(self.left_kitties.len())?;
+                    Ok(Response::ObserveLeftKitties {
+                        kitties: self.left_kitties.clone(),
+                    })
+                }
                 Request::OwnerOf { blind_box_id } => Ok(Response::OwnerOf {
                     owner: self.owner.get(&blind_box_id).unwrap().clone(),
                 }),
@@ -324,3 +377,82 @@ impl contracts::NativeContract for SubstrateKitties {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contracts::account_id_info;
+
+    /// Mirrors the messages, matching all the variants so that none is left out of the mirrors.
+    fn mirror_command(command: &Command) -> info::Command {
+        match command {
+            Command::Pack {} => info::Command::Pack {},
+            Command::Transfer { dest, blind_box_id } => info::Command::Transfer {
+                dest: dest.clone(),
+                blind_box_id: blind_box_id.clone(),
+            },
+            Command::Open { blind_box_id } => info::Command::Open {
+                blind_box_id: blind_box_id.clone(),
+            },
+            Command::Created(owner, hash) => info::Command::Created(account_id_info(owner), hash.0),
+        }
+    }
+
+    fn mirror_response(response: &Response) -> info::Response {
+        match response {
+            Response::ObserveBox { blind_box } => info::Response::ObserveBox {
+                blind_box: blind_box.clone(),
+            },
+            Response::ObserveOwnedBox { owned_box } => info::Response::ObserveOwnedBox {
+                owned_box: owned_box.clone(),
+            },
+            Response::ObserveLeftKitties { kitties } => info::Response::ObserveLeftKitties {
+                kitties: kitties.clone(),
+            },
+            Response::OwnerOf { owner } => info::Response::OwnerOf {
+                owner: account_id_info(owner),
+            },
+            Response::Error(Error::NotAuthorized) => info::Response::Error(Error::NotAuthorized),
+        }
+    }
+
+    #[test]
+    fn message_types_mirror_the_encoding() {
+        let alice = AccountId::new([1; 32]);
+        let commands = vec![
+            Command::Pack {},
+            Command::Transfer {
+                dest: ALICE.into(),
+                blind_box_id: "1".into(),
+            },
+            Command::Open {
+                blind_box_id: "1".into(),
+            },
+            Command::Created(alice.clone(), Hash::repeat_byte(2)),
+        ];
+        for command in commands {
+            assert_eq!(command.encode(), mirror_command(&command).encode());
+        }
+        let mut blind_box = BTreeMap::new();
+        blind_box.insert(
+            "1".to_string(),
+            BlindBox {
+                blind_box_id: "1".into(),
+            },
+        );
+        let responses = vec![
+            Response::ObserveBox { blind_box },
+            Response::ObserveOwnedBox {
+                owned_box: vec!["1".into()],
+            },
+            Response::ObserveLeftKitties {
+                kitties: vec![vec![1]],
+            },
+            Response::OwnerOf { owner: alice },
+            Response::Error(Error::NotAuthorized),
+        ];
+        for response in responses {
+            assert_eq!(response.encode(), mirror_response(&response).encode());
+        }
+    }
+}
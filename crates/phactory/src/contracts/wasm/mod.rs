@@ -0,0 +1,545 @@
+//! Confidential contracts written in ink! and compiled to WebAssembly.
+//!
+//! A `WasmContract` runs on the same command topic and query path as the native contracts. The
+//! commands and the queries carry the raw ink! call data (the selector followed by the SCALE
+//! encoded arguments). The contract storage is a key-value store living in the enclave memory,
+//! which is never exposed outside of pRuntime.
+//!
+//! The code is instrumented at load time to meter the gas deterministically, so that all the
+//! instances of a contract reach the same state after replaying the same commands.
+
+mod runtime;
+
+use super::registry::{self, InstallContext};
+use super::*;
+use crate::secret_channel::{Payload, SecretPeeler};
+use crate::types::BlockInfo;
+use phala_types::messaging::ContractDeployment;
+use runtime::{Resolver, Runtime, Termination, FLAG_REVERT};
+use scale_info::TypeInfo;
+use sp_core::H256;
+use wasmi::{Error as WasmiError, ImportsBuilder, ModuleInstance, Trap, TrapKind};
+
+pub use runtime::{CallContext, KvStore, StorageKey};
+
+/// The gas limit of a single command or query.
+pub const DEFAULT_GAS_LIMIT: u64 = 100_000_000;
+/// The max stack height (in wasm values) of the instrumented code.
+const MAX_STACK_HEIGHT: u32 = 64 * 1024;
+
+/// The commands to a wasm contract.
+#[derive(Encode, Decode, Debug, TypeInfo)]
+pub enum Command {
+    /// Calls a message of the contract with the ink! call data.
+    Call { input: Vec<u8> },
+}
+
+/// The queries to a wasm contract.
+#[derive(Encode, Decode, Debug, TypeInfo)]
+pub enum Request {
+    /// Calls a message of the contract with the ink! call data, discarding any state change.
+    Call { input: Vec<u8> },
+}
+
+#[derive(Encode, Decode, Debug, TypeInfo)]
+pub enum Response {
+    /// The data returned by the contract.
+    Output(Vec<u8>),
+}
+
+#[derive(Debug)]
+pub enum ExecError {
+    /// The code can not be loaded.
+    InvalidCode(String),
+    /// The code can not be instantiated, e.g. it imports an unsupported host function.
+    Instantiation(String),
+    /// The execution has reached the gas limit.
+    OutOfGas,
+    /// The execution trapped.
+    Trapped(String),
+    /// The contract reverted with the given output.
+    Reverted(Vec<u8>),
+}
+
+impl From<ExecError> for TransactionError {
+    fn from(err: ExecError) -> Self {
+        match err {
+            ExecError::InvalidCode(_) | ExecError::Instantiation(_) => TransactionError::BadCode,
+            ExecError::OutOfGas => TransactionError::OutOfGas,
+            ExecError::Trapped(_) => TransactionError::ContractTrapped,
+            ExecError::Reverted(_) => TransactionError::ContractReverted,
+        }
+    }
+}
+
+/// The outcome of a successful execution.
+#[derive(Debug)]
+pub struct ExecOutput {
+    pub data: Vec<u8>,
+    pub gas_used: u64,
+    /// The events emitted by the contract, in (topics, data) form.
+    pub events: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Loaded ink! code together with its confidential storage.
+pub struct WasmInstance {
+    address: ContractId,
+    module: wasmi::Module,
+    storage: KvStore,
+    gas_limit: u64,
+}
+
+impl WasmInstance {
+    /// Validates and instruments the code.
+    pub fn load(address: ContractId, code: &[u8], gas_limit: u64) -> Result<Self, ExecError> {
+        let invalid_code = |e: &dyn Debug| ExecError::InvalidCode(format!("{:?}", e));
+        let module = parity_wasm::deserialize_buffer(code).map_err(|e| invalid_code(&e))?;
+        let module =
+            pwasm_utils::inject_gas_counter(module, &pwasm_utils::rules::Set::default(), "seal0")
+                .map_err(|_| invalid_code(&"Failed to inject the gas counter"))?;
+        let module = pwasm_utils::stack_height::inject_limiter(module, MAX_STACK_HEIGHT)
+            .map_err(|e| invalid_code(&e))?;
+        let module =
+            wasmi::Module::from_parity_wasm_module(module).map_err(|e| invalid_code(&e))?;
+        Ok(WasmInstance {
+            address,
+            module,
+            storage: Default::default(),
+            gas_limit,
+        })
+    }
+
+    pub fn address(&self) -> ContractId {
+        self.address
+    }
+
+    /// Runs the constructor selected by the input.
+    pub fn deploy(
+        &mut self,
+        context: &CallContext,
+        input: Vec<u8>,
+    ) -> Result<ExecOutput, ExecError> {
+        self.execute("deploy", context, input, true, self.gas_limit)
+    }
+
+    /// Calls a message of the contract.
+    ///
+    /// The storage changes are committed only if `commit` is true and the call didn't revert.
+    pub fn call(
+        &mut self,
+        context: &CallContext,
+        input: Vec<u8>,
+        commit: bool,
+    ) -> Result<ExecOutput, ExecError> {
+        self.execute("call", context, input, commit, self.gas_limit)
+    }
+
+    /// Calls a message of the contract without committing, with at most the given gas.
+    pub fn query(
+        &self,
+        context: &CallContext,
+        input: Vec<u8>,
+        gas_limit: u64,
+    ) -> Result<ExecOutput, ExecError> {
+        self.run("call", context, input, gas_limit.min(self.gas_limit))
+            .map(|(output, _)| output)
+    }
+
+    fn execute(
+        &mut self,
+        export: &str,
+        context: &CallContext,
+        input: Vec<u8>,
+        commit: bool,
+        gas_limit: u64,
+    ) -> Result<ExecOutput, ExecError> {
+        let (output, changes) = self.run(export, context, input, gas_limit)?;
+        if commit {
+            for (key, value) in changes {
+                match value {
+                    Some(value) => self.storage.insert(key, value),
+                    None => self.storage.remove(&key),
+                };
+            }
+        }
+        Ok(output)
+    }
+
+    /// Runs the export against the committed storage, returning the storage changes made.
+    fn run(
+        &self,
+        export: &str,
+        context: &CallContext,
+        input: Vec<u8>,
+        gas_limit: u64,
+    ) -> Result<(ExecOutput, BTreeMap<StorageKey, Option<Vec<u8>>>), ExecError> {
+        let resolver = Resolver::default();
+        let imports = ImportsBuilder::new()
+            .with_resolver("env", &resolver)
+            .with_resolver("seal0", &resolver);
+        let instance = ModuleInstance::new(&self.module, &imports)
+            .map_err(|e| ExecError::Instantiation(format!("{:?}", e)))?;
+
+        let mut runtime = Runtime::new(context, &self.storage, input, gas_limit);
+        if let Some(memory) = resolver.memory() {
+            runtime.set_memory(memory);
+        }
+        let outcome = match instance.run_start(&mut runtime) {
+            Ok(instance) => match instance.invoke_export(export, &[], &mut runtime) {
+                Ok(_) => Ok((0, Vec::new())),
+                Err(WasmiError::Trap(trap)) => termination(trap),
+                Err(err) => Err(ExecError::Trapped(format!("{:?}", err))),
+            },
+            Err(trap) => termination(trap),
+        };
+        let gas_used = gas_limit - runtime.gas_left();
+        let events = core::mem::take(&mut runtime.events);
+        let changes = runtime.into_changes();
+
+        let (flags, data) = outcome?;
+        if flags & FLAG_REVERT != 0 {
+            return Err(ExecError::Reverted(data));
+        }
+        let output = ExecOutput {
+            data,
+            gas_used,
+            events,
+        };
+        Ok((output, changes))
+    }
+}
+
+/// Translates a trap into the returned (flags, data), or the error it stands for.
+fn termination(trap: Trap) -> Result<(u32, Vec<u8>), ExecError> {
+    if let TrapKind::Host(err) = trap.kind() {
+        match err.downcast_ref::<Termination>() {
+            Some(Termination::Return { flags, data }) => return Ok((*flags, data.clone())),
+            Some(Termination::OutOfGas) => return Err(ExecError::OutOfGas),
+            Some(Termination::Fault(msg)) => return Err(ExecError::Trapped(msg.to_string())),
+            None => {}
+        }
+    }
+    Err(ExecError::Trapped(format!("{:?}", trap)))
+}
+
+type CommandReceiver = PeelingReceiver<Command, Payload<Command>, SecretPeeler<Command>>;
+
+pub struct WasmContract {
+    instance: WasmInstance,
+    send_mq: MessageChannel,
+    cmd_rcv_mq: CommandReceiver,
+    receipts: CommandReceipts,
+    /// The events emitted by the contract, published to everyone in (topics, data) form.
+    events: ContractEvents,
+    /// The deployer and the code hash, if deployed on chain.
+    deployment: Option<(chain::AccountId, H256)>,
+}
+
+impl WasmContract {
+    pub fn new(
+        instance: WasmInstance,
+        send_mq: MessageChannel,
+        cmd_rcv_mq: CommandReceiver,
+    ) -> Self {
+        WasmContract {
+            instance,
+            send_mq,
+            cmd_rcv_mq,
+            receipts: CommandReceipts::new(true),
+            events: Default::default(),
+            deployment: None,
+        }
+    }
+
+    /// Instantiates a contract deployed on chain, running the constructor with the init args.
+    pub fn instantiate(
+        ctx: &mut InstallContext,
+        deployment: &ContractDeployment,
+        code: &[u8],
+        block_number: chain::BlockNumber,
+        now_ms: u64,
+    ) -> Result<Self, ExecError> {
+        let id = deployment.contract_id;
+        let mut instance = WasmInstance::load(id, code, DEFAULT_GAS_LIMIT)?;
+        let context = CallContext {
+            address: id.0,
+            caller: deployment.deployer.0,
+            block_number,
+            now_ms,
+        };
+        instance.deploy(&context, deployment.init_args.clone())?;
+
+        let ecdh_key = ctx
+            .contract_key
+            .derive_ecdh_key()
+            .expect("Failed to derive contract ecdh key");
+        let send_mq = ctx
+            .send_mq
+            .channel(MessageOrigin::Contract(id), ctx.contract_key.clone());
+        let cmd_queue = registry::subscribe_commands(ctx.recv_mq, id);
+        let cmd_rcv_mq = PeelingReceiver::new_secret(cmd_queue.into(), ecdh_key);
+        let mut contract = Self::new(instance, send_mq, cmd_rcv_mq);
+        contract.deployment = Some((deployment.deployer.0.into(), deployment.code_hash));
+        Ok(contract)
+    }
+
+    pub fn instance(&self) -> &WasmInstance {
+        &self.instance
+    }
+
+    fn handle_command(
+        &mut self,
+        block: &BlockInfo,
+        origin: MessageOrigin,
+        cmd: Command,
+    ) -> TransactionResult {
+        let caller = match origin {
+            MessageOrigin::AccountId(account) => account.0,
+            MessageOrigin::Contract(id) => id.0,
+            _ => return Err(TransactionError::BadOrigin),
+        };
+        let context = CallContext {
+            address: self.instance.address().0,
+            caller,
+            block_number: block.block_number,
+            now_ms: block.now_ms,
+        };
+        let id = self.id();
+        match cmd {
+            Command::Call { input } => {
+                let output = self.instance.call(&context, input, true).map_err(|err| {
+                    error!("Wasm contract call failed [{}]: {:?}", id, err);
+                    TransactionError::from(err)
+                })?;
+                info!(
+                    "Wasm contract call [{}] done, gas used: {}",
+                    id, output.gas_used
+                );
+                for event in output.events {
+                    self.events
+                        .publish(None, block.block_number, event.encode());
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Contract for WasmContract {
+    fn id(&self) -> ContractId {
+        self.instance.address()
+    }
+
+    fn handle_query(
+        &self,
+        origin: Option<&chain::AccountId>,
+        req: OpaqueQuery,
+    ) -> Result<OpaqueReply, OpaqueError> {
+        let context = CallContext {
+            address: self.instance.address().0,
+            caller: origin.map(|origin| *origin.as_ref()).unwrap_or_default(),
+            block_number: 0,
+            now_ms: 0,
+        };
+        let response = match deopaque_query(req)? {
+            Request::Call { input } => {
+                let gas_limit = query_budget::remaining().unwrap_or(u64::MAX);
+                let output = self
+                    .instance
+                    .query(&context, input, gas_limit)
+                    .map_err(|err| match err {
+                        ExecError::OutOfGas => OpaqueError::Timeout,
+                        err => OpaqueError::OtherError(format!("{:?}", err)),
+                    })?;
+                query_budget::charge(output.gas_used)?;
+                Response::Output(output.data)
+            }
+        };
+        Ok(response.encode())
+    }
+
+    fn process_messages(&mut self, env: &mut ExecuteEnv) {
+        let block_number = env.block.block_number;
+        loop {
+            let ok = phala_mq::select! {
+                next_cmd = self.cmd_rcv_mq => match next_cmd {
+                    Ok((index, cmd, origin)) => {
+                        let sent_at = self.cmd_rcv_mq.held_in().unwrap_or(block_number);
+                        let result = self.handle_command(env.block, origin.clone(), cmd);
+                        self.receipts
+                            .record(origin, sent_at, index, block_number, result);
+                    }
+                    Err(e) => {
+                        error!("Read command failed [{}]: {:?}", self.id(), e);
+                        let sent_at = self.cmd_rcv_mq.held_in().unwrap_or(block_number);
+                        self.receipts.record_bad_command(&e, sent_at, block_number);
+                    }
+                },
+            };
+            if ok.is_none() {
+                break;
+            }
+        }
+        if let Some(batch) = self.receipts.take_batch(block_number) {
+            self.send_mq.send(&batch);
+        }
+    }
+
+    fn command_receipt(
+        &self,
+        sender: &MessageOrigin,
+        sent_at: chain::BlockNumber,
+        index: u64,
+    ) -> Option<CommandReceipt> {
+        self.receipts.get(sender, sent_at, index).cloned()
+    }
+
+    fn events(&self) -> &ContractEvents {
+        &self.events
+    }
+
+    fn metadata(&self) -> ContractMetadata {
+        // The ink! messages are described by the ink! metadata of the code, which is not known
+        // here, so the code hash is reported as the version to look it up.
+        let (owner, version) = match &self.deployment {
+            Some((deployer, code_hash)) => (Some(deployer.clone()), format!("{:?}", code_hash)),
+            None => (None, String::new()),
+        };
+        ContractMetadata {
+            name: "ink!".into(),
+            version,
+            owner,
+            types: Some(MessageTypes::of::<Command, Request, Response>()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+    use phala_mq::{Message, MessageDispatcher, MessageSendQueue};
+    use sp_core::{sr25519, Pair};
+
+    const FLIPPER: &[u8] = include_bytes!("../../res/flipper.wasm");
+    const SEL_NEW: [u8; 4] = hex_literal::hex!("f81e7e1a");
+    const SEL_FLIP: [u8; 4] = hex_literal::hex!("cde4efa9");
+    const SEL_GET: [u8; 4] = hex_literal::hex!("6d4ce63c");
+
+    fn context() -> CallContext {
+        CallContext {
+            address: [1; 32],
+            caller: [2; 32],
+            block_number: 1,
+            now_ms: 0,
+        }
+    }
+
+    fn call_data(selector: [u8; 4], args: impl Encode) -> Vec<u8> {
+        let mut data = selector.to_vec();
+        args.encode_to(&mut data);
+        data
+    }
+
+    fn deploy_flipper(init: bool) -> WasmInstance {
+        let mut instance =
+            WasmInstance::load(ContractId::repeat_byte(1), FLIPPER, DEFAULT_GAS_LIMIT)
+                .expect("Failed to load flipper");
+        instance
+            .deploy(&context(), call_data(SEL_NEW, init))
+            .expect("Failed to deploy flipper");
+        instance
+    }
+
+    fn get(instance: &mut WasmInstance) -> bool {
+        let output = instance
+            .call(&context(), call_data(SEL_GET, ()), false)
+            .expect("Failed to call get");
+        Decode::decode(&mut &output.data[..]).expect("Failed to decode the output")
+    }
+
+    #[test]
+    fn flipper_works() {
+        let mut instance = deploy_flipper(false);
+        assert!(!get(&mut instance));
+
+        let output = instance
+            .call(&context(), call_data(SEL_FLIP, ()), true)
+            .expect("Failed to call flip");
+        assert!(output.gas_used > 0);
+        assert!(get(&mut instance));
+
+        // Uncommitted changes are discarded
+        instance
+            .call(&context(), call_data(SEL_FLIP, ()), false)
+            .expect("Failed to call flip");
+        assert!(get(&mut instance));
+    }
+
+    #[test]
+    fn out_of_gas() {
+        let mut instance = deploy_flipper(true);
+        instance.gas_limit = 10;
+        assert!(matches!(
+            instance.call(&context(), call_data(SEL_FLIP, ()), true),
+            Err(ExecError::OutOfGas)
+        ));
+        instance.gas_limit = DEFAULT_GAS_LIMIT;
+        assert!(get(&mut instance));
+        // Queries can be given less gas than the instance limit
+        assert!(matches!(
+            instance.query(&context(), call_data(SEL_GET, ()), 10),
+            Err(ExecError::OutOfGas)
+        ));
+    }
+
+    #[test]
+    fn flip_through_commands_and_queries() {
+        let instance = deploy_flipper(false);
+        let id = instance.address();
+        let key = sr25519::Pair::from_seed(&[1; 32]);
+        let ecdh_key = key.derive_ecdh_key().unwrap();
+
+        let send_mq = MessageSendQueue::default();
+        let mut recv_mq = MessageDispatcher::default();
+        let cmd_queue = registry::subscribe_commands(&mut recv_mq, id);
+        let cmd_mq = PeelingReceiver::new_secret(cmd_queue.into(), ecdh_key);
+        let mut contract = WasmContract::new(
+            instance,
+            send_mq.channel(MessageOrigin::Contract(id), key),
+            cmd_mq,
+        );
+
+        let query = |contract: &mut WasmContract| -> bool {
+            let req = Request::Call {
+                input: call_data(SEL_GET, ()),
+            };
+            let reply = contract.handle_query(None, &req.encode()).unwrap();
+            match Decode::decode(&mut &reply[..]).unwrap() {
+                Response::Output(data) => Decode::decode(&mut &data[..]).unwrap(),
+            }
+        };
+        assert!(!query(&mut contract));
+
+        let cmd: Payload<Command> = Payload::Plain(Command::Call {
+            input: call_data(SEL_FLIP, ()),
+        });
+        let sender = MessageOrigin::AccountId([3; 32].into());
+        recv_mq.dispatch(Message::new(sender, command_topic(id), cmd.encode()));
+
+        let storage = Storage::default();
+        let mut side_task_man = Default::default();
+        let mut block = BlockInfo {
+            block_number: 1,
+            now_ms: 0,
+            storage: &storage,
+            recv_mq: &mut recv_mq,
+            side_task_man: &mut side_task_man,
+        };
+        contract.process_messages(&mut ExecuteEnv {
+            block: &mut block,
+            contracts: &mut Default::default(),
+        });
+        assert!(query(&mut contract));
+    }
+}
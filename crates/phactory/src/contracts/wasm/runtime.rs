@@ -0,0 +1,380 @@
+//! The host environment (the `seal0` interface) exposed to the ink! contracts.
+//!
+//! Only the subset of functions used by the ink! contracts we ship is implemented. The gas is
+//! metered by the instrumentation injected at load time, which calls the imported `seal0::gas`
+//! function at the beginning of each metered block.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use log::debug;
+use parity_scale_codec::Encode;
+use sp_core::hashing;
+use wasmi::{
+    memory_units::Pages, Error as WasmiError, Externals, FuncInstance, FuncRef, HostError,
+    MemoryDescriptor, MemoryInstance, MemoryRef, ModuleImportResolver, RuntimeArgs, RuntimeValue,
+    Signature, Trap, TrapKind, ValueType,
+};
+
+pub type StorageKey = [u8; 32];
+pub type KvStore = BTreeMap<StorageKey, Vec<u8>>;
+
+/// The max number of memory pages an instance can ask for.
+pub const MAX_MEMORY_PAGES: u32 = 16;
+
+/// The gas charged for each host function call, on top of the instrumented instructions.
+const HOST_CALL_GAS: u64 = 100;
+/// The gas charged for each byte copied between the host and the sandbox.
+const BYTE_GAS: u64 = 1;
+
+/// The flag in `seal_return` indicating the state changes should be reverted.
+pub const FLAG_REVERT: u32 = 1;
+
+/// Return codes of the `seal0` functions.
+#[repr(u32)]
+enum ReturnCode {
+    Success = 0,
+    KeyNotFound = 3,
+}
+
+/// Reasons for which the execution is terminated by the host.
+#[derive(Debug)]
+pub enum Termination {
+    /// The contract called `seal_return`.
+    Return { flags: u32, data: Vec<u8> },
+    /// The gas limit has been reached.
+    OutOfGas,
+    /// The contract misbehaved, e.g. accessed memory out of bounds.
+    Fault(&'static str),
+}
+
+impl fmt::Display for Termination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl HostError for Termination {}
+
+macro_rules! host_functions {
+    (@ty $arg: ident) => { ValueType::I32 };
+    (@ret) => { None };
+    (@ret $ret: ident) => { Some(ValueType::$ret) };
+    ($($index: expr => $name: ident($($arg: ident),*) $(-> $ret: ident)?;)*) => {
+        mod host_index {
+            $(#[allow(non_upper_case_globals)] pub const $name: usize = $index;)*
+        }
+
+        fn host_signature(name: &str) -> Option<(usize, Signature)> {
+            match name {
+                $(stringify!($name) => Some((
+                    $index,
+                    Signature::new(
+                        &[$(host_functions!(@ty $arg)),*][..],
+                        host_functions!(@ret $($ret)?),
+                    ),
+                )),)*
+                _ => None,
+            }
+        }
+    };
+}
+
+host_functions! {
+    0 => gas(amount);
+    1 => seal_input(out_ptr, out_len_ptr);
+    2 => seal_return(flags, data_ptr, data_len);
+    3 => seal_get_storage(key_ptr, out_ptr, out_len_ptr) -> I32;
+    4 => seal_set_storage(key_ptr, value_ptr, value_len);
+    5 => seal_clear_storage(key_ptr);
+    6 => seal_value_transferred(out_ptr, out_len_ptr);
+    7 => seal_caller(out_ptr, out_len_ptr);
+    8 => seal_address(out_ptr, out_len_ptr);
+    9 => seal_block_number(out_ptr, out_len_ptr);
+    10 => seal_now(out_ptr, out_len_ptr);
+    11 => seal_hash_blake2_256(input_ptr, input_len, out_ptr);
+    12 => seal_hash_keccak_256(input_ptr, input_len, out_ptr);
+    13 => seal_deposit_event(topics_ptr, topics_len, data_ptr, data_len);
+}
+
+/// Resolves the imports of a contract module.
+///
+/// Keeps the imported memory so that the host functions can access it after instantiation.
+#[derive(Default)]
+pub struct Resolver {
+    memory: RefCell<Option<MemoryRef>>,
+}
+
+impl Resolver {
+    pub fn memory(&self) -> Option<MemoryRef> {
+        self.memory.borrow().clone()
+    }
+}
+
+impl ModuleImportResolver for Resolver {
+    fn resolve_func(&self, field_name: &str, signature: &Signature) -> Result<FuncRef, WasmiError> {
+        let (index, expected) = host_signature(field_name).ok_or_else(|| {
+            WasmiError::Instantiation(format!("Unknown host function: {}", field_name))
+        })?;
+        if signature != &expected {
+            return Err(WasmiError::Instantiation(format!(
+                "Signature mismatch for host function: {}",
+                field_name
+            )));
+        }
+        Ok(FuncInstance::alloc_host(expected, index))
+    }
+
+    fn resolve_memory(
+        &self,
+        field_name: &str,
+        descriptor: &MemoryDescriptor,
+    ) -> Result<MemoryRef, WasmiError> {
+        if field_name != "memory" {
+            return Err(WasmiError::Instantiation(format!(
+                "Unknown memory import: {}",
+                field_name
+            )));
+        }
+        let maximum = descriptor.maximum().unwrap_or(MAX_MEMORY_PAGES);
+        if descriptor.initial() > maximum || maximum > MAX_MEMORY_PAGES {
+            return Err(WasmiError::Instantiation("Too many memory pages".into()));
+        }
+        let memory = MemoryInstance::alloc(
+            Pages(descriptor.initial() as usize),
+            Some(Pages(maximum as usize)),
+        )?;
+        *self.memory.borrow_mut() = Some(memory.clone());
+        Ok(memory)
+    }
+}
+
+/// The information about the current execution exposed to the contract.
+pub struct CallContext {
+    pub address: [u8; 32],
+    pub caller: [u8; 32],
+    pub block_number: u32,
+    pub now_ms: u64,
+}
+
+/// The state of an ongoing execution.
+pub struct Runtime<'a> {
+    memory: Option<MemoryRef>,
+    context: &'a CallContext,
+    input: Vec<u8>,
+    /// The committed storage of the contract.
+    storage: &'a KvStore,
+    /// Uncommitted storage changes. `None` stands for a removed entry.
+    changes: BTreeMap<StorageKey, Option<Vec<u8>>>,
+    /// The events emitted by the contract, in (topics, data) form.
+    pub events: Vec<(Vec<u8>, Vec<u8>)>,
+    gas_left: u64,
+}
+
+impl<'a> Runtime<'a> {
+    pub fn new(
+        context: &'a CallContext,
+        storage: &'a KvStore,
+        input: Vec<u8>,
+        gas_limit: u64,
+    ) -> Self {
+        Runtime {
+            memory: None,
+            context,
+            input,
+            storage,
+            changes: Default::default(),
+            events: Default::default(),
+            gas_left: gas_limit,
+        }
+    }
+
+    pub fn set_memory(&mut self, memory: MemoryRef) {
+        self.memory = Some(memory);
+    }
+
+    pub fn gas_left(&self) -> u64 {
+        self.gas_left
+    }
+
+    /// Takes the storage changes made during the execution.
+    pub fn into_changes(self) -> BTreeMap<StorageKey, Option<Vec<u8>>> {
+        self.changes
+    }
+
+    fn charge(&mut self, amount: u64) -> Result<(), Trap> {
+        match self.gas_left.checked_sub(amount) {
+            Some(left) => {
+                self.gas_left = left;
+                Ok(())
+            }
+            None => {
+                self.gas_left = 0;
+                Err(Termination::OutOfGas.into())
+            }
+        }
+    }
+
+    fn memory(&self) -> Result<&MemoryRef, Trap> {
+        self.memory
+            .as_ref()
+            .ok_or_else(|| Termination::Fault("No memory").into())
+    }
+
+    fn read(&mut self, ptr: u32, len: u32) -> Result<Vec<u8>, Trap> {
+        self.charge(BYTE_GAS.saturating_mul(len as u64))?;
+        self.memory()?
+            .get(ptr, len as usize)
+            .map_err(|_| Termination::Fault("Memory access out of bounds").into())
+    }
+
+    fn read_key(&mut self, ptr: u32) -> Result<StorageKey, Trap> {
+        let mut key = StorageKey::default();
+        self.memory()?
+            .get_into(ptr, &mut key)
+            .map_err(|_| Termination::Fault("Memory access out of bounds"))?;
+        Ok(key)
+    }
+
+    fn write(&mut self, ptr: u32, data: &[u8]) -> Result<(), Trap> {
+        self.charge(BYTE_GAS.saturating_mul(data.len() as u64))?;
+        self.memory()?
+            .set(ptr, data)
+            .map_err(|_| Termination::Fault("Memory access out of bounds").into())
+    }
+
+    /// Writes `data` to the output buffer provided by the contract.
+    ///
+    /// The buffer capacity is read from `out_len_ptr`, and the actual length is written back.
+    fn write_output(&mut self, out_ptr: u32, out_len_ptr: u32, data: &[u8]) -> Result<(), Trap> {
+        let mut capacity = [0u8; 4];
+        self.memory()?
+            .get_into(out_len_ptr, &mut capacity)
+            .map_err(|_| Termination::Fault("Memory access out of bounds"))?;
+        if u32::from_le_bytes(capacity) < data.len() as u32 {
+            return Err(Termination::Fault("Output buffer too small").into());
+        }
+        self.write(out_ptr, data)?;
+        self.write(out_len_ptr, &(data.len() as u32).to_le_bytes())
+    }
+
+    fn get_storage(&self, key: &StorageKey) -> Option<&Vec<u8>> {
+        match self.changes.get(key) {
+            Some(value) => value.as_ref(),
+            None => self.storage.get(key),
+        }
+    }
+}
+
+impl Externals for Runtime<'_> {
+    fn invoke_index(
+        &mut self,
+        index: usize,
+        args: RuntimeArgs,
+    ) -> Result<Option<RuntimeValue>, Trap> {
+        if index != host_index::gas {
+            self.charge(HOST_CALL_GAS)?;
+        }
+        match index {
+            host_index::gas => {
+                let amount: u32 = args.nth_checked(0)?;
+                self.charge(amount as u64)?;
+            }
+            host_index::seal_input => {
+                let out_ptr: u32 = args.nth_checked(0)?;
+                let out_len_ptr: u32 = args.nth_checked(1)?;
+                let input = core::mem::take(&mut self.input);
+                self.write_output(out_ptr, out_len_ptr, &input)?;
+            }
+            host_index::seal_return => {
+                let flags: u32 = args.nth_checked(0)?;
+                let data_ptr: u32 = args.nth_checked(1)?;
+                let data_len: u32 = args.nth_checked(2)?;
+                let data = self.read(data_ptr, data_len)?;
+                return Err(Termination::Return { flags, data }.into());
+            }
+            host_index::seal_get_storage => {
+                let key_ptr: u32 = args.nth_checked(0)?;
+                let out_ptr: u32 = args.nth_checked(1)?;
+                let out_len_ptr: u32 = args.nth_checked(2)?;
+                let key = self.read_key(key_ptr)?;
+                let code = match self.get_storage(&key).cloned() {
+                    Some(value) => {
+                        self.write_output(out_ptr, out_len_ptr, &value)?;
+                        ReturnCode::Success
+                    }
+                    None => ReturnCode::KeyNotFound,
+                };
+                return Ok(Some(RuntimeValue::I32(code as i32)));
+            }
+            host_index::seal_set_storage => {
+                let key_ptr: u32 = args.nth_checked(0)?;
+                let value_ptr: u32 = args.nth_checked(1)?;
+                let value_len: u32 = args.nth_checked(2)?;
+                let key = self.read_key(key_ptr)?;
+                let value = self.read(value_ptr, value_len)?;
+                self.changes.insert(key, Some(value));
+            }
+            host_index::seal_clear_storage => {
+                let key_ptr: u32 = args.nth_checked(0)?;
+                let key = self.read_key(key_ptr)?;
+                self.changes.insert(key, None);
+            }
+            host_index::seal_value_transferred => {
+                let out_ptr: u32 = args.nth_checked(0)?;
+                let out_len_ptr: u32 = args.nth_checked(1)?;
+                // Value transfer is not supported in pRuntime.
+                self.write_output(out_ptr, out_len_ptr, &0u128.encode())?;
+            }
+            host_index::seal_caller => {
+                let out_ptr: u32 = args.nth_checked(0)?;
+                let out_len_ptr: u32 = args.nth_checked(1)?;
+                let caller = self.context.caller;
+                self.write_output(out_ptr, out_len_ptr, &caller)?;
+            }
+            host_index::seal_address => {
+                let out_ptr: u32 = args.nth_checked(0)?;
+                let out_len_ptr: u32 = args.nth_checked(1)?;
+                let address = self.context.address;
+                self.write_output(out_ptr, out_len_ptr, &address)?;
+            }
+            host_index::seal_block_number => {
+                let out_ptr: u32 = args.nth_checked(0)?;
+                let out_len_ptr: u32 = args.nth_checked(1)?;
+                let block_number = self.context.block_number.encode();
+                self.write_output(out_ptr, out_len_ptr, &block_number)?;
+            }
+            host_index::seal_now => {
+                let out_ptr: u32 = args.nth_checked(0)?;
+                let out_len_ptr: u32 = args.nth_checked(1)?;
+                let now = self.context.now_ms.encode();
+                self.write_output(out_ptr, out_len_ptr, &now)?;
+            }
+            host_index::seal_hash_blake2_256 | host_index::seal_hash_keccak_256 => {
+                let input_ptr: u32 = args.nth_checked(0)?;
+                let input_len: u32 = args.nth_checked(1)?;
+                let out_ptr: u32 = args.nth_checked(2)?;
+                let input = self.read(input_ptr, input_len)?;
+                let hash = if index == host_index::seal_hash_blake2_256 {
+                    hashing::blake2_256(&input)
+                } else {
+                    hashing::keccak_256(&input)
+                };
+                self.write(out_ptr, &hash)?;
+            }
+            host_index::seal_deposit_event => {
+                let topics_ptr: u32 = args.nth_checked(0)?;
+                let topics_len: u32 = args.nth_checked(1)?;
+                let data_ptr: u32 = args.nth_checked(2)?;
+                let data_len: u32 = args.nth_checked(3)?;
+                let topics = self.read(topics_ptr, topics_len)?;
+                let data = self.read(data_ptr, data_len)?;
+                debug!("Contract event: {}", hex::encode(&data));
+                self.events.push((topics, data));
+            }
+            _ => return Err(TrapKind::UnexpectedSignature.into()),
+        }
+        Ok(None)
+    }
+}
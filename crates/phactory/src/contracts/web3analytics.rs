@@ -1,5 +1,5 @@
 use super::account_id_from_hex;
-use super::{NativeContext, TransactionResult};
+use super::{NativeContext, QueryScratch, TransactionResult};
 use crate::contracts::AccountId;
 use crate::cryptography::aead;
 use std::collections::BTreeMap;
@@ -8,8 +8,9 @@ use anyhow::Result;
 use core::fmt;
 use parity_scale_codec::{Decode, Encode};
 use phala_mq::MessageOrigin;
+use scale_info::TypeInfo;
 
-use crate::contracts;
+use crate::contracts::{self, query_budget};
 use phala_types::messaging::Web3AnalyticsCommand as Command;
 
 pub type Sid = String;
@@ -23,7 +24,7 @@ const WEEK_IN_SECONDS: u32 = 7 * DAY_IN_SECONDS;
 const KEY: &[u8] =
     &hex_literal::hex!("290c3c5d812a4ba7ce33adf09598a462692a615beb6c80fdafb3f9e3bbef8bc6");
 
-#[derive(Encode, Decode, Debug, Clone)]
+#[derive(Encode, Decode, Debug, Clone, TypeInfo)]
 pub struct PageView {
     id: String,
     sid: Sid,
@@ -37,7 +38,7 @@ pub struct PageView {
     created_at: Timestamp,
 }
 
-#[derive(Encode, Decode, Debug, Clone)]
+#[derive(Encode, Decode, Debug, Clone, TypeInfo)]
 pub struct OnlineUser {
     sid: Sid,
     cid_count: String,
@@ -45,7 +46,7 @@ pub struct OnlineUser {
     timestamp: u32,
 }
 
-#[derive(Encode, Decode, Debug, Clone, Default)]
+#[derive(Encode, Decode, Debug, Clone, Default, TypeInfo)]
 pub struct HourlyPageViewStat {
     sid: Sid,
     pv_count: String,
@@ -54,7 +55,7 @@ pub struct HourlyPageViewStat {
     timestamp: u32,
 }
 
-#[derive(Encode, Decode, Debug, Clone)]
+#[derive(Encode, Decode, Debug, Clone, TypeInfo)]
 pub struct WeeklySite {
     sid: Sid,
     path: String,
@@ -62,7 +63,7 @@ pub struct WeeklySite {
     timestamp: u32,
 }
 
-#[derive(Encode, Decode, Debug, Clone)]
+#[derive(Encode, Decode, Debug, Clone, TypeInfo)]
 pub struct WeeklyDevice {
     sid: Sid,
     device: String,
@@ -70,20 +71,20 @@ pub struct WeeklyDevice {
     timestamp: u32,
 }
 
-#[derive(Encode, Decode, Debug, Clone)]
+#[derive(Encode, Decode, Debug, Clone, TypeInfo)]
 pub struct WeeklyClient {
     sid: Sid,
     cids: Vec<String>,
     timestamp: u32,
 }
 
-#[derive(Encode, Decode, Debug, Clone)]
+#[derive(Encode, Decode, Debug, Clone, TypeInfo)]
 pub struct SiteClient {
     sid: Sid,
     cids: Vec<String>,
 }
 
-#[derive(Encode, Decode, Debug, Clone)]
+#[derive(Encode, Decode, Debug, Clone, TypeInfo)]
 pub struct HourlyStat {
     hourly_page_view_stats: Vec<HourlyPageViewStat>,
     site_clients: Vec<SiteClient>,
@@ -106,7 +107,7 @@ impl HourlyStat {
     }
 }
 
-#[derive(Encode, Decode, Debug, Clone)]
+#[derive(Encode, Decode, Debug, Clone, TypeInfo)]
 pub struct DailyStat {
     stats: Vec<HourlyPageViewStat>,
 }
@@ -173,7 +174,7 @@ pub enum Request {
     },
 }
 
-#[derive(Encode, Decode, Debug)]
+#[derive(Encode, Decode, Debug, TypeInfo)]
 pub enum Response {
     SetPageView {
         page_view_count: u32,
@@ -212,7 +213,62 @@ pub enum Response {
     Error(String),
 }
 
+/// Mirrors of the messages containing `AccountId` or the types of `phala_types`, which have no `TypeInfo`
+mod info {
+    use super::*;
+    use crate::contracts::AccountIdInfo as AccountId;
+
+    #[derive(Encode, TypeInfo)]
+    pub enum Command {
+        SetConfiguration { skip_stat: bool },
+    }
+
+    #[derive(Encode, TypeInfo)]
+    pub enum Request {
+        SetPageView {
+            page_views: Vec<PageView>,
+            encrypted: bool,
+        },
+        ClearPageView {
+            timestamp: Timestamp,
+        },
+        GetOnlineUsers {
+            start: Timestamp,
+            end: Timestamp,
+        },
+        GetHourlyStats {
+            start: Timestamp,
+            end: Timestamp,
+            start_of_week: Timestamp,
+        },
+        GetDailyStats {
+            daily_stat: DailyStat,
+        },
+        GetWeeklySites {
+            weekly_sites_in_db: Vec<WeeklySite>,
+            weekly_sites_new: Vec<WeeklySite>,
+        },
+        GetWeeklyDevices {
+            weekly_devices_in_db: Vec<WeeklyDevice>,
+            weekly_devices_new: Vec<WeeklyDevice>,
+        },
+        GetTotalStat {
+            total_stat: HourlyPageViewStat,
+            count: String,
+        },
+        GetConfiguration {
+            account: AccountId,
+        },
+    }
+}
+
 pub struct Web3Analytics {
+    no_tracking: BTreeMap<AccountId, bool>,
+    /// The page views uploaded by the queries, and the stats computed from them.
+    stats: QueryScratch<Stats>,
+}
+
+struct Stats {
     encrypted: bool,
     page_views: Vec<PageView>,
     online_users: Vec<OnlineUser>,
@@ -224,12 +280,19 @@ pub struct Web3Analytics {
 
     key: Vec<u8>,
     parser: woothee::parser::Parser,
-
-    no_tracking: BTreeMap<AccountId, bool>,
 }
 
 impl Web3Analytics {
     pub fn new() -> Self {
+        Self {
+            no_tracking: BTreeMap::<AccountId, bool>::new(),
+            stats: QueryScratch::new(Stats::new()),
+        }
+    }
+}
+
+impl Stats {
+    fn new() -> Self {
         Self {
             encrypted: false,
             page_views: Vec::<PageView>::new(),
@@ -242,8 +305,6 @@ impl Web3Analytics {
             key: KEY.to_owned(),
 
             parser: woothee::parser::Parser::new(),
-
-            no_tracking: BTreeMap::<AccountId, bool>::new(),
         }
     }
 
@@ -890,6 +951,18 @@ impl contracts::NativeContract for Web3Analytics {
         contracts::WEB3_ANALYTICS
     }
 
+    fn message_types(&self) -> Option<contracts::MessageTypes> {
+        Some(contracts::MessageTypes::of::<
+            info::Command,
+            info::Request,
+            Response,
+        >())
+    }
+
+    fn state_digest(&self) -> Option<[u8; 32]> {
+        Some(sp_core::hashing::blake2_256(&self.no_tracking.encode()))
+    }
+
     fn handle_command(
         &mut self,
         _context: &mut NativeContext,
@@ -914,13 +987,17 @@ impl contracts::NativeContract for Web3Analytics {
         status
     }
 
-    fn handle_query(&mut self, origin: Option<&chain::AccountId>, req: Request) -> Response {
-        let inner = || -> Result<Response> {
+    fn handle_query(&self, origin: Option<&chain::AccountId>, req: Request) -> Response {
+        let inner = |stats: &mut Stats| -> Result<Response> {
             match req {
                 Request::SetPageView {
                     page_views,
                     encrypted,
                 } => {
+                    // Each new page view is looked up in the stored ones
+                    query_budget::charge_items_or_bail(
+                        page_views.len().saturating_mul(stats.page_views.len() + 1),
+                    )?;
                     for page_view in page_views {
                         if page_view.uid.len() == 64
                             && self
@@ -929,33 +1006,34 @@ impl contracts::NativeContract for Web3Analytics {
                         {
                             continue;
                         }
-                        let b = self
+                        let b = stats
                             .page_views
                             .clone()
                             .into_iter()
                             .any(|x| x.id == page_view.id);
                         if !b {
-                            self.page_views.push(page_view);
+                            stats.page_views.push(page_view);
                         }
                     }
 
-                    self.encrypted = encrypted;
+                    stats.encrypted = encrypted;
 
                     Ok(Response::SetPageView {
-                        page_view_count: self.page_views.len() as u32,
+                        page_view_count: stats.page_views.len() as u32,
                     })
                 }
                 Request::ClearPageView { timestamp: _ } => {
-                    self.page_views.clear();
+                    stats.page_views.clear();
                     Ok(Response::ClearPageView {
-                        page_view_count: self.page_views.len() as u32,
+                        page_view_count: stats.page_views.len() as u32,
                     })
                 }
                 Request::GetOnlineUsers { start, end } => {
-                    self.update_online_users(start, end);
+                    query_budget::charge_items_or_bail(stats.page_views.len())?;
+                    stats.update_online_users(start, end);
                     Ok(Response::GetOnlineUsers {
-                        online_users: self.online_users.clone(),
-                        encrypted: self.encrypted,
+                        online_users: stats.online_users.clone(),
+                        encrypted: stats.encrypted,
                     })
                 }
                 Request::GetHourlyStats {
@@ -963,44 +1041,56 @@ impl contracts::NativeContract for Web3Analytics {
                     end,
                     start_of_week,
                 } => {
-                    self.update_hourly_stats(start, end, start_of_week);
+                    query_budget::charge_items_or_bail(stats.page_views.len())?;
+                    stats.update_hourly_stats(start, end, start_of_week);
                     Ok(Response::GetHourlyStats {
-                        hourly_stat: self.hourly_stat.clone(),
-                        encrypted: self.encrypted,
+                        hourly_stat: stats.hourly_stat.clone(),
+                        encrypted: stats.encrypted,
                     })
                 }
                 Request::GetDailyStats { daily_stat } => {
-                    self.update_daily_stats(daily_stat);
+                    query_budget::charge_items_or_bail(daily_stat.stats.len())?;
+                    stats.update_daily_stats(daily_stat);
                     Ok(Response::GetDailyStats {
-                        daily_stat: self.daily_stat.clone(),
-                        encrypted: self.encrypted,
+                        daily_stat: stats.daily_stat.clone(),
+                        encrypted: stats.encrypted,
                     })
                 }
                 Request::GetWeeklySites {
                     weekly_sites_in_db,
                     weekly_sites_new,
                 } => {
-                    self.update_weekly_sites(weekly_sites_in_db, weekly_sites_new);
+                    query_budget::charge_items_or_bail(
+                        weekly_sites_in_db
+                            .len()
+                            .saturating_mul(weekly_sites_new.len()),
+                    )?;
+                    stats.update_weekly_sites(weekly_sites_in_db, weekly_sites_new);
                     Ok(Response::GetWeeklySites {
-                        weekly_sites: self.weekly_sites.clone(),
-                        encrypted: self.encrypted,
+                        weekly_sites: stats.weekly_sites.clone(),
+                        encrypted: stats.encrypted,
                     })
                 }
                 Request::GetWeeklyDevices {
                     weekly_devices_in_db,
                     weekly_devices_new,
                 } => {
-                    self.update_weekly_devices(weekly_devices_in_db, weekly_devices_new);
+                    query_budget::charge_items_or_bail(
+                        weekly_devices_in_db
+                            .len()
+                            .saturating_mul(weekly_devices_new.len()),
+                    )?;
+                    stats.update_weekly_devices(weekly_devices_in_db, weekly_devices_new);
                     Ok(Response::GetWeeklyDevices {
-                        weekly_devices: self.weekly_devices.clone(),
-                        encrypted: self.encrypted,
+                        weekly_devices: stats.weekly_devices.clone(),
+                        encrypted: stats.encrypted,
                     })
                 }
                 Request::GetTotalStat { total_stat, count } => {
-                    self.update_total_stat(total_stat, count);
+                    stats.update_total_stat(total_stat, count);
                     Ok(Response::GetTotalStat {
-                        total_stat: self.total_stat.clone(),
-                        encrypted: self.encrypted,
+                        total_stat: stats.total_stat.clone(),
+                        encrypted: stats.encrypted,
                     })
                 }
                 Request::GetConfiguration { account } => {
@@ -1016,9 +1106,135 @@ impl contracts::NativeContract for Web3Analytics {
                 }
             }
         };
-        match inner() {
+        match self.stats.with(inner) {
             Err(error) => Response::Error(error.to_string()),
             Ok(resp) => resp,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contracts::account_id_info;
+
+    /// Mirrors the messages, matching all the variants so that none is left out of the mirrors.
+    fn mirror_command(command: &Command) -> info::Command {
+        match command {
+            Command::SetConfiguration { skip_stat } => info::Command::SetConfiguration {
+                skip_stat: *skip_stat,
+            },
+        }
+    }
+
+    fn mirror_request(request: &Request) -> info::Request {
+        match request.clone() {
+            Request::SetPageView {
+                page_views,
+                encrypted,
+            } => info::Request::SetPageView {
+                page_views,
+                encrypted,
+            },
+            Request::ClearPageView { timestamp } => info::Request::ClearPageView { timestamp },
+            Request::GetOnlineUsers { start, end } => info::Request::GetOnlineUsers { start, end },
+            Request::GetHourlyStats {
+                start,
+                end,
+                start_of_week,
+            } => info::Request::GetHourlyStats {
+                start,
+                end,
+                start_of_week,
+            },
+            Request::GetDailyStats { daily_stat } => info::Request::GetDailyStats { daily_stat },
+            Request::GetWeeklySites {
+                weekly_sites_in_db,
+                weekly_sites_new,
+            } => info::Request::GetWeeklySites {
+                weekly_sites_in_db,
+                weekly_sites_new,
+            },
+            Request::GetWeeklyDevices {
+                weekly_devices_in_db,
+                weekly_devices_new,
+            } => info::Request::GetWeeklyDevices {
+                weekly_devices_in_db,
+                weekly_devices_new,
+            },
+            Request::GetTotalStat { total_stat, count } => {
+                info::Request::GetTotalStat { total_stat, count }
+            }
+            Request::GetConfiguration { account } => info::Request::GetConfiguration {
+                account: account_id_info(&account),
+            },
+        }
+    }
+
+    #[test]
+    fn message_types_mirror_the_encoding() {
+        let command = Command::SetConfiguration { skip_stat: true };
+        assert_eq!(command.encode(), mirror_command(&command).encode());
+
+        let page_view = PageView {
+            id: "id".into(),
+            sid: "sid".into(),
+            cid: "cid".into(),
+            uid: "uid".into(),
+            host: "host".into(),
+            path: "/".into(),
+            referrer: "referrer".into(),
+            ip: "ip".into(),
+            user_agent: "agent".into(),
+            created_at: 1,
+        };
+        let weekly_site = WeeklySite {
+            sid: "sid".into(),
+            path: "/".into(),
+            count: "1".into(),
+            timestamp: 1,
+        };
+        let weekly_device = WeeklyDevice {
+            sid: "sid".into(),
+            device: "device".into(),
+            count: "1".into(),
+            timestamp: 1,
+        };
+        let requests = vec![
+            Request::SetPageView {
+                page_views: vec![page_view],
+                encrypted: true,
+            },
+            Request::ClearPageView { timestamp: 1 },
+            Request::GetOnlineUsers { start: 1, end: 2 },
+            Request::GetHourlyStats {
+                start: 1,
+                end: 2,
+                start_of_week: 3,
+            },
+            Request::GetDailyStats {
+                daily_stat: DailyStat {
+                    stats: vec![Default::default()],
+                },
+            },
+            Request::GetWeeklySites {
+                weekly_sites_in_db: vec![weekly_site.clone()],
+                weekly_sites_new: vec![weekly_site],
+            },
+            Request::GetWeeklyDevices {
+                weekly_devices_in_db: vec![weekly_device.clone()],
+                weekly_devices_new: vec![weekly_device],
+            },
+            Request::GetTotalStat {
+                total_stat: Default::default(),
+                count: "1".into(),
+            },
+            Request::GetConfiguration {
+                account: AccountId::new([1; 32]),
+            },
+        ];
+        for request in requests {
+            assert_eq!(request.encode(), mirror_request(&request).encode());
+        }
+    }
+}
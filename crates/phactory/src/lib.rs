@@ -15,7 +15,6 @@ use rand::*;
 use side_task::SideTaskManager;
 
 use crate::light_validation::LightValidation;
-use std::collections::BTreeMap;
 use std::path::PathBuf;
 use std::str;
 
@@ -40,7 +39,7 @@ use phala_crypto::{
     ecdh::EcdhKey,
     sr25519::{Persistence, Sr25519SecretKey, KDF, SEED_BYTES},
 };
-use phala_mq::{BindTopic, ContractId, MessageDispatcher, MessageOrigin, MessageSendQueue};
+use phala_mq::{BindTopic, MessageDispatcher, MessageOrigin, MessageSendQueue};
 use phala_pallets::pallet_mq;
 use phala_types::WorkerRegistrationInfo;
 
@@ -50,6 +49,9 @@ mod bin_api_service;
 mod contracts;
 mod cryptography;
 mod light_validation;
+mod mq_log;
+mod mq_spill;
+mod mq_trace;
 mod prpc_service;
 mod rpc_types;
 mod secret_channel;
@@ -59,7 +61,7 @@ mod types;
 mod side_task;
 
 use crate::light_validation::utils::storage_map_prefix_twox_64_concat;
-use contracts::{ExecuteEnv, SYSTEM};
+use contracts::SYSTEM;
 use storage::{Storage, StorageExt};
 use types::BlockInfo;
 use types::Error;
@@ -69,7 +71,16 @@ use types::Error;
 type RuntimeHasher = <chain::Runtime as frame_system::Config>::Hashing;
 
 struct RuntimeState {
-    contracts: BTreeMap<ContractId, Box<dyn contracts::Contract + Send>>,
+    contracts: contracts::ContractMap,
+    /// Native contracts selected by the worker, unless configured on chain.
+    selected_contracts: Vec<&'static contracts::registry::ContractEntry>,
+    /// Contracts deployed on chain, waiting for their keys from the gatekeeper.
+    pending_deployments: Vec<phala_types::messaging::ContractDeployment>,
+    /// The commands of the enabled native contracts waiting for their keys.
+    held_commands:
+        std::collections::BTreeMap<phala_mq::ContractId, contracts::registry::HeldCommands>,
+    query_budgets: contracts::query_budget::QueryBudgets,
+    query_nonces: contracts::query_nonces::QueryNonces,
     send_mq: MessageSendQueue,
     recv_mq: MessageDispatcher,
 
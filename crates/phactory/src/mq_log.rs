@@ -0,0 +1,345 @@
+//! Persists the write-ahead log of the egress message queue with the `Sealing` PAL.
+//!
+//! The records appended in a block are sealed as a segment of the log when the log is committed,
+//! i.e. once per block, unless the block left the queue untouched. Every `COMPACTION_SEGMENTS`
+//! segments, the snapshot of the queue, kept in memory as the records are appended, is sealed in
+//! place of the segments. The log is tied to the identity key of the worker, and discarded when the
+//! worker starts with another identity.
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use parity_scale_codec::{Decode, Encode};
+use phala_mq::wal::{CommitError, LogRecord, QueueSnapshot, WriteAheadLog};
+use sp_core::sr25519;
+
+use crate::pal::Sealing;
+
+/// The egress message queue log filepath
+pub const MQ_LOG_FILE: &str = "mq_log.seal";
+
+/// The number of segments sealed between two compactions.
+const COMPACTION_SEGMENTS: u64 = 100;
+
+#[derive(Debug, Encode, Decode)]
+struct PersistentMqLog {
+    identity: sr25519::Public,
+    snapshot: QueueSnapshot,
+    /// The first segment not compacted into the snapshot.
+    next_segment: u64,
+}
+
+#[derive(Debug, Encode, Decode)]
+enum MqLogSeal {
+    V1(PersistentMqLog),
+}
+
+#[derive(Default)]
+struct LogState {
+    snapshot: QueueSnapshot,
+    /// The records appended since the last commit.
+    pending: Vec<LogRecord>,
+    /// The first segment not compacted into the sealed snapshot.
+    first_segment: u64,
+    /// The segment sealed by the next commit.
+    next_segment: u64,
+}
+
+pub struct SealedMqLog<P> {
+    platform: P,
+    dir: PathBuf,
+    identity: sr25519::Public,
+    state: Mutex<LogState>,
+}
+
+fn segment_path(dir: &Path, segment: u64) -> PathBuf {
+    dir.join(format!("mq_log.{}.seal", segment))
+}
+
+impl<P: Sealing> SealedMqLog<P> {
+    /// Opens the log in the sealing path, restoring the snapshot and the segments sealed with the
+    /// same identity.
+    pub fn open(platform: P, sealing_path: &str, identity: sr25519::Public) -> Self {
+        let dir = PathBuf::from(sealing_path);
+        let sealed = match platform.unseal_data(dir.join(MQ_LOG_FILE)) {
+            Ok(sealed) => sealed,
+            Err(err) => {
+                warn!("Failed to unseal the mq log: {:?}", err);
+                None
+            }
+        };
+        let sealed = match sealed.map(|data| MqLogSeal::decode(&mut &data[..])) {
+            Some(Ok(MqLogSeal::V1(log))) if log.identity == identity => Some(log),
+            Some(Ok(_)) => {
+                warn!("Discarded the mq log of another identity");
+                None
+            }
+            Some(Err(err)) => {
+                warn!("Discarded the broken mq log: {:?}", err);
+                None
+            }
+            None => None,
+        };
+        let log = SealedMqLog {
+            platform,
+            dir,
+            identity,
+            state: Default::default(),
+        };
+        match sealed {
+            Some(PersistentMqLog {
+                snapshot,
+                next_segment,
+                ..
+            }) => log.restore(snapshot, next_segment),
+            None => log.reset(),
+        }
+        log
+    }
+
+    pub fn snapshot(&self) -> QueueSnapshot {
+        self.state.lock().unwrap().snapshot.clone()
+    }
+
+    /// Replays the segments sealed after the snapshot, up to the first one missing.
+    fn restore(&self, mut snapshot: QueueSnapshot, first_segment: u64) {
+        let mut next_segment = first_segment;
+        loop {
+            let sealed = self
+                .platform
+                .unseal_data(segment_path(&self.dir, next_segment));
+            let records = match sealed {
+                Ok(Some(data)) => match Vec::<LogRecord>::decode(&mut &data[..]) {
+                    Ok(records) => records,
+                    Err(err) => {
+                        warn!(
+                            "Discarded the broken mq log segment {}: {:?}",
+                            next_segment, err
+                        );
+                        break;
+                    }
+                },
+                Ok(None) => break,
+                Err(err) => {
+                    warn!(
+                        "Failed to unseal the mq log segment {}: {:?}",
+                        next_segment, err
+                    );
+                    break;
+                }
+            };
+            for record in records.iter() {
+                snapshot.apply(record);
+            }
+            next_segment += 1;
+        }
+        *self.state.lock().unwrap() = LogState {
+            snapshot,
+            pending: Vec::new(),
+            first_segment,
+            next_segment,
+        };
+    }
+
+    /// Starts an empty log, removing the segments left by a previous one.
+    fn reset(&self) {
+        let mut segment = 0;
+        while let Ok(Some(_)) = self.platform.unseal_data(segment_path(&self.dir, segment)) {
+            let _ = self.platform.remove_data(segment_path(&self.dir, segment));
+            segment += 1;
+        }
+        let mut state = self.state.lock().unwrap();
+        if let Err(err) = self.compact(&mut state) {
+            warn!("{}", err.0);
+        }
+    }
+
+    /// Seals the snapshot in place of the segments sealed so far.
+    fn compact(&self, state: &mut LogState) -> Result<(), CommitError> {
+        let data = MqLogSeal::V1(PersistentMqLog {
+            identity: self.identity,
+            snapshot: state.snapshot.clone(),
+            next_segment: state.next_segment,
+        });
+        self.platform
+            .seal_data(self.dir.join(MQ_LOG_FILE), &data.encode())
+            .map_err(|err| CommitError(format!("Seal mq log failed: {:?}", err)))?;
+        // The compacted segments are skipped when restoring, even if they fail to be removed
+        for segment in state.first_segment..state.next_segment {
+            let _ = self.platform.remove_data(segment_path(&self.dir, segment));
+        }
+        state.first_segment = state.next_segment;
+        Ok(())
+    }
+}
+
+impl<P: Sealing + Send + Sync> WriteAheadLog for SealedMqLog<P> {
+    fn append(&self, record: &LogRecord) {
+        let mut state = self.state.lock().unwrap();
+        state.snapshot.apply(record);
+        // Only the last consumed index matters
+        let consumed = |record: Option<&LogRecord>| matches!(record, Some(LogRecord::Consumed(_)));
+        if consumed(Some(record)) && consumed(state.pending.last()) {
+            state.pending.pop();
+        }
+        state.pending.push(record.clone());
+    }
+
+    fn commit(&self) -> Result<(), CommitError> {
+        let mut state = self.state.lock().unwrap();
+        // A block leaving the queue untouched is replayed the same without its consumed index, so
+        // it's only sealed along with the next change to the queue.
+        let untouched = state
+            .pending
+            .iter()
+            .all(|record| matches!(record, LogRecord::Consumed(_)));
+        if untouched {
+            return Ok(());
+        }
+        let segment = state.next_segment;
+        self.platform
+            .seal_data(segment_path(&self.dir, segment), &state.pending.encode())
+            .map_err(|err| CommitError(format!("Seal mq log segment failed: {:?}", err)))?;
+        state.pending.clear();
+        state.next_segment += 1;
+        if state.next_segment - state.first_segment >= COMPACTION_SEGMENTS {
+            self.compact(&mut state)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use phala_mq::{MessageOrigin, MessageSendQueue};
+    use sp_core::Pair;
+    use std::collections::BTreeMap;
+    use std::convert::Infallible;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    pub(crate) struct MemorySealing(Arc<Mutex<BTreeMap<PathBuf, Vec<u8>>>>);
+
+    impl MemorySealing {
+        /// The number of the sealed files.
+        pub(crate) fn len(&self) -> usize {
+            self.0.lock().unwrap().len()
+        }
+
+        /// Swaps the contents of two sealed files.
+        pub(crate) fn swap(&self, a: &Path, b: &Path) {
+            let mut files = self.0.lock().unwrap();
+            let data_a = files.remove(a).unwrap();
+            let data_b = files.remove(b).unwrap();
+            files.insert(a.to_path_buf(), data_b);
+            files.insert(b.to_path_buf(), data_a);
+        }
+    }
+
+    impl Sealing for MemorySealing {
+        type SealError = Infallible;
+        type UnsealError = Infallible;
+
+        fn seal_data(&self, path: impl AsRef<Path>, data: &[u8]) -> Result<(), Infallible> {
+            let mut files = self.0.lock().unwrap();
+            files.insert(path.as_ref().to_path_buf(), data.to_vec());
+            Ok(())
+        }
+
+        fn unseal_data(&self, path: impl AsRef<Path>) -> Result<Option<Vec<u8>>, Infallible> {
+            Ok(self.0.lock().unwrap().get(path.as_ref()).cloned())
+        }
+
+        fn remove_data(&self, path: impl AsRef<Path>) -> Result<(), Infallible> {
+            self.0.lock().unwrap().remove(path.as_ref());
+            Ok(())
+        }
+    }
+
+    fn open_queue(platform: &MemorySealing, key: &sr25519::Pair) -> MessageSendQueue {
+        let log = SealedMqLog::open(platform.clone(), "sealing", key.public());
+        let snapshot = log.snapshot();
+        MessageSendQueue::with_log(Arc::new(log), snapshot)
+    }
+
+    #[test]
+    fn restarted_queue_resumes_at_the_logged_sequence() {
+        let platform = MemorySealing::default();
+        let key = sr25519::Pair::from_seed(&[1; 32]);
+        let sender = MessageOrigin::Worker(key.public());
+        let send = |queue: &MessageSendQueue, n: u32| {
+            let channel = queue.channel(sender.clone(), key.clone());
+            for i in 0..n {
+                channel.sendto(&i, &b"topic"[..]);
+            }
+        };
+        let sequences = |queue: &MessageSendQueue| -> Vec<u64> {
+            let messages = queue.messages(&sender);
+            messages.iter().map(|message| message.sequence).collect()
+        };
+
+        let queue = open_queue(&platform, &key);
+        send(&queue, 3);
+        // The first message is accepted on chain
+        queue.purge(|_| 1);
+        queue.end_block(1, 5).unwrap();
+        // Not committed
+        send(&queue, 1);
+
+        // Restart
+        let queue = open_queue(&platform, &key);
+        assert_eq!(sequences(&queue), vec![1, 2]);
+
+        // The messages enqueued again while replaying the blocks are skipped
+        send(&queue, 3);
+        assert_eq!(sequences(&queue), vec![1, 2]);
+        queue.end_block(1, 5).unwrap();
+        send(&queue, 1);
+        assert_eq!(sequences(&queue), vec![1, 2, 3]);
+        queue.end_block(2, 0).unwrap();
+
+        // A replay enqueuing fewer messages doesn't swallow the ones of the later blocks
+        let queue = open_queue(&platform, &key);
+        send(&queue, 1);
+        queue.end_block(2, 0).unwrap();
+        send(&queue, 1);
+        assert_eq!(sequences(&queue), vec![1, 2, 3, 4]);
+
+        // The log of another identity is discarded
+        let other = sr25519::Pair::from_seed(&[2; 32]);
+        assert_eq!(open_queue(&platform, &other).count_messages(), 0);
+    }
+
+    #[test]
+    fn segments_are_sealed_for_the_changes_and_compacted() {
+        let platform = MemorySealing::default();
+        let key = sr25519::Pair::from_seed(&[1; 32]);
+        let sender = MessageOrigin::Worker(key.public());
+        let queue = open_queue(&platform, &key);
+        let channel = queue.channel(sender, key.clone());
+
+        // The snapshot
+        assert_eq!(platform.len(), 1);
+        channel.sendto(&0u32, &b"topic"[..]);
+        queue.end_block(1, 0).unwrap();
+        assert_eq!(platform.len(), 2);
+        // Untouched
+        queue.end_block(2, 0).unwrap();
+        assert_eq!(platform.len(), 2);
+
+        for block in 3..COMPACTION_SEGMENTS as u32 + 2 {
+            channel.sendto(&block, &b"topic"[..]);
+            queue.end_block(block, 1).unwrap();
+        }
+        // Compacted into the snapshot
+        assert_eq!(platform.len(), 1);
+        let restored = SealedMqLog::open(platform.clone(), "sealing", key.public()).snapshot();
+        assert_eq!(restored.count_messages(), COMPACTION_SEGMENTS as usize);
+        assert_eq!(
+            restored.consumed.map(|index| index.block_number),
+            Some(COMPACTION_SEGMENTS as u32 + 1)
+        );
+    }
+}
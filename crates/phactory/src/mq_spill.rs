@@ -0,0 +1,166 @@
+//! Spills the messages overflowing the bounded ingress queues to the sealed storage.
+//!
+//! Each spilling queue gets a sequence of files, `mq_spill-<key>-<n>.seal`, one per message, read
+//! back in order once the queue drains, and removed once read. The spilled messages are not
+//! restored on restart, for the queues are rebuilt by replaying the chain, which spills them again.
+//!
+//! The files are kept by the host, which could swap, replay or delete them. So each sealed record
+//! carries the key of its queue, its number and the session of the spill, and a record missing or
+//! not matching where it's read from is fatal: the enclave can't go on with the ingress messages
+//! lost.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use parity_scale_codec::{Decode, Encode};
+use phala_mq::{Message, Spill};
+
+use crate::pal::Sealing;
+
+#[derive(Default)]
+struct SpillFiles {
+    /// The number of the oldest spilled message.
+    head: u64,
+    /// The number of the next spilled message.
+    tail: u64,
+}
+
+#[derive(Encode, Decode)]
+struct SpilledRecord {
+    key: Vec<u8>,
+    n: u64,
+    /// The session of the spill, telling apart the files left by a previous run.
+    session: [u8; 32],
+    value: (u64, Message),
+}
+
+pub struct SealedSpill<P> {
+    platform: P,
+    dir: PathBuf,
+    session: [u8; 32],
+    queues: Mutex<BTreeMap<Vec<u8>, SpillFiles>>,
+}
+
+impl<P: Sealing> SealedSpill<P> {
+    pub fn new(platform: P, sealing_path: &str) -> Self {
+        SealedSpill {
+            platform,
+            dir: PathBuf::from(sealing_path),
+            session: crate::generate_random_info(),
+            queues: Default::default(),
+        }
+    }
+
+    fn path(&self, key: &[u8], n: u64) -> PathBuf {
+        self.dir
+            .join(format!("mq_spill-{}-{}.seal", hex::encode(key), n))
+    }
+}
+
+impl<P: Sealing + Send + Sync> Spill<(u64, Message)> for SealedSpill<P> {
+    fn push(&self, key: &[u8], value: (u64, Message)) {
+        let mut queues = self.queues.lock().unwrap();
+        let files = queues.entry(key.to_vec()).or_default();
+        let record = SpilledRecord {
+            key: key.to_vec(),
+            n: files.tail,
+            session: self.session,
+            value,
+        };
+        self.platform
+            .seal_data(self.path(key, files.tail), &record.encode())
+            .expect("Seal spilled message failed");
+        files.tail += 1;
+    }
+
+    fn pop(&self, key: &[u8]) -> Option<(u64, Message)> {
+        let mut queues = self.queues.lock().unwrap();
+        let files = queues.get_mut(key)?;
+        if files.head == files.tail {
+            return None;
+        }
+        let path = self.path(key, files.head);
+        let data = match self.platform.unseal_data(&path) {
+            Ok(Some(data)) => data,
+            Ok(None) => panic!("Spilled message {:?} is missing", path),
+            Err(err) => panic!("Failed to unseal spilled message {:?}: {:?}", path, err),
+        };
+        let record = SpilledRecord::decode(&mut &data[..])
+            .unwrap_or_else(|err| panic!("Failed to decode spilled message {:?}: {:?}", path, err));
+        if record.key != key || record.n != files.head || record.session != self.session {
+            panic!("Spilled message {:?} doesn't match its file", path);
+        }
+        files.head += 1;
+        // Free up the space taken by the popped message
+        if let Err(err) = self.platform.remove_data(&path) {
+            warn!("Failed to remove spilled message {:?}: {:?}", path, err);
+        }
+        Some(record.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mq_log::tests::MemorySealing;
+    use phala_mq::MessageOrigin;
+
+    fn message(n: u64) -> (u64, Message) {
+        let sender = MessageOrigin::Pallet(b"test".to_vec());
+        (n, Message::new(sender, &b"topic"[..], n.encode()))
+    }
+
+    #[test]
+    fn spilled_messages_pop_in_order() {
+        let platform = MemorySealing::default();
+        let spill = SealedSpill::new(platform.clone(), "sealing");
+        for n in 0..3 {
+            spill.push(b"a", message(n));
+        }
+        spill.push(b"b", message(10));
+        assert_eq!(spill.pop(b"a"), Some(message(0)));
+        assert_eq!(spill.pop(b"b"), Some(message(10)));
+        assert_eq!(spill.pop(b"b"), None);
+        assert_eq!(spill.pop(b"a"), Some(message(1)));
+        assert_eq!(spill.pop(b"a"), Some(message(2)));
+        assert_eq!(spill.pop(b"a"), None);
+        // The popped files are removed
+        assert_eq!(platform.len(), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn swapped_messages_are_fatal() {
+        let platform = MemorySealing::default();
+        let spill = SealedSpill::new(platform.clone(), "sealing");
+        spill.push(b"a", message(0));
+        spill.push(b"a", message(1));
+        platform.swap(&spill.path(b"a", 0), &spill.path(b"a", 1));
+        spill.pop(b"a");
+    }
+
+    #[test]
+    #[should_panic]
+    fn replayed_messages_are_fatal() {
+        let platform = MemorySealing::default();
+        let previous = SealedSpill::new(platform.clone(), "sealing");
+        previous.push(b"a", message(0));
+        let path = previous.path(b"a", 0);
+        let stale = platform.unseal_data(&path).unwrap().unwrap();
+        let spill = SealedSpill::new(platform.clone(), "sealing");
+        spill.push(b"a", message(1));
+        platform.seal_data(&path, &stale).unwrap();
+        spill.pop(b"a");
+    }
+
+    #[test]
+    #[should_panic]
+    fn missing_messages_are_fatal() {
+        let platform = MemorySealing::default();
+        let spill = SealedSpill::new(platform.clone(), "sealing");
+        spill.push(b"a", message(0));
+        platform.remove_data(&spill.path(b"a", 0)).unwrap();
+        spill.pop(b"a");
+    }
+}
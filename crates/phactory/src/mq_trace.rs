@@ -0,0 +1,239 @@
+//! An opt-in ring buffer of the hops of the messages received from the chain, to find out where a
+//! message got lost.
+//!
+//! The messages are keyed by the sender and a sequence. The messages synced from off-chain have
+//! their ingress sequence, the one they were signed with. The others are numbered after the last
+//! message received from the sender since the tracing started, even if its trace was dropped, so
+//! their sequences are unique among the traced ones. The hops after the dispatch are matched to
+//! the messages by the local index of the dispatcher, so the messages spilled by a bounded queue
+//! into later blocks are not traced past the dispatch.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Display;
+use std::sync::Mutex;
+
+use chain::pallet_mq::OutboundMessageInfo;
+use phala_mq::{Message, MessageOrigin};
+
+#[derive(Debug, Clone)]
+pub enum Hop {
+    /// Handled on chain, and kept as the dead letter if it failed.
+    OnChain { dead_letter: Option<u64> },
+    /// Routed to the off-chain components by the chain.
+    Received,
+    /// Dispatched to the subscribers of the destination.
+    Dispatched { subscribers: usize },
+    /// Taken by a subscriber, and decrypted if it was.
+    Peeled,
+    /// Failed to be taken by a subscriber.
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct MessageTrace {
+    pub sender: MessageOrigin,
+    pub sequence: u64,
+    pub destination: Vec<u8>,
+    pub hops: Vec<(chain::BlockNumber, Hop)>,
+}
+
+#[derive(Default)]
+struct Tracer {
+    /// The max number of traced messages, 0 if the tracing is disabled.
+    capacity: usize,
+    block_number: chain::BlockNumber,
+    traces: VecDeque<MessageTrace>,
+    /// The keys of the messages dispatched in the current block, by their local index.
+    dispatched: BTreeMap<u64, (MessageOrigin, u64)>,
+    /// The sequence after the last message received from each sender.
+    next_sequences: BTreeMap<MessageOrigin, u64>,
+}
+
+impl Tracer {
+    fn new(capacity: usize) -> Self {
+        Tracer {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    fn begin_block(&mut self, block_number: chain::BlockNumber) {
+        self.block_number = block_number;
+        self.dispatched.clear();
+    }
+
+    fn received(&mut self, index: u64, message: &Message, info: Option<&OutboundMessageInfo>) {
+        let next_sequence = self
+            .next_sequences
+            .entry(message.sender.clone())
+            .or_default();
+        let sequence = info
+            .and_then(|info| info.sequence)
+            .unwrap_or(*next_sequence);
+        *next_sequence = sequence + 1;
+        let mut hops = Vec::new();
+        if let Some(info) = info {
+            let dead_letter = info.dead_letter;
+            hops.push((self.block_number, Hop::OnChain { dead_letter }));
+        }
+        hops.push((self.block_number, Hop::Received));
+        if self.traces.len() == self.capacity {
+            self.traces.pop_front();
+        }
+        self.traces.push_back(MessageTrace {
+            sender: message.sender.clone(),
+            sequence,
+            destination: message.destination.path().clone(),
+            hops,
+        });
+        self.dispatched
+            .insert(index, (message.sender.clone(), sequence));
+    }
+
+    fn add_hop(&mut self, index: u64, hop: Hop) {
+        let (sender, sequence) = match self.dispatched.get(&index) {
+            Some(key) => key,
+            None => return,
+        };
+        let block_number = self.block_number;
+        let trace = self
+            .traces
+            .iter_mut()
+            .rev()
+            .find(|trace| &trace.sender == sender && trace.sequence == *sequence);
+        if let Some(trace) = trace {
+            trace.hops.push((block_number, hop));
+        }
+    }
+
+    fn traces(&self, sender: &MessageOrigin, sequence: Option<u64>) -> Vec<MessageTrace> {
+        self.traces
+            .iter()
+            .filter(|trace| &trace.sender == sender)
+            .filter(|trace| sequence.map_or(true, |sequence| trace.sequence == sequence))
+            .cloned()
+            .collect()
+    }
+}
+
+lazy_static! {
+    static ref TRACER: Mutex<Tracer> = Default::default();
+}
+
+fn with_tracer(f: impl FnOnce(&mut Tracer)) {
+    let mut tracer = TRACER.lock().unwrap();
+    if tracer.capacity > 0 {
+        f(&mut tracer);
+    }
+}
+
+/// Starts to trace up to `capacity` messages, dropping the oldest ones. Disables the tracing if 0.
+pub fn set_capacity(capacity: usize) {
+    *TRACER.lock().unwrap() = Tracer::new(capacity);
+}
+
+/// Starts to trace the messages of a block, dispatched with the local indexes from 0.
+pub fn begin_block(block_number: chain::BlockNumber) {
+    with_tracer(|tracer| tracer.begin_block(block_number))
+}
+
+pub fn received(index: u64, message: &Message, info: Option<&OutboundMessageInfo>) {
+    with_tracer(|tracer| tracer.received(index, message, info))
+}
+
+pub fn dispatched(index: u64, subscribers: usize) {
+    with_tracer(|tracer| tracer.add_hop(index, Hop::Dispatched { subscribers }))
+}
+
+pub fn peeled(index: u64) {
+    with_tracer(|tracer| tracer.add_hop(index, Hop::Peeled))
+}
+
+pub fn failed(index: u64, reason: impl Display) {
+    with_tracer(|tracer| tracer.add_hop(index, Hop::Failed(reason.to_string())))
+}
+
+/// The traced messages of the sender, the oldest first. None if the tracing is disabled.
+pub fn traces(sender: &MessageOrigin, sequence: Option<u64>) -> Option<Vec<MessageTrace>> {
+    let tracer = TRACER.lock().unwrap();
+    if tracer.capacity == 0 {
+        return None;
+    }
+    Some(tracer.traces(sender, sequence))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hops_are_traced_by_sender_and_sequence() {
+        let sender = MessageOrigin::Gatekeeper;
+        let message = Message::new(sender.clone(), b"topic".to_vec(), vec![]);
+        let mut tracer = Tracer::new(2);
+        tracer.begin_block(1);
+        for index in 0..3 {
+            tracer.received(index, &message, None);
+            tracer.add_hop(index, Hop::Dispatched { subscribers: 1 });
+        }
+        tracer.add_hop(1, Hop::Peeled);
+        tracer.add_hop(2, Hop::Failed("decrypt failed".into()));
+
+        // The oldest message was dropped
+        let all = tracer.traces(&sender, None);
+        let sequences: Vec<_> = all.iter().map(|trace| trace.sequence).collect();
+        assert_eq!(sequences, vec![1, 2]);
+        assert!(matches!(
+            all[0].hops[..],
+            [
+                (1, Hop::Received),
+                (1, Hop::Dispatched { subscribers: 1 }),
+                (1, Hop::Peeled)
+            ]
+        ));
+        assert!(matches!(&all[1].hops[2], (1, Hop::Failed(reason)) if reason == "decrypt failed"));
+        assert_eq!(tracer.traces(&sender, Some(2)).len(), 1);
+
+        // The hops of the messages of the last block are not matched any more
+        tracer.begin_block(2);
+        tracer.add_hop(1, Hop::Peeled);
+        assert_eq!(tracer.traces(&sender, Some(1))[0].hops.len(), 3);
+    }
+
+    #[test]
+    fn synced_messages_are_traced_by_their_ingress_sequence() {
+        let sender = MessageOrigin::Gatekeeper;
+        let message = Message::new(sender.clone(), b"topic".to_vec(), vec![]);
+        let mut tracer = Tracer::new(2);
+        tracer.begin_block(1);
+        let info = OutboundMessageInfo {
+            sequence: Some(7),
+            dead_letter: Some(3),
+        };
+        tracer.received(0, &message, Some(&info));
+        let traces = tracer.traces(&sender, Some(7));
+        let hops = &traces[0].hops;
+        assert!(matches!(hops[0], (1, Hop::OnChain { dead_letter }) if dead_letter == Some(3)));
+        assert!(matches!(hops[1], (1, Hop::Received)));
+        // The messages without one are numbered after the last traced one
+        tracer.received(1, &message, None);
+        assert_eq!(tracer.traces(&sender, Some(8)).len(), 1);
+    }
+
+    #[test]
+    fn dropped_traces_keep_their_sequences() {
+        let sender = MessageOrigin::Gatekeeper;
+        let message = Message::new(sender.clone(), b"topic".to_vec(), vec![]);
+        let pallet = MessageOrigin::Pallet(b"pallet".to_vec());
+        let other = Message::new(pallet, b"topic".to_vec(), vec![]);
+        let mut tracer = Tracer::new(1);
+        tracer.begin_block(1);
+        tracer.received(0, &message, None);
+        // Drops the trace of the sender
+        tracer.received(1, &other, None);
+        tracer.received(2, &message, None);
+        let traces = tracer.traces(&sender, None);
+        assert_eq!(traces.len(), 1);
+        assert_eq!(traces[0].sequence, 1);
+    }
+}
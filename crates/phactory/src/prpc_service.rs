@@ -8,9 +8,8 @@ use pb::{
 use phactory_api::{blocks, crypto, prpc as pb};
 use phala_types::{contract, WorkerPublicKey,
                   messaging::{CoordinateInfo, GeolocationCommand}};
-use crate::secret_channel::{
-    PeelingReceiver, SecretMessageChannel,
-};
+use crate::secret_channel::SecretMessageChannel;
+use std::sync::Arc;
 
 type RpcResult<T> = Result<T, RpcError>;
 
@@ -24,6 +23,12 @@ fn from_debug(e: impl core::fmt::Debug) -> RpcError {
 
 pub const VERSION: u32 = 1;
 
+/// The max number of queries in a `BatchContractQuery` call.
+const MAX_BATCH_QUERIES: usize = 64;
+
+/// The max number of events streamed in response to a `SubscribeContractEvents` call.
+const MAX_EVENTS_PER_RESPONSE: usize = 64;
+
 fn now() -> u64 {
     use std::time::SystemTime;
     let now = SystemTime::now()
@@ -32,10 +37,10 @@ fn now() -> u64 {
     now.as_secs()
 }
 
-// Drop latest messages if needed to fit in size.
+// Drop latest messages of the lowest priority if needed to fit in size.
 fn fit_size(mut messages: pb::EgressMessages, size: usize) -> pb::EgressMessages {
     while messages.encoded_size() > size {
-        for (_, queue) in messages.iter_mut() {
+        for (_, queue) in messages.iter_mut().rev() {
             if queue.pop().is_some() {
                 break;
             }
@@ -69,12 +74,25 @@ impl<Platform: pal::Platform> Phactory<Platform> {
         let ecdh_public_key = state.map(|state| hex::encode(&state.ecdh_key.public()));
         let dev_mode = self.dev_mode;
 
-        let (state_root, pending_messages, counters) = match state.as_ref() {
+        let (state_root, pending_messages, counters, bounded_queues) = match state.as_ref() {
             Some(state) => {
                 let state_root = hex::encode(state.chain_storage.root());
                 let pending_messages = state.send_mq.count_messages();
                 let counters = state.storage_synchronizer.counters();
-                (state_root, pending_messages, counters)
+                let bounded_queues = state
+                    .recv_mq
+                    .topic_stats()
+                    .into_iter()
+                    .map(|topic| pb::MessageQueueStats {
+                        topic: String::from_utf8_lossy(&topic.topic).into(),
+                        subscribers: topic.subscribers,
+                        depth: topic.stats.depth,
+                        dropped: topic.stats.dropped,
+                        rejected: topic.stats.rejected,
+                        spilled: topic.stats.spilled,
+                    })
+                    .collect();
+                (state_root, pending_messages, counters, bounded_queues)
             }
             None => Default::default(),
         };
@@ -117,6 +135,7 @@ impl<Platform: pal::Platform> Phactory<Platform> {
                 rust_peak_used: m_usage.rust_peak_used as _,
                 total_peak_used: m_usage.total_peak_used as _,
             }),
+            bounded_queues,
         }
     }
 
@@ -222,8 +241,12 @@ impl<Platform: pal::Platform> Phactory<Platform> {
                 .map_err(from_display)?;
 
             state.purge_mq();
-            self.handle_inbound_messages(block.block_header.number)?;
+            let consumed = self.handle_inbound_messages(block.block_header.number)?;
             self.poll_side_tasks(block.block_header.number)?;
+            self.runtime_state()?
+                .send_mq
+                .end_block(block.block_header.number, consumed)
+                .map_err(from_debug)?;
             last_block = block.block_header.number;
         }
 
@@ -319,78 +342,43 @@ impl<Platform: pal::Platform> Phactory<Platform> {
         };
 
         let id_pair = identity_key.clone();
-        let send_mq = MessageSendQueue::default();
-        let mut recv_mq = MessageDispatcher::default();
-
-        let mut contracts: BTreeMap<ContractId, Box<dyn contracts::Contract + Send>> =
-            Default::default();
-
-        if self.dev_mode {
-            // Install contracts when running in dev_mode.
-            info!("Install contracts in dev mode");
-
-            macro_rules! install_contract {
-                ($id: expr, $inner: expr) => {{
-                    let contract_id = contract::id256($id);
-                    let sender = MessageOrigin::native_contract($id);
-                    let mq = send_mq.channel(sender, id_pair.clone());
-                    // TODO.kevin: use real contract key
-                    let contract_key = ecdh_key.clone();
-                    let cmd_mq = PeelingReceiver::new_secret(
-                        recv_mq
-                            .subscribe(contract::command_topic(contract_id))
-                            .into(),
-                        contract_key,
-                    );
-                    let wrapped = Box::new(contracts::NativeCompatContract::new(
-                        $inner,
-                        mq,
-                        cmd_mq,
-                        ecdh_key.clone(),
-                    ));
-                    contracts.insert(contract_id, wrapped);
-                }};
-            }
-
-            install_contract!(contracts::BALANCES, contracts::balances::Balances::new());
-            install_contract!(contracts::ASSETS, contracts::assets::Assets::new());
-            // TODO.kevin:
-            // install_contract!(contracts::DIEM, contracts::diem::Diem::new());
-            install_contract!(
-                contracts::SUBSTRATE_KITTIES,
-                contracts::substrate_kitties::SubstrateKitties::new()
-            );
-            install_contract!(
-                contracts::BTC_LOTTERY,
-                contracts::btc_lottery::BtcLottery::new(Some(id_pair.clone()))
-            );
-            // TODO.kevin: This is temporaryly disabled due to the dependency on CPUID which is not allowed in SGX.
-            // install_contract!(
-            //     contracts::WEB3_ANALYTICS,
-            //     contracts::web3analytics::Web3Analytics::new()
-            // );
-            install_contract!(
-                contracts::DATA_PLAZA,
-                contracts::data_plaza::DataPlaza::new()
+        let send_mq = if self.args.persist_mq {
+            let log = mq_log::SealedMqLog::open(
+                self.platform.clone(),
+                &self.args.sealing_path,
+                identity_key.public(),
             );
-            install_contract!(
-                contracts::GEOLOCATION,
-                contracts::geolocation::Geolocation::new()
+            let snapshot = log.snapshot();
+            info!(
+                "Restored {} egress messages from the mq log",
+                snapshot.count_messages()
             );
+            MessageSendQueue::with_log(Arc::new(log), snapshot)
+        } else {
+            MessageSendQueue::default()
+        };
+        let mut recv_mq = MessageDispatcher::default();
+        recv_mq.set_spill(Arc::new(mq_spill::SealedSpill::new(
+            self.platform.clone(),
+            &self.args.sealing_path,
+        )));
+        mq_trace::set_capacity(self.args.trace_messages as usize);
 
-            install_contract!(
-                contracts::GUESS_NUMBER,
-                contracts::guess_number::GuessNumber::new()
-            );
+        let contracts: contracts::ContractMap = Default::default();
 
-            install_contract!(
-                contracts::BTC_PRICE_BOT,
-                contracts::btc_price_bot::BtcPriceBot::new()
-            );
-        }
+        let selected_contracts =
+            contracts::registry::resolve(&self.args.native_contracts, self.dev_mode)
+                .map_err(from_display)?;
+        let query_budgets = contracts::query_budget::QueryBudgets::parse(&self.args.query_budgets)
+            .map_err(from_display)?;
 
         let mut runtime_state = RuntimeState {
             contracts,
+            selected_contracts,
+            pending_deployments: Default::default(),
+            held_commands: Default::default(),
+            query_budgets,
+            query_nonces: Default::default(),
             send_mq,
             recv_mq,
             storage_synchronizer,
@@ -479,11 +467,14 @@ impl<Platform: pal::Platform> Phactory<Platform> {
     }
 
     fn get_egress_messages(&mut self, output_buf_len: usize) -> RpcResult<pb::EgressMessages> {
-        let messages: Vec<_> = self
-            .runtime_state
-            .as_ref()
-            .map(|state| state.send_mq.all_messages_grouped().into_iter().collect())
-            .unwrap_or_default();
+        let messages: Vec<_> = match &self.runtime_state {
+            Some(state) => {
+                // The messages must be durable before they leave
+                state.send_mq.commit_log().map_err(from_debug)?;
+                state.send_mq.all_messages_prioritized()
+            }
+            None => Vec::new(),
+        };
         // Prune messages if needed to avoid the OUTPUT BUFFER overflow.
         Ok(fit_size(messages, output_buf_len))
     }
@@ -529,15 +520,17 @@ impl<Platform: pal::Platform> Phactory<Platform> {
         Ok(())
     }
 
-    fn contract_query(
-        &mut self,
-        request: pb::ContractQueryRequest,
-    ) -> RpcResult<pb::ContractQueryResponse> {
+    /// Verifies the signature of a client request, returning the account signed it, if any.
+    fn verify_origin(
+        &self,
+        signature: Option<&pb::Signature>,
+        encoded_encrypted_data: &[u8],
+    ) -> RpcResult<Option<chain::AccountId>> {
         // Validate signature
-        let origin = if let Some(sig) = &request.signature {
-            let current_block = self.get_info().blocknum - 1;
+        let origin = if let Some(sig) = signature {
+            let current_block = self.get_info().blocknum.saturating_sub(1);
             // At most two level cert chain supported
-            match sig.verify(&request.encoded_encrypted_data, current_block, 2) {
+            match sig.verify(encoded_encrypted_data, current_block, 2) {
                 Ok(key_chain) => match &key_chain[..] {
                     [root_pubkey, ..] => Some(root_pubkey.clone()),
                     _ => {
@@ -555,17 +548,6 @@ impl<Platform: pal::Platform> Phactory<Platform> {
 
         info!("Verifying signature passed! origin={:?}", origin);
 
-        let ecdh_key = self.runtime_state()?.ecdh_key.clone();
-
-        // Decrypt data
-        let encrypted_req = request.decode_encrypted_data()?;
-        let data = encrypted_req.decrypt(&ecdh_key).map_err(from_debug)?;
-
-        // Decode head
-        let mut data_cursor = &data[..];
-        let head = contract::ContractQueryHead::decode(&mut data_cursor)?;
-        let data_cursor = data_cursor;
-
         // Origin
         let accid_origin = match origin {
             Some(origin) => {
@@ -576,21 +558,157 @@ impl<Platform: pal::Platform> Phactory<Platform> {
             }
             None => None,
         };
+        Ok(accid_origin)
+    }
+
+    fn contract_query(
+        &mut self,
+        request: pb::ContractQueryRequest,
+    ) -> RpcResult<pb::ContractQueryResponse> {
+        let accid_origin =
+            self.verify_origin(request.signature.as_ref(), &request.encoded_encrypted_data)?;
+        let encrypted_resp = self.handle_encrypted_query(
+            accid_origin.as_ref(),
+            &request.contract_id,
+            request.head_version,
+            request.decode_encrypted_data()?,
+        )?;
+        Ok(pb::ContractQueryResponse::new(encrypted_resp))
+    }
+
+    fn batch_contract_query(
+        &mut self,
+        request: pb::BatchContractQueryRequest,
+    ) -> RpcResult<pb::BatchContractQueryResponse> {
+        let block_number = self.get_info().blocknum.saturating_sub(1);
+        if request.after_block > 0 && block_number <= request.after_block {
+            return Err(from_display(format!(
+                "Not synced past block {} yet",
+                request.after_block
+            )));
+        }
+        let accid_origin = self.verify_origin(
+            request.signature.as_ref(),
+            &request.encoded_encrypted_queries,
+        )?;
+        let queries = request.decode_encrypted_queries()?;
+        if queries.len() > MAX_BATCH_QUERIES {
+            return Err(from_display("Too many queries"));
+        }
+        if !request.contract_ids.is_empty() && request.contract_ids.len() != queries.len() {
+            return Err(from_display("Contract ids mismatch the queries"));
+        }
+        let mut results = Vec::with_capacity(queries.len());
+        for (index, query) in queries.into_iter().enumerate() {
+            let contract_id = request
+                .contract_ids
+                .get(index)
+                .map(|id| &id[..])
+                .unwrap_or_default();
+            let result = self.handle_encrypted_query(
+                accid_origin.as_ref(),
+                contract_id,
+                request.head_version,
+                query,
+            );
+            let result = match result {
+                Ok(encrypted_resp) => pb::ContractQueryResult::new(encrypted_resp, String::new()),
+                Err(err) => pb::ContractQueryResult {
+                    encoded_encrypted_data: Vec::new(),
+                    error: err.to_string(),
+                },
+            };
+            results.push(result);
+        }
+        Ok(pb::BatchContractQueryResponse {
+            results,
+            block_number,
+        })
+    }
+
+    /// Decrypts a `ContractQuery`, dispatches it to the contract and encrypts the response.
+    ///
+    /// The query is decrypted with the key of the given contract, or with the worker key if no
+    /// contract is given, which only the system contract accepts.
+    fn handle_encrypted_query(
+        &mut self,
+        accid_origin: Option<&chain::AccountId>,
+        contract_id: &[u8],
+        head_version: u32,
+        encrypted_req: crypto::EncryptedData,
+    ) -> RpcResult<crypto::EncryptedData> {
+        let target = if contract_id.is_empty() {
+            None
+        } else if contract_id.len() == 32 {
+            Some(contract::ContractId::from_slice(contract_id))
+        } else {
+            return Err(from_display("Bad contract id"));
+        };
+        let ecdh_key = match &target {
+            Some(id) => self
+                .system
+                .as_ref()
+                .ok_or_else(|| from_display("Runtime not initialized"))?
+                .contract_ecdh_key(id)
+                .ok_or_else(|| from_display("Contract key not available"))?,
+            None => self.runtime_state()?.ecdh_key.clone(),
+        };
+
+        // Decrypt data
+        let data = encrypted_req.decrypt(&ecdh_key).map_err(from_debug)?;
+
+        // Decode head
+        let mut data_cursor = &data[..];
+        let (head, valid_until_block) =
+            contract::ContractQueryHead::decode_versioned(head_version, &mut data_cursor)?;
+        let data_cursor = data_cursor;
+
+        // The query must target the contract it is encrypted to
+        let expected = target.unwrap_or_else(|| contract::id256(SYSTEM));
+        if head.id != expected {
+            return Err(from_display("Query not encrypted to the contract key"));
+        }
+
+        // Reject stale and replayed queries
+        let state = self.runtime_state()?;
+        let current_block = state
+            .storage_synchronizer
+            .counters()
+            .next_block_number
+            .saturating_sub(1);
+        state.query_nonces.check_query(
+            accid_origin,
+            head.nonce,
+            valid_until_block,
+            current_block,
+        )?;
 
         // Dispatch
-        let ref_origin = accid_origin.as_ref();
+        let ref_origin = accid_origin;
 
         let res = if head.id == contract::id256(SYSTEM) {
-            let system = self.system()?;
-            let response = system.handle_query(ref_origin, types::deopaque_query(data_cursor)?);
+            let state = self
+                .runtime_state
+                .as_ref()
+                .ok_or_else(|| from_display("Runtime not initialized"))?;
+            let system = self
+                .system
+                .as_mut()
+                .ok_or_else(|| from_display("Runtime not initialized"))?;
+            let response = system.handle_query(
+                ref_origin,
+                &state.contracts,
+                types::deopaque_query(data_cursor)?,
+            );
             response.encode()
         } else {
             let state = self.runtime_state()?;
+            let budget = state.query_budgets.get(&head.id);
             let contract = state
                 .contracts
-                .get_mut(&head.id)
+                .get(&head.id)
                 .ok_or_else(|| from_display("Contract not found"))?;
-            contract.handle_query(ref_origin, data_cursor)?
+            contracts::query_budget::run(budget, || contract.handle_query(ref_origin, data_cursor))?
         };
 
         // Encode response
@@ -609,7 +727,41 @@ impl<Platform: pal::Platform> Phactory<Platform> {
         )
         .map_err(from_debug)?;
 
-        Ok(pb::ContractQueryResponse::new(encrypted_resp))
+        Ok(encrypted_resp)
+    }
+
+    fn subscribe_contract_events(
+        &mut self,
+        request: pb::ContractEventsRequest,
+    ) -> RpcResult<Vec<pb::ContractEvent>> {
+        let subscriber =
+            self.verify_origin(request.signature.as_ref(), &request.encoded_encrypted_data)?;
+
+        let state = self.runtime_state()?;
+        let ecdh_key = state.ecdh_key.clone();
+        let encrypted_req = request.decode_encrypted_data()?;
+        let data = encrypted_req.decrypt(&ecdh_key).map_err(from_debug)?;
+        let subscription = contract::ContractEventsSubscription::decode(&mut &data[..])?;
+
+        let contract = state
+            .contracts
+            .get(&subscription.contract)
+            .ok_or_else(|| from_display("Contract not found"))?;
+        contract
+            .events()
+            .since(subscription.from_sequence, subscriber.as_ref())
+            .take(MAX_EVENTS_PER_RESPONSE)
+            .map(|event| {
+                let encrypted = crypto::EncryptedData::encrypt(
+                    &ecdh_key,
+                    &encrypted_req.pubkey,
+                    crate::generate_random_iv(),
+                    &event.encode(),
+                )
+                .map_err(from_debug)?;
+                Ok(pb::ContractEvent::new(encrypted))
+            })
+            .collect()
     }
 
     #[allow(unused_unsafe)]
@@ -657,7 +809,9 @@ impl<Platform: pal::Platform> Phactory<Platform> {
         (code, data)
     }
 
-    fn handle_inbound_messages(&mut self, block_number: chain::BlockNumber) -> RpcResult<()> {
+    /// Dispatches the ingress messages of the block, returning the number of them.
+    fn handle_inbound_messages(&mut self, block_number: chain::BlockNumber) -> RpcResult<u64> {
+        let dev_mode = self.dev_mode;
         let state = self
             .runtime_state
             .as_mut()
@@ -672,10 +826,40 @@ impl<Platform: pal::Platform> Phactory<Platform> {
             .chain_storage
             .mq_messages()
             .map_err(|_| from_display("Can not get mq messages from storage"))?;
+        let infos = state
+            .chain_storage
+            .mq_message_infos()
+            .map_err(|_| from_display("Can not get mq message infos from storage"))?;
+
+        // Hold the commands of the enabled native contracts from now on, and uninstall the ones
+        // disabled on chain.
+        for entry in contracts::registry::NATIVE_CONTRACTS {
+            let selected = state.selected_contracts.iter().any(|e| e.id == entry.id);
+            let enabled = crate::system::chain_state::read_native_contract_config(
+                entry.id,
+                &state.chain_storage,
+            )
+            .unwrap_or(selected);
+            let id = contract::id256(entry.id);
+            if !enabled {
+                if state.contracts.remove(&id).is_some() {
+                    info!("Uninstalling native contract {}({})", entry.name, entry.id);
+                }
+                state.held_commands.remove(&id);
+            } else if !state.contracts.contains_key(&id) && !state.held_commands.contains_key(&id) {
+                let held_commands =
+                    contracts::registry::HeldCommands::subscribe(&mut state.recv_mq, id);
+                state.held_commands.insert(id, held_commands);
+            }
+        }
 
         state.recv_mq.reset_local_index();
+        mq_trace::begin_block(block_number);
 
-        for message in messages {
+        // The local indexes of the messages start from 0 after the reset
+        let mut consumed = 0;
+        for (index, message) in (0u64..).zip(messages) {
+            consumed = index + 1;
             use phala_types::messaging::SystemEvent;
             macro_rules! log_message {
                 ($msg: expr, $t: ident) => {{
@@ -700,7 +884,9 @@ impl<Platform: pal::Platform> Phactory<Platform> {
             } else {
                 info!("mq dispatching message: {:?}", message);
             }
-            state.recv_mq.dispatch(message);
+            mq_trace::received(index, &message, infos.get(index as usize));
+            let subscribers = state.recv_mq.dispatch(message);
+            mq_trace::dispatched(index, subscribers);
         }
 
         let mut guard = scopeguard::guard(&mut state.recv_mq, |mq| {
@@ -731,13 +917,100 @@ impl<Platform: pal::Platform> Phactory<Platform> {
             return Err(from_display("System process events failed"));
         }
 
-        let mut env = ExecuteEnv { block: &mut block };
+        // Install the native contracts once their keys are available, replaying the commands held
+        // for them.
+        for entry in contracts::registry::NATIVE_CONTRACTS {
+            let id = contract::id256(entry.id);
+            let mut held_commands = match state.held_commands.remove(&id) {
+                Some(held_commands) => held_commands,
+                None => continue,
+            };
+            held_commands.hold(block.block_number);
+            let has_gatekeeper =
+                crate::system::chain_state::read_master_pubkey(block.storage).is_some();
+            let contract_key = match system.contract_key(&id) {
+                Some(key) => key,
+                // A dev chain may run without any gatekeeper to distribute the keys
+                None if dev_mode && !has_gatekeeper => {
+                    let key = contracts::registry::derive_contract_key(&state.identity_key, &id);
+                    system.set_dev_contract_key(id, key.clone());
+                    key
+                }
+                None => {
+                    state.held_commands.insert(id, held_commands);
+                    continue;
+                }
+            };
+            info!("Installing native contract {}({})", entry.name, entry.id);
+            let mut ctx = contracts::registry::InstallContext {
+                send_mq: &state.send_mq,
+                recv_mq: &mut *block.recv_mq,
+                identity_key: &state.identity_key,
+                contract_key: &contract_key,
+                held_commands: Some(held_commands),
+            };
+            let contract = (entry.constructor)(&mut ctx);
+            state.contracts.insert(contract.id(), contract);
+        }
 
-        for contract in state.contracts.values_mut() {
-            contract.process_messages(&mut env);
+        // Instantiate the contracts deployed on chain once their keys are available.
+        state.pending_deployments.extend(system.take_deployments());
+        for deployment in std::mem::take(&mut state.pending_deployments) {
+            let contract_key = match system.contract_key(&deployment.contract_id) {
+                Some(key) => key,
+                None => {
+                    state.pending_deployments.push(deployment);
+                    continue;
+                }
+            };
+            let code = match crate::system::chain_state::read_contract_code(
+                &deployment.code_hash,
+                block.storage,
+            ) {
+                Some(code) => code,
+                None => {
+                    error!(
+                        "Code {} of contract {} not found",
+                        hex::encode(&deployment.code_hash),
+                        hex::encode(&deployment.contract_id)
+                    );
+                    continue;
+                }
+            };
+            info!(
+                "Instantiating contract {}",
+                hex::encode(&deployment.contract_id)
+            );
+            let mut ctx = contracts::registry::InstallContext {
+                send_mq: &state.send_mq,
+                recv_mq: &mut *block.recv_mq,
+                identity_key: &state.identity_key,
+                contract_key: &contract_key,
+                held_commands: None,
+            };
+            match contracts::wasm::WasmContract::instantiate(
+                &mut ctx,
+                &deployment,
+                &code,
+                block.block_number,
+                block.now_ms,
+            ) {
+                Ok(contract) => {
+                    state.contracts.insert(contract.id(), Box::new(contract));
+                }
+                Err(err) => {
+                    error!(
+                        "Failed to instantiate contract {}: {:?}",
+                        hex::encode(&deployment.contract_id),
+                        err
+                    );
+                }
+            }
         }
 
-        Ok(())
+        contracts::process_messages(&mut state.contracts, &mut block);
+
+        Ok(consumed)
     }
 
     fn poll_side_tasks(&mut self, block_number: chain::BlockNumber) -> RpcResult<()> {
@@ -830,6 +1103,20 @@ impl<Platform: pal::Platform> PhactoryApi for RpcService<'_, Platform> {
         self.phactory.contract_query(request)
     }
 
+    fn batch_contract_query(
+        &mut self,
+        request: pb::BatchContractQueryRequest,
+    ) -> RpcResult<pb::BatchContractQueryResponse> {
+        self.phactory.batch_contract_query(request)
+    }
+
+    fn subscribe_contract_events(
+        &mut self,
+        request: pb::ContractEventsRequest,
+    ) -> RpcResult<Vec<pb::ContractEvent>> {
+        self.phactory.subscribe_contract_events(request)
+    }
+
     fn get_worker_state(
         &mut self,
         request: pb::GetWorkerStateRequest,
@@ -860,4 +1147,50 @@ impl<Platform: pal::Platform> PhactoryApi for RpcService<'_, Platform> {
             pb::EchoMessage{ echo_msg }
         )
     }
+
+    fn get_message_trace(
+        &mut self,
+        request: pb::GetMessageTraceRequest,
+    ) -> RpcResult<pb::GetMessageTraceResponse> {
+        let sender = request.decode_sender()?;
+        let traces = match mq_trace::traces(&sender, request.sequence) {
+            Some(traces) => traces,
+            None => return Ok(Default::default()),
+        };
+        let traces = traces
+            .into_iter()
+            .map(|trace| pb::MessageTrace {
+                sequence: trace.sequence,
+                destination: String::from_utf8_lossy(&trace.destination).into(),
+                hops: trace
+                    .hops
+                    .into_iter()
+                    .map(|(block_number, hop)| {
+                        use mq_trace::Hop;
+                        let (kind, detail) = match hop {
+                            Hop::OnChain { dead_letter } => (
+                                "on_chain",
+                                dead_letter.map(|id| id.to_string()).unwrap_or_default(),
+                            ),
+                            Hop::Received => ("received", String::new()),
+                            Hop::Dispatched { subscribers } => {
+                                ("dispatched", subscribers.to_string())
+                            }
+                            Hop::Peeled => ("peeled", String::new()),
+                            Hop::Failed(reason) => ("failed", reason),
+                        };
+                        pb::MessageHop {
+                            block_number,
+                            kind: kind.into(),
+                            detail,
+                        }
+                    })
+                    .collect(),
+            })
+            .collect();
+        Ok(pb::GetMessageTraceResponse {
+            enabled: true,
+            traces,
+        })
+    }
 }
@@ -2,22 +2,113 @@ pub use receiver::*;
 pub use sender::*;
 
 use crate::light_validation::utils::storage_map_prefix_blake2_128_concat;
-use phactory_api::crypto::EncryptedData;
+use phactory_api::crypto::{aead, ecdh, EncryptedData};
 use parity_scale_codec::{Decode, Encode};
 
 #[derive(Encode, Decode, Debug)]
 pub enum Payload<T> {
     Plain(T),
     Encrypted(EncryptedData),
+    Group(GroupMessage),
+}
+
+/// A message broadcasted to the members of a group channel.
+#[derive(Encode, Decode, Debug)]
+pub enum GroupMessage {
+    /// The key of a new epoch, encrypted to each member.
+    Key {
+        epoch: u32,
+        keys: Vec<(ecdh::EcdhPublicKey, EncryptedData)>,
+    },
+    /// A message encrypted with the key of the epoch.
+    Data {
+        epoch: u32,
+        iv: aead::IV,
+        data: Vec<u8>,
+    },
 }
 
 mod sender {
-    use phactory_api::crypto::{ecdh, EncryptedData};
+    use super::{GroupMessage, Payload};
+    use phactory_api::crypto::{aead, ecdh, EncryptedData};
     use parity_scale_codec::Encode;
+    use phala_crypto::sr25519::{Persistence, KDF};
     use phala_mq::{BindTopic, Path, Sr25519MessageChannel};
+    use sp_core::sr25519;
+    use std::collections::BTreeSet;
 
     pub type KeyPair = ecdh::EcdhKey;
 
+    /// The sending side of a group channel, which broadcasts a message to all the members at once.
+    ///
+    /// The messages are encrypted with a group key, which is encrypted to each member and sent
+    /// ahead of the first message of its epoch. The key is rotated on every membership change, so
+    /// the members removed can't read the later messages, nor the members added the earlier ones.
+    ///
+    /// The key of an epoch is derived from the key of the sender, the topic and the epoch, so all
+    /// the instances of a contract agree on it. A topic shouldn't be reused by another group.
+    pub struct Group {
+        topic: Path,
+        epoch: u32,
+        members: BTreeSet<ecdh::EcdhPublicKey>,
+        /// Whether the key of the epoch is yet to be sent to the members.
+        key_pending: bool,
+    }
+
+    impl Group {
+        pub fn new(topic: impl Into<Path>) -> Self {
+            Group {
+                topic: topic.into(),
+                epoch: 0,
+                members: Default::default(),
+                key_pending: true,
+            }
+        }
+
+        pub fn topic(&self) -> &Path {
+            &self.topic
+        }
+
+        pub fn epoch(&self) -> u32 {
+            self.epoch
+        }
+
+        pub fn members(&self) -> impl Iterator<Item = &ecdh::EcdhPublicKey> {
+            self.members.iter()
+        }
+
+        pub fn add_member(&mut self, pubkey: ecdh::EcdhPublicKey) {
+            if self.members.insert(pubkey) {
+                self.rotate();
+            }
+        }
+
+        pub fn remove_member(&mut self, pubkey: &ecdh::EcdhPublicKey) {
+            if self.members.remove(pubkey) {
+                self.rotate();
+            }
+        }
+
+        /// Moves to a new epoch with a new key, sent to the members ahead of the next message.
+        pub fn rotate(&mut self) {
+            self.epoch += 1;
+            self.key_pending = true;
+        }
+
+        /// Derives the key of the current epoch from the key of the sender.
+        fn derive_key(&self, sender_key: &KeyPair) -> [u8; 32] {
+            let root = sr25519::Pair::restore_from_secret_key(&sender_key.secret());
+            let epoch = self.epoch.to_be_bytes();
+            let info: &[&[u8]] = &[b"group_key", &self.topic, &epoch];
+            let derived = root
+                .derive_sr25519_pair(info)
+                .expect("Derive group key failed?");
+            let mut key = [0u8; 32];
+            key.copy_from_slice(&derived.dump_secret_key()[..32]);
+            key
+        }
+    }
+
     #[allow(unused)] // TODO.kevin: remove this.
     pub struct SecretMessageChannel<'a> {
         key: &'a KeyPair,
@@ -64,20 +155,77 @@ mod sender {
         ) {
             self.sendto(<M as BindTopic>::topic(), message, remote_pubkey)
         }
+
+        /// Broadcasts the message to the members of the group, sending them the key of the epoch
+        /// first if it's not sent yet.
+        pub fn send_to_group<M: Encode>(&self, group: &mut Group, message: &M) {
+            let group_key = group.derive_key(self.key);
+            if group.key_pending {
+                let keys = group
+                    .members
+                    .iter()
+                    .map(|member| {
+                        let iv = crate::generate_random_iv();
+                        let key = EncryptedData::encrypt(self.key, member, iv, &group_key)
+                            .expect("Encrypt group key failed?");
+                        (*member, key)
+                    })
+                    .collect();
+                let payload = Payload::<M>::Group(GroupMessage::Key {
+                    epoch: group.epoch,
+                    keys,
+                });
+                self.mq.send_data(payload.encode(), group.topic.clone());
+                group.key_pending = false;
+            }
+            let iv = crate::generate_random_iv();
+            let mut data = message.encode();
+            aead::encrypt(&iv, &group_key, &mut data).expect("Encrypt message failed?");
+            let payload = Payload::<M>::Group(GroupMessage::Data {
+                epoch: group.epoch,
+                iv,
+                data,
+            });
+            self.mq.send_data(payload.encode(), group.topic.clone())
+        }
     }
 }
 
 mod receiver {
-    use super::Payload;
+    use super::{GroupMessage, Payload};
+    use core::fmt;
     use core::marker::PhantomData;
-    use phactory_api::crypto::ecdh;
+    use phactory_api::crypto::{aead, ecdh};
     use parity_scale_codec::Decode;
-    use phala_mq::{MessageOrigin, ReceiveError, TypedReceiver};
+    use phala_mq::{Message, MessageOrigin, ReceiveError, TypedReceiver};
+    use std::collections::VecDeque;
+
+    /// A message taken from the queue but failed to be decoded or peeled.
+    ///
+    /// It's returned in an `anyhow::Error` by `PeelingReceiver::try_next`, so that the sender of
+    /// the message can be told about the failure.
+    #[derive(Debug)]
+    pub struct BadMessage {
+        pub origin: MessageOrigin,
+        /// The index of the message among the messages dispatched in its block.
+        pub sequence: u64,
+        pub error: anyhow::Error,
+    }
+
+    impl fmt::Display for BadMessage {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "Bad message from {:?}: {}", self.origin, self.error)
+        }
+    }
+
+    impl std::error::Error for BadMessage {}
 
     pub trait Peeler {
         type Wrp;
         type Msg;
-        fn peel(&self, msg: Self::Wrp) -> Result<Self::Msg, anyhow::Error>;
+        /// Peels the message, or returns None if it only updates the peeler, e.g. with a group
+        /// key.
+        fn peel(&mut self, msg: Self::Wrp) -> Result<Option<Self::Msg>, anyhow::Error>;
     }
 
     pub struct PlainPeeler<T>(PhantomData<T>);
@@ -85,8 +233,8 @@ mod receiver {
     impl<T> Peeler for PlainPeeler<T> {
         type Wrp = T;
         type Msg = T;
-        fn peel(&self, msg: Self::Wrp) -> Result<Self::Msg, anyhow::Error> {
-            Ok(msg)
+        fn peel(&mut self, msg: Self::Wrp) -> Result<Option<Self::Msg>, anyhow::Error> {
+            Ok(Some(msg))
         }
     }
 
@@ -107,16 +255,74 @@ mod receiver {
     impl<T: Decode> Peeler for SecretPeeler<T> {
         type Wrp = Payload<T>;
         type Msg = T;
-        fn peel(&self, msg: Self::Wrp) -> Result<Self::Msg, anyhow::Error> {
+        fn peel(&mut self, msg: Self::Wrp) -> Result<Option<Self::Msg>, anyhow::Error> {
             match msg {
-                Payload::Plain(msg) => Ok(msg),
+                Payload::Plain(msg) => Ok(Some(msg)),
                 Payload::Encrypted(msg) => {
                     let data = msg.decrypt(&self.ecdh_key).map_err(|err| {
                         anyhow::anyhow!("SecretPeeler decrypt message failed: {:?}", err)
                     })?;
                     let msg = Decode::decode(&mut &data[..])
                         .map_err(|_| anyhow::anyhow!("SCALE decode decrypted data failed"))?;
-                    Ok(msg)
+                    Ok(Some(msg))
+                }
+                Payload::Group(_) => Err(anyhow::anyhow!("SecretPeeler got a group message")),
+            }
+        }
+    }
+
+    /// Peels the messages of a group channel, as well as the ones encrypted to the member alone.
+    pub struct GroupPeeler<T> {
+        secret: SecretPeeler<T>,
+        /// The epoch and the key of the group, if the member is in the group.
+        key: Option<(u32, Vec<u8>)>,
+    }
+
+    impl<T> GroupPeeler<T> {
+        pub fn new(ecdh_key: ecdh::EcdhKey) -> Self {
+            GroupPeeler {
+                secret: SecretPeeler::new(ecdh_key),
+                key: None,
+            }
+        }
+    }
+
+    impl<T: Decode> Peeler for GroupPeeler<T> {
+        type Wrp = Payload<T>;
+        type Msg = T;
+        fn peel(&mut self, msg: Self::Wrp) -> Result<Option<Self::Msg>, anyhow::Error> {
+            let msg = match msg {
+                Payload::Group(msg) => msg,
+                msg => return self.secret.peel(msg),
+            };
+            match msg {
+                GroupMessage::Key { epoch, keys } => {
+                    // No key for the members removed
+                    self.key = None;
+                    let pubkey = self.secret.ecdh_key.public();
+                    if let Some((_, key)) = keys.iter().find(|(member, _)| member == &pubkey) {
+                        let key = key.decrypt(&self.secret.ecdh_key).map_err(|err| {
+                            anyhow::anyhow!("GroupPeeler decrypt group key failed: {:?}", err)
+                        })?;
+                        self.key = Some((epoch, key));
+                    }
+                    Ok(None)
+                }
+                GroupMessage::Data {
+                    epoch,
+                    iv,
+                    mut data,
+                } => {
+                    let key = match &self.key {
+                        Some((key_epoch, key)) if *key_epoch == epoch => key,
+                        _ => return Err(anyhow::anyhow!("No group key for epoch {}", epoch)),
+                    };
+                    let data = aead::decrypt(&iv, key, &mut data).map_err(|err| {
+                        anyhow::anyhow!("GroupPeeler decrypt message failed: {:?}", err)
+                    })?;
+                    let msg = Decode::decode(&mut &data[..])
+                        .map_err(|_| anyhow::anyhow!("SCALE decode decrypted data failed"))?;
+                    Ok(Some(msg))
                 }
             }
         }
@@ -124,15 +330,35 @@ mod receiver {
 
     pub struct PeelingReceiver<Msg, Wrp, Plr> {
         receiver: TypedReceiver<Wrp>,
+        /// The messages held since the earlier blocks, with the block each was dispatched in,
+        /// taken before the ones in the receiver.
+        backlog: VecDeque<(chain::BlockNumber, u64, Message)>,
+        /// The block the last taken message was dispatched in, if taken from the backlog.
+        held_in: Option<chain::BlockNumber>,
         peeler: Plr,
         _msg: PhantomData<Msg>,
     }
 
+    impl<Msg, Wrp, Plr> PeelingReceiver<Msg, Wrp, Plr> {
+        /// Puts the messages held since the earlier blocks in front of the receiver.
+        pub fn with_backlog(mut self, backlog: Vec<(chain::BlockNumber, u64, Message)>) -> Self {
+            self.backlog.extend(backlog);
+            self
+        }
+
+        /// The block the last taken message was dispatched in, or None if in the current block.
+        pub fn held_in(&self) -> Option<chain::BlockNumber> {
+            self.held_in
+        }
+    }
+
     impl<Msg, Wrp> PeelingReceiver<Msg, Wrp, PlainPeeler<Msg>> {
         #[allow(unused)]
         pub fn new_plain(receiver: TypedReceiver<Wrp>) -> Self {
             PeelingReceiver {
                 receiver,
+                backlog: Default::default(),
+                held_in: None,
                 peeler: PlainPeeler(Default::default()),
                 _msg: Default::default(),
             }
@@ -143,12 +369,27 @@ mod receiver {
         pub fn new_secret(receiver: TypedReceiver<Wrp>, ecdh_key: ecdh::EcdhKey) -> Self {
             PeelingReceiver {
                 receiver,
+                backlog: Default::default(),
+                held_in: None,
                 peeler: SecretPeeler::new(ecdh_key),
                 _msg: Default::default(),
             }
         }
     }
 
+    impl<Msg, Wrp> PeelingReceiver<Msg, Wrp, GroupPeeler<Msg>> {
+        #[allow(unused)]
+        pub fn new_group(receiver: TypedReceiver<Wrp>, ecdh_key: ecdh::EcdhKey) -> Self {
+            PeelingReceiver {
+                receiver,
+                backlog: Default::default(),
+                held_in: None,
+                peeler: GroupPeeler::new(ecdh_key),
+                _msg: Default::default(),
+            }
+        }
+    }
+
     impl<Msg, Plr, Wrp> PeelingReceiver<Msg, Wrp, Plr>
     where
         Plr: Peeler<Wrp = Wrp, Msg = Msg>,
@@ -156,19 +397,73 @@ mod receiver {
         Wrp: Decode,
     {
         pub fn try_next(&mut self) -> Result<Option<(u64, Msg, MessageOrigin)>, anyhow::Error> {
-            let omsg = self
-                .receiver
-                .try_next()
-                .map_err(|e| anyhow::anyhow!("{}", e))?;
-            let (seq, msg, origin) = match omsg {
+            loop {
+                // The messages in the backlog are out of the trace of this block
+                let index = if self.backlog.is_empty() {
+                    self.receiver.peek_ind().ok().flatten()
+                } else {
+                    None
+                };
+                let result = self.take_next();
+                if let Some(index) = index {
+                    match &result {
+                        Ok(_) => crate::mq_trace::peeled(index),
+                        Err(err) => crate::mq_trace::failed(index, err),
+                    }
+                }
+                match result? {
+                    Some((seq, Some(msg), origin)) => return Ok(Some((seq, msg, origin))),
+                    // The message only updated the peeler
+                    Some((_, None, _)) => continue,
+                    None => return Ok(None),
+                }
+            }
+        }
+
+        fn take_next(
+            &mut self,
+        ) -> Result<Option<(u64, Option<Msg>, MessageOrigin)>, anyhow::Error> {
+            self.held_in = None;
+            let omsg = match self.backlog.pop_front() {
+                Some((block_number, seq, message)) => {
+                    self.held_in = Some(block_number);
+                    Some((seq, message))
+                }
+                None => self
+                    .receiver
+                    .try_next_raw()
+                    .map_err(|e| anyhow::anyhow!("{}", e))?,
+            };
+            let (seq, message) = match omsg {
                 Some(x) => x,
                 None => return Ok(None),
             };
-            let msg = self.peeler.peel(msg)?;
+            let origin = message.sender;
+            let msg = match &origin {
+                // Remote locations send plain messages over XCM, not wrapped in a `Payload`
+                MessageOrigin::MultiLocation(_) => match origin.as_location() {
+                    Some(_) => Msg::decode(&mut &message.payload[..])
+                        .map(Some)
+                        .map_err(|e| anyhow::anyhow!("Decode message failed: {}", e)),
+                    None => Err(anyhow::anyhow!("Bad remote location")),
+                },
+                _ => Wrp::decode(&mut &message.payload[..])
+                    .map_err(|e| anyhow::anyhow!("Decode message failed: {}", e))
+                    .and_then(|msg| self.peeler.peel(msg)),
+            };
+            let msg = msg.map_err(|error| BadMessage {
+                origin: origin.clone(),
+                sequence: seq,
+                error,
+            })?;
             Ok(Some((seq, msg, origin)))
         }
 
+        /// The index of the next message, 0 for the held ones to go before the current block.
         pub fn peek_ind(&self) -> Result<Option<u64>, ReceiveError> {
+            if !self.backlog.is_empty() {
+                return Ok(Some(0));
+            }
             self.receiver.peek_ind()
         }
     }
@@ -185,3 +480,93 @@ pub fn storage_prefix_for_topic_pubkey(topic: &[u8]) -> Vec<u8> {
 
     storage_map_prefix_blake2_128_concat(module_prefix, storage_prefix, &topic)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use phala_mq::{MessageOrigin, MessageSendQueue};
+    use sp_core::{sr25519, Pair};
+
+    fn ecdh_key(seed: u8) -> KeyPair {
+        KeyPair::create(&[seed; 32]).unwrap()
+    }
+
+    /// Peels all the messages sent, None for the ones failing.
+    fn peel_all(queue: &MessageSendQueue, mut peeler: GroupPeeler<u32>) -> Vec<Option<u32>> {
+        queue
+            .all_messages()
+            .into_iter()
+            .filter_map(|message| {
+                let payload = Decode::decode(&mut &message.message.payload[..]).unwrap();
+                match peeler.peel(payload) {
+                    Ok(Some(n)) => Some(Some(n)),
+                    Ok(None) => None,
+                    Err(_) => Some(None),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn group_keys_are_rotated_on_membership_changes() {
+        let signer = sr25519::Pair::from_seed(&[1; 32]);
+        let queue = MessageSendQueue::new();
+        let mq = queue.channel(MessageOrigin::Worker(signer.public()), signer);
+        let key = ecdh_key(1);
+        let key_map = |_: &[u8]| -> Option<ecdh::EcdhPublicKey> { None };
+        let channel = SecretMessageChannel::new(&key, &mq, &key_map);
+        let (alice, bob) = (ecdh_key(2), ecdh_key(3));
+
+        let mut group = Group::new(&b"group"[..]);
+        group.add_member(alice.public());
+        group.add_member(bob.public());
+        channel.send_to_group(&mut group, &1u32);
+        channel.send_to_group(&mut group, &2u32);
+        group.remove_member(&bob.public());
+        channel.send_to_group(&mut group, &3u32);
+
+        // The key is sent once per epoch, and each message once for all the members
+        assert_eq!(queue.all_messages().len(), 5);
+        assert_eq!(
+            peel_all(&queue, GroupPeeler::new(alice)),
+            vec![Some(1), Some(2), Some(3)]
+        );
+        assert_eq!(
+            peel_all(&queue, GroupPeeler::new(bob)),
+            vec![Some(1), Some(2), None]
+        );
+        assert_eq!(
+            peel_all(&queue, GroupPeeler::new(ecdh_key(4))),
+            vec![None, None, None]
+        );
+    }
+
+    #[test]
+    fn group_keys_are_agreed_by_the_instances_of_the_sender() {
+        let signer = sr25519::Pair::from_seed(&[1; 32]);
+        let key = ecdh_key(1);
+        let key_map = |_: &[u8]| -> Option<ecdh::EcdhPublicKey> { None };
+        let alice = ecdh_key(2);
+        let member = alice.public();
+        let send = |message: u32| {
+            let queue = MessageSendQueue::new();
+            let mq = queue.channel(MessageOrigin::Worker(signer.public()), signer.clone());
+            let channel = SecretMessageChannel::new(&key, &mq, &key_map);
+            let mut group = Group::new(&b"group"[..]);
+            group.add_member(member);
+            channel.send_to_group(&mut group, &message);
+            queue
+                .all_messages()
+                .into_iter()
+                .map(|message| Decode::decode(&mut &message.message.payload[..]).unwrap())
+                .collect::<Vec<Payload<u32>>>()
+        };
+
+        // The key sent by an instance opens the messages sent by another one
+        let mut peeler = GroupPeeler::new(alice);
+        let key_message = send(1).remove(0);
+        assert!(matches!(peeler.peel(key_message), Ok(None)));
+        let data_message = send(2).remove(1);
+        assert!(matches!(peeler.peel(data_message), Ok(Some(2))));
+    }
+}
@@ -73,6 +73,10 @@ mod storage_ext {
         fn mq_messages(&self) -> Result<Vec<Message>, Error> {
             self.get_decoded_or_default(storage_prefix("PhalaMq", "OutboundMessages"))
         }
+        /// How the messages of `mq_messages` got through the chain, in the same order.
+        fn mq_message_infos(&self) -> Result<Vec<chain::pallet_mq::OutboundMessageInfo>, Error> {
+            self.get_decoded_or_default(storage_prefix("PhalaMq", "OutboundMessageInfos"))
+        }
         fn timestamp_now(&self) -> Option<chain::Moment> {
             self.get_decoded(storage_prefix("Timestamp", "Now"))
         }
@@ -13,6 +13,7 @@ use phala_types::{
 };
 use sp_core::{hashing, sr25519};
 
+use crate::contracts::{self, registry};
 use crate::types::BlockInfo;
 
 use std::{
@@ -88,6 +89,8 @@ pub(crate) struct Gatekeeper<MsgChan> {
     mining_events: TypedReceiver<MiningReportEvent>,
     system_events: TypedReceiver<SystemEvent>,
     workers: BTreeMap<WorkerPublicKey, WorkerInfo>,
+    // Contracts deployed on chain, whose keys are dispatched to all workers
+    deployed_contracts: Vec<contracts::ContractId>,
     // Randomness
     last_random_number: RandomNumber,
     iv_seq: u64,
@@ -115,6 +118,7 @@ where
             mining_events: recv_mq.subscribe_bound(),
             system_events: recv_mq.subscribe_bound(),
             workers: Default::default(),
+            deployed_contracts: Default::default(),
             last_random_number: [0_u8; 32],
             iv_seq: 0,
             tokenomic_params: tokenomic::test_params(),
@@ -160,13 +164,15 @@ where
         self.master_pubkey_on_chain = true;
     }
 
-    pub fn share_master_key(
+    /// Encrypts the given secret to the given ecdh pubkey.
+    ///
+    /// Returns the ecdh pubkey of the source, the encrypted data and the IV.
+    fn encrypt_secret(
         &mut self,
-        pubkey: &WorkerPublicKey,
         ecdh_pubkey: &EcdhPublicKey,
+        mut data: Vec<u8>,
         block_number: chain::BlockNumber,
-    ) {
-        info!("Gatekeeper: try dispatch master key");
+    ) -> (EcdhPublicKey, Vec<u8>, aead::IV) {
         let derived_key = self
             .master_key
             .derive_sr25519_pair(&[&crate::generate_random_info()])
@@ -177,17 +183,67 @@ where
         let secret = ecdh::agree(&my_ecdh_key, &ecdh_pubkey.0)
             .expect("should never fail with valid ecdh key; qed.");
         let iv = self.generate_iv(block_number);
-        let mut data = self.master_key.dump_secret_key().to_vec();
 
-        aead::encrypt(&iv, &secret, &mut data).expect("Failed to encrypt master key");
+        aead::encrypt(&iv, &secret, &mut data).expect("Failed to encrypt secret");
+        let my_ecdh_pubkey = my_ecdh_key
+            .public()
+            .as_ref()
+            .try_into()
+            .expect("should never fail given pubkey with correct length; qed;");
+        (my_ecdh_pubkey, data, iv)
+    }
+
+    pub fn share_master_key(
+        &mut self,
+        pubkey: &WorkerPublicKey,
+        ecdh_pubkey: &EcdhPublicKey,
+        block_number: chain::BlockNumber,
+    ) {
+        info!("Gatekeeper: try dispatch master key");
+        let data = self.master_key.dump_secret_key().to_vec();
+        let (my_ecdh_pubkey, data, iv) = self.encrypt_secret(ecdh_pubkey, data, block_number);
         self.egress
             .push_message(KeyDistribution::master_key_distribution(
                 *pubkey,
-                my_ecdh_key
-                    .public()
-                    .as_ref()
-                    .try_into()
-                    .expect("should never fail given pubkey with correct length; qed;"),
+                my_ecdh_pubkey,
+                data,
+                iv,
+            ));
+    }
+
+    /// Dispatches the keys of the native contracts and the deployed contracts to the given worker.
+    pub fn share_contract_keys(
+        &mut self,
+        pubkey: &WorkerPublicKey,
+        ecdh_pubkey: &EcdhPublicKey,
+        block_number: chain::BlockNumber,
+    ) {
+        info!("Gatekeeper: try dispatch contract keys to {}", hex::encode(pubkey));
+        let contract_ids: Vec<_> = registry::NATIVE_CONTRACTS
+            .iter()
+            .map(|entry| contracts::id256(entry.id))
+            .chain(self.deployed_contracts.iter().cloned())
+            .collect();
+        for contract_id in contract_ids {
+            self.share_contract_key(pubkey, ecdh_pubkey, contract_id, block_number);
+        }
+    }
+
+    fn share_contract_key(
+        &mut self,
+        pubkey: &WorkerPublicKey,
+        ecdh_pubkey: &EcdhPublicKey,
+        contract_id: contracts::ContractId,
+        block_number: chain::BlockNumber,
+    ) {
+        let contract_key = registry::derive_contract_key(&self.master_key, &contract_id);
+        let data = contract_key.dump_secret_key().to_vec();
+        let (my_ecdh_pubkey, data, iv) = self.encrypt_secret(ecdh_pubkey, data, block_number);
+        self.egress
+            .push_message(KeyDistribution::contract_key_distribution(
+                *pubkey,
+                contract_id,
+                my_ecdh_pubkey,
                 data,
                 iv,
             ));
@@ -510,6 +566,16 @@ where
                 .workers
                 .entry(*pubkey)
                 .or_insert_with(|| WorkerInfo::new(*pubkey));
+
+            match super::chain_state::read_worker_ecdh_pubkey(pubkey, self.block.storage) {
+                Some(ecdh_pubkey) => {
+                    self.state
+                        .share_contract_keys(pubkey, &ecdh_pubkey, self.block.block_number);
+                }
+                None => {
+                    error!("No ecdh pubkey found for worker {}", hex::encode(pubkey));
+                }
+            }
         }
 
         let log_on = log::log_enabled!(log::Level::Debug);
@@ -591,6 +657,30 @@ where
                 }
             }
             SystemEvent::HeartbeatChallenge(_) => {}
+            SystemEvent::ContractDeployed(deployment) => {
+                info!(
+                    "Gatekeeper: dispatch the key of deployed contract {}",
+                    hex::encode(&deployment.contract_id)
+                );
+                let contract_id = deployment.contract_id;
+                self.state.deployed_contracts.push(contract_id);
+                let workers: Vec<_> = self.state.workers.keys().cloned().collect();
+                for pubkey in workers {
+                    match super::chain_state::read_worker_ecdh_pubkey(&pubkey, self.block.storage) {
+                        Some(ecdh_pubkey) => {
+                            self.state.share_contract_key(
+                                &pubkey,
+                                &ecdh_pubkey,
+                                contract_id,
+                                self.block.block_number,
+                            );
+                        }
+                        None => {
+                            error!("No ecdh pubkey found for worker {}", hex::encode(&pubkey));
+                        }
+                    }
+                }
+            }
         }
     }
 
@@ -917,6 +1007,7 @@ pub mod tests {
             sender: sender.clone(),
             destination: M::topic().into(),
             payload: msg.encode(),
+            expiry: None,
         }
     }
 
@@ -958,6 +1049,7 @@ pub mod tests {
                 sender: MessageOrigin::Gatekeeper,
                 destination: M::topic().into(),
                 payload: message.encode(),
+                expiry: None,
             };
             self.messages.borrow_mut().push(message);
         }
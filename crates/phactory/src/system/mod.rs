@@ -1,8 +1,14 @@
 mod gk;
 mod master_key;
 
-use crate::{benchmark, types::BlockInfo};
+use crate::{
+    benchmark,
+    contracts::{registry, ContractMap},
+    types::BlockInfo,
+};
+use std::collections::BTreeMap;
 use anyhow::Result;
+use core::convert::TryFrom;
 use core::fmt;
 use log::info;
 
@@ -10,60 +16,24 @@ use crate::pal;
 use chain::pallet_registry::RegistryEvent;
 pub use phactory_api::prpc::{GatekeeperRole, GatekeeperStatus};
 use parity_scale_codec::{Decode, Encode};
-use phala_crypto::{aead, ecdh, sr25519::KDF};
+use phala_crypto::{
+    aead, ecdh,
+    sr25519::{Persistence, Sr25519SecretKey, KDF},
+};
 use phala_mq::{
-    BadOrigin, MessageDispatcher, MessageOrigin, MessageSendQueue, Sr25519MessageChannel,
+    ContractId, MessageDispatcher, MessageOrigin, MessageSendQueue, Sr25519MessageChannel,
     TypedReceiveError, TypedReceiver,
 };
 use phala_types::{
     messaging::{
-        DispatchMasterKeyEvent, GatekeeperChange, GatekeeperLaunch, HeartbeatChallenge,
-        KeyDistribution, MiningReportEvent, NewGatekeeperEvent, SystemEvent, WorkerEvent,
+        ContractDeployment, DispatchContractKeyEvent, DispatchMasterKeyEvent, GatekeeperChange,
+        GatekeeperLaunch, HeartbeatChallenge, KeyDistribution, MiningReportEvent, NewGatekeeperEvent, SystemEvent, WorkerEvent,
     },
-    MasterPublicKey, WorkerPublicKey,
+    contract::ContractId32, ContractPublicKey, EcdhPublicKey, MasterPublicKey, WorkerPublicKey,
 };
-use sp_core::{hashing::blake2_256, sr25519, Pair, U256};
-
-pub type TransactionResult = Result<(), TransactionError>;
-
-#[derive(Encode, Decode, Debug, Clone)]
-pub enum TransactionError {
-    BadInput,
-    BadOrigin,
-    // general
-    InsufficientBalance,
-    NoBalance,
-    UnknownError,
-    BadContractId,
-    BadCommand,
-    SymbolExist,
-    AssetIdNotFound,
-    NotAssetOwner,
-    BadSecret,
-    BadMachineId,
-    FailedToSign,
-    BadDecimal,
-    DestroyNotAllowed,
-    // for pdiem
-    BadAccountInfo,
-    BadLedgerInfo,
-    BadTrustedStateData,
-    BadEpochChangedProofData,
-    BadTrustedState,
-    InvalidAccount,
-    BadTransactionWithProof,
-    FailedToVerify,
-    FailedToGetTransaction,
-    FailedToCalculateBalance,
-    BadChainId,
-    TransferringNotAllowed,
-}
+use sp_core::{hashing::blake2_256, sr25519, Pair, H256, U256};
 
-impl From<BadOrigin> for TransactionError {
-    fn from(_: BadOrigin) -> TransactionError {
-        TransactionError::BadOrigin
-    }
-}
+pub use phactory_api::system::{Request, Response, TransactionError, TransactionResult};
 
 #[derive(Debug)]
 struct BenchState {
@@ -201,6 +171,7 @@ impl WorkerState {
             SystemEvent::HeartbeatChallenge(seed_info) => {
                 self.handle_heartbeat_challenge(block, seed_info, callback, log_on);
             }
+            SystemEvent::ContractDeployed(_) => {}
         };
     }
 
@@ -345,6 +316,10 @@ pub struct System<Platform> {
     // Worker
     identity_key: sr25519::Pair,
     worker_state: WorkerState,
+    // Contract keys dispatched by the gatekeeper
+    contract_keys: BTreeMap<ContractId, sr25519::Pair>,
+    // Contracts deployed on chain, waiting to be instantiated
+    deployments: Vec<ContractDeployment>,
     // Gatekeeper
     master_key: Option<sr25519::Pair>,
     pub(crate) gatekeeper: Option<gk::Gatekeeper<Sr25519MessageChannel>>,
@@ -373,6 +348,8 @@ impl<Platform: pal::Platform> System<Platform> {
             key_distribution_events: recv_mq.subscribe_bound(),
             identity_key: identity_key.clone(),
             worker_state: WorkerState::new(pubkey),
+            contract_keys: Default::default(),
+            deployments: Default::default(),
             master_key,
             gatekeeper: None,
         }
@@ -381,9 +358,30 @@ impl<Platform: pal::Platform> System<Platform> {
     pub fn handle_query(
         &mut self,
         _accid_origin: Option<&chain::AccountId>,
-        _req: Request,
+        contracts: &ContractMap,
+        req: Request,
     ) -> Response {
-        Response::Error("Unreachable!".to_string())
+        match req {
+            Request::CommandReceipt {
+                contract,
+                sender,
+                sent_at,
+                index,
+            } => match contracts.get(&contract) {
+                Some(contract) => {
+                    Response::CommandReceipt(contract.command_receipt(&sender, sent_at, index))
+                }
+                None => Response::Error("Contract not found".to_string()),
+            },
+            Request::ContractMetadata { contract } => match contracts.get(&contract) {
+                Some(contract) => Response::ContractMetadata(contract.metadata()),
+                None => Response::Error("Contract not found".to_string()),
+            },
+            Request::ContractKey { contract } => match self.contract_ecdh_key(&contract) {
+                Some(key) => Response::ContractKey(EcdhPublicKey(key.public())),
+                None => Response::Error("Contract key not available".to_string()),
+            },
+        }
     }
 
     pub fn process_messages(&mut self, block: &mut BlockInfo) -> anyhow::Result<()> {
@@ -451,9 +449,27 @@ impl<Platform: pal::Platform> System<Platform> {
     fn process_system_event(&mut self, block: &BlockInfo, event: &SystemEvent) -> Result<()> {
         self.worker_state
             .process_event(block, event, &mut WorkerSMDelegate(&self.egress), true);
+        if let SystemEvent::ContractDeployed(deployment) = event {
+            info!(
+                "Contract {} deployed by {}",
+                hex::encode(&deployment.contract_id),
+                hex::encode(&deployment.deployer)
+            );
+            if let Some(gatekeeper) = &self.gatekeeper {
+                if gatekeeper.registered_on_chain() {
+                    self.publish_contract_pubkey(deployment.contract_id);
+                }
+            }
+            self.deployments.push(deployment.clone());
+        }
         Ok(())
     }
 
+    /// Takes the contracts deployed since the last call, to be instantiated by the caller.
+    pub fn take_deployments(&mut self) -> Vec<ContractDeployment> {
+        std::mem::take(&mut self.deployments)
+    }
+
     fn set_master_key(&mut self, master_key: sr25519::Pair, need_restart: bool) {
         if self.master_key.is_none() {
             master_key::seal(self.sealing_path.clone(), &master_key, &self.identity_key, &self.platform);
@@ -513,6 +529,9 @@ impl<Platform: pal::Platform> System<Platform> {
                 );
                 if let Some(gatekeeper) = &mut self.gatekeeper {
                     gatekeeper.master_pubkey_uploaded();
+                    if gatekeeper.registered_on_chain() {
+                        self.publish_contract_pubkeys();
+                    }
                 }
             }
         }
@@ -629,7 +648,102 @@ impl<Platform: pal::Platform> System<Platform> {
             KeyDistribution::MasterKeyDistribution(dispatch_master_key_event) => {
                 self.process_master_key_distribution(origin, dispatch_master_key_event)
             }
+            KeyDistribution::ContractKeyDistribution(dispatch_contract_key_event) => {
+                self.process_contract_key_distribution(origin, dispatch_contract_key_event)
+            }
+        }
+    }
+
+    /// Upload the pubkeys of the native contracts on chain via worker egress
+    fn publish_contract_pubkeys(&self) {
+        for entry in registry::NATIVE_CONTRACTS {
+            self.publish_contract_pubkey(crate::contracts::id256(entry.id));
+        }
+    }
+
+    /// Upload the pubkey of a contract on chain via worker egress
+    fn publish_contract_pubkey(&self, contract: ContractId) {
+        let master_key = match &self.master_key {
+            Some(key) => key,
+            None => return,
+        };
+        let pubkey = registry::derive_contract_key(master_key, &contract).public();
+        info!(
+            "Gatekeeper: upload contract key {} of {} on chain",
+            hex::encode(&pubkey),
+            hex::encode(&contract)
+        );
+        self.egress
+            .send(&RegistryEvent::ContractPubkey { contract, pubkey });
+    }
+
+    /// Process encrypted contract key from mq
+    fn process_contract_key_distribution(
+        &mut self,
+        origin: MessageOrigin,
+        event: DispatchContractKeyEvent,
+    ) {
+        if !origin.is_gatekeeper() {
+            error!("Invalid origin {:?} sent a {:?}", origin, event);
+            return;
+        };
+
+        let my_pubkey = self.identity_key.public();
+        if my_pubkey == event.dest {
+            let my_ecdh_key = self
+                .identity_key
+                .derive_ecdh_key()
+                .expect("Should never failed with valid identity key; qed.");
+            let secret = ecdh::agree(&my_ecdh_key, &event.ecdh_pubkey.0)
+                .expect("Should never failed with valid ecdh key; qed.");
+
+            let mut key_buff = event.encrypted_contract_key.clone();
+            let contract_key = match aead::decrypt(&event.iv, &secret, &mut key_buff[..]) {
+                Ok(key) => key,
+                Err(err) => {
+                    error!("Failed to decrypt dispatched contract key: {:?}", err);
+                    return;
+                }
+            };
+            let contract_key = match Sr25519SecretKey::try_from(&contract_key[..]) {
+                Ok(secret) => sr25519::Pair::restore_from_secret_key(&secret),
+                Err(_) => {
+                    error!("Invalid length of dispatched contract key");
+                    return;
+                }
+            };
+            info!(
+                "Received contract key {} for contract {:?}",
+                hex::encode(contract_key.public()),
+                event.contract_id
+            );
+            self.contract_keys.insert(event.contract_id, contract_key);
+        }
+    }
+
+    /// The key of the given contract, if it has been dispatched to this worker.
+    ///
+    /// A worker holding the master key derives contract keys on its own.
+    pub fn contract_key(&self, contract_id: &ContractId) -> Option<sr25519::Pair> {
+        if let Some(key) = self.contract_keys.get(contract_id) {
+            return Some(key.clone());
         }
+        self.master_key
+            .as_ref()
+            .map(|master_key| registry::derive_contract_key(master_key, contract_id))
+    }
+
+    /// Sets a contract key generated by the worker itself, on a dev chain without any gatekeeper.
+    pub fn set_dev_contract_key(&mut self, contract_id: ContractId, key: sr25519::Pair) {
+        self.contract_keys.insert(contract_id, key);
+    }
+
+    /// The ecdh key of the given contract, to which the queries to the contract are encrypted.
+    pub fn contract_ecdh_key(&self, contract_id: &ContractId) -> Option<ecdh::EcdhKey> {
+        self.contract_key(contract_id).map(|key| {
+            key.derive_ecdh_key()
+                .expect("Should never failed with valid contract key; qed.")
+        })
     }
 
     /// Process encrypted master key from mq
@@ -707,18 +821,10 @@ impl fmt::Display for Error {
     }
 }
 
-#[derive(Encode, Decode, Debug, Clone)]
-pub enum Request {}
-
-#[derive(Encode, Decode, Debug)]
-pub enum Response {
-    Error(String),
-}
-
 pub mod chain_state {
     use super::*;
-    use crate::light_validation::utils::storage_prefix;
-    use crate::storage::Storage;
+    use crate::light_validation::utils::{storage_map_prefix_twox_64_concat, storage_prefix};
+    use crate::storage::{Storage, StorageExt};
     use parity_scale_codec::Decode;
 
     pub fn is_gatekeeper(pubkey: &WorkerPublicKey, chain_storage: &Storage) -> bool {
@@ -734,7 +840,67 @@ pub mod chain_state {
         gatekeepers.contains(pubkey)
     }
 
-    #[allow(dead_code)]
+    pub fn read_worker_ecdh_pubkey(
+        pubkey: &WorkerPublicKey,
+        chain_storage: &Storage,
+    ) -> Option<EcdhPublicKey> {
+        type Workers = chain::pallet_registry::Workers<chain::Runtime>;
+        type WorkerInfo = chain::pallet_registry::WorkerInfo<chain::AccountId>;
+        use chain::pallet_mq::StorageMapTrait as _;
+
+        let key = storage_map_prefix_twox_64_concat(
+            Workers::module_prefix(),
+            Workers::storage_prefix(),
+            pubkey,
+        );
+        chain_storage
+            .get_decoded::<WorkerInfo>(&key)
+            .map(|info| info.ecdh_pubkey)
+    }
+
+    pub fn read_contract_pubkey(
+        contract: &ContractId,
+        chain_storage: &Storage,
+    ) -> Option<ContractPublicKey> {
+        type ContractKey = chain::pallet_registry::ContractKey<chain::Runtime>;
+        use chain::pallet_mq::StorageMapTrait as _;
+
+        let key = storage_map_prefix_twox_64_concat(
+            ContractKey::module_prefix(),
+            ContractKey::storage_prefix(),
+            contract,
+        );
+        chain_storage.get_decoded::<ContractPublicKey>(&key)
+    }
+
+    pub fn read_contract_code(code_hash: &H256, chain_storage: &Storage) -> Option<Vec<u8>> {
+        type ContractCode = chain::pallet_registry::ContractCode<chain::Runtime>;
+        use chain::pallet_mq::StorageMapTrait as _;
+
+        let key = storage_map_prefix_twox_64_concat(
+            ContractCode::module_prefix(),
+            ContractCode::storage_prefix(),
+            code_hash,
+        );
+        chain_storage.get_decoded::<Vec<u8>>(&key)
+    }
+
+    /// Whether the native contract is enabled or disabled on chain, None if left to the worker.
+    pub fn read_native_contract_config(
+        contract: ContractId32,
+        chain_storage: &Storage,
+    ) -> Option<bool> {
+        type NativeContracts = chain::pallet_registry::NativeContracts<chain::Runtime>;
+        use chain::pallet_mq::StorageMapTrait as _;
+
+        let key = storage_map_prefix_twox_64_concat(
+            NativeContracts::module_prefix(),
+            NativeContracts::storage_prefix(),
+            &contract,
+        );
+        chain_storage.get_decoded::<bool>(&key)
+    }
+
     pub fn read_master_pubkey(chain_storage: &Storage) -> Option<MasterPublicKey> {
         let key = storage_prefix("PhalaRegistry", "GatekeeperMasterPubkey");
         chain_storage
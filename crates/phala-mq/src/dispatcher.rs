@@ -1,8 +1,10 @@
 use core::marker::PhantomData;
 
-use alloc::{collections::BTreeMap, vec::Vec};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
 
-use crate::simple_mpsc::{channel, ReceiveError, Receiver as RawReceiver, Sender, Seq};
+use crate::simple_mpsc::{
+    channel, ChannelStats, Overflow, ReceiveError, Receiver as RawReceiver, Sender, Seq, Spill,
+};
 use crate::types::{Message, Path};
 use crate::{BindTopic, MessageOrigin};
 use derive_more::Display;
@@ -14,11 +16,97 @@ impl Seq for (u64, Message) {
     }
 }
 
+/// A pattern of the paths to subscribe.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Matcher {
+    /// Matches the paths starting with the prefix.
+    Prefix(Path),
+    /// Matches the paths with the same number of `/` separated segments as the glob, where each
+    /// segment matches the one of the glob. A `*` in a segment of the glob matches any bytes
+    /// other than `/`, e.g. `phala/contract/*/command` matches the command topics of all the
+    /// contracts.
+    Glob(Path),
+}
+
+impl Matcher {
+    pub fn matches(&self, path: &[u8]) -> bool {
+        match self {
+            Matcher::Prefix(prefix) => path.starts_with(prefix),
+            Matcher::Glob(glob) => {
+                let mut globs = glob.split(|b| *b == b'/');
+                let mut segments = path.split(|b| *b == b'/');
+                loop {
+                    match (globs.next(), segments.next()) {
+                        (Some(glob), Some(segment)) => {
+                            if !glob_matches(glob, segment) {
+                                return false;
+                            }
+                        }
+                        (None, None) => return true,
+                        _ => return false,
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Matches a segment against a glob segment, where a `*` matches any bytes.
+fn glob_matches(glob: &[u8], segment: &[u8]) -> bool {
+    match glob.iter().position(|b| *b == b'*') {
+        None => glob == segment,
+        Some(star) => {
+            let (head, tail) = (&glob[..star], &glob[star + 1..]);
+            if !segment.starts_with(head) {
+                return false;
+            }
+            let rest = &segment[head.len()..];
+            (0..=rest.len()).any(|skip| glob_matches(tail, &rest[skip..]))
+        }
+    }
+}
+
+/// What the queue of a subscriber does with a new message when it's full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drops the oldest message in the queue to make room for the new one.
+    DropOldest,
+    /// Drops the new message.
+    Reject,
+    /// Moves the new message to the spill store of the dispatcher until the subscriber catches
+    /// up. Rejects the message if the dispatcher has no spill store.
+    Spill,
+}
+
+pub type MessageSpill = dyn Spill<(u64, Message)>;
+
+/// The counters of the queues subscribing to a bounded topic, summed up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicStats {
+    pub topic: Path,
+    pub subscribers: u64,
+    pub stats: ChannelStats,
+}
+
+/// Dispatches the messages to the subscribers of their paths.
+///
+/// A message is delivered to each subscription whose path or pattern matches its path, first to
+/// the subscriptions of the exact path and then to the pattern subscriptions, each in the order
+/// they were made. Overlapping subscriptions get their own copy of the message, sharing the same
+/// index.
+///
+/// The queues of the subscribers are unbounded, unless the capacity of the topic is set with
+/// `set_capacity`. Then each subscriber keeps up to that many messages in memory, and handles
+/// the overflowing ones with its `OverflowPolicy`.
 #[derive(Default)]
 pub struct MessageDispatcher {
     subscribers: BTreeMap<Path, Vec<Sender<(u64, Message)>>>,
+    /// The pattern subscriptions, in the order they were made.
+    match_subscribers: Vec<(Matcher, Sender<(u64, Message)>)>,
     local_index: u64,
-    //match_subscribers: Vec<Matcher, Vec<Sender<Message>>>,
+    capacities: BTreeMap<Path, usize>,
+    spill: Option<Arc<MessageSpill>>,
+    next_spill_id: u64,
 }
 
 pub type Receiver<T> = RawReceiver<(u64, T)>;
@@ -27,19 +115,66 @@ impl MessageDispatcher {
     pub fn new() -> Self {
         MessageDispatcher {
             subscribers: Default::default(),
+            match_subscribers: Default::default(),
             local_index: 0,
+            capacities: Default::default(),
+            spill: None,
+            next_spill_id: 0,
         }
     }
 
+    /// Bounds the queues of the subscribers to the topic, subscribing afterwards.
+    pub fn set_capacity(&mut self, path: impl Into<Path>, capacity: usize) {
+        self.capacities.insert(path.into(), capacity);
+    }
+
+    /// Sets the store of the messages spilled by the subscribers with `OverflowPolicy::Spill`.
+    pub fn set_spill(&mut self, spill: Arc<MessageSpill>) {
+        self.spill = Some(spill);
+    }
+
     /// Subscribe messages which are sent to `path`.
     /// Returns a Receiver channel end.
     pub fn subscribe(&mut self, path: impl Into<Path>) -> Receiver<Message> {
+        self.subscribe_with_policy(path, OverflowPolicy::Reject)
+    }
+
+    /// Subscribe messages which are sent to `path`, handling the overflow of a bounded topic with
+    /// the policy.
+    /// Returns a Receiver channel end.
+    pub fn subscribe_with_policy(
+        &mut self,
+        path: impl Into<Path>,
+        policy: OverflowPolicy,
+    ) -> Receiver<Message> {
+        let path = path.into();
         let (rx, tx) = channel();
-        let entry = self.subscribers.entry(path.into()).or_default();
+        if let Some(capacity) = self.capacities.get(&path) {
+            let overflow = match (policy, &self.spill) {
+                (OverflowPolicy::DropOldest, _) => Overflow::DropOldest,
+                (OverflowPolicy::Spill, Some(spill)) => {
+                    let mut key = path.clone();
+                    key.extend_from_slice(&self.next_spill_id.to_be_bytes());
+                    self.next_spill_id += 1;
+                    Overflow::Spill(spill.clone(), key)
+                }
+                _ => Overflow::Reject,
+            };
+            tx.set_capacity(Some(*capacity), overflow);
+        }
+        let entry = self.subscribers.entry(path).or_default();
         entry.push(tx);
         rx
     }
 
+    /// Subscribe messages which are sent to the paths matching `matcher`.
+    /// Returns a Receiver channel end.
+    pub fn subscribe_matching(&mut self, matcher: Matcher) -> Receiver<Message> {
+        let (rx, tx) = channel();
+        self.match_subscribers.push((matcher, tx));
+        rx
+    }
+
     /// Subscribe messages which implementing BindTopic
     /// Returns a TypedReceiver channel end.
     pub fn subscribe_bound<T: Decode + BindTopic>(&mut self) -> TypedReceiver<T> {
@@ -47,27 +182,56 @@ impl MessageDispatcher {
     }
 
     /// Dispatch a message.
-    /// Returns number of receivers dispatched to.
+    /// Returns number of receivers dispatched to, counting each subscription matching the path,
+    /// except the ones rejecting the message for being full.
     pub fn dispatch(&mut self, message: Message) -> usize {
         let mut count = 0;
         let sn = self.local_index;
         self.local_index += 1;
-        if let Some(receivers) = self.subscribers.get_mut(message.destination.path()) {
-            receivers.retain(|receiver| {
-                if let Err(error) = receiver.send((sn, message.clone())) {
-                    use crate::simple_mpsc::SendError::*;
-                    match error {
-                        ReceiverGone => false,
-                    }
-                } else {
-                    count += 1;
-                    true
+        let mut send = |receiver: &Sender<(u64, Message)>| {
+            if let Err(error) = receiver.send((sn, message.clone())) {
+                use crate::simple_mpsc::SendError::*;
+                match error {
+                    ReceiverGone => false,
+                    Full => true,
                 }
-            });
+            } else {
+                count += 1;
+                true
+            }
+        };
+        if let Some(receivers) = self.subscribers.get_mut(message.destination.path()) {
+            receivers.retain(|receiver| send(receiver));
         }
+        let path = message.destination.path();
+        self.match_subscribers
+            .retain(|(matcher, receiver)| !matcher.matches(path) || send(receiver));
         count
     }
 
+    /// The counters of the queues of the bounded topics.
+    pub fn topic_stats(&self) -> Vec<TopicStats> {
+        self.capacities
+            .keys()
+            .map(|topic| {
+                let receivers = self.subscribers.get(topic).map(|v| &v[..]).unwrap_or(&[]);
+                let mut stats = ChannelStats::default();
+                for receiver in receivers {
+                    let receiver_stats = receiver.stats();
+                    stats.depth += receiver_stats.depth;
+                    stats.dropped += receiver_stats.dropped;
+                    stats.rejected += receiver_stats.rejected;
+                    stats.spilled += receiver_stats.spilled;
+                }
+                TopicStats {
+                    topic: topic.clone(),
+                    subscribers: receivers.len() as u64,
+                    stats,
+                }
+            })
+            .collect()
+    }
+
     pub fn reset_local_index(&mut self) {
         self.local_index = 0;
     }
@@ -78,6 +242,9 @@ impl MessageDispatcher {
         for subscriber in self.subscribers.values_mut().flatten() {
             count += subscriber.clear();
         }
+        for (_, subscriber) in self.match_subscribers.iter() {
+            count += subscriber.clear();
+        }
         count
     }
 }
@@ -114,6 +281,11 @@ impl<T: Decode> TypedReceiver<T> {
         Ok(Some((sn, typed, msg.sender)))
     }
 
+    /// Takes the next message without decoding its payload.
+    pub fn try_next_raw(&mut self) -> Result<Option<(u64, Message)>, ReceiveError> {
+        self.queue.try_next()
+    }
+
     pub fn peek_ind(&self) -> Result<Option<u64>, ReceiveError> {
         self.queue.peek_ind()
     }
@@ -166,3 +338,106 @@ macro_rules! select {
         rv
     }};
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Mutex;
+    use alloc::{collections::VecDeque, vec};
+
+    fn dispatch(dispatcher: &mut MessageDispatcher, path: &[u8]) -> usize {
+        let sender = MessageOrigin::Gatekeeper;
+        dispatcher.dispatch(Message::new(sender, path.to_vec(), vec![]))
+    }
+
+    fn indexes(receiver: &mut Receiver<Message>) -> Vec<u64> {
+        let mut indexes = vec![];
+        while let Ok(Some((sn, _))) = receiver.try_next() {
+            indexes.push(sn);
+        }
+        indexes
+    }
+
+    #[test]
+    fn overlapping_subscriptions_get_their_own_copies() {
+        let mut dispatcher = MessageDispatcher::new();
+        let mut exact = dispatcher.subscribe(b"phala/contract/01/command".to_vec());
+        let mut prefix =
+            dispatcher.subscribe_matching(Matcher::Prefix(b"phala/contract/".to_vec()));
+        let mut glob =
+            dispatcher.subscribe_matching(Matcher::Glob(b"phala/contract/*/command".to_vec()));
+
+        assert_eq!(dispatch(&mut dispatcher, b"phala/contract/01/command"), 3);
+        assert_eq!(dispatch(&mut dispatcher, b"phala/contract/02/command"), 2);
+        assert_eq!(dispatch(&mut dispatcher, b"phala/contract/02/command/x"), 1);
+        assert_eq!(dispatch(&mut dispatcher, b"phala/system"), 0);
+
+        assert_eq!(indexes(&mut exact), vec![0]);
+        assert_eq!(indexes(&mut prefix), vec![0, 1, 2]);
+        assert_eq!(indexes(&mut glob), vec![0, 1]);
+
+        // Dropped receivers are not counted
+        drop(prefix);
+        assert_eq!(dispatch(&mut dispatcher, b"phala/contract/01/command"), 2);
+    }
+
+    #[test]
+    fn globs_match_within_segments() {
+        let glob = Matcher::Glob(b"a/b*c/*".to_vec());
+        assert!(glob.matches(b"a/bc/x"));
+        assert!(glob.matches(b"a/bxyc/"));
+        assert!(!glob.matches(b"a/bx/yc/z"));
+        assert!(!glob.matches(b"a/bc"));
+        assert!(!glob.matches(b"a/bc/x/y"));
+    }
+
+    #[derive(Default)]
+    struct MemorySpill(Mutex<BTreeMap<Vec<u8>, VecDeque<(u64, Message)>>>);
+
+    impl Spill<(u64, Message)> for MemorySpill {
+        fn push(&self, key: &[u8], value: (u64, Message)) {
+            let mut spilled = self.0.lock();
+            spilled.entry(key.to_vec()).or_default().push_back(value);
+        }
+
+        fn pop(&self, key: &[u8]) -> Option<(u64, Message)> {
+            self.0.lock().get_mut(key)?.pop_front()
+        }
+    }
+
+    #[test]
+    fn bounded_topics_apply_the_overflow_policies() {
+        let mut dispatcher = MessageDispatcher::new();
+        dispatcher.set_spill(Arc::new(MemorySpill::default()));
+        dispatcher.set_capacity(b"topic".to_vec(), 2);
+        let mut reject = dispatcher.subscribe(b"topic".to_vec());
+        let mut drop_oldest =
+            dispatcher.subscribe_with_policy(b"topic".to_vec(), OverflowPolicy::DropOldest);
+        let mut spill = dispatcher.subscribe_with_policy(b"topic".to_vec(), OverflowPolicy::Spill);
+
+        assert_eq!(dispatch(&mut dispatcher, b"topic"), 3);
+        assert_eq!(dispatch(&mut dispatcher, b"topic"), 3);
+        // The rejecting subscriber is not counted once it's full
+        assert_eq!(dispatch(&mut dispatcher, b"topic"), 2);
+        assert_eq!(dispatch(&mut dispatcher, b"topic"), 2);
+
+        let stats = dispatcher.topic_stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].subscribers, 3);
+        assert_eq!(
+            stats[0].stats,
+            ChannelStats {
+                depth: 8,
+                dropped: 2,
+                rejected: 2,
+                spilled: 2,
+            }
+        );
+
+        assert_eq!(indexes(&mut reject), vec![0, 1]);
+        assert_eq!(indexes(&mut drop_oldest), vec![2, 3]);
+        // The spilled messages are refilled in order
+        assert_eq!(indexes(&mut spill), vec![0, 1, 2, 3]);
+        assert_eq!(dispatcher.topic_stats()[0].stats.depth, 0);
+    }
+}
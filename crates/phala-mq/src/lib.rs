@@ -5,6 +5,7 @@ pub extern crate serde_sgx as serde;
 
 extern crate alloc;
 
+pub mod location;
 mod signer;
 pub mod types;
 
@@ -14,15 +15,22 @@ mod dispatcher;
 mod send_queue;
 #[cfg(any(feature = "queue", feature = "dispatcher"))]
 mod simple_mpsc;
+#[cfg(feature = "queue")]
+pub mod wal;
 
 #[cfg(feature = "dispatcher")]
-pub use dispatcher::{MessageDispatcher, TypedReceiveError, TypedReceiver};
+pub use dispatcher::{
+    Matcher, MessageDispatcher, MessageSpill, OverflowPolicy, TopicStats, TypedReceiveError,
+    TypedReceiver,
+};
 #[cfg(feature = "queue")]
 pub use send_queue::{MessageChannel, MessageSendQueue};
 #[cfg(any(feature = "queue", feature = "dispatcher"))]
-pub use simple_mpsc::{ReceiveError, Receiver};
+pub use simple_mpsc::{ChannelStats, ReceiveError, Receiver, Spill};
 
-pub use signer::MessageSigner;
+#[cfg(feature = "signers")]
+pub use signer::IdentityVerifier;
+pub use signer::{MessageSignature, MessageSigner, MessageVerifier};
 
 pub use types::*;
 
@@ -0,0 +1,120 @@
+//! The locations of the remote senders, e.g. parachains sending messages over XCM.
+//!
+//! `MultiLocation` mirrors the SCALE encoding of the XCM v1 `MultiLocation`, so the locations
+//! received over XCM can be re-decoded here without depending on XCM. Only the junctions which
+//! can identify a sender are supported, the locations with a `Plurality` fail to decode.
+
+use alloc::vec::Vec;
+use parity_scale_codec::{Decode, Encode, EncodeLike, Error, Input, Output};
+
+/// The max number of junctions in the interior of a location, as in XCM.
+pub const MAX_JUNCTIONS: usize = 8;
+
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum NetworkId {
+    Any,
+    Named(Vec<u8>),
+    Polkadot,
+    Kusama,
+}
+
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Junction {
+    Parachain(#[codec(compact)] u32),
+    AccountId32 {
+        network: NetworkId,
+        id: [u8; 32],
+    },
+    AccountIndex64 {
+        network: NetworkId,
+        #[codec(compact)]
+        index: u64,
+    },
+    AccountKey20 {
+        network: NetworkId,
+        key: [u8; 20],
+    },
+    PalletInstance(u8),
+    GeneralIndex(#[codec(compact)] u128),
+    GeneralKey(Vec<u8>),
+    OnlyChild,
+}
+
+/// A location relative to the chain.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MultiLocation {
+    /// The number of levels up to the common ancestor, e.g. 1 for the relay chain.
+    pub parents: u8,
+    /// The junctions down from the ancestor, up to `MAX_JUNCTIONS` of them.
+    pub interior: Vec<Junction>,
+}
+
+impl MultiLocation {
+    /// The location of a sibling parachain, or somewhere inside it.
+    pub fn sibling(para_id: u32, interior: impl IntoIterator<Item = Junction>) -> Self {
+        let mut junctions = alloc::vec![Junction::Parachain(para_id)];
+        junctions.extend(interior);
+        MultiLocation {
+            parents: 1,
+            interior: junctions,
+        }
+    }
+
+    /// Returns the id of the sibling parachain the location is in, if any
+    pub fn sibling_parachain(&self) -> Option<u32> {
+        match (self.parents, self.interior.first()) {
+            (1, Some(Junction::Parachain(para_id))) => Some(*para_id),
+            _ => None,
+        }
+    }
+}
+
+impl Encode for MultiLocation {
+    fn encode_to<W: Output + ?Sized>(&self, dest: &mut W) {
+        self.parents.encode_to(dest);
+        // The variant index of `Junctions` is the number of junctions
+        (self.interior.len() as u8).encode_to(dest);
+        for junction in self.interior.iter() {
+            junction.encode_to(dest);
+        }
+    }
+}
+
+impl EncodeLike for MultiLocation {}
+
+impl Decode for MultiLocation {
+    fn decode<I: Input>(input: &mut I) -> Result<Self, Error> {
+        let parents = u8::decode(input)?;
+        let len = u8::decode(input)? as usize;
+        if len > MAX_JUNCTIONS {
+            return Err("Too many junctions in MultiLocation".into());
+        }
+        let interior = (0..len)
+            .map(|_| Junction::decode(input))
+            .collect::<Result<_, _>>()?;
+        Ok(MultiLocation { parents, interior })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn encoded_as_xcm_v1() {
+        let location = MultiLocation::sibling(2000, vec![Junction::PalletInstance(3)]);
+        // parents, X2, Parachain(compact 2000), PalletInstance(3)
+        let encoded = vec![1, 2, 0, 0x41, 0x1f, 4, 3];
+        assert_eq!(location.encode(), encoded);
+        assert_eq!(
+            MultiLocation::decode(&mut &encoded[..]).ok(),
+            Some(location)
+        );
+
+        // X9
+        assert!(MultiLocation::decode(&mut &[1, 9][..]).is_err());
+        // Plurality
+        assert!(MultiLocation::decode(&mut &[1, 1, 8, 0, 0][..]).is_err());
+    }
+}
@@ -1,4 +1,5 @@
 use crate::types::{Message, MessageToBeSigned, SignedMessage};
+use crate::wal::{CommitError, ConsumedIndex, LogRecord, QueueSnapshot, WriteAheadLog};
 use crate::{MessageOrigin, MessageSigner, Mutex, SenderId};
 use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
 
@@ -8,17 +9,83 @@ struct Channel {
     sequence: u64,
     messages: Vec<SignedMessage>,
     dummy: bool,
+    /// The number of the coming messages already restored from the log, to be skipped.
+    restored: u64,
 }
 
 #[derive(Clone, Default)]
 pub struct MessageSendQueue {
     inner: Arc<Mutex<BTreeMap<SenderId, Channel>>>,
+    log: Option<Arc<dyn WriteAheadLog>>,
+    /// The last block consumed before the restart, up to which the replayed messages are skipped.
+    replay_until: Option<u32>,
 }
 
 impl MessageSendQueue {
     pub fn new() -> Self {
         MessageSendQueue {
             inner: Default::default(),
+            log: None,
+            replay_until: None,
+        }
+    }
+
+    /// Creates a queue restored from the snapshot of its log, recording the changes to the log.
+    ///
+    /// The messages of each sender are restored up to the sequence in the snapshot. As many
+    /// messages enqueued by the sender afterwards, until the end of the last block consumed before
+    /// the restart, are expected to be the same ones, enqueued again while replaying the blocks,
+    /// and are skipped.
+    pub fn with_log(log: Arc<dyn WriteAheadLog>, snapshot: QueueSnapshot) -> Self {
+        let inner = snapshot
+            .senders
+            .into_iter()
+            .map(|(sender, snapshot)| {
+                let channel = Channel {
+                    sequence: snapshot.next_sequence,
+                    messages: snapshot.messages,
+                    dummy: false,
+                    restored: snapshot.next_sequence,
+                };
+                (sender, channel)
+            })
+            .collect();
+        MessageSendQueue {
+            inner: Arc::new(Mutex::new(inner)),
+            log: Some(log),
+            replay_until: snapshot.consumed.map(|index| index.block_number),
+        }
+    }
+
+    /// Records the ingress messages consumed in the block, and commits the log.
+    ///
+    /// The replay is over at the end of the last block consumed before the restart, so the
+    /// messages enqueued afterwards are no longer skipped.
+    pub fn end_block(&self, block_number: u32, consumed: u64) -> Result<(), CommitError> {
+        let log = match &self.log {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+        log.append(&LogRecord::Consumed(ConsumedIndex {
+            block_number,
+            count: consumed,
+        }));
+        if self
+            .replay_until
+            .map_or(true, |replay_until| block_number >= replay_until)
+        {
+            for channel in self.inner.lock().values_mut() {
+                channel.restored = 0;
+            }
+        }
+        log.commit()
+    }
+
+    /// Commits the log, if any.
+    pub fn commit_log(&self) -> Result<(), CommitError> {
+        match &self.log {
+            Some(log) => log.commit(),
+            None => Ok(()),
         }
     }
 
@@ -32,10 +99,22 @@ impl MessageSendQueue {
         constructor: impl FnOnce(u64) -> SignedMessage,
     ) {
         let mut inner = self.inner.lock();
-        let entry = inner.entry(sender).or_default();
+        let entry = inner.entry(sender.clone()).or_default();
+        if entry.restored > 0 {
+            entry.restored -= 1;
+            return;
+        }
         if !entry.dummy {
             let message = constructor(entry.sequence);
+            if let Some(log) = &self.log {
+                log.append(&LogRecord::Enqueued(message.clone()));
+            }
             entry.messages.push(message);
+        } else if let Some(log) = &self.log {
+            log.append(&LogRecord::Dropped {
+                sender,
+                sequence: entry.sequence,
+            });
         }
         entry.sequence += 1;
     }
@@ -62,6 +141,16 @@ impl MessageSendQueue {
             .collect()
     }
 
+    /// The messages grouped by sender, in the order of their priority to be synced to the chain.
+    ///
+    /// The senders of a higher `MessageClass` come first, and the ones of the same class are
+    /// ordered by origin.
+    pub fn all_messages_prioritized(&self) -> Vec<(MessageOrigin, Vec<SignedMessage>)> {
+        let mut messages: Vec<_> = self.all_messages_grouped().into_iter().collect();
+        messages.sort_by_key(|(sender, _)| sender.class());
+        messages
+    }
+
     pub fn messages(&self, sender: &SenderId) -> Vec<SignedMessage> {
         let inner = self.inner.lock();
         inner.get(sender).map(|x| x.messages.clone()).unwrap_or_default()
@@ -79,7 +168,17 @@ impl MessageSendQueue {
         let mut inner = self.inner.lock();
         for (k, v) in inner.iter_mut() {
             let seq = next_sequence_for(k);
+            let count = v.messages.len();
             v.messages.retain(|msg| msg.sequence >= seq);
+            if v.messages.len() == count {
+                continue;
+            }
+            if let Some(log) = &self.log {
+                log.append(&LogRecord::Purged {
+                    sender: k.clone(),
+                    next_sequence: seq,
+                });
+            }
         }
     }
 }
@@ -107,6 +206,15 @@ mod msg_channel {
         }
 
         pub fn send_data(&self, payload: Vec<u8>, to: impl Into<Path>) {
+            self.send_data_until(payload, to, None)
+        }
+
+        /// Sends the data to be handled on chain no later than the block `expiry`.
+        pub fn send_data_with_expiry(&self, payload: Vec<u8>, to: impl Into<Path>, expiry: u32) {
+            self.send_data_until(payload, to, Some(expiry))
+        }
+
+        fn send_data_until(&self, payload: Vec<u8>, to: impl Into<Path>, expiry: Option<u32>) {
             let sender = self.sender.clone();
             let signer = &self.signer;
 
@@ -115,6 +223,7 @@ mod msg_channel {
                     sender,
                     destination: to.into().into(),
                     payload,
+                    expiry,
                 };
                 let be_signed = MessageToBeSigned {
                     message: &message,
@@ -136,6 +245,11 @@ mod msg_channel {
             self.send_data(message.encode(), to)
         }
 
+        /// Sends the message to be handled on chain no later than the block `expiry`.
+        pub fn sendto_with_expiry<M: Encode>(&self, message: &M, to: impl Into<Path>, expiry: u32) {
+            self.send_data_with_expiry(message.encode(), to, expiry)
+        }
+
         pub fn send<M: Encode + BindTopic>(&self, message: &M) {
             self.sendto(message, <M as BindTopic>::topic())
         }
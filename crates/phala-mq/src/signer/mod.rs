@@ -1,4 +1,5 @@
 use alloc::vec::Vec;
+use parity_scale_codec::{Decode, DecodeAll, Encode};
 
 use crate::SignedMessage;
 
@@ -9,15 +10,126 @@ pub trait MessageVerifier {
     fn verify(&self, message: &SignedMessage) -> bool;
 }
 
+/// The signature of a message, tagged with its scheme.
+///
+/// The tagged signatures are encoded like `sp_runtime::MultiSignature`. For compatibility, the
+/// signature of a message can also be a bare 64-byte sr25519 one.
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+pub enum MessageSignature {
+    Ed25519([u8; 64]),
+    Sr25519([u8; 64]),
+    Ecdsa([u8; 65]),
+}
+
+impl MessageSignature {
+    /// Parses the signature of a message, bare or tagged.
+    pub fn parse(raw: &[u8]) -> Option<Self> {
+        if raw.len() == 64 {
+            let mut signature = [0u8; 64];
+            signature.copy_from_slice(raw);
+            return Some(Self::Sr25519(signature));
+        }
+        Self::decode_all(raw).ok()
+    }
+}
+
+#[cfg(feature = "signers")]
+pub use signers::IdentityVerifier;
+
 #[cfg(feature = "signers")]
 mod signers {
-    use super::MessageSigner;
+    use super::{MessageSignature, MessageSigner, MessageVerifier};
+    use crate::SignedMessage;
     use alloc::vec::Vec;
-    use sp_core::{crypto::Pair as PairTrait, sr25519};
+    use parity_scale_codec::Encode;
+    use sp_core::{crypto::Pair as PairTrait, ecdsa, ed25519, hashing::blake2_256, sr25519};
 
+    /// Signs with bare signatures, as the existing sr25519 signers do.
     impl MessageSigner for sr25519::Pair {
         fn sign(&self, data: &[u8]) -> Vec<u8> {
             PairTrait::sign(self, data).0.to_vec()
         }
     }
+
+    impl MessageSigner for ed25519::Pair {
+        fn sign(&self, data: &[u8]) -> Vec<u8> {
+            MessageSignature::Ed25519(PairTrait::sign(self, data).0).encode()
+        }
+    }
+
+    impl MessageSigner for ecdsa::Pair {
+        fn sign(&self, data: &[u8]) -> Vec<u8> {
+            MessageSignature::Ecdsa(PairTrait::sign(self, data).0).encode()
+        }
+    }
+
+    /// Verifies the messages signed by the owner of a 32-byte identity, with any scheme.
+    ///
+    /// As for the `AccountId32` of a `MultiSigner`, the identity is the public key for sr25519
+    /// and ed25519, and the blake2_256 hash of the compressed public key for ECDSA.
+    pub struct IdentityVerifier(pub [u8; 32]);
+
+    impl MessageVerifier for IdentityVerifier {
+        fn verify(&self, message: &SignedMessage) -> bool {
+            let data = message.data_be_signed();
+            match MessageSignature::parse(&message.signature) {
+                Some(MessageSignature::Sr25519(signature)) => sr25519::Pair::verify(
+                    &sr25519::Signature::from_raw(signature),
+                    &data,
+                    &sr25519::Public::from_raw(self.0),
+                ),
+                Some(MessageSignature::Ed25519(signature)) => ed25519::Pair::verify(
+                    &ed25519::Signature::from_raw(signature),
+                    &data,
+                    &ed25519::Public::from_raw(self.0),
+                ),
+                Some(MessageSignature::Ecdsa(signature)) => {
+                    match ecdsa::Signature::from_raw(signature).recover(&data) {
+                        Some(public) => blake2_256(public.as_ref()) == self.0,
+                        None => false,
+                    }
+                }
+                None => false,
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::{Message, MessageOrigin};
+        use alloc::vec;
+
+        fn signed_message(signer: &impl MessageSigner) -> SignedMessage {
+            let mut message = SignedMessage {
+                message: Message::new(MessageOrigin::Gatekeeper, b"topic".to_vec(), vec![]),
+                sequence: 0,
+                signature: vec![],
+            };
+            message.signature = signer.sign(&message.data_be_signed());
+            message
+        }
+
+        #[test]
+        fn signatures_are_verified_by_scheme() {
+            let sr25519_pair = sr25519::Pair::from_seed(&[1; 32]);
+            let ed25519_pair = ed25519::Pair::from_seed(&[1; 32]);
+            let ecdsa_pair = ecdsa::Pair::from_seed(&[1; 32]);
+            let sr25519_id = IdentityVerifier(sr25519_pair.public().0);
+            let ed25519_id = IdentityVerifier(ed25519_pair.public().0);
+            let ecdsa_id = IdentityVerifier(blake2_256(ecdsa_pair.public().as_ref()));
+
+            // The sr25519 signatures stay bare
+            assert_eq!(signed_message(&sr25519_pair).signature.len(), 64);
+            assert!(sr25519_id.verify(&signed_message(&sr25519_pair)));
+            assert!(ed25519_id.verify(&signed_message(&ed25519_pair)));
+            assert!(ecdsa_id.verify(&signed_message(&ecdsa_pair)));
+
+            assert!(!sr25519_id.verify(&signed_message(&ed25519_pair)));
+            assert!(!ed25519_id.verify(&signed_message(&ecdsa_pair)));
+            let mut tampered = signed_message(&ecdsa_pair);
+            tampered.sequence = 1;
+            assert!(!ecdsa_id.verify(&tampered));
+        }
+    }
 }
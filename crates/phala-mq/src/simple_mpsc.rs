@@ -4,10 +4,44 @@ use alloc::sync::Arc;
 use alloc::vec::Vec;
 use derive_more::Display;
 
+/// Stores the values overflowing the channels outside of the memory, in FIFO order per key.
+///
+/// A store must not lose values: `pop` returns None only if no value is left under the key.
+pub trait Spill<T>: Send + Sync {
+    fn push(&self, key: &[u8], value: T);
+    fn pop(&self, key: &[u8]) -> Option<T>;
+}
+
+/// What a full channel does with a new value.
+pub enum Overflow<T> {
+    /// Drops the oldest value in the channel to make room for the new one.
+    DropOldest,
+    /// Drops the new value.
+    Reject,
+    /// Moves the new value to the spill store under the key, until the receiver catches up.
+    Spill(Arc<dyn Spill<T>>, Vec<u8>),
+}
+
+/// The counters of a channel.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelStats {
+    /// The number of values in the channel, including the spilled ones.
+    pub depth: u64,
+    pub dropped: u64,
+    pub rejected: u64,
+    pub spilled: u64,
+}
+
 struct Channel<T> {
     deque: VecDeque<T>,
     sender_count: usize,
     receiver_gone: bool,
+    /// The max number of values kept in memory, unbounded if None.
+    capacity: Option<usize>,
+    overflow: Overflow<T>,
+    /// The number of values in the spill store.
+    spilled_len: usize,
+    stats: ChannelStats,
 }
 
 impl<T> Channel<T> {
@@ -20,6 +54,58 @@ impl<T> Channel<T> {
             deque: VecDeque::with_capacity(cap),
             sender_count: 1,
             receiver_gone: false,
+            capacity: None,
+            overflow: Overflow::Reject,
+            spilled_len: 0,
+            stats: Default::default(),
+        }
+    }
+
+    fn push(&mut self, value: T) -> Result<(), SendError> {
+        if let Overflow::Spill(spill, key) = &self.overflow {
+            // Keep the order with the values already spilled
+            if self.spilled_len > 0 {
+                spill.push(key, value);
+                self.spilled_len += 1;
+                self.stats.spilled += 1;
+                return Ok(());
+            }
+        }
+        let full = matches!(self.capacity, Some(cap) if self.deque.len() >= cap);
+        if full {
+            match &self.overflow {
+                Overflow::DropOldest => {
+                    self.deque.pop_front();
+                    self.stats.dropped += 1;
+                }
+                Overflow::Reject => {
+                    self.stats.rejected += 1;
+                    return Err(SendError::Full);
+                }
+                Overflow::Spill(spill, key) => {
+                    spill.push(key, value);
+                    self.spilled_len += 1;
+                    self.stats.spilled += 1;
+                    return Ok(());
+                }
+            }
+        }
+        self.deque.push_back(value);
+        Ok(())
+    }
+
+    /// Moves the spilled values back to the memory once it's drained.
+    fn refill(&mut self) {
+        if !self.deque.is_empty() || self.spilled_len == 0 {
+            return;
+        }
+        if let Overflow::Spill(spill, key) = &self.overflow {
+            let cap = self.capacity.unwrap_or(usize::MAX);
+            while self.spilled_len > 0 && self.deque.len() < cap {
+                let value = spill.pop(key).expect("The spill store lost values");
+                self.spilled_len -= 1;
+                self.deque.push_back(value);
+            }
         }
     }
 }
@@ -31,6 +117,8 @@ pub struct Sender<T>(ArcCh<T>);
 pub enum SendError {
     #[display(fmt = "The receiver of the channel has gone")]
     ReceiverGone,
+    #[display(fmt = "The channel is full")]
+    Full,
 }
 
 impl<T> Sender<T> {
@@ -39,15 +127,36 @@ impl<T> Sender<T> {
         if ch.receiver_gone {
             Err(SendError::ReceiverGone)
         } else {
-            ch.deque.push_back(value);
             // TODO.kevin: awake the receiver task
-            Ok(())
+            ch.push(value)
         }
     }
 
     pub fn clear(&self) -> usize {
         let mut ch = self.0.lock();
-        ch.deque.drain(..).count()
+        let mut count = 0;
+        loop {
+            ch.refill();
+            if ch.deque.is_empty() {
+                break count;
+            }
+            count += ch.deque.drain(..).count();
+        }
+    }
+
+    /// Bounds the number of values kept in memory, applied to the values sent afterwards.
+    pub fn set_capacity(&self, capacity: Option<usize>, overflow: Overflow<T>) {
+        let mut ch = self.0.lock();
+        ch.capacity = capacity;
+        ch.overflow = overflow;
+    }
+
+    pub fn stats(&self) -> ChannelStats {
+        let ch = self.0.lock();
+        ChannelStats {
+            depth: (ch.deque.len() + ch.spilled_len) as u64,
+            ..ch.stats
+        }
     }
 }
 
@@ -80,6 +189,7 @@ impl<T> Receiver<T> {
     #[allow(clippy::should_implement_trait)]
     pub fn try_next(&mut self) -> Result<Option<T>, ReceiveError> {
         let mut ch = self.0.lock();
+        ch.refill();
         if let Some(value) = ch.deque.pop_front() {
             return Ok(Some(value));
         } else if ch.sender_count == 0 {
@@ -90,17 +200,25 @@ impl<T> Receiver<T> {
 
     pub fn drain(&mut self) -> impl Iterator<Item = T> {
         let mut ch = self.0.lock();
-        ch.deque.drain(..).collect::<Vec<_>>().into_iter()
+        let mut values = Vec::new();
+        loop {
+            ch.refill();
+            if ch.deque.is_empty() {
+                break values.into_iter();
+            }
+            values.extend(ch.deque.drain(..));
+        }
     }
 
     pub fn clear(&mut self) {
-        let _ = self.0.lock().deque.drain(..);
+        let _ = self.drain();
     }
 }
 
 impl<T: Seq> Receiver<T> {
     pub fn peek_ind(&self) -> Result<Option<u64>, ReceiveError> {
-        let ch = self.0.lock();
+        let mut ch = self.0.lock();
+        ch.refill();
         if let Some(value) = ch.deque.get(0) {
             return Ok(Some(value.seq()));
         } else if ch.sender_count == 0 {
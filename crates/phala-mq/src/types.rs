@@ -3,10 +3,12 @@ use alloc::string::String;
 use core::hash::{Hash, Hasher};
 use primitive_types::H256;
 
-use parity_scale_codec::{Decode, Encode};
+use parity_scale_codec::{Decode, Encode, Error as CodecError, Input, Output};
 use sp_core::crypto::{AccountId32, UncheckedFrom};
 use derive_more::Display;
 
+use crate::location::MultiLocation;
+
 pub type Path = Vec<u8>;
 pub type SenderId = MessageOrigin;
 pub type ContractId = H256;
@@ -17,8 +19,6 @@ pub fn contract_id256(id: u32) -> ContractId {
 }
 
 /// The origin of a Phala message
-// TODO: should we use XCM MultiLocation directly?
-// [Reference](https://github.com/paritytech/xcm-format#multilocation-universal-destination-identifiers)
 #[derive(Encode, Decode, Debug, Clone, Eq, PartialOrd, Ord, Display)]
 pub enum MessageOrigin {
     /// Runtime pallets (identified by pallet name)
@@ -33,13 +33,26 @@ pub enum MessageOrigin {
     /// A user
     #[display(fmt = "AccountId({})", "hex::encode(_0)")]
     AccountId(AccountId),
-    /// A remote location (parachain, etc.)
+    /// A remote location (parachain, etc.), as the encoded `location::MultiLocation`
     #[display(fmt = "MultiLocation({})", "hex::encode(_0)")]
     MultiLocation(Vec<u8>),
     /// All gatekeepers share the same origin
     Gatekeeper,
 }
 
+/// The class of the messages of an origin, deciding their priority to be synced to the chain.
+///
+/// The classes are ordered from the highest priority to the lowest.
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MessageClass {
+    /// The messages keeping the system running, e.g. the heartbeats of the workers.
+    System,
+    /// The messages of the gatekeepers.
+    Gatekeeper,
+    /// The messages of the contracts.
+    Contract,
+}
+
 impl Hash for MessageOrigin {
     fn hash<H: Hasher>(&self, state: &mut H) {
         let encoded = Encode::encode(self);
@@ -68,6 +81,19 @@ impl MessageOrigin {
         matches!(self, Self::Contract(_) | Self::Worker(_) | Self::Gatekeeper)
     }
 
+    /// Builds the origin of a remote location
+    pub fn location(location: &MultiLocation) -> Self {
+        Self::MultiLocation(location.encode())
+    }
+
+    /// Returns the remote location of the origin, if it's from a valid one
+    pub fn as_location(&self) -> Option<MultiLocation> {
+        match self {
+            Self::MultiLocation(encoded) => Decode::decode(&mut &encoded[..]).ok(),
+            _ => None,
+        }
+    }
+
     /// Returns if the origin is from a Pallet
     pub fn is_pallet(&self) -> bool {
         matches!(self, Self::Pallet(_))
@@ -78,6 +104,15 @@ impl MessageOrigin {
         matches!(self, Self::Gatekeeper)
     }
 
+    /// Returns the class of the messages from the origin
+    pub fn class(&self) -> MessageClass {
+        match self {
+            Self::Gatekeeper => MessageClass::Gatekeeper,
+            Self::Contract(_) => MessageClass::Contract,
+            _ => MessageClass::System,
+        }
+    }
+
     /// Returns the account id if the origin is from a user, or `Err(BadOrigin)` otherwise
     pub fn account(self) -> Result<AccountId32, BadOrigin> {
         match self {
@@ -217,11 +252,79 @@ macro_rules! bind_contract32 {
     }
 }
 
-#[derive(Encode, Decode, Debug, Clone, Eq, PartialEq)]
+/// The leading byte of the encoding of a message with an expiry
+///
+/// A message without expiry is encoded as it always was, starting with the variant index of its
+/// sender, so the existing messages and the data signed for them are encoded the same. The tag is
+/// never a variant index of `MessageOrigin`.
+const EXPIRING_MESSAGE_TAG: u8 = 0xff;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Message {
     pub sender: SenderId,
     pub destination: Topic,
     pub payload: Vec<u8>,
+    /// The number of the last block the message can be handled on chain, never expiring if None.
+    pub expiry: Option<u32>,
+}
+
+impl Encode for Message {
+    fn encode_to<T: Output + ?Sized>(&self, dest: &mut T) {
+        if let Some(expiry) = self.expiry {
+            dest.push_byte(EXPIRING_MESSAGE_TAG);
+            expiry.encode_to(dest);
+        }
+        self.sender.encode_to(dest);
+        self.destination.encode_to(dest);
+        self.payload.encode_to(dest);
+    }
+}
+
+impl Decode for Message {
+    fn decode<I: Input>(input: &mut I) -> Result<Self, CodecError> {
+        let first = input.read_byte()?;
+        let (expiry, sender) = if first == EXPIRING_MESSAGE_TAG {
+            (Some(u32::decode(input)?), SenderId::decode(input)?)
+        } else {
+            let mut input = Unread {
+                byte: Some(first),
+                input,
+            };
+            (None, SenderId::decode(&mut input)?)
+        };
+        Ok(Message {
+            sender,
+            destination: Decode::decode(input)?,
+            payload: Decode::decode(input)?,
+            expiry,
+        })
+    }
+}
+
+/// An input with a byte put back in front of it
+struct Unread<'a, I> {
+    byte: Option<u8>,
+    input: &'a mut I,
+}
+
+impl<'a, I: Input> Input for Unread<'a, I> {
+    fn remaining_len(&mut self) -> Result<Option<usize>, CodecError> {
+        let unread = self.byte.is_some() as usize;
+        Ok(self.input.remaining_len()?.map(|len| len + unread))
+    }
+
+    fn read(&mut self, into: &mut [u8]) -> Result<(), CodecError> {
+        match (self.byte.take(), into.split_first_mut()) {
+            (Some(byte), Some((first, rest))) => {
+                *first = byte;
+                self.input.read(rest)
+            }
+            (byte, _) => {
+                self.byte = byte;
+                self.input.read(into)
+            }
+        }
+    }
 }
 
 impl Message {
@@ -234,9 +337,21 @@ impl Message {
             sender: sender.into(),
             destination: Topic::new(destination),
             payload,
+            expiry: None,
         }
     }
 
+    /// Sets the last block the message can be handled on chain.
+    pub fn with_expiry(mut self, block_number: u32) -> Self {
+        self.expiry = Some(block_number);
+        self
+    }
+
+    /// Returns if the message can't be handled at the block anymore
+    pub fn is_expired(&self, block_number: u32) -> bool {
+        matches!(self.expiry, Some(expiry) if block_number > expiry)
+    }
+
     pub fn decode_payload<T: Decode>(&self) -> Option<T> {
         Decode::decode(&mut &self.payload[..]).ok()
     }
@@ -285,3 +400,35 @@ impl<'a> MessageToBeSigned<'a> {
         self.encode()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn messages_without_expiry_are_encoded_as_before() {
+        #[derive(Encode)]
+        struct LegacyMessage {
+            sender: SenderId,
+            destination: Topic,
+            payload: Vec<u8>,
+        }
+
+        let message = Message::new(MessageOrigin::Gatekeeper, &b"topic"[..], vec![1, 2]);
+        let legacy = LegacyMessage {
+            sender: message.sender.clone(),
+            destination: message.destination.clone(),
+            payload: message.payload.clone(),
+        };
+        let encoded = legacy.encode();
+        assert_eq!(message.encode(), encoded);
+        let decoded = Message::decode(&mut &encoded[..]).ok();
+        assert_eq!(decoded, Some(message.clone()));
+
+        let message = message.with_expiry(42);
+        let encoded = message.encode();
+        assert_eq!(encoded[0], EXPIRING_MESSAGE_TAG);
+        assert_eq!(Message::decode(&mut &encoded[..]).ok(), Some(message));
+    }
+}
@@ -0,0 +1,112 @@
+//! An optional write-ahead log of the send queue.
+//!
+//! The send queue keeps its messages in memory, so the messages not yet accepted on chain are
+//! lost when pRuntime restarts, until it replays the chain far enough to enqueue them again. With a
+//! log attached, the queue records each change, along with the ingress messages consumed in each
+//! block. The records are committed at the end of each block and before the egress messages are
+//! handed out, so no message leaves pRuntime before it's durable.
+//!
+//! A restarted pRuntime restores the queue from the log, and skips the messages enqueued again
+//! while replaying the blocks up to the last consumed one, so the egress resumes at the exact
+//! sequence of each sender. The skipped messages are counted rather than compared, which relies
+//! on the replay enqueuing the same messages in the same order, as the processing of the blocks is
+//! deterministic.
+
+use crate::types::SignedMessage;
+use crate::SenderId;
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use parity_scale_codec::{Decode, Encode};
+
+/// A change to the send queue.
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+pub enum LogRecord {
+    /// A message was enqueued.
+    Enqueued(SignedMessage),
+    /// A message was dropped by a sender in dummy mode, taking up its sequence.
+    Dropped { sender: SenderId, sequence: u64 },
+    /// The messages of the sender before the sequence were accepted on chain and purged.
+    Purged {
+        sender: SenderId,
+        next_sequence: u64,
+    },
+    /// The ingress messages of the block were dispatched and consumed.
+    Consumed(ConsumedIndex),
+}
+
+/// The ingress messages consumed up to the end of a block.
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConsumedIndex {
+    pub block_number: u32,
+    /// The number of the ingress messages of the block.
+    pub count: u64,
+}
+
+/// Failed to make the log durable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommitError(pub String);
+
+pub trait WriteAheadLog: Send + Sync {
+    /// Appends a record to the log, which becomes durable with the next `commit`.
+    fn append(&self, record: &LogRecord);
+
+    /// Makes the records appended so far durable.
+    fn commit(&self) -> Result<(), CommitError>;
+}
+
+/// The state of the send queue, rebuilt from its log.
+#[derive(Encode, Decode, Debug, Default, Clone, PartialEq, Eq)]
+pub struct QueueSnapshot {
+    pub senders: BTreeMap<SenderId, SenderSnapshot>,
+    /// The last block whose ingress messages were consumed.
+    pub consumed: Option<ConsumedIndex>,
+}
+
+#[derive(Encode, Decode, Debug, Default, Clone, PartialEq, Eq)]
+pub struct SenderSnapshot {
+    /// The sequence of the next message of the sender.
+    pub next_sequence: u64,
+    /// The messages not yet accepted on chain.
+    pub messages: Vec<SignedMessage>,
+}
+
+impl QueueSnapshot {
+    pub fn apply(&mut self, record: &LogRecord) {
+        match record {
+            LogRecord::Enqueued(message) => {
+                let sender = self
+                    .senders
+                    .entry(message.message.sender.clone())
+                    .or_default();
+                sender.next_sequence = message.sequence + 1;
+                sender.messages.push(message.clone());
+            }
+            LogRecord::Dropped { sender, sequence } => {
+                self.senders
+                    .entry(sender.clone())
+                    .or_default()
+                    .next_sequence = sequence + 1;
+            }
+            LogRecord::Purged {
+                sender,
+                next_sequence,
+            } => {
+                if let Some(sender) = self.senders.get_mut(sender) {
+                    sender
+                        .messages
+                        .retain(|message| message.sequence >= *next_sequence);
+                }
+            }
+            LogRecord::Consumed(index) => {
+                self.consumed = Some(*index);
+            }
+        }
+    }
+
+    /// The number of messages not yet accepted on chain.
+    pub fn count_messages(&self) -> usize {
+        self.senders
+            .values()
+            .map(|sender| sender.messages.len())
+            .sum()
+    }
+}
@@ -18,10 +18,17 @@ pub const GEOLOCATION: ContractId32 = 8;
 pub const GUESS_NUMBER: ContractId32 = 100;
 pub const BTC_PRICE_BOT: ContractId32 = 101;
 
+/// The version of `ContractQueryHead`, the one implied by the requests not declaring a version.
+pub const QUERY_HEAD_V1: u32 = 1;
+/// The version of `ContractQueryHeadV2`.
+pub const QUERY_HEAD_V2: u32 = 2;
+
 /// Contract query request parameters, to be encrypted.
+///
+/// The request carrying the query declares the version of its head.
 #[derive(Encode, Decode, Debug)]
-pub struct ContractQuery<Data> {
-    pub head: ContractQueryHead,
+pub struct ContractQuery<Data, Head = ContractQueryHead> {
+    pub head: Head,
     /// The request data.
     pub data: Data,
 }
@@ -32,9 +39,49 @@ pub struct ContractQueryHead {
     /// The contract id.
     pub id: ContractId,
     /// A random byte array generated by the client.
+    ///
+    /// A signed query reusing a recent nonce of its signer is rejected as a replay.
     pub nonce: [u8; 32],
 }
 
+/// Contract query head bounding the lifetime of the query
+#[derive(Encode, Decode, Debug)]
+pub struct ContractQueryHeadV2 {
+    /// The contract id.
+    pub id: ContractId,
+    /// A random byte array generated by the client, as in `ContractQueryHead`.
+    pub nonce: [u8; 32],
+    /// The last block in which the query is valid.
+    pub valid_until_block: u32,
+}
+
+impl ContractQueryHead {
+    /// Decodes a head of the given version.
+    ///
+    /// Returns the head with the last block in which the query is valid, if bounded. Version 0
+    /// stands for the requests not declaring a version.
+    pub fn decode_versioned(
+        version: u32,
+        input: &mut &[u8],
+    ) -> Result<(Self, Option<u32>), codec::Error> {
+        match version {
+            0 | QUERY_HEAD_V1 => Ok((Self::decode(input)?, None)),
+            QUERY_HEAD_V2 => {
+                let head = ContractQueryHeadV2::decode(input)?;
+                let valid_until_block = Some(head.valid_until_block);
+                Ok((
+                    Self {
+                        id: head.id,
+                        nonce: head.nonce,
+                    },
+                    valid_until_block,
+                ))
+            }
+            _ => Err("Unsupported query head version".into()),
+        }
+    }
+}
+
 /// Contract query response, to be encrypted.
 #[derive(Encode, Decode, Debug)]
 pub struct ContractQueryResponse<Data> {
@@ -44,6 +91,26 @@ pub struct ContractQueryResponse<Data> {
     pub result: Data,
 }
 
+/// Subscription to the events of a contract, to be encrypted.
+#[derive(Encode, Decode, Debug)]
+pub struct ContractEventsSubscription {
+    /// The contract id.
+    pub contract: ContractId,
+    /// The sequence of the first event to receive.
+    pub from_sequence: u64,
+}
+
+/// An event published by a contract, to be encrypted to the subscriber.
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+pub struct ContractEvent {
+    /// The sequence of the event among the events of the contract, starting from 0.
+    pub sequence: u64,
+    /// The block in which the event was published.
+    pub block_number: u32,
+    /// The SCALE encoded event.
+    pub data: Vec<u8>,
+}
+
 pub struct Data(pub Vec<u8>);
 
 impl Encode for Data {
@@ -66,6 +133,8 @@ pub enum ContractQueryError {
     DecodeError,
     /// Other errors reported during the contract query execution.
     OtherError(String),
+    /// The query ran out of its budget.
+    Timeout,
 }
 
 impl From<ContractQueryError> for prpc::server::Error {
@@ -77,3 +146,8 @@ impl From<ContractQueryError> for prpc::server::Error {
 pub fn command_topic(id: ContractId) -> Vec<u8> {
     format!("phala/contract/{}/command", hex::encode(&id)).as_bytes().to_vec()
 }
+
+/// The topic the workers running a contract report the results of its aggregated side tasks to.
+pub fn side_task_topic(id: ContractId) -> Vec<u8> {
+    format!("phala/contract/{}/side_task", hex::encode(&id)).as_bytes().to_vec()
+}
@@ -16,15 +16,16 @@ pub mod messaging {
     use alloc::vec::Vec;
     use codec::{Decode, Encode};
     use core::fmt::Debug;
-    use sp_core::U256;
+    use sp_core::{H256, U256};
 
     #[cfg(feature = "enable_serde")]
     use serde::{Deserialize, Serialize};
 
     use super::{EcdhPublicKey, MasterPublicKey, WorkerPublicKey};
     use crate::contract;
+    pub use phala_mq::location::{Junction, MultiLocation, NetworkId};
     pub use phala_mq::types::*;
-    pub use phala_mq::{bind_contract32, bind_topic};
+    pub use phala_mq::{bind_contract32, bind_topic, MessageSignature};
 
     // TODO.kevin: reuse the Payload in secret_channel.rs.
     #[derive(Encode, Decode, Debug)]
@@ -322,6 +323,9 @@ pub mod messaging {
     pub enum SystemEvent {
         WorkerEvent(WorkerEventWithKey),
         HeartbeatChallenge(HeartbeatChallenge),
+        /// pallet-registry --> worker
+        ///  A confidential contract is deployed, the workers should instantiate it.
+        ContractDeployed(ContractDeployment),
     }
 
     impl SystemEvent {
@@ -330,6 +334,67 @@ pub mod messaging {
         }
     }
 
+    #[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+    pub struct ContractDeployment {
+        /// The contract id, derived from the deployer and the salt.
+        pub contract_id: contract::ContractId,
+        /// The account deploying the contract.
+        pub deployer: AccountId,
+        /// The hash of the code in `pallet_registry::ContractCode`.
+        pub code_hash: H256,
+        /// The arguments passed to the constructor.
+        pub init_args: Vec<u8>,
+    }
+
+    bind_topic!(CommandReceiptBatch, b"^phala/contract/receipts");
+    /// The receipts of the commands a contract processed in a block, reported on chain.
+    #[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+    pub struct CommandReceiptBatch {
+        pub block_number: u32,
+        pub receipts: Vec<CompactReceipt>,
+    }
+
+    #[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+    pub struct CompactReceipt {
+        pub sender: MessageOrigin,
+        /// The block the command was pushed on chain in.
+        pub sent_at: u32,
+        /// The index of the command among the messages pushed on chain in the block.
+        pub index: u64,
+        /// The SCALE encoded `TransactionError` of pRuntime if the command failed.
+        pub error: Option<Vec<u8>>,
+    }
+
+    /// The result of an aggregated side task of a contract, reported on chain by one of the
+    /// workers running the contract, to `contract::side_task_topic`.
+    #[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+    pub struct SideTaskResult {
+        /// The id of the task, counted by the contract.
+        pub task_id: u64,
+        /// The SCALE encoded result, or None if the task failed or didn't finish in time.
+        ///
+        /// The result is encrypted with a key derived from the contract key, prefixed with the IV.
+        pub encrypted_value: Option<Vec<u8>>,
+        /// The signature of `data_be_signed` by the contract key.
+        ///
+        /// Only the workers holding the key of the contract can vote on its side tasks.
+        pub signature: crate::Sr25519Signature,
+    }
+
+    impl SideTaskResult {
+        /// The data signed by the contract key, binding the result to the contract and the worker
+        /// reporting it.
+        pub fn data_be_signed(
+            contract: &contract::ContractId,
+            worker: &WorkerPublicKey,
+            task_id: u64,
+            encrypted_value: &Option<Vec<u8>>,
+        ) -> Vec<u8> {
+            const CONTEXT: &[u8] = b"phala/side_task_result";
+            (CONTEXT, contract, worker, task_id, encrypted_value).encode()
+        }
+    }
+
     #[derive(Encode, Decode, Debug, Default, Clone, PartialEq, Eq)]
     pub struct HeartbeatChallenge {
         pub seed: U256,
@@ -452,6 +517,7 @@ pub mod messaging {
     #[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
     pub enum KeyDistribution {
         MasterKeyDistribution(DispatchMasterKeyEvent),
+        ContractKeyDistribution(DispatchContractKeyEvent),
     }
 
     impl KeyDistribution {
@@ -468,6 +534,22 @@ pub mod messaging {
                 iv,
             })
         }
+
+        pub fn contract_key_distribution(
+            dest: WorkerPublicKey,
+            contract_id: contract::ContractId,
+            ecdh_pubkey: EcdhPublicKey,
+            encrypted_contract_key: Vec<u8>,
+            iv: AeadIV,
+        ) -> KeyDistribution {
+            KeyDistribution::ContractKeyDistribution(DispatchContractKeyEvent {
+                dest,
+                contract_id,
+                ecdh_pubkey,
+                encrypted_contract_key,
+                iv,
+            })
+        }
     }
 
     type AeadIV = [u8; 12];
@@ -483,6 +565,20 @@ pub mod messaging {
         pub iv: AeadIV,
     }
 
+    #[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+    pub struct DispatchContractKeyEvent {
+        /// The target to dispatch contract key
+        pub dest: WorkerPublicKey,
+        /// The contract the key belongs to
+        pub contract_id: contract::ContractId,
+        /// The ecdh public key of contract key source
+        pub ecdh_pubkey: EcdhPublicKey,
+        /// Contract key encrypted with aead key
+        pub encrypted_contract_key: Vec<u8>,
+        /// Aead IV
+        pub iv: AeadIV,
+    }
+
     // Messages: Gatekeeper
     bind_topic!(GatekeeperEvent, b"phala/gatekeeper/event");
     #[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
@@ -75,8 +75,11 @@ fn generate_methods<T: Service>(
 
         let method = match (method.client_streaming(), method.server_streaming()) {
             (false, false) => generate_unary(method, proto_path, compile_well_known_types, path),
+            (false, true) => {
+                generate_server_streaming(method, proto_path, compile_well_known_types, path)
+            }
             _ => {
-                panic!("Only unary method supported");
+                panic!("Client streaming RPC not supported");
             }
         };
 
@@ -105,3 +108,24 @@ fn generate_unary<T: Method>(
         }
     }
 }
+
+fn generate_server_streaming<T: Method>(
+    method: &T,
+    proto_path: &str,
+    compile_well_known_types: bool,
+    path: String,
+) -> TokenStream {
+    let ident = format_ident!("{}", method.name());
+    let (request, response) = method.request_response_name(proto_path, compile_well_known_types);
+
+    quote! {
+        /// Returns the messages the server has ready in this round of the stream.
+        pub async fn #ident(
+            &self,
+            request: #request,
+        ) -> Result<alloc::vec::Vec<#response>, prpc::client::Error> {
+            let response = self.client.request(#path, prpc::codec::encode_message_to_vec(&request)).await?;
+            Ok(prpc::codec::decode_stream(&response[..])?)
+        }
+    }
+}
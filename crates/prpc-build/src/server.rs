@@ -114,8 +114,15 @@ fn generate_trait_methods<T: Service>(
                         -> Result<#res_message, prpc::server::Error>;
                 }
             }
+            (false, true) => {
+                quote! {
+                    #method_doc
+                    fn #name(&mut self, request: #req_message)
+                        -> Result<Vec<#res_message>, prpc::server::Error>;
+                }
+            }
             _ => {
-                panic!("Streaming RPC not supported");
+                panic!("Client streaming RPC not supported");
             }
         };
 
@@ -181,8 +188,14 @@ fn generate_methods<T: Service>(
                 method_ident,
                 server_trait,
             ),
+            (false, true) => generate_server_streaming(
+                method,
+                proto_path,
+                compile_well_known_types,
+                method_ident,
+            ),
             _ => {
-                panic!("Streaming RPC not supported");
+                panic!("Client streaming RPC not supported");
             }
         };
 
@@ -212,3 +225,18 @@ fn generate_unary<T: Method>(
         Ok(prpc::codec::encode_message_to_vec(&response))
     }
 }
+
+fn generate_server_streaming<T: Method>(
+    method: &T,
+    proto_path: &str,
+    compile_well_known_types: bool,
+    method_ident: Ident,
+) -> TokenStream {
+    let (request, _response) = method.request_response_name(proto_path, compile_well_known_types);
+
+    quote! {
+        let input: #request = prpc::Message::decode(data.as_ref())?;
+        let responses = self.inner.#method_ident(input)?;
+        Ok(prpc::codec::encode_stream_to_vec(&responses))
+    }
+}
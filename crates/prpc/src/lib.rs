@@ -109,4 +109,64 @@ pub mod codec {
         msg.encode_raw(&mut buf);
         buf
     }
+
+    /// Encodes the messages of a server streaming response, each prefixed with its length.
+    ///
+    /// A server streaming RPC is served in rounds: each call returns the messages the server has
+    /// ready, and the client resumes the stream with the cursor carried by its next request.
+    pub fn encode_stream_to_vec<M: Message>(msgs: &[M]) -> Vec<u8> {
+        let len = msgs
+            .iter()
+            .map(|msg| {
+                let len = msg.encoded_len();
+                prost::length_delimiter_len(len) + len
+            })
+            .sum();
+        let mut buf = Vec::with_capacity(len);
+        for msg in msgs {
+            // Never fails since the buffer grows as needed
+            let _ = msg.encode_length_delimited(&mut buf);
+        }
+        buf
+    }
+
+    /// Decodes the messages of a server streaming response.
+    pub fn decode_stream<M: Message + Default>(mut buf: &[u8]) -> Result<Vec<M>, DecodeError> {
+        let mut msgs = Vec::new();
+        while !buf.is_empty() {
+            msgs.push(M::decode_length_delimited(&mut buf)?);
+        }
+        Ok(msgs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Message, PartialEq)]
+    struct Event {
+        #[prost(uint64, tag = "1")]
+        sequence: u64,
+        #[prost(bytes, tag = "2")]
+        data: Vec<u8>,
+    }
+
+    #[test]
+    fn streams_round_trip() {
+        let events = alloc::vec![
+            Event {
+                sequence: 0,
+                data: alloc::vec![],
+            },
+            Event {
+                sequence: 1,
+                data: alloc::vec![1, 2, 3],
+            },
+        ];
+        let buf = codec::encode_stream_to_vec(&events);
+        assert_eq!(codec::decode_stream::<Event>(&buf).unwrap(), events);
+        assert!(codec::decode_stream::<Event>(&[]).unwrap().is_empty());
+        assert!(codec::decode_stream::<Event>(&buf[..buf.len() - 1]).is_err());
+    }
 }
@@ -30,7 +30,7 @@ frame_support::construct_runtime!(
 		Balances: pallet_balances::{Pallet, Call, Storage, Config<T>, Event<T>},
 		Bridge: bridge::{Pallet, Call, Storage, Event<T>},
 		BridgeTransfer: bridge_transfer::{Pallet, Call, Storage, Event<T>},
-		PhalaMq: mq::{Pallet, Call, Storage},
+		PhalaMq: mq::{Pallet, Call, Storage, Event},
 		PhalaRegistry: reg::{Pallet, Call, Event, Storage},
 		Timestamp: pallet_timestamp::{Pallet, Call, Storage, Inherent},
 	}
@@ -73,6 +73,7 @@ impl frame_system::Config for Test {
 
 parameter_types! {
 	pub const ExistentialDeposit: u64 = 1;
+	pub const MaxDeadLetters: u32 = 16;
 }
 
 ord_parameter_types! {
@@ -121,8 +122,11 @@ impl Config for Test {
 }
 
 impl mq::Config for Test {
+	type Event = Event;
 	type CallMatcher = MqCallMatcher;
 	type QueueNotifyConfig = ();
+	type MaxDeadLetters = MaxDeadLetters;
+	type RemoteOrigin = frame_system::EnsureNever<phala_types::messaging::MultiLocation>;
 }
 
 pub struct MqCallMatcher;
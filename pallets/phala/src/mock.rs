@@ -33,11 +33,12 @@ frame_support::construct_runtime!(
 		Timestamp: pallet_timestamp::{Pallet, Call, Storage, Inherent},
 		Balances: pallet_balances::{Pallet, Call, Storage, Config<T>, Event<T>},
 		// Pallets to test
-		PhalaMq: mq::{Pallet, Call},
+		PhalaMq: mq::{Pallet, Call, Event},
 		PhalaRegistry: registry::{Pallet, Event, Storage, Config<T>},
 		PhalaMining: mining::{Pallet, Event<T>, Storage, Config},
 		PhalaStakePool: stakepool::{Pallet, Event<T>},
 		PhalaOneshotTransfer: ott::{Pallet, Event<T>},
+		MockXcm: mock_xcm::{Pallet, Origin},
 	}
 );
 
@@ -55,6 +56,7 @@ parameter_types! {
 	pub const MaxPoolWorkers: u32 = 10;
 	pub const VerifyPRuntime: bool = false;
 	pub const VerifyRelaychainGenesisBlockHash: bool = true;
+	pub const MaxDeadLetters: u32 = 2;
 }
 impl system::Config for Test {
 	type BaseCallFilter = frame_support::traits::Everything;
@@ -104,9 +106,70 @@ impl pallet_timestamp::Config for Test {
 pub const DOLLARS: Balance = 1_000_000_000_000;
 pub const CENTS: Balance = DOLLARS / 100;
 
+thread_local! {
+	/// Whether `MockQueueNotifyConfig` fails the messages
+	pub static FAIL_MESSAGES: std::cell::Cell<bool> = std::cell::Cell::new(false);
+}
+
+pub struct MockQueueNotifyConfig;
+impl mq::QueueNotifyConfig for MockQueueNotifyConfig {
+	fn on_message_received(_message: &Message) -> frame_support::dispatch::DispatchResult {
+		if FAIL_MESSAGES.with(|fail| fail.get()) {
+			Err(sp_runtime::DispatchError::Other("Mock failure"))
+		} else {
+			Ok(())
+		}
+	}
+}
+
 impl mq::Config for Test {
-	type QueueNotifyConfig = ();
+	type Event = Event;
+	type QueueNotifyConfig = MockQueueNotifyConfig;
 	type CallMatcher = MqCallMatcher;
+	type MaxDeadLetters = MaxDeadLetters;
+	type RemoteOrigin = mock_xcm::EnsureXcm;
+}
+
+impl mock_xcm::Config for Test {}
+
+/// Provides the origin of the remote locations, like `pallet_xcm` does
+#[frame_support::pallet]
+pub mod mock_xcm {
+	use frame_support::pallet_prelude::*;
+	use frame_system::pallet_prelude::*;
+	use phala_types::messaging::MultiLocation;
+
+	#[pallet::config]
+	pub trait Config: frame_system::Config {}
+
+	#[pallet::pallet]
+	pub struct Pallet<T>(_);
+
+	#[pallet::hooks]
+	impl<T: Config> Hooks<BlockNumberFor<T>> for Pallet<T> {}
+
+	#[pallet::call]
+	impl<T: Config> Pallet<T> {}
+
+	/// The origin of a call sent by a remote location
+	#[pallet::origin]
+	#[derive(PartialEq, Eq, Clone, RuntimeDebug, Encode, Decode)]
+	pub struct Origin(pub MultiLocation);
+
+	/// Ensures the call is sent by a remote location, returning the location
+	pub struct EnsureXcm;
+	impl<O: Into<Result<Origin, O>> + From<Origin>> EnsureOrigin<O> for EnsureXcm {
+		type Success = MultiLocation;
+
+		fn try_origin(outer: O) -> Result<MultiLocation, O> {
+			outer.into().map(|Origin(location)| location)
+		}
+
+		#[cfg(feature = "runtime-benchmarks")]
+		fn successful_origin() -> O {
+			O::from(Origin(MultiLocation::sibling(2000, None)))
+		}
+	}
 }
 
 pub struct MqCallMatcher;
@@ -228,6 +291,7 @@ pub fn take_messages() -> Vec<Message> {
 	let messages = PhalaMq::messages();
 	println!("messages(): {:?}", messages);
 	mq::OutboundMessages::<Test>::kill();
+	mq::OutboundMessageInfos::<Test>::kill();
 	messages
 }
 
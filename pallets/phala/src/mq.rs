@@ -11,15 +11,28 @@ pub mod pallet {
 	use frame_system::pallet_prelude::*;
 
 	use phala_types::messaging::{
-		BindTopic, CommandPayload, ContractCommand, Message, MessageOrigin, Path, SignedMessage,
+		BindTopic, CommandPayload, ContractCommand, Message, MessageOrigin, MultiLocation, Path,
+		SignedMessage, Topic,
 	};
 	use primitive_types::H256;
-	use sp_std::vec::Vec;
+	use sp_runtime::traits::UniqueSaturatedInto;
+	use sp_std::{vec, vec::Vec};
+
+	/// The max number of messages synced by `sync_offchain_messages` at a time
+	pub const MAX_SYNC_BATCH_SIZE: usize = 128;
 
 	#[pallet::config]
 	pub trait Config: frame_system::Config + crate::registry::Config {
+		type Event: From<Event> + IsType<<Self as frame_system::Config>::Event>;
 		type QueueNotifyConfig: QueueNotifyConfig;
 		type CallMatcher: CallMatcher<Self>;
+
+		/// The max number of dead letters kept, the oldest ones are purged to make room
+		#[pallet::constant]
+		type MaxDeadLetters: Get<u32>;
+
+		/// The origin of the messages from the remote locations, e.g. the XCM origin
+		type RemoteOrigin: EnsureOrigin<Self::Origin, Success = MultiLocation>;
 	}
 
 	const STORAGE_VERSION: StorageVersion = StorageVersion::new(0);
@@ -43,11 +56,67 @@ pub mod pallet {
 	#[pallet::getter(fn messages)]
 	pub type OutboundMessages<T> = StorageValue<_, Vec<Message>, ValueQuery>;
 
+	/// How an outbound message got through the chain
+	#[derive(Encode, Decode, Clone, PartialEq, Eq, RuntimeDebug)]
+	pub struct OutboundMessageInfo {
+		/// The ingress sequence of the message, if it was synced from off-chain
+		pub sequence: Option<u64>,
+		/// The id of the dead letter, if the message failed to be handled on chain
+		pub dead_letter: Option<u64>,
+	}
+
+	/// The info of the outbound messages at the current block, in the same order.
+	///
+	/// It will be cleared at the beginning of every block.
+	#[pallet::storage]
+	pub type OutboundMessageInfos<T> = StorageValue<_, Vec<OutboundMessageInfo>, ValueQuery>;
+
+	/// A message failed to be handled on chain
+	#[derive(Encode, Decode, Clone, PartialEq, Eq, RuntimeDebug)]
+	pub struct DeadLetter<BlockNumber> {
+		pub message: Message,
+		/// The error of the last attempt to handle the message
+		pub error: DispatchError,
+		/// The block of the last attempt to handle the message
+		pub block: BlockNumber,
+	}
+
+	/// The messages failed to be handled on chain, by id
+	#[pallet::storage]
+	pub type DeadLetters<T: Config> = StorageMap<_, Twox64Concat, u64, DeadLetter<T::BlockNumber>>;
+
+	/// The id of the next dead letter
+	#[pallet::storage]
+	pub type NextDeadLetterId<T> = StorageValue<_, u64, ValueQuery>;
+
+	/// The ids of the dead letters, from the oldest one
+	///
+	/// It holds at most `MaxDeadLetters` ids.
+	#[pallet::storage]
+	pub type DeadLetterIds<T> = StorageValue<_, Vec<u64>, ValueQuery>;
+
+	#[pallet::event]
+	#[pallet::generate_deposit(pub(super) fn deposit_event)]
+	pub enum Event {
+		/// A message failed to be handled and is kept as a dead letter. \[id, sender, error\]
+		MessageFailed(u64, MessageOrigin, DispatchError),
+		/// A message expired before it was handled. \[sender\]
+		MessageExpired(MessageOrigin),
+		/// A dead letter was handled successfully on retry. \[id\]
+		DeadLetterRetried(u64),
+		/// A dead letter was purged. \[id\]
+		DeadLetterPurged(u64),
+	}
+
 	#[pallet::error]
 	pub enum Error<T> {
 		BadSender,
 		BadSequence,
 		BadDestination,
+		TooManyMessages,
+		UnknownDeadLetter,
+		MessageExpired,
+		LocationNotAllowed,
 	}
 
 	#[pallet::call]
@@ -64,31 +133,122 @@ pub mod pallet {
 		) -> DispatchResult {
 			ensure_signed(origin)?;
 
-			// Check sender
 			let sender = &signed_message.message.sender;
-			ensure!(sender.is_offchain(), Error::<T>::BadSender);
+			let expected_seq = OffchainIngress::<T>::get(sender).unwrap_or(0);
+			Self::check_offchain_message(&signed_message, expected_seq)?;
+			// Update ingress
+			OffchainIngress::<T>::insert(sender.clone(), expected_seq + 1);
+			// Call dispatch_message
+			Self::dispatch(signed_message.message, Some(signed_message.sequence));
+			Ok(())
+		}
 
-			// Check destination
+		/// Syncs a batch of unverified offchain messages to the message queue
+		///
+		/// The messages are checked like in `sync_offchain_message`, and accepted all or none for
+		/// each sender. The messages of a sender must continue its ingress sequence in order. A
+		/// sender failing the checks doesn't affect the others in the batch, and the call only
+		/// fails if no sender is accepted.
+		#[pallet::weight(
+			10_000 + (10_000 + T::DbWeight::get().writes(1)) * signed_messages.len() as Weight
+		)]
+		pub fn sync_offchain_messages(
+			origin: OriginFor<T>,
+			signed_messages: Vec<SignedMessage>,
+		) -> DispatchResult {
+			ensure_signed(origin)?;
 			ensure!(
-				signed_message.message.destination.is_valid(),
-				Error::<T>::BadDestination
+				signed_messages.len() <= MAX_SYNC_BATCH_SIZE,
+				Error::<T>::TooManyMessages
 			);
 
-			// Check ingress sequence
-			let expected_seq = OffchainIngress::<T>::get(sender).unwrap_or(0);
+			// Group the messages by sender, keeping their order
+			let mut batches: Vec<(MessageOrigin, Vec<SignedMessage>)> = Vec::new();
+			for signed_message in signed_messages {
+				let sender = &signed_message.message.sender;
+				match batches.iter_mut().find(|(s, _)| s == sender) {
+					Some((_, batch)) => batch.push(signed_message),
+					None => batches.push((sender.clone(), vec![signed_message])),
+				}
+			}
+
+			let mut accepted = false;
+			let mut first_error = None;
+			for (sender, batch) in batches {
+				let expected_seq = OffchainIngress::<T>::get(&sender).unwrap_or(0);
+				let checked = batch
+					.iter()
+					.enumerate()
+					.try_for_each(|(i, signed_message)| {
+						Self::check_offchain_message(signed_message, expected_seq + i as u64)
+					});
+				if let Err(err) = checked {
+					first_error.get_or_insert(err);
+					continue;
+				}
+				OffchainIngress::<T>::insert(&sender, expected_seq + batch.len() as u64);
+				for signed_message in batch {
+					Self::dispatch(signed_message.message, Some(signed_message.sequence));
+				}
+				accepted = true;
+			}
+			match first_error {
+				Some(err) if !accepted => Err(err),
+				_ => Ok(()),
+			}
+		}
+
+		/// Handles a dead letter again, removing it on success
+		///
+		/// On failure, the dead letter is kept with the new error.
+		#[pallet::weight(10_000 + T::DbWeight::get().writes(2))]
+		pub fn retry_dead_letter(origin: OriginFor<T>, id: u64) -> DispatchResult {
+			ensure_root(origin)?;
+			let mut letter = DeadLetters::<T>::get(id).ok_or(Error::<T>::UnknownDeadLetter)?;
 			ensure!(
-				signed_message.sequence == expected_seq,
-				Error::<T>::BadSequence
+				!letter.message.is_expired(Self::block_number()),
+				Error::<T>::MessageExpired
 			);
-			// Validate signature
-			crate::registry::Pallet::<T>::check_message(&signed_message)?;
-			// Update ingress
-			OffchainIngress::<T>::insert(sender.clone(), expected_seq + 1);
-			// Call dispatch_message
-			Self::dispatch_message(signed_message.message);
+			match T::QueueNotifyConfig::on_message_received(&letter.message) {
+				Ok(()) => {
+					Self::remove_dead_letter(id);
+					Self::deposit_event(Event::DeadLetterRetried(id));
+				}
+				Err(err) => {
+					letter.error = err;
+					letter.block = frame_system::Pallet::<T>::block_number();
+					DeadLetters::<T>::insert(id, letter);
+				}
+			}
+			Ok(())
+		}
+
+		/// Removes the dead letters
+		#[pallet::weight(10_000 + T::DbWeight::get().writes(ids.len() as Weight + 1))]
+		pub fn purge_dead_letters(origin: OriginFor<T>, ids: Vec<u64>) -> DispatchResult {
+			ensure_root(origin)?;
+			for id in ids {
+				if Self::remove_dead_letter(id) {
+					Self::deposit_event(Event::DeadLetterPurged(id));
+				}
+			}
 			Ok(())
 		}
 
+		/// Pushes a message from a remote location, e.g. received over XCM
+		///
+		/// The location and the destination are checked by
+		/// `QueueNotifyConfig::is_remote_message_allowed`.
+		#[pallet::weight(10_000 + T::DbWeight::get().writes(1))]
+		pub fn push_remote_message(
+			origin: OriginFor<T>,
+			destination: Vec<u8>,
+			payload: Vec<u8>,
+		) -> DispatchResult {
+			let location = T::RemoteOrigin::ensure_origin(origin)?;
+			Self::dispatch_remote_message(&location, destination, payload)
+		}
+
 		// Messaging API for end user.
 		// TODO.kevin: confirm the weight
 		#[pallet::weight(10_000 + T::DbWeight::get().writes(1))]
@@ -106,18 +266,76 @@ pub mod pallet {
 	}
 
 	impl<T: Config> Pallet<T> {
+		/// Checks an offchain message expected to have the sequence
+		fn check_offchain_message(
+			signed_message: &SignedMessage,
+			expected_seq: u64,
+		) -> DispatchResult {
+			// Check sender
+			let sender = &signed_message.message.sender;
+			ensure!(sender.is_offchain(), Error::<T>::BadSender);
+
+			// Check destination
+			ensure!(
+				signed_message.message.destination.is_valid(),
+				Error::<T>::BadDestination
+			);
+
+			// Check ingress sequence
+			ensure!(
+				signed_message.sequence == expected_seq,
+				Error::<T>::BadSequence
+			);
+			// Validate signature
+			crate::registry::Pallet::<T>::check_message(signed_message)
+		}
+
 		/// Push a validated message to the queue
+		///
+		/// The message is dropped if expired. If it fails to be handled on chain, it's kept as a
+		/// dead letter, but still pushed to the off-chain components.
 		pub fn dispatch_message(message: Message) {
-			// Notify subcribers
-			if let Err(_err) = T::QueueNotifyConfig::on_message_received(&message) {
-				// TODO: Consider to emit a message as warning. We can't stop dispatching message in any situation.
+			Self::dispatch(message, None)
+		}
+
+		/// Push a validated message with its ingress sequence, if synced from off-chain
+		fn dispatch(message: Message, sequence: Option<u64>) {
+			if message.is_expired(Self::block_number()) {
+				Self::deposit_event(Event::MessageExpired(message.sender));
+				return;
 			}
+			// Notify subcribers
+			let dead_letter = match T::QueueNotifyConfig::on_message_received(&message) {
+				Ok(()) => None,
+				// We can't stop dispatching message in any situation.
+				Err(err) => Some(Self::record_dead_letter(message.clone(), err)),
+			};
 			// Notify the off-chain components
 			if T::QueueNotifyConfig::should_push_message(&message) {
 				OutboundMessages::<T>::append(message);
+				OutboundMessageInfos::<T>::append(OutboundMessageInfo {
+					sequence,
+					dead_letter,
+				});
 			}
 		}
 
+		/// Push a message from a remote location, checked like in `push_remote_message`
+		pub fn dispatch_remote_message(
+			location: &MultiLocation,
+			destination: impl Into<Path>,
+			payload: Vec<u8>,
+		) -> DispatchResult {
+			let destination = Topic::new(destination);
+			ensure!(
+				T::QueueNotifyConfig::is_remote_message_allowed(location, &destination),
+				Error::<T>::LocationNotAllowed
+			);
+			let message = Message::new(MessageOrigin::location(location), destination, payload);
+			Self::dispatch_message(message);
+			Ok(())
+		}
+
 		pub fn push_message_to<M: Encode>(
 			topic: impl Into<Path>,
 			sender: MessageOrigin,
@@ -139,6 +357,47 @@ pub mod pallet {
 		pub fn offchain_ingress(sender: &MessageOrigin) -> Option<u64> {
 			OffchainIngress::<T>::get(sender)
 		}
+
+		fn block_number() -> u32 {
+			frame_system::Pallet::<T>::block_number().unique_saturated_into()
+		}
+
+		/// Keeps the message as a dead letter, returning its id
+		fn record_dead_letter(message: Message, error: DispatchError) -> u64 {
+			let mut ids = DeadLetterIds::<T>::get();
+			if ids.len() as u32 >= T::MaxDeadLetters::get() {
+				let oldest = ids.remove(0);
+				DeadLetters::<T>::remove(oldest);
+				Self::deposit_event(Event::DeadLetterPurged(oldest));
+			}
+			let id = NextDeadLetterId::<T>::mutate(|next| {
+				let id = *next;
+				*next += 1;
+				id
+			});
+			let sender = message.sender.clone();
+			DeadLetters::<T>::insert(
+				id,
+				DeadLetter {
+					message,
+					error,
+					block: frame_system::Pallet::<T>::block_number(),
+				},
+			);
+			ids.push(id);
+			DeadLetterIds::<T>::put(ids);
+			Self::deposit_event(Event::MessageFailed(id, sender, error));
+			id
+		}
+
+		/// Removes a dead letter, returning if it existed
+		fn remove_dead_letter(id: u64) -> bool {
+			if DeadLetters::<T>::take(id).is_none() {
+				return false;
+			}
+			DeadLetterIds::<T>::mutate(|ids| ids.retain(|&live| live != id));
+			true
+		}
 	}
 
 	#[pallet::hooks]
@@ -146,6 +405,7 @@ pub mod pallet {
 		fn on_initialize(_now: BlockNumberFor<T>) -> Weight {
 			// Clear the previously pushed offchain messages
 			OutboundMessages::<T>::kill();
+			OutboundMessageInfos::<T>::kill();
 
 			// Send out queued message from the previous block
 			if let Some(msgs) = QueuedOutboundMessage::<T>::take() {
@@ -167,6 +427,12 @@ pub mod pallet {
 		fn on_message_received(_message: &Message) -> DispatchResult {
 			Ok(())
 		}
+		/// If true, the remote location can send the message to the destination
+		///
+		/// By default, the sibling parachains can send messages to the off-chain components.
+		fn is_remote_message_allowed(location: &MultiLocation, destination: &Topic) -> bool {
+			location.sibling_parachain().is_some() && destination.is_offchain()
+		}
 	}
 	impl QueueNotifyConfig for () {}
 
@@ -237,3 +503,213 @@ pub mod pallet {
 /// Provides `SignedExtension` to check message sequence.
 mod check_seq;
 pub use check_seq::{tag, CheckMqSequence};
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::mock::{
+		mock_xcm, new_test_ext, set_block_1, take_events, take_messages, Event as TestEvent,
+		Origin, PhalaMq, Test, FAIL_MESSAGES,
+	};
+	use frame_support::{assert_noop, assert_ok};
+	use phala_types::messaging::{
+		Junction, Message, MessageOrigin, MultiLocation, SignedMessage, Topic,
+	};
+	use sp_core::{sr25519, Pair};
+	use sp_runtime::DispatchError;
+
+	fn signed_message(key: &sr25519::Pair, sequence: u64) -> SignedMessage {
+		let mut signed_message = SignedMessage {
+			message: Message::new(
+				MessageOrigin::Worker(key.public()),
+				Topic::new(*b"topic"),
+				Vec::new(),
+			),
+			sequence,
+			signature: Vec::new(),
+		};
+		signed_message.signature = key.sign(&signed_message.data_be_signed()).0.to_vec();
+		signed_message
+	}
+
+	#[test]
+	fn batches_are_atomic_per_sender() {
+		new_test_ext().execute_with(|| {
+			let alice = sr25519::Pair::from_seed(&[1; 32]);
+			let bob = sr25519::Pair::from_seed(&[2; 32]);
+			let mut forged = signed_message(&bob, 1);
+			forged.signature = signed_message(&alice, 1).signature;
+			assert_ok!(PhalaMq::sync_offchain_messages(
+				Origin::signed(1),
+				vec![
+					signed_message(&alice, 0),
+					signed_message(&bob, 0),
+					signed_message(&alice, 1),
+					forged,
+				]
+			));
+			let ingress = |key: &sr25519::Pair| {
+				OffchainIngress::<Test>::get(MessageOrigin::Worker(key.public()))
+			};
+			assert_eq!(ingress(&alice), Some(2));
+			// The valid message of bob is not accepted without the forged one
+			assert_eq!(ingress(&bob), None);
+			assert_eq!(PhalaMq::messages().len(), 2);
+			let sequences: Vec<_> = OutboundMessageInfos::<Test>::get()
+				.into_iter()
+				.map(|info| info.sequence)
+				.collect();
+			assert_eq!(sequences, vec![Some(0), Some(1)]);
+
+			// Fails if no sender is accepted
+			assert_noop!(
+				PhalaMq::sync_offchain_messages(Origin::signed(1), vec![signed_message(&alice, 0)]),
+				Error::<Test>::BadSequence
+			);
+			let too_many = (2..)
+				.take(MAX_SYNC_BATCH_SIZE + 1)
+				.map(|sequence| signed_message(&alice, sequence))
+				.collect();
+			assert_noop!(
+				PhalaMq::sync_offchain_messages(Origin::signed(1), too_many),
+				Error::<Test>::TooManyMessages
+			);
+		});
+	}
+	fn dead_letter_ids() -> Vec<u64> {
+		let mut ids: Vec<u64> = DeadLetters::<Test>::iter_keys().collect();
+		ids.sort_unstable();
+		ids
+	}
+
+	#[test]
+	fn failed_messages_become_dead_letters() {
+		new_test_ext().execute_with(|| {
+			set_block_1();
+			let sender = MessageOrigin::Gatekeeper;
+			let message = |i: u8| Message::new(sender.clone(), Topic::new(*b"topic"), vec![i]);
+			FAIL_MESSAGES.with(|fail| fail.set(true));
+			for i in 0..3 {
+				PhalaMq::dispatch_message(message(i));
+			}
+			// Still pushed to the off-chain components
+			let dead_letters: Vec<_> = OutboundMessageInfos::<Test>::get()
+				.into_iter()
+				.map(|info| info.dead_letter)
+				.collect();
+			assert_eq!(dead_letters, vec![Some(0), Some(1), Some(2)]);
+			assert_eq!(take_messages().len(), 3);
+			// The oldest one is purged to keep at most `MaxDeadLetters`
+			assert_eq!(dead_letter_ids(), vec![1, 2]);
+			let error = DispatchError::Other("Mock failure");
+			assert_eq!(
+				take_events(),
+				vec![
+					TestEvent::PhalaMq(Event::MessageFailed(0, sender.clone(), error)),
+					TestEvent::PhalaMq(Event::MessageFailed(1, sender.clone(), error)),
+					TestEvent::PhalaMq(Event::DeadLetterPurged(0)),
+					TestEvent::PhalaMq(Event::MessageFailed(2, sender.clone(), error)),
+				]
+			);
+
+			// Retrying a failing message keeps it
+			assert_noop!(
+				PhalaMq::retry_dead_letter(Origin::signed(1), 1),
+				DispatchError::BadOrigin
+			);
+			assert_ok!(PhalaMq::retry_dead_letter(Origin::root(), 1));
+			assert_eq!(dead_letter_ids(), vec![1, 2]);
+			FAIL_MESSAGES.with(|fail| fail.set(false));
+			assert_ok!(PhalaMq::retry_dead_letter(Origin::root(), 1));
+			assert_eq!(dead_letter_ids(), vec![2]);
+			assert_eq!(DeadLetterIds::<Test>::get(), vec![2]);
+			assert_ok!(PhalaMq::purge_dead_letters(Origin::root(), vec![0, 2]));
+			assert_eq!(dead_letter_ids(), Vec::<u64>::new());
+			assert_eq!(DeadLetterIds::<Test>::get(), Vec::<u64>::new());
+			assert_eq!(
+				take_events(),
+				vec![
+					TestEvent::PhalaMq(Event::DeadLetterRetried(1)),
+					TestEvent::PhalaMq(Event::DeadLetterPurged(2)),
+				]
+			);
+
+			// Expired messages are dropped
+			PhalaMq::dispatch_message(message(3).with_expiry(0));
+			assert!(take_messages().is_empty());
+			assert_eq!(
+				take_events(),
+				vec![TestEvent::PhalaMq(Event::MessageExpired(sender))]
+			);
+		});
+	}
+
+	#[test]
+	fn the_expiry_of_offchain_messages_is_signed() {
+		new_test_ext().execute_with(|| {
+			set_block_1();
+			let alice = sr25519::Pair::from_seed(&[1; 32]);
+			let sign = |mut signed_message: SignedMessage| {
+				signed_message.signature = alice.sign(&signed_message.data_be_signed()).0.to_vec();
+				signed_message
+			};
+			let mut expired = signed_message(&alice, 0);
+			expired.message = expired.message.with_expiry(0);
+			let expired = sign(expired);
+			// Can't be extended without the signature of the sender
+			let mut extended = expired.clone();
+			extended.message.expiry = Some(100);
+			assert_noop!(
+				PhalaMq::sync_offchain_message(Origin::signed(1), extended),
+				crate::registry::Error::<Test>::InvalidSignature
+			);
+			assert_ok!(PhalaMq::sync_offchain_message(Origin::signed(1), expired));
+			assert!(take_messages().is_empty());
+			let sender = MessageOrigin::Worker(alice.public());
+			assert_eq!(
+				take_events(),
+				vec![TestEvent::PhalaMq(Event::MessageExpired(sender))]
+			);
+		});
+	}
+	#[test]
+	fn sibling_parachains_can_send_messages() {
+		new_test_ext().execute_with(|| {
+			let xcm = |location: &MultiLocation| Origin::from(mock_xcm::Origin(location.clone()));
+			let location = MultiLocation::sibling(2000, vec![Junction::PalletInstance(3)]);
+			let command_topic = b"phala/contract/01/command".to_vec();
+			assert_ok!(PhalaMq::push_remote_message(
+				xcm(&location),
+				command_topic.clone(),
+				vec![1]
+			));
+			let messages = take_messages();
+			assert_eq!(messages.len(), 1);
+			assert_eq!(messages[0].sender.as_location(), Some(location.clone()));
+
+			// Only over XCM
+			assert_noop!(
+				PhalaMq::push_remote_message(Origin::root(), command_topic.clone(), vec![]),
+				DispatchError::BadOrigin
+			);
+			// Can't send to the on-chain destinations
+			assert_noop!(
+				PhalaMq::push_remote_message(
+					xcm(&location),
+					b"^phala/registry/event".to_vec(),
+					vec![]
+				),
+				Error::<Test>::LocationNotAllowed
+			);
+			// Nor from the relay chain
+			let relay_chain = MultiLocation {
+				parents: 1,
+				interior: vec![],
+			};
+			assert_noop!(
+				PhalaMq::push_remote_message(xcm(&relay_chain), command_topic, vec![]),
+				Error::<Test>::LocationNotAllowed
+			);
+		});
+	}
+}
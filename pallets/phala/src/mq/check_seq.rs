@@ -2,18 +2,20 @@ use super::{Call, CallMatcher, Config, IntoH256, OffchainIngress};
 
 use codec::{Decode, Encode};
 use frame_support::weights::DispatchInfo;
-use phala_types::messaging::MessageOrigin;
+use phala_types::messaging::{MessageOrigin, SignedMessage};
 use sp_runtime::traits::{DispatchInfoOf, Dispatchable, SignedExtension};
 use sp_runtime::transaction_validity::{
 	InvalidTransaction, TransactionValidity, TransactionValidityError, ValidTransaction,
 };
+use sp_std::collections::btree_map::BTreeMap;
 use sp_std::marker::PhantomData;
 use sp_std::vec;
 use sp_std::vec::Vec;
 
 /// Requires a message queue message must has correct sequence id.
 ///
-/// We only care about `sync_offchain_message` call.
+/// We only care about `sync_offchain_message` and `sync_offchain_messages` calls. A batch is
+/// checked by the first message of each sender in it.
 ///
 /// When a message comes to the transaction pool, we drop it immediately if its sequence is
 /// less than the expected one. Otherwise we keep the message in the pool for a while, hoping there
@@ -37,6 +39,26 @@ impl<T> CheckMqSequence<T> {
 	}
 }
 
+/// The sequences of the messages of each sender in the call, if it syncs offchain messages
+fn sequences<T: Config>(call: &T::Call) -> Option<BTreeMap<&MessageOrigin, Vec<u64>>>
+where
+	T::AccountId: IntoH256,
+{
+	let messages: Vec<&SignedMessage> = match T::CallMatcher::match_call(call)? {
+		Call::sync_offchain_message(signed_message) => vec![signed_message],
+		Call::sync_offchain_messages(signed_messages) => signed_messages.iter().collect(),
+		_ => return None,
+	};
+	let mut sequences: BTreeMap<&MessageOrigin, Vec<u64>> = BTreeMap::new();
+	for signed_message in messages {
+		sequences
+			.entry(&signed_message.message.sender)
+			.or_default()
+			.push(signed_message.sequence);
+	}
+	Some(sequences)
+}
+
 impl<T: Config> sp_std::fmt::Debug for CheckMqSequence<T> {
 	#[cfg(feature = "std")]
 	fn fmt(&self, f: &mut sp_std::fmt::Formatter) -> sp_std::fmt::Result {
@@ -72,21 +94,22 @@ where
 		_info: &DispatchInfoOf<Self::Call>,
 		_len: usize,
 	) -> Result<(), TransactionValidityError> {
-		let signed_message = match T::CallMatcher::match_call(call) {
-			Some(Call::sync_offchain_message(signed_message)) => signed_message,
+		let sequences = match sequences::<T>(call) {
+			Some(sequences) if !sequences.is_empty() => sequences,
 			_ => return Ok(()),
 		};
-		let sender = &signed_message.message.sender;
-		let sequence = signed_message.sequence;
-		let expected_seq = OffchainIngress::<T>::get(sender).unwrap_or(0);
-		// Strictly require the message to include must match the expected sequence id
-		if sequence != expected_seq {
-			return Err(if sequence < expected_seq {
-				InvalidTransaction::Stale
-			} else {
-				InvalidTransaction::Future
+		let mut all_stale = true;
+		for (sender, sequences) in sequences {
+			let sequence = sequences[0];
+			let expected_seq = OffchainIngress::<T>::get(sender).unwrap_or(0);
+			// Strictly require the message to include must match the expected sequence id
+			if sequence > expected_seq {
+				return Err(InvalidTransaction::Future.into());
 			}
-			.into());
+			all_stale &= sequence < expected_seq;
+		}
+		if all_stale {
+			return Err(InvalidTransaction::Stale.into());
 		}
 		Ok(())
 	}
@@ -98,26 +121,30 @@ where
 		_info: &DispatchInfoOf<Self::Call>,
 		_len: usize,
 	) -> TransactionValidity {
-		let signed_message = match T::CallMatcher::match_call(call) {
-			Some(Call::sync_offchain_message(signed_message)) => signed_message,
+		let sequences = match sequences::<T>(call) {
+			Some(sequences) if !sequences.is_empty() => sequences,
 			_ => return Ok(ValidTransaction::default()),
 		};
-		let sender = &signed_message.message.sender;
-		let sequence = signed_message.sequence;
-		let expected_seq = OffchainIngress::<T>::get(sender).unwrap_or(0);
-		// Drop the stale message immediately
-		if sequence < expected_seq {
+		let mut provides = vec![];
+		let mut requires = vec![];
+		for (sender, sequences) in sequences {
+			let sequence = sequences[0];
+			let expected_seq = OffchainIngress::<T>::get(sender).unwrap_or(0);
+			// Skip the stale messages
+			if sequence < expected_seq {
+				continue;
+			}
+			// Otherwise build a dependency graph based on (sender, sequence), hoping that it can
+			// be included later
+			provides.extend(sequences.iter().map(|sequence| tag(sender, *sequence)));
+			if sequence > expected_seq {
+				requires.push(tag(sender, sequence - 1));
+			}
+		}
+		// Drop the stale messages immediately
+		if provides.is_empty() {
 			return InvalidTransaction::Stale.into();
 		}
-
-		// Otherwise build a dependency graph based on (sender, sequence), hoping that it can be
-		// included later
-		let provides = vec![tag(sender, sequence)];
-		let requires = if sequence > expected_seq {
-			vec![tag(sender, sequence - 1)]
-		} else {
-			vec![]
-		};
 		Ok(ValidTransaction {
 			provides,
 			requires,
@@ -164,6 +191,43 @@ mod tests {
 		})
 	}
 
+	#[test]
+	fn test_check_mq_seq_of_batches() {
+		new_test_ext().execute_with(|| {
+			OffchainIngress::<Test>::insert(&MessageOrigin::Worker(worker_pubkey(1)), 1);
+			OffchainIngress::<Test>::insert(&MessageOrigin::Worker(worker_pubkey(2)), 2);
+			let info = DispatchInfo::default();
+			let len = 0_usize;
+			// all stale
+			let call = sync_batch_call(&[(1, 0), (2, 1)]);
+			assert_noop!(
+				extra().validate(&1, &call, &info, len),
+				InvalidTransaction::Stale
+			);
+			assert_noop!(
+				extra().pre_dispatch(&1, &call, &info, len),
+				InvalidTransaction::Stale
+			);
+			// partially stale
+			let call = sync_batch_call(&[(1, 0), (2, 2), (2, 3)]);
+			assert_eq!(
+				extra().validate(&1, &call, &info, len).unwrap().provides,
+				vec![tag(&worker(2), 2), tag(&worker(2), 3)]
+			);
+			assert_ok!(extra().pre_dispatch(&1, &call, &info, len));
+			// future
+			let call = sync_batch_call(&[(1, 1), (2, 3)]);
+			assert_eq!(
+				extra().validate(&1, &call, &info, len).unwrap().requires,
+				vec![tag(&worker(2), 2)]
+			);
+			assert_noop!(
+				extra().pre_dispatch(&1, &call, &info, len),
+				InvalidTransaction::Future
+			);
+		})
+	}
+
 	fn extra() -> CheckMqSequence<Test> {
 		CheckMqSequence::<Test>::new()
 	}
@@ -179,4 +243,20 @@ mod tests {
 			signature: Vec::new(),
 		}))
 	}
+
+	fn worker(i: u8) -> MessageOrigin {
+		MessageOrigin::Worker(worker_pubkey(i))
+	}
+
+	fn sync_batch_call(messages: &[(u8, u64)]) -> TestCall {
+		let messages = messages
+			.iter()
+			.map(|(i, seq)| SignedMessage {
+				message: Message::new(worker(*i), Topic::new(*b""), Vec::new()),
+				sequence: *seq,
+				signature: Vec::new(),
+			})
+			.collect();
+		TestCall::PhalaMq(Call::<Test>::sync_offchain_messages(messages))
+	}
 }
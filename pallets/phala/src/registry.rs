@@ -10,29 +10,35 @@ pub mod pallet {
 		traits::{StorageVersion, UnixTime},
 	};
 	use frame_system::pallet_prelude::*;
-	use sp_core::H256;
-	use sp_runtime::SaturatedConversion;
+	use sp_core::{ecdsa, ed25519, sr25519, H256};
+	use sp_runtime::{traits::Verify, AccountId32, MultiSignature, SaturatedConversion};
 	use sp_std::prelude::*;
-	use sp_std::{convert::TryFrom, vec};
+	use sp_std::vec;
 
 	use crate::attestation::{AttestationValidator, Error as AttestationError};
-	use crate::mq::MessageOriginInfo;
+	use crate::mq::{IntoH256, MessageOriginInfo};
 	// Re-export
 	pub use crate::attestation::{Attestation, IasValidator};
 
 	use phala_types::{
+		contract::ContractId32,
 		messaging::{
-			self, bind_topic, DecodedMessage, GatekeeperChange, GatekeeperLaunch, MessageOrigin,
-			SignedMessage, SystemEvent, WorkerEvent,
+			self, bind_topic, CommandReceiptBatch, ContractDeployment, DecodedMessage,
+			GatekeeperChange, GatekeeperLaunch, MessageOrigin, MessageSignature, SignedMessage,
+			SystemEvent, WorkerEvent,
 		},
 		ContractPublicKey, EcdhPublicKey, MasterPublicKey, WorkerPublicKey, WorkerRegistrationInfo,
 	};
 
+	/// The max length of the code of a confidential contract
+	pub const MAX_CODE_LEN: usize = 512 * 1024;
+
 	bind_topic!(RegistryEvent, b"^phala/registry/event");
 	#[derive(Encode, Decode, Clone, Debug)]
 	pub enum RegistryEvent {
 		BenchReport { start_time: u64, iterations: u64 },
 		MasterPubkey { master_pubkey: MasterPublicKey },
+		ContractPubkey { contract: H256, pubkey: ContractPublicKey },
 	}
 
 	#[pallet::config]
@@ -78,6 +84,19 @@ pub mod pallet {
 	#[pallet::storage]
 	pub type ContractKey<T> = StorageMap<_, Twox64Concat, H256, ContractPublicKey>;
 
+	/// Mapping from code hash to the code of confidential contracts
+	#[pallet::storage]
+	pub type ContractCode<T> = StorageMap<_, Twox64Concat, H256, Vec<u8>>;
+
+	/// Mapping from contract address to the deployment info
+	#[pallet::storage]
+	pub type Contracts<T: Config> = StorageMap<_, Twox64Concat, H256, ContractInfo<T::AccountId>>;
+
+	/// The native contracts enabled (true) or disabled (false) on all the workers, overriding the
+	/// contracts selected by each worker
+	#[pallet::storage]
+	pub type NativeContracts<T> = StorageMap<_, Twox64Concat, ContractId32, bool>;
+
 	/// Pubkey for secret topics.
 	#[pallet::storage]
 	pub type TopicKey<T> = StorageMap<_, Blake2_128Concat, Vec<u8>, Vec<u8>>;
@@ -104,6 +123,17 @@ pub mod pallet {
 	#[pallet::generate_deposit(pub(super) fn deposit_event)]
 	pub enum Event {
 		GatekeeperAdded(WorkerPublicKey),
+		/// A contract pubkey derived from the master key is published. \[contract, pubkey\]
+		ContractKeyPublished(H256, ContractPublicKey),
+		/// The code of a confidential contract is uploaded. \[code_hash\]
+		CodeUploaded(H256),
+		/// A confidential contract is deployed. \[contract, code_hash\]
+		ContractDeployed(H256, H256),
+		/// A contract reported the receipts of its commands. \[contract, receipts\]
+		CommandReceipts(H256, CommandReceiptBatch),
+		/// A native contract is enabled or disabled on chain, or left to the workers if None.
+		/// \[contract, enabled\]
+		NativeContractConfigured(ContractId32, Option<bool>),
 	}
 
 	#[pallet::error]
@@ -133,6 +163,11 @@ pub mod pallet {
 		InvalidMasterPubkey,
 		MasterKeyMismatch,
 		MasterKeyUninitialized,
+		ContractKeyMismatch,
+		// Contract deployment related
+		CodeNotFound,
+		ContractAlreadyExists,
+		CodeTooLarge,
 		// GenesisBlockHash related
 		GenesisBlockHashRejected,
 		GenesisBlockHashAlreadyExists,
@@ -147,6 +182,7 @@ pub mod pallet {
 	impl<T: Config> Pallet<T>
 	where
 		T: crate::mq::Config,
+		T::AccountId: IntoH256,
 	{
 		#[pallet::weight(10_000 + T::DbWeight::get().writes(1))]
 		pub fn force_set_benchmark_duration(origin: OriginFor<T>, value: u32) -> DispatchResult {
@@ -196,6 +232,82 @@ pub mod pallet {
 			Ok(())
 		}
 
+		/// Enables or disables a native contract on all the workers
+		///
+		/// The configuration overrides the contracts selected by each worker. With `enabled` set to
+		/// None, it's removed and each worker decides again.
+		#[pallet::weight(10_000 + T::DbWeight::get().writes(1))]
+		pub fn set_native_contract(
+			origin: OriginFor<T>,
+			contract: ContractId32,
+			enabled: Option<bool>,
+		) -> DispatchResult {
+			ensure_root(origin)?;
+			match enabled {
+				Some(enabled) => NativeContracts::<T>::insert(contract, enabled),
+				None => NativeContracts::<T>::remove(contract),
+			}
+			Self::deposit_event(Event::NativeContractConfigured(contract, enabled));
+			Ok(())
+		}
+
+		/// Uploads the code of a confidential contract
+		///
+		/// The code is identified by its blake2_256 hash, and can be deployed multiple times. The
+		/// weight grows with the code length, up to `MAX_CODE_LEN`.
+		#[pallet::weight(
+			10_000 + T::DbWeight::get().writes(1) + 10 * code.len().min(MAX_CODE_LEN) as Weight
+		)]
+		pub fn upload_code(origin: OriginFor<T>, code: Vec<u8>) -> DispatchResult {
+			ensure_signed(origin)?;
+			ensure!(code.len() <= MAX_CODE_LEN, Error::<T>::CodeTooLarge);
+			let code_hash = H256(crate::hashing::blake2_256(&code));
+			if !ContractCode::<T>::contains_key(&code_hash) {
+				ContractCode::<T>::insert(&code_hash, code);
+				Self::deposit_event(Event::CodeUploaded(code_hash));
+			}
+			Ok(())
+		}
+
+		/// Deploys a confidential contract from the uploaded code
+		///
+		/// The contract id is derived from the deployer and the `salt`. The workers instantiate
+		/// the contract with `init_args` once they receive the `ContractDeployed` system event.
+		#[pallet::weight(10_000 + T::DbWeight::get().writes(1))]
+		pub fn deploy_contract(
+			origin: OriginFor<T>,
+			code_hash: H256,
+			salt: Vec<u8>,
+			init_args: Vec<u8>,
+		) -> DispatchResult {
+			let deployer = ensure_signed(origin)?;
+			ensure!(
+				ContractCode::<T>::contains_key(&code_hash),
+				Error::<T>::CodeNotFound
+			);
+			let contract_id = Self::contract_id(&deployer, &salt);
+			ensure!(
+				!Contracts::<T>::contains_key(&contract_id),
+				Error::<T>::ContractAlreadyExists
+			);
+			Contracts::<T>::insert(
+				&contract_id,
+				ContractInfo {
+					deployer: deployer.clone(),
+					code_hash,
+					salt,
+				},
+			);
+			Self::push_message(SystemEvent::ContractDeployed(ContractDeployment {
+				contract_id,
+				deployer: deployer.into_h256(),
+				code_hash,
+				init_args,
+			}));
+			Self::deposit_event(Event::ContractDeployed(contract_id, code_hash));
+			Ok(())
+		}
+
 		/// Force register a topic pubkey
 		#[pallet::weight(10_000 + T::DbWeight::get().writes(1))]
 		pub fn force_register_topic_pubkey(
@@ -420,6 +532,11 @@ pub mod pallet {
 	where
 		T: crate::mq::Config,
 	{
+		/// Derives the id of a contract deployed by `deployer` with `salt`
+		pub fn contract_id(deployer: &T::AccountId, salt: &[u8]) -> H256 {
+			H256(crate::hashing::blake2_256(&(deployer, salt).encode()))
+		}
+
 		pub fn check_message(message: &SignedMessage) -> DispatchResult {
 			let pubkey_copy: ContractPublicKey;
 			let pubkey = match &message.message.sender {
@@ -441,14 +558,21 @@ pub mod pallet {
 
 		fn verify_signature(pubkey: &WorkerPublicKey, message: &SignedMessage) -> DispatchResult {
 			let raw_sig = &message.signature;
-			ensure!(raw_sig.len() == 64, Error::<T>::InvalidSignatureLength);
-			let sig = sp_core::sr25519::Signature::try_from(raw_sig.as_slice())
-				.or(Err(Error::<T>::MalformedSignature))?;
-			let data = message.data_be_signed();
+			// A bare sr25519 signature, or one tagged with its scheme
 			ensure!(
-				sp_io::crypto::sr25519_verify(&sig, &data, pubkey),
-				Error::<T>::InvalidSignature
+				(64..=66).contains(&raw_sig.len()),
+				Error::<T>::InvalidSignatureLength
 			);
+			let sig: MultiSignature =
+				match MessageSignature::parse(raw_sig).ok_or(Error::<T>::MalformedSignature)? {
+					MessageSignature::Sr25519(sig) => sr25519::Signature::from_raw(sig).into(),
+					MessageSignature::Ed25519(sig) => ed25519::Signature::from_raw(sig).into(),
+					MessageSignature::Ecdsa(sig) => ecdsa::Signature::from_raw(sig).into(),
+				};
+			let data = message.data_be_signed();
+			// The key identifies the signer as an `AccountId32` does, whatever the scheme
+			let signer = AccountId32::from(pubkey.0);
+			ensure!(sig.verify(&data[..], &signer), Error::<T>::InvalidSignature);
 			Ok(())
 		}
 
@@ -507,10 +631,35 @@ pub mod pallet {
 						}
 					}
 				}
+				RegistryEvent::ContractPubkey { contract, pubkey } => {
+					let gatekeepers = Gatekeeper::<T>::get();
+					if !gatekeepers.contains(worker_pubkey) {
+						return Err(Error::<T>::InvalidGatekeeper.into());
+					}
+
+					match ContractKey::<T>::try_get(&contract) {
+						Ok(saved_pubkey) => {
+							ensure!(saved_pubkey == pubkey, Error::<T>::ContractKeyMismatch);
+						}
+						_ => {
+							ContractKey::<T>::insert(contract, pubkey);
+							Self::deposit_event(Event::ContractKeyPublished(contract, pubkey));
+						}
+					}
+				}
 			}
 			Ok(())
 		}
 
+		pub fn on_receipts_received(message: DecodedMessage<CommandReceiptBatch>) -> DispatchResult {
+			let contract = match &message.sender {
+				MessageOrigin::Contract(id) => *id,
+				_ => return Err(Error::<T>::InvalidSender.into()),
+			};
+			Self::deposit_event(Event::CommandReceipts(contract, message.payload));
+			Ok(())
+		}
+
 		#[cfg(test)]
 		pub(crate) fn internal_set_benchmark(worker: &WorkerPublicKey, score: Option<u32>) {
 			Workers::<T>::mutate(worker, |w| {
@@ -636,7 +785,7 @@ pub mod pallet {
 	pub struct WorkerInfo<AccountId> {
 		// identity
 		pubkey: WorkerPublicKey,
-		ecdh_pubkey: EcdhPublicKey,
+		pub ecdh_pubkey: EcdhPublicKey,
 		// system
 		runtime_version: u32,
 		last_updated: u64,
@@ -648,6 +797,13 @@ pub mod pallet {
 		features: Vec<u32>,
 	}
 
+	#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+	pub struct ContractInfo<AccountId> {
+		pub deployer: AccountId,
+		pub code_hash: H256,
+		pub salt: Vec<u8>,
+	}
+
 	impl<T: Config> From<AttestationError> for Error<T> {
 		fn from(err: AttestationError) -> Self {
 			match err {
@@ -670,7 +826,8 @@ pub mod pallet {
 		use super::*;
 		use crate::mock::{
 			ecdh_pubkey, elapse_seconds, new_test_ext, set_block_1,
-			setup_relaychain_genesis_allowlist, worker_pubkey, Origin, Test,
+			setup_relaychain_genesis_allowlist, setup_workers, take_events, take_messages,
+			worker_pubkey, Event as TestEvent, Origin, Test,
 		};
 		// Pallets
 		use crate::mock::PhalaRegistry;
@@ -748,6 +905,212 @@ pub mod pallet {
 			});
 		}
 
+		#[test]
+		fn test_publish_contract_key() {
+			use phala_types::messaging::Topic;
+			new_test_ext().execute_with(|| {
+				set_block_1();
+				setup_workers(1);
+
+				let gatekeeper = WorkerPublicKey::from_raw([0u8; 32]);
+				let contract = H256::repeat_byte(1);
+				let publish = |sender: WorkerPublicKey, pubkey: ContractPublicKey| {
+					PhalaRegistry::on_message_received(DecodedMessage::<RegistryEvent> {
+						sender: MessageOrigin::Worker(sender),
+						destination: Topic::new(*b"^phala/registry/event"),
+						payload: RegistryEvent::ContractPubkey { contract, pubkey },
+					})
+				};
+
+				// Only gatekeepers can publish contract keys
+				assert_noop!(
+					publish(worker_pubkey(1), worker_pubkey(2)),
+					Error::<Test>::InvalidGatekeeper
+				);
+				assert_ok!(publish(gatekeeper, worker_pubkey(2)));
+				assert_eq!(ContractKey::<Test>::get(contract), Some(worker_pubkey(2)));
+				// Publishing the same key again is fine, but a different key is rejected
+				assert_ok!(publish(gatekeeper, worker_pubkey(2)));
+				assert_noop!(
+					publish(gatekeeper, worker_pubkey(3)),
+					Error::<Test>::ContractKeyMismatch
+				);
+			});
+		}
+
+		#[test]
+		fn test_set_native_contract() {
+			new_test_ext().execute_with(|| {
+				set_block_1();
+				assert_noop!(
+					PhalaRegistry::set_native_contract(Origin::signed(1), 7, Some(true)),
+					sp_runtime::DispatchError::BadOrigin
+				);
+				assert_ok!(PhalaRegistry::set_native_contract(
+					Origin::root(),
+					7,
+					Some(false)
+				));
+				assert_eq!(NativeContracts::<Test>::get(7), Some(false));
+				assert_ok!(PhalaRegistry::set_native_contract(Origin::root(), 7, None));
+				assert_eq!(NativeContracts::<Test>::get(7), None);
+				assert_eq!(
+					take_events().as_slice(),
+					[
+						TestEvent::PhalaRegistry(Event::NativeContractConfigured(7, Some(false))),
+						TestEvent::PhalaRegistry(Event::NativeContractConfigured(7, None)),
+					]
+				);
+			});
+		}
+
+		#[test]
+		fn test_deploy_contract() {
+			new_test_ext().execute_with(|| {
+				set_block_1();
+				let code = vec![0u8, 0x61, 0x73, 0x6d];
+				let code_hash = H256(crate::hashing::blake2_256(&code));
+
+				// The code must be uploaded before deployment
+				assert_noop!(
+					PhalaRegistry::deploy_contract(Origin::signed(1), code_hash, vec![1], vec![]),
+					Error::<Test>::CodeNotFound
+				);
+				assert_noop!(
+					PhalaRegistry::upload_code(Origin::signed(1), vec![0u8; MAX_CODE_LEN + 1]),
+					Error::<Test>::CodeTooLarge
+				);
+				assert_ok!(PhalaRegistry::upload_code(Origin::signed(1), code.clone()));
+				assert_eq!(ContractCode::<Test>::get(code_hash), Some(code));
+
+				let _ = take_messages();
+				assert_ok!(PhalaRegistry::deploy_contract(
+					Origin::signed(1),
+					code_hash,
+					vec![1],
+					vec![2, 3]
+				));
+				let contract_id = PhalaRegistry::contract_id(&1, &[1]);
+				assert_eq!(
+					Contracts::<Test>::get(contract_id),
+					Some(ContractInfo {
+						deployer: 1,
+						code_hash,
+						salt: vec![1],
+					})
+				);
+				let messages = take_messages();
+				let deployment = match messages.as_slice() {
+					[m] => m.decode_payload::<SystemEvent>(),
+					_ => panic!("Wrong messages"),
+				};
+				assert!(matches!(
+					deployment,
+					Some(SystemEvent::ContractDeployed(ContractDeployment {
+						contract_id: id,
+						code_hash: hash,
+						init_args,
+						..
+					})) if id == contract_id && hash == code_hash && init_args == vec![2, 3]
+				));
+
+				// The same deployer can't reuse the salt, but others can
+				assert_noop!(
+					PhalaRegistry::deploy_contract(Origin::signed(1), code_hash, vec![1], vec![]),
+					Error::<Test>::ContractAlreadyExists
+				);
+				assert_ok!(PhalaRegistry::deploy_contract(
+					Origin::signed(2),
+					code_hash,
+					vec![1],
+					vec![]
+				));
+				assert_ne!(PhalaRegistry::contract_id(&2, &[1]), contract_id);
+			});
+		}
+
+		#[test]
+		fn test_verify_signature_by_scheme() {
+			use phala_types::messaging::Message;
+			use sp_core::{crypto::Pair, hashing::blake2_256};
+			new_test_ext().execute_with(|| {
+				let mut message = SignedMessage {
+					message: Message::new(MessageOrigin::Gatekeeper, b"topic".to_vec(), vec![]),
+					sequence: 0,
+					signature: vec![],
+				};
+				let data = message.data_be_signed();
+				let mut verify = |pubkey: [u8; 32], signature: Vec<u8>| {
+					message.signature = signature;
+					PhalaRegistry::verify_signature(&WorkerPublicKey::from_raw(pubkey), &message)
+				};
+
+				// The sr25519 signatures are bare
+				let sr25519_pair = sr25519::Pair::from_seed(&[1; 32]);
+				let signature = sr25519_pair.sign(&data).0.to_vec();
+				assert_ok!(verify(sr25519_pair.public().0, signature));
+				// The others are tagged with the scheme
+				let ed25519_pair = ed25519::Pair::from_seed(&[1; 32]);
+				let signature = MessageSignature::Ed25519(ed25519_pair.sign(&data).0).encode();
+				assert_ok!(verify(ed25519_pair.public().0, signature.clone()));
+				assert_noop!(
+					verify(sr25519_pair.public().0, signature),
+					Error::<Test>::InvalidSignature
+				);
+				let ecdsa_pair = ecdsa::Pair::from_seed(&[1; 32]);
+				let signature = MessageSignature::Ecdsa(ecdsa_pair.sign(&data).0).encode();
+				let identity = blake2_256(ecdsa_pair.public().as_ref());
+				assert_ok!(verify(identity, signature));
+
+				assert_noop!(
+					verify(identity, vec![0; 63]),
+					Error::<Test>::InvalidSignatureLength
+				);
+				assert_noop!(
+					verify(identity, vec![9; 65]),
+					Error::<Test>::MalformedSignature
+				);
+			});
+		}
+
+		#[test]
+		fn test_receipts_reported_by_contracts() {
+			use phala_types::messaging::{CompactReceipt, Topic};
+			new_test_ext().execute_with(|| {
+				set_block_1();
+				let batch = CommandReceiptBatch {
+					block_number: 1,
+					receipts: vec![CompactReceipt {
+						sender: MessageOrigin::AccountId(H256::repeat_byte(1)),
+						sent_at: 1,
+						index: 0,
+						error: Some(vec![1]),
+					}],
+				};
+				let report = |sender: MessageOrigin| {
+					PhalaRegistry::on_receipts_received(DecodedMessage::<CommandReceiptBatch> {
+						sender,
+						destination: Topic::new(*b"^phala/contract/receipts"),
+						payload: batch.clone(),
+					})
+				};
+
+				assert_noop!(
+					report(MessageOrigin::Worker(worker_pubkey(1))),
+					Error::<Test>::InvalidSender
+				);
+				let contract = H256::repeat_byte(2);
+				assert_ok!(report(MessageOrigin::Contract(contract)));
+				assert_eq!(
+					take_events().as_slice(),
+					[TestEvent::PhalaRegistry(Event::CommandReceipts(
+						contract,
+						batch.clone()
+					))]
+				);
+			});
+		}
+
 		#[test]
 		fn test_pruntime_allowlist_works() {
 			new_test_ext().execute_with(|| {
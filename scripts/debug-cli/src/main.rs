@@ -47,6 +47,28 @@ enum Cli {
     DecodeGenesisBlockInfo {
         b64_data: String,
     },
+    DecodeContractMetadata {
+        #[structopt(short)]
+        hex_data: String,
+    },
+    /// Encodes a contract message given in JSON, according to the contract metadata
+    EncodeContractMessage {
+        /// The hex encoded contract metadata
+        #[structopt(long)]
+        metadata: String,
+        /// command, request or response
+        kind: MessageKind,
+        json: String,
+    },
+    /// Decodes a contract message into JSON, according to the contract metadata
+    DecodeContractMessage {
+        /// The hex encoded contract metadata
+        #[structopt(long)]
+        metadata: String,
+        /// command, request or response
+        kind: MessageKind,
+        hex_data: String,
+    },
     EcdhKey {
         privkey: String,
     },
@@ -62,12 +84,40 @@ enum Cli {
     }
 }
 
+#[derive(Debug)]
+enum MessageKind {
+    Command,
+    Request,
+    Response,
+}
+
+impl std::str::FromStr for MessageKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "command" => Ok(MessageKind::Command),
+            "request" => Ok(MessageKind::Request),
+            "response" => Ok(MessageKind::Response),
+            _ => Err(format!("Unknown message kind: {}", s)),
+        }
+    }
+}
+
 #[derive(Debug, StructOpt)]
 enum RpcCommand {
     GetWorkerState {
         pubkey: String,
     },
     GetInfo,
+    /// Shows the hops of the messages traced by pRuntime, started with `--trace-messages`
+    Trace {
+        /// The hex encoded `MessageOrigin` of the sender, e.g. `0x03<account id>` for a user
+        sender: String,
+        /// The sequence of the message, or all the traced messages of the sender if omitted
+        #[structopt(long)]
+        sequence: Option<u64>,
+    },
 }
 
 
@@ -155,6 +205,33 @@ async fn main() {
             let gi = phactory_api::blocks::GenesisBlockInfo::decode(&mut &data[..]).unwrap();
             println!("{:?}", gi);
         }
+        Cli::DecodeContractMetadata { hex_data } => {
+            use phactory_api::contract_metadata::ContractMetadata;
+            decode_hex_print::<ContractMetadata>(&hex_data);
+        }
+        Cli::EncodeContractMessage {
+            metadata,
+            kind,
+            json,
+        } => {
+            let (types, ty) = contract_message_type(&metadata, kind);
+            let value: serde_json::Value =
+                serde_json::from_str(&argument_or_stdin(&json)).expect("Failed to parse json");
+            let data = phactory_api::contract_metadata::json::encode(&types.registry, &ty, &value)
+                .expect("Failed to encode message");
+            println!("0x{}", hex::encode(data));
+        }
+        Cli::DecodeContractMessage {
+            metadata,
+            kind,
+            hex_data,
+        } => {
+            let (types, ty) = contract_message_type(&metadata, kind);
+            let data = decode_hex(&hex_data);
+            let value = phactory_api::contract_metadata::json::decode(&types.registry, &ty, &data)
+                .expect("Failed to decode message");
+            println!("{}", value);
+        }
         Cli::EcdhKey { privkey } => {
             use phala_crypto::ecdh;
 
@@ -196,6 +273,14 @@ async fn handle_rpc_command(command: RpcCommand, url: String) {
             let rv = client.get_info(()).await;
             print_result(rv);
         },
+        RpcCommand::Trace { sender, sequence } => {
+            use phala_types::messaging::MessageOrigin;
+            let sender = MessageOrigin::decode(&mut &decode_hex(&sender)[..])
+                .expect("Failed to decode sender");
+            let request = phactory_api::prpc::GetMessageTraceRequest::new(sender, sequence);
+            let rv = client.get_message_trace(request).await;
+            print_result(rv);
+        },
     }
 
 }
@@ -215,6 +300,28 @@ fn decode_hex_print<T: Decode + Debug>(hex_data: &str) -> T {
     message.unwrap()
 }
 
+fn contract_message_type(
+    metadata_hex: &str,
+    kind: MessageKind,
+) -> (
+    phactory_api::contract_metadata::MessageTypes,
+    phactory_api::contract_metadata::TypeId,
+) {
+    use phactory_api::contract_metadata::ContractMetadata;
+    let data = decode_hex(metadata_hex);
+    let metadata =
+        ContractMetadata::decode(&mut data.as_slice()).expect("Failed to decode metadata");
+    let types = metadata
+        .types
+        .expect("The contract doesn't describe its message types");
+    let ty = match kind {
+        MessageKind::Command => types.command,
+        MessageKind::Request => types.query_request,
+        MessageKind::Response => types.query_response,
+    };
+    (types, ty)
+}
+
 fn decode_b64(b64_str: &str) -> Vec<u8> {
     base64::decode(b64_str.trim()).expect("Failed to decode b64_data")
 }
@@ -178,6 +178,13 @@ struct Args {
     )]
     max_sync_msgs_per_round: u64,
 
+    #[structopt(
+        default_value = "65536",
+        long,
+        help = "Max size in bytes of the messages submitted in a single extrinsic"
+    )]
+    max_sync_batch_size: usize,
+
     #[structopt(long, help = "Enable geolocaltion report")]
     enable_geolocation: bool,
 
@@ -1123,6 +1130,7 @@ async fn bridge(args: Args) -> Result<()> {
                     args.tip,
                     args.longevity,
                     args.max_sync_msgs_per_round,
+                    args.max_sync_batch_size,
                 );
                 msg_sync.maybe_sync_mq_egress().await?;
             }
@@ -1,7 +1,11 @@
 use anyhow::{anyhow, Result};
+use codec::Encode;
 use core::marker::PhantomData;
 use log::{error, info};
+use phactory_api::prpc::EgressMessages;
+use phala_types::messaging::{MessageClass, MessageOrigin, SignedMessage};
 use sp_core::H256;
+use std::collections::{BTreeMap, VecDeque};
 use std::time::Duration;
 
 use crate::{
@@ -13,6 +17,11 @@ use subxt::Signer;
 
 use super::{chain_client::update_signer_nonce, runtimes, PrClient, SrSigner, XtClient};
 
+/// The max number of messages submitted in a single extrinsic.
+///
+/// Must not exceed `MAX_SYNC_BATCH_SIZE` of `pallet_mq`.
+const MAX_BATCH_MESSAGES: usize = 128;
+
 // TODO.kevin: This struct is no longer needed. Just use a simple function to do the job.
 /// Hold everything needed to sync some egress messages back to the blockchain
 pub struct MsgSync<'a> {
@@ -30,6 +39,8 @@ pub struct MsgSync<'a> {
     longevity: u64,
     /// Max number of messages to sync at a time.
     max_sync_msgs_per_round: u64,
+    /// Max size in bytes of the messages submitted in a single extrinsic.
+    max_sync_batch_size: usize,
 }
 
 impl<'a> MsgSync<'a> {
@@ -41,6 +52,7 @@ impl<'a> MsgSync<'a> {
         tip: u64,
         longevity: u64,
         max_sync_msgs_per_round: u64,
+        max_sync_batch_size: usize,
     ) -> Self {
         Self {
             client,
@@ -50,6 +62,7 @@ impl<'a> MsgSync<'a> {
             tip,
             longevity,
             max_sync_msgs_per_round,
+            max_sync_batch_size,
         }
     }
 
@@ -91,9 +104,8 @@ impl<'a> MsgSync<'a> {
             None
         };
 
-        let mut sync_msgs_count = 0;
-
-        'sync_outer: for (sender, messages) in messages {
+        let mut pending = vec![];
+        for (sender, messages) in messages {
             if messages.is_empty() {
                 continue;
             }
@@ -101,66 +113,78 @@ impl<'a> MsgSync<'a> {
 
             info!("Next seq for {} is {}", sender, min_seq);
 
-            for message in messages {
-                if message.sequence < min_seq {
-                    info!("{} has been submitted. Skipping...", message.sequence);
-                    continue;
-                }
-                let msg_info = format!(
-                    "sender={} seq={} dest={} nonce={:?}",
-                    sender,
-                    message.sequence,
-                    String::from_utf8_lossy(&message.message.destination.path()[..]),
-                    self.signer.nonce()
-                );
-                info!("Submitting message: {}", msg_info);
-                let extrinsic = self
-                    .client
-                    .create_signed(
-                        runtimes::phala_mq::SyncOffchainMessageCall {
-                            _runtime: PhantomData,
-                            message,
-                        },
-                        self.signer,
-                        ExtraConfig {
-                            tip: self.tip,
-                            era: era.clone(),
-                        },
-                    )
-                    .await;
-                self.signer.increment_nonce();
-                match extrinsic {
-                    Ok(extrinsic) => {
-                        let client = self.client.clone();
-                        tokio::spawn(async move {
-                            const TIMEOUT: u64 = 120;
-                            let fut = client.submit_extrinsic(extrinsic);
-                            let result =
-                                tokio::time::timeout(Duration::from_secs(TIMEOUT), fut).await;
-                            match result {
-                                Err(_) => {
-                                    error!("Submit message timed out: {}", msg_info);
-                                }
-                                Ok(Err(err)) => {
-                                    error!("Error submitting message {}: {:?}", msg_info, err);
-                                }
-                                Ok(Ok(hash)) => {
-                                    info!("Message submited: {} xt-hash={:?}", msg_info, hash);
-                                }
-                            }
-                        });
-                    }
-                    Err(err) => {
-                        panic!("Failed to sign the call: {:?}", err);
+            let messages = messages
+                .into_iter()
+                .filter(|message| {
+                    if message.sequence < min_seq {
+                        info!("{} has been submitted. Skipping...", message.sequence);
+                        return false;
                     }
+                    true
+                })
+                .collect();
+            pending.push((sender, messages));
+        }
+
+        let scheduled = schedule(pending, self.max_sync_msgs_per_round as usize);
+        let sync_msgs_count = scheduled.len();
+        for messages in batch(scheduled, self.max_sync_batch_size) {
+            let msg_info = format!(
+                "count={} first=({}) nonce={:?}",
+                messages.len(),
+                messages
+                    .first()
+                    .map(|message| format!(
+                        "sender={} seq={}",
+                        message.message.sender, message.sequence
+                    ))
+                    .unwrap_or_default(),
+                self.signer.nonce()
+            );
+            info!("Submitting messages: {}", msg_info);
+            let extrinsic = self
+                .client
+                .create_signed(
+                    runtimes::phala_mq::SyncOffchainMessagesCall {
+                        _runtime: PhantomData,
+                        messages,
+                    },
+                    self.signer,
+                    ExtraConfig {
+                        tip: self.tip,
+                        era: era.clone(),
+                    },
+                )
+                .await;
+            self.signer.increment_nonce();
+            match extrinsic {
+                Ok(extrinsic) => {
+                    let client = self.client.clone();
+                    tokio::spawn(async move {
+                        const TIMEOUT: u64 = 120;
+                        let fut = client.submit_extrinsic(extrinsic);
+                        let result = tokio::time::timeout(Duration::from_secs(TIMEOUT), fut).await;
+                        match result {
+                            Err(_) => {
+                                error!("Submit messages timed out: {}", msg_info);
+                            }
+                            Ok(Err(err)) => {
+                                error!("Error submitting messages {}: {:?}", msg_info, err);
+                            }
+                            Ok(Ok(hash)) => {
+                                info!("Messages submited: {} xt-hash={:?}", msg_info, hash);
+                            }
+                        }
+                    });
                 }
-                sync_msgs_count += 1;
-                if sync_msgs_count >= self.max_sync_msgs_per_round {
-                    info!("Synced {} messages, take a break", sync_msgs_count);
-                    break 'sync_outer;
+                Err(err) => {
+                    panic!("Failed to sign the call: {:?}", err);
                 }
             }
         }
+        if sync_msgs_count as u64 >= self.max_sync_msgs_per_round {
+            info!("Synced {} messages, take a break", sync_msgs_count);
+        }
         Ok(())
     }
 
@@ -175,3 +199,130 @@ impl<'a> MsgSync<'a> {
         Ok(())
     }
 }
+
+/// Picks up to `budget` messages to submit, by the priority of their senders.
+///
+/// The classes are served from the highest priority to the lowest. Within a class, the senders
+/// take turns, one message at a time, so a chatty sender can't use up the budget of the others.
+/// The messages of each sender keep their order.
+fn schedule(messages: EgressMessages, budget: usize) -> Vec<(MessageOrigin, SignedMessage)> {
+    let mut classes: BTreeMap<MessageClass, Vec<(MessageOrigin, VecDeque<SignedMessage>)>> =
+        BTreeMap::new();
+    for (sender, messages) in messages {
+        let class = sender.class();
+        classes
+            .entry(class)
+            .or_default()
+            .push((sender, messages.into()));
+    }
+    let mut scheduled = vec![];
+    for senders in classes.values_mut() {
+        loop {
+            let mut picked = false;
+            for (sender, messages) in senders.iter_mut() {
+                if scheduled.len() >= budget {
+                    return scheduled;
+                }
+                if let Some(message) = messages.pop_front() {
+                    scheduled.push((sender.clone(), message));
+                    picked = true;
+                }
+            }
+            if !picked {
+                break;
+            }
+        }
+    }
+    scheduled
+}
+
+/// Packs the messages into batches of up to `MAX_BATCH_MESSAGES` messages and `max_size` bytes,
+/// keeping their order.
+///
+/// A message larger than `max_size` gets a batch of its own.
+fn batch(
+    messages: Vec<(MessageOrigin, SignedMessage)>,
+    max_size: usize,
+) -> Vec<Vec<SignedMessage>> {
+    let mut batches: Vec<Vec<SignedMessage>> = vec![];
+    let mut size = 0;
+    for (_, message) in messages {
+        let message_size = message.encoded_size();
+        match batches.last_mut() {
+            Some(batch) if batch.len() < MAX_BATCH_MESSAGES && size + message_size <= max_size => {
+                size += message_size;
+                batch.push(message);
+            }
+            _ => {
+                size = message_size;
+                batches.push(vec![message]);
+            }
+        }
+    }
+    batches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use phala_types::messaging::Message;
+
+    fn messages(sender: &MessageOrigin, sequences: std::ops::Range<u64>) -> Vec<SignedMessage> {
+        sequences
+            .map(|sequence| SignedMessage {
+                message: Message::new(sender.clone(), b"topic".to_vec(), vec![]),
+                sequence,
+                signature: vec![],
+            })
+            .collect()
+    }
+
+    #[test]
+    fn messages_are_batched_by_size() {
+        let sender = MessageOrigin::native_contract(1);
+        let pending: Vec<_> = messages(&sender, 0..(MAX_BATCH_MESSAGES as u64 + 3))
+            .into_iter()
+            .map(|message| (sender.clone(), message))
+            .collect();
+        let size = pending[0].1.encoded_size();
+        let lens = |batches: Vec<Vec<SignedMessage>>| -> Vec<usize> {
+            batches.iter().map(|batch| batch.len()).collect()
+        };
+        assert_eq!(
+            lens(batch(pending.clone(), usize::MAX)),
+            vec![MAX_BATCH_MESSAGES, 3]
+        );
+        assert_eq!(lens(batch(pending[..5].to_vec(), size * 2)), vec![2, 2, 1]);
+        assert_eq!(lens(batch(pending[..2].to_vec(), 0)), vec![1, 1]);
+    }
+
+    #[test]
+    fn senders_are_scheduled_by_priority_and_in_turns() {
+        let chatty = MessageOrigin::native_contract(1);
+        let quiet = MessageOrigin::native_contract(2);
+        let gatekeeper = MessageOrigin::Gatekeeper;
+        let worker = MessageOrigin::Worker(Default::default());
+        let pending = vec![
+            (chatty.clone(), messages(&chatty, 0..10)),
+            (quiet.clone(), messages(&quiet, 5..7)),
+            (worker.clone(), messages(&worker, 3..4)),
+            (gatekeeper.clone(), messages(&gatekeeper, 0..2)),
+        ];
+        let scheduled: Vec<_> = schedule(pending, 7)
+            .into_iter()
+            .map(|(sender, message)| (sender, message.sequence))
+            .collect();
+        assert_eq!(
+            scheduled,
+            vec![
+                (worker, 3),
+                (gatekeeper.clone(), 0),
+                (gatekeeper, 1),
+                (chatty.clone(), 0),
+                (quiet.clone(), 5),
+                (chatty, 1),
+                (quiet, 6),
+            ]
+        );
+    }
+}
@@ -158,6 +158,12 @@ pub mod phala_mq {
         pub _runtime: PhantomData<T>,
         pub message: SignedMessage,
     }
+
+    #[derive(Clone, Debug, PartialEq, Call, Encode)]
+    pub struct SyncOffchainMessagesCall<T: PhalaMq> {
+        pub _runtime: PhantomData<T>,
+        pub messages: Vec<SignedMessage>,
+    }
 }
 
 pub mod mining_staking {
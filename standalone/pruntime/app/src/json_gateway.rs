@@ -0,0 +1,219 @@
+//! An opt-in JSON gateway to the contract queries, for development.
+//!
+//! The gateway acts as an anonymous client of `ContractQuery`: it encrypts the query to the
+//! contract key, or to the worker for the system contract, with an ephemeral ECDH key, and
+//! converts the request and the response between JSON and SCALE according to the message types
+//! in the contract metadata. The queries are not signed, so the contracts see no origin.
+
+use std::collections::BTreeMap;
+use std::convert::{TryFrom, TryInto};
+use std::sync::Mutex;
+
+use parity_scale_codec::{Decode, Encode};
+use phactory_api::contract_metadata::{json, MessageTypes};
+use phactory_api::crypto::{ecdh, EncryptedData};
+use phactory_api::prpc::{self, server::ProtoError, Message};
+use phactory_api::system::{Request as SystemRequest, Response as SystemResponse};
+use phala_types::contract::{self, ContractId, ContractQuery, ContractQueryHead};
+use rocket_contrib::json::{Json, JsonValue};
+use serde_json::Value;
+
+lazy_static! {
+    /// The message types of the contracts, fetched on the first query to each contract.
+    static ref MESSAGE_TYPES: Mutex<BTreeMap<ContractId, MessageTypes>> = Default::default();
+    /// The ecdh public keys of the contracts, fetched on the first query to each contract.
+    static ref CONTRACT_KEYS: Mutex<BTreeMap<ContractId, ecdh::EcdhPublicKey>> = Default::default();
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct JsonQuery {
+    /// The contract id, either a `ContractId32` number or a hex string.
+    pub contract: Value,
+    pub request: Value,
+}
+
+#[post("/json_query", format = "json", data = "<query>")]
+pub fn json_query(query: Json<JsonQuery>) -> JsonValue {
+    match handle_query(&query) {
+        Ok(payload) => json!({
+            "status": "ok",
+            "payload": payload
+        }),
+        Err(err) => json!({
+            "status": "error",
+            "payload": err
+        }),
+    }
+}
+
+fn handle_query(query: &JsonQuery) -> Result<Value, String> {
+    let contract = parse_contract_id(&query.contract)?;
+    let types = message_types(contract)?;
+    let request = json::encode(&types.registry, &types.query_request, &query.request)
+        .map_err(|err| format!("Failed to encode the request: {}", err))?;
+    let response = query_contract(contract, request)?;
+    json::decode(&types.registry, &types.query_response, &response)
+        .map_err(|err| format!("Failed to decode the response: {}", err))
+}
+
+fn parse_contract_id(value: &Value) -> Result<ContractId, String> {
+    match value {
+        Value::Number(n) => n
+            .as_u64()
+            .and_then(|n| u32::try_from(n).ok())
+            .map(contract::id256),
+        Value::String(s) => hex::decode(s.trim_start_matches("0x"))
+            .ok()
+            .filter(|bytes| bytes.len() == 32)
+            .map(|bytes| ContractId::from_slice(&bytes)),
+        _ => None,
+    }
+    .ok_or_else(|| format!("Invalid contract id: {}", value))
+}
+
+fn message_types(contract: ContractId) -> Result<MessageTypes, String> {
+    if let Some(types) = MESSAGE_TYPES.lock().unwrap().get(&contract) {
+        return Ok(types.clone());
+    }
+    let request = SystemRequest::ContractMetadata { contract }.encode();
+    let response = query_contract(contract::id256(contract::SYSTEM), request)?;
+    let metadata = match SystemResponse::decode(&mut &response[..]) {
+        Ok(SystemResponse::ContractMetadata(metadata)) => metadata,
+        Ok(SystemResponse::Error(err)) => return Err(err),
+        Ok(_) => return Err("Unexpected response to the metadata query".into()),
+        Err(err) => return Err(format!("Failed to decode the metadata: {:?}", err)),
+    };
+    let types = metadata.types.ok_or_else(|| {
+        format!(
+            "Contract {} doesn't describe its message types",
+            metadata.name
+        )
+    })?;
+    MESSAGE_TYPES
+        .lock()
+        .unwrap()
+        .insert(contract, types.clone());
+    Ok(types)
+}
+
+fn contract_ecdh_pubkey(contract: ContractId) -> Result<ecdh::EcdhPublicKey, String> {
+    if let Some(pubkey) = CONTRACT_KEYS.lock().unwrap().get(&contract) {
+        return Ok(*pubkey);
+    }
+    let request = SystemRequest::ContractKey { contract }.encode();
+    let response = query_contract(contract::id256(contract::SYSTEM), request)?;
+    let pubkey = match SystemResponse::decode(&mut &response[..]) {
+        Ok(SystemResponse::ContractKey(pubkey)) => pubkey.0,
+        Ok(SystemResponse::Error(err)) => return Err(err),
+        Ok(_) => return Err("Unexpected response to the key query".into()),
+        Err(err) => return Err(format!("Failed to decode the contract key: {:?}", err)),
+    };
+    CONTRACT_KEYS.lock().unwrap().insert(contract, pubkey);
+    Ok(pubkey)
+}
+
+/// Sends an unsigned query to the contract, returning the SCALE encoded response.
+///
+/// The queries to the system contract are encrypted to the worker, the others to the contract.
+fn query_contract(contract: ContractId, data: Vec<u8>) -> Result<Vec<u8>, String> {
+    let (remote_pubkey, contract_id) = if contract == contract::id256(contract::SYSTEM) {
+        (worker_ecdh_pubkey()?, vec![])
+    } else {
+        (
+            contract_ecdh_pubkey(contract)?,
+            contract.as_bytes().to_vec(),
+        )
+    };
+    let key = ecdh::EcdhKey::create(&rand::random())
+        .map_err(|err| format!("Failed to create ecdh key: {:?}", err))?;
+    let nonce: [u8; 32] = rand::random();
+    let query = ContractQuery {
+        head: ContractQueryHead {
+            id: contract,
+            nonce,
+        },
+        data: contract::Data(data),
+    };
+    let encrypted = EncryptedData::encrypt(&key, &remote_pubkey, rand::random(), &query.encode())
+        .map_err(|err| format!("Failed to encrypt the query: {:?}", err))?;
+
+    let request =
+        prpc::ContractQueryRequest::new(encrypted, None, contract_id, contract::QUERY_HEAD_V1);
+    let response: prpc::ContractQueryResponse = call("PhactoryAPI.ContractQuery", &request)?;
+    let data = response
+        .decode_encrypted_data()
+        .map_err(|err| format!("Failed to decode the response: {:?}", err))?
+        .decrypt(&key)
+        .map_err(|err| format!("Failed to decrypt the response: {:?}", err))?;
+
+    // The response is the nonce from the query followed by the encoded result
+    if data.len() < nonce.len() || data[..nonce.len()] != nonce[..] {
+        return Err("Nonce mismatch".into());
+    }
+    Ok(data[nonce.len()..].to_vec())
+}
+
+fn worker_ecdh_pubkey() -> Result<ecdh::EcdhPublicKey, String> {
+    let info: prpc::PhactoryInfo = call("PhactoryAPI.GetInfo", &())?;
+    info.ecdh_public_key
+        .and_then(|pubkey| hex::decode(pubkey).ok())
+        .and_then(|pubkey| pubkey.as_slice().try_into().ok())
+        .ok_or_else(|| "Runtime not initialized".into())
+}
+
+fn call<Resp: Message + Default>(method: &str, request: &impl Message) -> Result<Resp, String> {
+    let (status, body) = crate::call_prpc(method, &request.encode_to_vec())
+        .map_err(|err| format!("ECALL Enclave Failed {}", err.as_str()))?;
+    if status != 200 {
+        return Err(ProtoError::decode(&body[..])
+            .map(|err| err.message)
+            .unwrap_or_else(|_| format!("pRPC status code: {}", status)));
+    }
+    Resp::decode(&body[..]).map_err(|err| format!("Failed to decode {}: {}", method, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use phactory_api::contract_metadata::ContractMetadata;
+    use serde_json::json;
+
+    #[test]
+    fn metadata_drives_the_json_codec() {
+        let metadata = ContractMetadata {
+            name: "Echo".into(),
+            version: "0.1".into(),
+            owner: None,
+            types: Some(MessageTypes::of::<u32, (u8, bool), String>()),
+        };
+        let response = SystemResponse::ContractMetadata(metadata).encode();
+        let types = match SystemResponse::decode(&mut &response[..]) {
+            Ok(SystemResponse::ContractMetadata(metadata)) => metadata.types.unwrap(),
+            _ => panic!("Bad metadata response"),
+        };
+
+        let request = json::encode(&types.registry, &types.query_request, &json!([7, true]));
+        assert_eq!(request.unwrap(), (7u8, true).encode());
+        let response = json::decode(
+            &types.registry,
+            &types.query_response,
+            &String::from("pong").encode(),
+        );
+        assert_eq!(response.unwrap(), json!("pong"));
+    }
+
+    #[test]
+    fn contract_ids_are_numbers_or_hex() {
+        assert_eq!(
+            parse_contract_id(&json!(contract::SYSTEM)),
+            Ok(contract::id256(contract::SYSTEM))
+        );
+        let id = format!("0x{}", "01".repeat(32));
+        assert_eq!(
+            parse_contract_id(&json!(id)),
+            Ok(ContractId::repeat_byte(1))
+        );
+        assert!(parse_contract_id(&json!("0x01")).is_err());
+        assert!(parse_contract_id(&json!(-1)).is_err());
+    }
+}
@@ -37,6 +37,7 @@ extern crate ring_compat;
 mod attestation;
 mod contract_input;
 mod contract_output;
+mod json_gateway;
 
 use colored::Colorize;
 use sgx_types::*;
@@ -45,7 +46,9 @@ use sgx_urts::SgxEnclave;
 use std::env;
 use std::path;
 use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::RwLock;
+use std::time::{Duration, Instant};
 
 use rocket::data::Data;
 use rocket::http::Method;
@@ -73,6 +76,26 @@ struct Args {
     /// Run benchmark at startup.
     #[structopt(long)]
     init_bench: bool,
+
+    /// Native contracts to enable, or to disable when prefixed with `-`, separated by commas.
+    /// Applied on top of the default set installed in dev mode.
+    #[structopt(long, use_delimiter = true, allow_hyphen_values = true)]
+    native_contracts: Vec<String>,
+
+    /// Budgets of the contract queries in steps (about a wasm instruction each), separated by
+    /// commas. `steps` sets the default, `contract=steps` sets the budget of a contract.
+    #[structopt(long, use_delimiter = true)]
+    query_budget: Vec<String>,
+
+    /// Keep a write-ahead log of the egress message queue, so that the unsent messages survive a
+    /// restart.
+    #[structopt(long)]
+    persist_mq: bool,
+
+    /// Trace the hops of up to this many ingress messages, to be queried with the
+    /// `GetMessageTrace` RPC. 0 to disable the tracing.
+    #[structopt(long, default_value = "0")]
+    trace_messages: u32,
 }
 
 static ENCLAVE_FILE: &'static str = "enclave.signed.so";
@@ -80,6 +103,17 @@ static ENCLAVE_STATE_FILE: &'static str = "enclave.token";
 
 const ENCLAVE_OUTPUT_BUF_MAX_LEN: usize = 10 * 2048 * 1024 as usize;
 
+/// How long a long-polling `BatchContractQuery` is held waiting for its block.
+const LONG_POLL_TIMEOUT: Duration = Duration::from_secs(30);
+const LONG_POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// The max number of long-polling requests held at once.
+///
+/// Each of them occupies a Rocket worker, so the cap keeps some workers free for the other APIs.
+/// The requests over the cap are forwarded to the enclave without waiting.
+const MAX_LONG_POLL_WAITERS: usize = 4;
+
+static LONG_POLL_WAITERS: AtomicUsize = AtomicUsize::new(0);
+
 lazy_static! {
     static ref ENCLAVE: RwLock<Option<SgxEnclave>> = RwLock::new(None);
     static ref ENCLAVE_STATE_FILE_PATH: &'static str = {
@@ -93,6 +127,8 @@ lazy_static! {
         { env::var("ALLOW_CORS").unwrap_or_else(|_| "".to_string()) != "" };
     static ref ENABLE_KICK_API: bool =
         { env::var("ENABLE_KICK_API").unwrap_or_else(|_| "".to_string()) != "" };
+    static ref ENABLE_JSON_GATEWAY: bool =
+        { env::var("ENABLE_JSON_GATEWAY").unwrap_or_else(|_| "".to_string()) != "" };
 }
 
 fn destroy_enclave() {
@@ -461,20 +497,14 @@ fn kick() {
     std::process::exit(0);
 }
 
-#[post("/<method>", data = "<data>")]
-fn prpc_proxy(method: String, data: Data) -> Custom<Vec<u8>> {
+/// Calls a pRPC method of the enclave, returning the status code and the response body.
+fn call_prpc(method: &str, data: &[u8]) -> Result<(u16, Vec<u8>), sgx_status_t> {
     let eid = crate::get_eid();
 
     let path_bytes = method.as_bytes();
     let path_len = path_bytes.len();
     let path_ptr = path_bytes.as_ptr();
 
-    let data = match crate::read_data(data) {
-        Some(data) => data,
-        None => {
-            return Custom(Status::BadRequest, b"Read body failed".to_vec());
-        }
-    };
     let data_len = data.len();
     let data_ptr = data.as_ptr();
 
@@ -507,14 +537,76 @@ fn prpc_proxy(method: String, data: Data) -> Custom<Vec<u8>> {
     match result {
         crate::sgx_status_t::SGX_SUCCESS => {
             let output_slice = unsafe { std::slice::from_raw_parts(output_ptr, output_len) };
+            Ok((status_code, output_slice.to_vec()))
+        }
+        _ => Err(result),
+    }
+}
+
+/// Holds a long-polling `BatchContractQuery` until the enclave has synced past its `after_block`,
+/// or the poll times out. The enclave rejects the queries if the block is still not synced.
+fn wait_for_block(data: &[u8]) {
+    use prpc::Message as _;
+
+    struct Waiter;
+    impl Drop for Waiter {
+        fn drop(&mut self) {
+            LONG_POLL_WAITERS.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    let after_block = match prpc::BatchContractQueryRequest::decode(data) {
+        Ok(request) if request.after_block > 0 => request.after_block,
+        _ => return,
+    };
+    if LONG_POLL_WAITERS.fetch_add(1, Ordering::SeqCst) >= MAX_LONG_POLL_WAITERS {
+        LONG_POLL_WAITERS.fetch_sub(1, Ordering::SeqCst);
+        warn!(
+            "Too many long-polling queries, not waiting for block {}",
+            after_block
+        );
+        return;
+    }
+    let _waiter = Waiter;
+    let deadline = Instant::now() + LONG_POLL_TIMEOUT;
+    while Instant::now() < deadline {
+        let synced = match call_prpc("PhactoryAPI.GetInfo", &[]) {
+            // `blocknum` is the next block to dispatch
+            Ok((200, body)) => prpc::PhactoryInfo::decode(&body[..])
+                .map(|info| info.blocknum > after_block.saturating_add(1))
+                .unwrap_or(false),
+            _ => false,
+        };
+        if synced {
+            return;
+        }
+        thread::sleep(LONG_POLL_INTERVAL);
+    }
+}
+
+#[post("/<method>", data = "<data>")]
+fn prpc_proxy(method: String, data: Data) -> Custom<Vec<u8>> {
+    let data = match crate::read_data(data) {
+        Some(data) => data,
+        None => {
+            return Custom(Status::BadRequest, b"Read body failed".to_vec());
+        }
+    };
+
+    if method == "PhactoryAPI.BatchContractQuery" {
+        wait_for_block(&data);
+    }
+
+    match call_prpc(&method, &data) {
+        Ok((status_code, output)) => {
             if let Some(status) = Status::from_code(status_code) {
-                Custom(status, output_slice.to_vec())
+                Custom(status, output)
             } else {
                 error!("[-] prpc: Invalid status code: {}!", status_code);
                 Custom(Status::ServiceUnavailable, vec![])
             }
         }
-        _ => {
+        Err(result) => {
             error!("[-] ECALL Enclave Failed {}!", result.as_str());
             Custom(Status::ServiceUnavailable, vec![])
         }
@@ -582,6 +674,12 @@ fn rocket() -> rocket::Rocket {
         server = server.mount("/", routes![kick]);
     }
 
+    if *ENABLE_JSON_GATEWAY {
+        info!("ENABLE `json_query` API");
+
+        server = server.mount("/", routes![json_gateway::json_query]);
+    }
+
     server = server.mount("/prpc", routes![prpc_proxy]);
     print_rpc_methods("/prpc", prpc::phactory_api_server::supported_methods());
 
@@ -633,6 +731,10 @@ fn main() {
         init_bench: args.init_bench,
         version: env!("CARGO_PKG_VERSION").into(),
         git_revision: git_revision(),
+        native_contracts: args.native_contracts,
+        query_budgets: args.query_budget,
+        persist_mq: args.persist_mq,
+        trace_messages: args.trace_messages,
     };
     info!("init_args: {:#?}", init_args);
     let encoded_args = init_args.encode();
@@ -12,7 +12,7 @@ use sgx_tcrypto::*;
 use sgx_tse::*;
 use sgx_tstd::io::ErrorKind;
 use sgx_tstd::os::unix::prelude::OsStrExt as _;
-use sgx_tstd::sgxfs::{read as sgxfs_read, write as sgxfs_write};
+use sgx_tstd::sgxfs::{read as sgxfs_read, remove as sgxfs_remove, write as sgxfs_write};
 use sgx_types::*;
 use std::convert::TryFrom;
 
@@ -74,6 +74,12 @@ impl Sealing for SgxPlatform {
             }
         }
     }
+
+    fn remove_data(&self, path: impl AsRef<std::path::Path>) -> Result<(), Self::SealError> {
+        let path = to_tstd_path(path.as_ref());
+        Ok(sgxfs_remove(path)
+            .map_err(|err| anyhow!("Remove sealed data failed: {:?} path={:?}", err, path))?)
+    }
 }
 
 impl RA for SgxPlatform {
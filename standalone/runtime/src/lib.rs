@@ -135,7 +135,7 @@ pub const VERSION: RuntimeVersion = RuntimeVersion {
 	// and set impl_version to 0. If only runtime
 	// implementation changes and behavior does not, then leave spec_version as
 	// is and increment impl_version.
-	spec_version: 2,
+	spec_version: 3,
 	impl_version: 0,
 	apis: RUNTIME_API_VERSIONS,
 	transaction_version: 1,
@@ -1085,6 +1085,7 @@ parameter_types! {
 	pub const MaxPoolWorkers: u32 = 200;
 	pub const VerifyPRuntime: bool = false;
 	pub const VerifyRelaychainGenesisBlockHash: bool = false;
+	pub const MaxDeadLetters: u32 = 1024;
 }
 
 impl pallet_registry::Config for Runtime {
@@ -1096,8 +1097,12 @@ impl pallet_registry::Config for Runtime {
 	type GovernanceOrigin = EnsureRootOrHalfCouncil;
 }
 impl pallet_mq::Config for Runtime {
+	type Event = Event;
 	type QueueNotifyConfig = msg_routing::MessageRouteConfig;
 	type CallMatcher = MqCallMatcher;
+	type MaxDeadLetters = MaxDeadLetters;
+	// No XCM on the standalone chain. A parachain runtime takes the location from `EnsureXcm`.
+	type RemoteOrigin = frame_system::EnsureNever<phala_types::messaging::MultiLocation>;
 }
 impl pallet_mining::Config for Runtime {
 	type Event = Event;
@@ -1171,7 +1176,7 @@ construct_runtime!(
 		ChainBridge: pallet_bridge::{Pallet, Call, Storage, Event<T>},
 		BridgeTransfer: pallet_bridge_transfer::{Pallet, Call, Event<T>, Storage},
 		// Phala new pallets
-		PhalaMq: pallet_mq::{Pallet, Call, Storage},
+		PhalaMq: pallet_mq::{Pallet, Call, Storage, Event},
 		PhalaRegistry: pallet_registry::{Pallet, Call, Event, Storage, Config<T>},
 		PhalaMining: pallet_mining::{Pallet, Call, Event<T>, Storage, Config},
 		PhalaStakePool: pallet_stakepool::{Pallet, Call, Event<T>, Storage},
@@ -31,6 +31,7 @@ impl pallet_mq::QueueNotifyConfig for MessageRouteConfig {
 
         route_handlers! {
             PhalaRegistry::on_message_received,
+            PhalaRegistry::on_receipts_received,
             PhalaMining::on_gk_message_received,
             PhalaMining::on_mining_message_received,
             BridgeTransfer::on_message_received,